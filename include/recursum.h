@@ -0,0 +1,95 @@
+#ifndef RECURSUM_H
+#define RECURSUM_H
+
+/* Generated with cbindgen; do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Outcome of a call into the library.
+ */
+typedef enum RecursumStatus {
+  RECURSUM_STATUS_OK = 0,
+  /**
+   * A required pointer was null, or a path was not valid on this platform.
+   */
+  RECURSUM_STATUS_INVALID_ARGUMENT = 1,
+  /**
+   * The root could not be walked, e.g. because it is not a directory.
+   */
+  RECURSUM_STATUS_WALK_ERROR = 2,
+  /**
+   * The callback returned non-zero.
+   */
+  RECURSUM_STATUS_CANCELLED = 3,
+  /**
+   * An internal error; this is a bug.
+   */
+  RECURSUM_STATUS_PANIC = 4,
+} RecursumStatus;
+
+/**
+ * The result of hashing one file.
+ *
+ * Pointers are only valid for the duration of the callback.
+ */
+typedef struct RecursumFileResult {
+  /**
+   * NUL-terminated path of the file.
+   */
+  const char *path;
+  /**
+   * NUL-terminated hex digest, or null if the file could not be hashed.
+   */
+  const char *digest;
+  /**
+   * Size in bytes.
+   */
+  uint64_t size;
+  /**
+   * 0 if the file was hashed, otherwise the OS error code (or -1 if there was none).
+   */
+  int error;
+  /**
+   * False if the file changed while being hashed.
+   */
+  bool stable;
+} RecursumFileResult;
+
+/**
+ * Called once per file, in walk order. Return non-zero to stop hashing.
+ */
+typedef int (*RecursumCallback)(const struct RecursumFileResult *result, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Hash every file under the directory `root` on `threads` threads (0 for one per CPU),
+ * calling `callback` with each result and `user_data`.
+ *
+ * Files are walked in sorted depth-first order, including hidden files, without following symlinks.
+ *
+ * # Safety
+ *
+ * `root` must be a valid NUL-terminated string.
+ */
+enum RecursumStatus recursum_hash_tree(const char *root,
+                                       uint32_t threads,
+                                       RecursumCallback callback,
+                                       void *user_data);
+
+/**
+ * NUL-terminated version of the library, valid for the lifetime of the program.
+ */
+const char *recursum_version(void);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* RECURSUM_H */
@@ -0,0 +1,77 @@
+//! Which file each hashing thread is reading, for live displays of a run.
+//!
+//! Every file started and finished takes a lock, so this is only tracked if
+//! [HashOptions::activity](crate::HashOptions::activity) is set.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Worker {
+    /// Counting from 0, in the order threads first started a file.
+    number: usize,
+    reading: Option<(PathBuf, Instant)>,
+}
+
+#[derive(Debug, Default)]
+pub struct Activity {
+    workers: Mutex<HashMap<ThreadId, Worker>>,
+}
+
+/// What one hashing thread is doing.
+#[derive(Debug, Clone)]
+pub struct WorkerState {
+    pub number: usize,
+    /// The file being read and for how long, if any.
+    pub reading: Option<(PathBuf, Duration)>,
+}
+
+/// Marks the thread idle again when dropped.
+pub(crate) struct Reading<'a>(&'a Activity);
+
+impl Drop for Reading<'_> {
+    fn drop(&mut self) {
+        let mut workers = self.0.workers.lock().expect("activity lock poisoned");
+        if let Some(w) = workers.get_mut(&thread::current().id()) {
+            w.reading = None;
+        }
+    }
+}
+
+impl Activity {
+    /// Record that this thread has started reading `path`, until the returned guard is dropped.
+    pub(crate) fn start(&self, path: &Path) -> Reading<'_> {
+        let mut workers = self.workers.lock().expect("activity lock poisoned");
+        let number = workers.len();
+        workers
+            .entry(thread::current().id())
+            .or_insert(Worker {
+                number,
+                reading: None,
+            })
+            .reading = Some((path.to_path_buf(), Instant::now()));
+        Reading(self)
+    }
+
+    /// Every thread which has hashed a file, in order of their numbers.
+    pub fn workers(&self) -> Vec<WorkerState> {
+        let now = Instant::now();
+        let mut states: Vec<_> = self
+            .workers
+            .lock()
+            .expect("activity lock poisoned")
+            .values()
+            .map(|w| WorkerState {
+                number: w.number,
+                reading: w
+                    .reading
+                    .as_ref()
+                    .map(|(path, since)| (path.clone(), now.duration_since(*since))),
+            })
+            .collect();
+        states.sort_by_key(|w| w.number);
+        states
+    }
+}
@@ -0,0 +1,142 @@
+//! Totals grouped by file extension or by directory, for --aggregate:
+//! what is actually taking all the space and time, without a separate pass over the output.
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use recursum::sink::OutputSink;
+use recursum::FileHash;
+
+use crate::progress::HumanBytes;
+
+/// Group for files with no extension.
+const NO_EXTENSION: &str = "(none)";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    /// By lowercased extension, e.g. `jpg`.
+    Extension,
+    /// By the directory this many levels below the input directory.
+    Directory(usize),
+}
+
+impl FromStr for Aggregate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "ext" => Ok(Self::Extension),
+            None if s == "dir" => Ok(Self::Directory(1)),
+            Some(("dir", depth)) => match depth.parse() {
+                Ok(d) if d > 0 => Ok(Self::Directory(d)),
+                _ => Err(format!(
+                    "invalid depth '{}'; expected a positive integer",
+                    depth
+                )),
+            },
+            _ => Err(format!(
+                "unknown aggregate '{}'; expected ext, dir or dir:<depth>",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Group {
+    files: u64,
+    bytes: u64,
+    elapsed: Duration,
+}
+
+/// Collects totals per group, and prints them on stderr when finished, largest first.
+pub struct Aggregator {
+    by: Aggregate,
+    /// paths are grouped relative to this, if they are under it
+    root: Option<PathBuf>,
+    groups: HashMap<String, Group>,
+}
+
+impl Aggregator {
+    pub fn new(by: Aggregate, root: Option<PathBuf>) -> Self {
+        Self {
+            by,
+            root,
+            groups: HashMap::new(),
+        }
+    }
+
+    fn group(&self, path: &Path) -> String {
+        match self.by {
+            Aggregate::Extension => path
+                .extension()
+                .map(|e| e.to_string_lossy().to_lowercase())
+                .unwrap_or_else(|| NO_EXTENSION.to_string()),
+            Aggregate::Directory(depth) => {
+                let (base, rel) = match &self.root {
+                    Some(root) => match path.strip_prefix(root) {
+                        Ok(rel) => (root.as_path(), rel),
+                        Err(_) => (Path::new(""), path),
+                    },
+                    None => (Path::new(""), path),
+                };
+                let dirs = rel.parent().unwrap_or(Path::new(""));
+                let mut group = base.to_path_buf();
+                let mut taken = 0;
+                for c in dirs.components() {
+                    if taken == depth {
+                        break;
+                    }
+                    if let Component::Normal(_) = c {
+                        taken += 1;
+                    }
+                    group.push(c);
+                }
+                if group.as_os_str().is_empty() {
+                    ".".to_string()
+                } else {
+                    group.display().to_string()
+                }
+            }
+        }
+    }
+
+    fn write(&self, w: &mut dyn Write) -> io::Result<()> {
+        match self.by {
+            Aggregate::Extension => writeln!(w, "by extension:")?,
+            Aggregate::Directory(depth) => writeln!(w, "by directory (depth {}):", depth)?,
+        }
+        let mut groups: Vec<_> = self.groups.iter().collect();
+        groups.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then_with(|| a.0.cmp(b.0)));
+        for (name, group) in groups {
+            writeln!(
+                w,
+                "  {:>10} files {:>12} {:>12}  {}",
+                group.files,
+                HumanBytes(group.bytes).to_string(),
+                format!("{:.2?}", group.elapsed),
+                name
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl OutputSink for Aggregator {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        let name = self.group(&result.path);
+        let group = self.groups.entry(name).or_default();
+        group.files += 1;
+        group.bytes += result.size;
+        group.elapsed += result.elapsed;
+        Ok(())
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        let stderr = io::stderr();
+        let mut w = stderr.lock();
+        self.write(&mut w)
+    }
+}
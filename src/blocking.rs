@@ -0,0 +1,88 @@
+//! Hashing on a pool of OS threads, for use without an async runtime.
+//!
+//! Shares the hashing core with [hash_stream](crate::hash_stream),
+//! and likewise yields results in the same order as the paths, with backpressure.
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use crossbeam_channel::{bounded, Receiver, Sender};
+
+use crate::walk::Walker;
+use crate::{hash_path, queue_length, FileHash, HashError, HashOptions};
+
+type Job = (PathBuf, Sender<Result<FileHash, HashError>>);
+
+/// Iterator over the results of [hash_iter] or [hash_tree] (or of the rayon engine's `pool::hash_iter`).
+///
+/// Dropping it stops hashing once the threads finish their current files.
+/// Cancelling [HashOptions::cancel] also abandons the current files, and ends the iterator.
+pub struct HashIter {
+    pending: Receiver<Receiver<Result<FileHash, HashError>>>,
+}
+
+impl HashIter {
+    pub(crate) fn new(pending: Receiver<Receiver<Result<FileHash, HashError>>>) -> Self {
+        Self { pending }
+    }
+}
+
+impl Iterator for HashIter {
+    type Item = Result<FileHash, HashError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self.pending.recv().ok()?;
+        Some(result.recv().expect("hashing thread panicked"))
+    }
+}
+
+/// Hash files on `opts.jobs` threads, yielding results in the same order as the paths.
+///
+/// Paths are pulled from `paths` on another thread, up to a few times `opts.jobs` ahead of the results being consumed.
+pub fn hash_iter<I>(paths: I, opts: HashOptions) -> HashIter
+where
+    I: IntoIterator<Item = PathBuf>,
+    I::IntoIter: Send + 'static,
+{
+    let jobs = opts.jobs.max(1);
+    let queue_len = queue_length(jobs);
+    let (job_sender, job_receiver) = bounded::<Job>(queue_len);
+    let (pending_sender, pending) = bounded(queue_len);
+
+    for _ in 0..jobs {
+        let job_receiver = job_receiver.clone();
+        thread::spawn(move || {
+            if opts.numa {
+                crate::platform::pin_to_next_numa_node();
+            }
+            for (path, result_sender) in job_receiver {
+                // the receiver is only gone if the iterator was dropped
+                let _ = result_sender.send(hash_path(path, opts));
+            }
+        });
+    }
+
+    let paths = paths.into_iter();
+    thread::spawn(move || {
+        for path in paths {
+            if opts.cancel.is_some_and(|c| c.is_cancelled()) {
+                // ends the iterator once the pending results are taken
+                break;
+            }
+            let (result_sender, result_receiver) = bounded(1);
+            if pending_sender.send(result_receiver).is_err() {
+                // iterator dropped
+                break;
+            }
+            job_sender
+                .send((path, result_sender))
+                .expect("hashing threads stopped");
+        }
+    });
+
+    HashIter::new(pending)
+}
+
+/// Hash every file found by `walker` under `root`; see [hash_iter].
+pub fn hash_tree(root: &Path, walker: &dyn Walker, opts: HashOptions) -> HashIter {
+    hash_iter(walker.files(root), opts)
+}
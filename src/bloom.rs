@@ -0,0 +1,58 @@
+//! A bloom filter of byte strings, for sets of known digests too large to hold exactly.
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+
+/// Answers whether it may contain an item, which is wrong for about the chosen fraction of items not in it,
+/// or whether it certainly does not.
+#[derive(Debug)]
+pub struct Bloom {
+    bits: Vec<u64>,
+    /// number of bits
+    m: u64,
+    /// bits set per item
+    k: u32,
+}
+
+impl Bloom {
+    /// Sized for `items` items with the false-positive rate `fp_rate`.
+    pub fn new(items: usize, fp_rate: f64) -> Self {
+        let n = items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let m = (-n * fp_rate.ln() / (ln2 * ln2)).ceil().max(64.0) as u64;
+        let k = ((m as f64 / n) * ln2).round().clamp(1.0, 32.0) as u32;
+        Self {
+            bits: vec![0; m.div_ceil(64) as usize],
+            m,
+            k,
+        }
+    }
+
+    /// Bytes used by the filter.
+    pub fn size(&self) -> usize {
+        self.bits.len() * 8
+    }
+
+    /// The bits for an item, by double hashing.
+    fn indices(&self, item: &[u8]) -> impl Iterator<Item = u64> + 'static {
+        let hash = |seed: u64| {
+            let mut h = DefaultHasher::new();
+            h.write_u64(seed);
+            h.write(item);
+            h.finish()
+        };
+        let (h1, h2) = (hash(0), hash(1) | 1);
+        let m = self.m;
+        (0..self.k as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % m)
+    }
+
+    pub fn insert(&mut self, item: &[u8]) {
+        for idx in self.indices(item) {
+            self.bits[(idx / 64) as usize] |= 1 << (idx % 64);
+        }
+    }
+
+    pub fn may_contain(&self, item: &[u8]) -> bool {
+        self.indices(item)
+            .all(|idx| self.bits[(idx / 64) as usize] & (1 << (idx % 64)) != 0)
+    }
+}
@@ -0,0 +1,144 @@
+//! Fitting a run into a fixed maintenance window, for --max-duration.
+//!
+//! Once the budget is nearly spent, no more files are started: the files being hashed are finished,
+//! the outputs are closed as usual, holding every file hashed so far, and the paths which were not hashed
+//! are written to the --checkpoint file, one per line, to be hashed by a later run (e.g. `recursum - < FILE`).
+//!
+//! The checkpoint is written to a temporary file beside it, which replaces it at the end,
+//! so that a run may read its paths from the previous run's checkpoint and write its own to the same file;
+//! a device or pipe (e.g. `/dev/null`) is written to directly instead.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tempfile::NamedTempFile;
+
+use crate::disk_space::directory;
+
+/// Exit status of a run which stopped because it ran out of time, so that scripts can tell it from a failure.
+pub const EXIT_OUT_OF_TIME: i32 = 3;
+/// Fraction of the budget kept back for finishing the files in flight and closing the outputs...
+const WRAP_UP_FRACTION: u32 = 20;
+/// ...up to this long.
+const MAX_WRAP_UP: Duration = Duration::from_secs(10 * 60);
+
+/// Where the checkpoint is written: a temporary file to move into place, or a device or pipe.
+enum Target {
+    Temporary(NamedTempFile),
+    Special(File),
+}
+
+impl Write for Target {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Temporary(f) => f.write(buf),
+            Self::Special(f) => f.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Temporary(f) => f.flush(),
+            Self::Special(f) => f.flush(),
+        }
+    }
+}
+
+struct Checkpoint {
+    /// until it is finished
+    file: Option<BufWriter<Target>>,
+    /// paths written to it
+    count: u64,
+    /// the first error writing it
+    error: Option<io::Error>,
+}
+
+pub struct Budget {
+    path: PathBuf,
+    /// when to stop starting files
+    stop_at: Instant,
+    exhausted: AtomicBool,
+    checkpoint: Mutex<Checkpoint>,
+}
+
+impl Budget {
+    /// A budget of `max` from `started`, writing unhashed paths to `checkpoint`;
+    /// it is replaced even if empty, so that one left by an earlier run is never mistaken for this one's.
+    pub fn new(started: Instant, max: Duration, checkpoint: &Path) -> io::Result<Self> {
+        let wrap_up = (max / WRAP_UP_FRACTION).min(MAX_WRAP_UP);
+        let target = if fs::metadata(checkpoint).is_ok_and(|m| !m.is_file()) {
+            Target::Special(OpenOptions::new().write(true).open(checkpoint)?)
+        } else {
+            Target::Temporary(NamedTempFile::new_in(directory(checkpoint))?)
+        };
+        Ok(Self {
+            path: checkpoint.to_path_buf(),
+            stop_at: started + (max - wrap_up),
+            exhausted: AtomicBool::new(false),
+            checkpoint: Mutex::new(Checkpoint {
+                file: Some(BufWriter::new(target)),
+                count: 0,
+                error: None,
+            }),
+        })
+    }
+
+    /// Whether to hash `path`: true until the budget is nearly spent; after that,
+    /// false, and the path is added to the checkpoint instead.
+    pub fn admit(&self, path: &Path) -> bool {
+        if !self.exhausted.load(Ordering::Relaxed) {
+            if Instant::now() < self.stop_at {
+                return true;
+            }
+            self.exhausted.store(true, Ordering::Relaxed);
+        }
+        let mut guard = self.checkpoint.lock().expect("checkpoint lock poisoned");
+        let checkpoint = &mut *guard;
+        checkpoint.count += 1;
+        let line = format!("{}\n", path.to_string_lossy());
+        let written = match (&mut checkpoint.file, &checkpoint.error) {
+            (Some(file), None) => file.write_all(line.as_bytes()),
+            _ => Ok(()),
+        };
+        if let Err(e) = written {
+            checkpoint.error = Some(e);
+        }
+        false
+    }
+
+    /// Whether any path was left unhashed.
+    pub fn exhausted(&self) -> bool {
+        self.exhausted.load(Ordering::Relaxed)
+    }
+
+    /// How many paths have been left unhashed so far.
+    pub fn remaining(&self) -> u64 {
+        self.checkpoint
+            .lock()
+            .expect("checkpoint lock poisoned")
+            .count
+    }
+
+    /// Write out the checkpoint, replacing any earlier one, and return how many paths it lists.
+    pub fn finish(&self) -> io::Result<u64> {
+        let mut checkpoint = self.checkpoint.lock().expect("checkpoint lock poisoned");
+        if let Some(e) = checkpoint.error.take() {
+            return Err(e);
+        }
+        let target = match checkpoint.file.take() {
+            Some(file) => file.into_inner().map_err(io::IntoInnerError::into_error)?,
+            None => return Ok(checkpoint.count),
+        };
+        if let Target::Temporary(tmp) = target {
+            tmp.persist(&self.path).map_err(|e| e.error)?;
+        }
+        Ok(checkpoint.count)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
@@ -0,0 +1,48 @@
+//! Read buffers which are kept by each thread and reused for every file it hashes,
+//! rather than allocated per file.
+use std::cell::RefCell;
+
+const PAGE_SIZE: usize = 4096;
+
+/// Page-aligned, so that reads can go straight into it from the page cache (or, one day, with direct I/O).
+#[derive(Clone, Copy)]
+#[repr(C, align(4096))]
+struct Page([u8; PAGE_SIZE]);
+
+/// A buffer which grows to the largest size asked of it, and never shrinks.
+struct ReadBuffer {
+    pages: Vec<Page>,
+}
+
+impl ReadBuffer {
+    /// The first `len` bytes of the buffer, growing it if necessary.
+    fn get(&mut self, len: usize) -> &mut [u8] {
+        let n_pages = len.div_ceil(PAGE_SIZE).max(1);
+        if self.pages.len() < n_pages {
+            self.pages.resize(n_pages, Page([0; PAGE_SIZE]));
+        }
+        // Page is a plain byte array with no padding, so the pages are one contiguous run of bytes
+        let bytes = unsafe {
+            std::slice::from_raw_parts_mut(
+                self.pages.as_mut_ptr() as *mut u8,
+                self.pages.len() * PAGE_SIZE,
+            )
+        };
+        &mut bytes[..len.max(1)]
+    }
+}
+
+thread_local! {
+    static BUFFER: RefCell<ReadBuffer> = const { RefCell::new(ReadBuffer { pages: Vec::new() }) };
+}
+
+/// Call `f` with this thread's buffer, `len` bytes long.
+///
+/// If the buffer is already in use further up the stack (e.g. a hasher which itself hashes files),
+/// a temporary one is allocated instead.
+pub(crate) fn with_buffer<T>(len: usize, f: impl FnOnce(&mut [u8]) -> T) -> T {
+    BUFFER.with(|cell| match cell.try_borrow_mut() {
+        Ok(mut buffer) => f(buffer.get(len)),
+        Err(_) => f(ReadBuffer { pages: Vec::new() }.get(len)),
+    })
+}
@@ -0,0 +1,234 @@
+//! Managing --cache files: inspecting them, removing stale or unwanted entries,
+//! and exporting one built on one copy of a dataset to seed runs on identical copies elsewhere.
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use structopt::StructOpt;
+
+use recursum::hash_cache::HashCache;
+use recursum::walk::PathGlobs;
+
+use crate::progress::HumanBytes;
+
+#[derive(Debug, StructOpt)]
+pub struct CacheOpt {
+    #[structopt(subcommand)]
+    command: CacheCommand,
+}
+
+#[derive(Debug, StructOpt)]
+enum CacheCommand {
+    /// Write a cache's entries, e.g. to copy to another machine with a copy of the dataset. Paths are relative to the directory the cache was built for, so the export can be imported wherever the dataset is. Files match their entries if they have the same path, size and modification time, so copy the data with modification times preserved (e.g. rsync -a).
+    Export(ExportOpt),
+    /// Add the entries of exported caches to a cache, creating it if it does not exist. Entries already in the cache are kept, unless --overwrite is given. Fails if the caches were made with different algorithms, seeds or digest lengths.
+    Import(ImportOpt),
+    /// Print how many files and bytes a cache has entries for, what it was made with, and how many files the last run using it found unchanged.
+    Stats(StatsOpt),
+    /// Remove entries for files which no longer exist, or have changed since they were cached (so would be hashed again anyway), making the cache smaller and quicker to load.
+    Vacuum(VacuumOpt),
+    /// Remove entries matching globs, so that those files are hashed again next time whether or not they seem to have changed, e.g. after restoring them from a backup which kept their modification times.
+    Invalidate(InvalidateOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct ExportOpt {
+    /// The cache, as given to --cache.
+    #[structopt(parse(from_os_str))]
+    cache: PathBuf,
+    /// Write to this file instead of stdout.
+    #[structopt(short = "o", long = "output", parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// Only export entries under this directory (relative to the directory the cache was built for), and make their paths relative to it, e.g. to seed a cache for a copy of one subdirectory.
+    #[structopt(long = "under", parse(from_os_str))]
+    under: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+struct ImportOpt {
+    /// The cache to add to, as given to --cache.
+    #[structopt(parse(from_os_str))]
+    cache: PathBuf,
+    /// Exported caches; - reads stdin.
+    #[structopt(parse(from_os_str), required = true, min_values = 1)]
+    exports: Vec<PathBuf>,
+    /// Put the imported entries under this directory (relative to the directory the cache is for), e.g. to import an export of a subdirectory made with `export --under`.
+    #[structopt(long = "under", parse(from_os_str))]
+    under: Option<PathBuf>,
+    /// Replace entries already in the cache with imported ones for the same paths.
+    #[structopt(long = "overwrite")]
+    overwrite: bool,
+}
+
+#[derive(Debug, StructOpt)]
+struct StatsOpt {
+    /// The cache, as given to --cache.
+    #[structopt(parse(from_os_str))]
+    cache: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+struct VacuumOpt {
+    /// The cache, as given to --cache.
+    #[structopt(parse(from_os_str))]
+    cache: PathBuf,
+    /// The directory the cache is for, as given to recursum with --cache.
+    #[structopt(parse(from_os_str))]
+    root: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+struct InvalidateOpt {
+    /// The cache, as given to --cache.
+    #[structopt(parse(from_os_str))]
+    cache: PathBuf,
+    /// Globs of paths to remove, relative to the directory the cache is for. `*` does not match `/`, but `**` matches any number of directories. A directory's path removes everything under it.
+    #[structopt(required = true, min_values = 1)]
+    globs: Vec<String>,
+}
+
+pub fn run(opt: CacheOpt) {
+    match opt.command {
+        CacheCommand::Export(o) => export(o),
+        CacheCommand::Import(o) => import(o),
+        CacheCommand::Stats(o) => stats(o),
+        CacheCommand::Vacuum(o) => vacuum(o),
+        CacheCommand::Invalidate(o) => invalidate(o),
+    }
+}
+
+fn save(cache: &HashCache, path: &Path) {
+    cache
+        .save(path)
+        .unwrap_or_else(|e| panic!("could not save cache {:?}: {}", path, e));
+}
+
+fn load(path: &Path) -> HashCache {
+    let loaded = if path == Path::new("-") {
+        HashCache::read(io::stdin().lock())
+    } else {
+        HashCache::load(path)
+    };
+    loaded.unwrap_or_else(|e| panic!("could not read cache {:?}: {}", path, e))
+}
+
+fn export(opt: ExportOpt) {
+    let cache = load(&opt.cache);
+    let exported = match &opt.under {
+        Some(dir) => {
+            let subset = HashCache::new(&cache.hasher);
+            subset.merge(
+                cache.entries().into_iter().filter_map(|(path, entry)| {
+                    let relative = path.strip_prefix(dir).ok()?.to_path_buf();
+                    Some((relative, entry))
+                }),
+                true,
+            );
+            subset
+        }
+        None => cache,
+    };
+    let written = match &opt.output {
+        Some(path) => File::create(path).and_then(|f| {
+            let mut w = BufWriter::new(f);
+            exported.write(&mut w)?;
+            w.flush()
+        }),
+        None => {
+            let stdout = io::stdout();
+            let mut w = BufWriter::new(stdout.lock());
+            exported.write(&mut w).and_then(|_| w.flush())
+        }
+    };
+    written.unwrap_or_else(|e| panic!("could not write export: {}", e));
+    eprintln!("Exported {} entries", exported.len());
+}
+
+fn import(opt: ImportOpt) {
+    let mut cache = match HashCache::load(&opt.cache) {
+        Ok(c) => Some(c),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => panic!("could not read cache {:?}: {}", opt.cache, e),
+    };
+    let (mut read, mut merged) = (0, 0);
+    for path in &opt.exports {
+        let export = load(path);
+        // a new cache takes the first export's hasher
+        let cache = cache.get_or_insert_with(|| HashCache::new(&export.hasher));
+        if export.hasher != cache.hasher {
+            panic!(
+                "{:?} holds digests made with {}, but cache {:?} holds digests made with {}",
+                path, export.hasher, opt.cache, cache.hasher
+            );
+        }
+        let entries = export.entries();
+        read += entries.len();
+        merged += cache.merge(
+            entries.into_iter().map(|(p, e)| match &opt.under {
+                Some(dir) => (dir.join(p), e),
+                None => (p, e),
+            }),
+            opt.overwrite,
+        );
+    }
+    let cache = cache.expect("at least one export is required");
+    save(&cache, &opt.cache);
+    eprintln!(
+        "Imported {} of {} entries into {:?}, which now has {}",
+        merged,
+        read,
+        opt.cache,
+        cache.len()
+    );
+}
+
+fn stats(opt: StatsOpt) {
+    let cache = load(&opt.cache);
+    let entries = cache.entries();
+    let bytes: u64 = entries.iter().map(|(_, e)| e.size).sum();
+    println!("hasher: {}", cache.hasher);
+    println!("entries: {}", entries.len());
+    println!("bytes: {} ({})", bytes, HumanBytes(bytes));
+    if let Ok(meta) = fs::metadata(&opt.cache) {
+        println!("cache size: {}", HumanBytes(meta.len()));
+    }
+    match cache.last_run() {
+        Some((hits, misses)) if hits + misses > 0 => println!(
+            "last run: {} of {} files unchanged ({:.1}%)",
+            hits,
+            hits + misses,
+            100.0 * hits as f64 / (hits + misses) as f64
+        ),
+        _ => println!("last run: not recorded"),
+    }
+}
+
+fn vacuum(opt: VacuumOpt) {
+    if !opt.root.is_dir() {
+        panic!("{:?} is not a directory", opt.root);
+    }
+    let cache = load(&opt.cache);
+    let removed = cache.retain(|path, entry| {
+        fs::metadata(opt.root.join(path)).is_ok_and(|meta| entry.matches(&meta))
+    });
+    save(&cache, &opt.cache);
+    eprintln!(
+        "Removed {} stale entries from {:?}, which now has {}",
+        removed,
+        opt.cache,
+        cache.len()
+    );
+}
+
+fn invalidate(opt: InvalidateOpt) {
+    let globs = PathGlobs::new(&opt.globs).unwrap_or_else(|e| panic!("invalid glob: {}", e));
+    let cache = load(&opt.cache);
+    let removed = cache.retain(|path, _| !globs.is_match(path));
+    save(&cache, &opt.cache);
+    eprintln!(
+        "Removed {} entries from {:?}, which now has {}",
+        removed,
+        opt.cache,
+        cache.len()
+    );
+}
@@ -0,0 +1,63 @@
+//! Stopping hashing early, e.g. on Ctrl-C.
+//!
+//! A cancelled [hash_stream](crate::hash_stream) stops pulling paths (so [walk_paths](crate::walk_paths)
+//! stops walking), abandons files part-way through reading them, and ends its stream of results;
+//! [write_results_until](crate::sink::write_results_until) then returns an error rather than finishing the sink,
+//! so that output is not mistaken for complete.
+//! Each stage notices the next time it makes progress, so a stage waiting for input
+//! (e.g. paths from stdin) only stops once that input arrives or ends.
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A flag which, once set, tells every stage of hashing to stop.
+///
+/// Setting it is a single atomic store, so it is safe to cancel from a signal handler.
+#[derive(Debug, Default)]
+pub struct CancellationToken {
+    cancelled: AtomicBool,
+}
+
+impl CancellationToken {
+    pub const fn new() -> Self {
+        Self {
+            cancelled: AtomicBool::new(false),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// An error if cancelled.
+    pub fn check(&self) -> io::Result<()> {
+        if self.is_cancelled() {
+            Err(cancelled())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The error returned by anything which stopped because it was cancelled.
+pub fn cancelled() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "cancelled")
+}
+
+/// A reader which fails once the token is cancelled, to abandon large files part-way through.
+pub(crate) struct CancellableRead<'a, R> {
+    pub inner: R,
+    pub cancel: Option<&'a CancellationToken>,
+}
+
+impl<R: Read> Read for CancellableRead<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(c) = self.cancel {
+            c.check()?;
+        }
+        self.inner.read(buf)
+    }
+}
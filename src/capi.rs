@@ -0,0 +1,167 @@
+//! C ABI, enabled with the `capi` feature.
+//!
+//! The header is generated with `cbindgen --config cbindgen.toml --output include/recursum.h`.
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use crate::blocking::hash_tree;
+use crate::walk::{WalkOptions, WalkerKind};
+use crate::{queue_length, HashOptions};
+
+/// Outcome of a call into the library.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecursumStatus {
+    Ok = 0,
+    /// A required pointer was null, or a path was not valid on this platform.
+    InvalidArgument = 1,
+    /// The root could not be walked, e.g. because it is not a directory.
+    WalkError = 2,
+    /// The callback returned non-zero.
+    Cancelled = 3,
+    /// An internal error; this is a bug.
+    Panic = 4,
+}
+
+/// The result of hashing one file.
+///
+/// Pointers are only valid for the duration of the callback.
+#[repr(C)]
+pub struct RecursumFileResult {
+    /// NUL-terminated path of the file.
+    pub path: *const c_char,
+    /// NUL-terminated hex digest, or null if the file could not be hashed.
+    pub digest: *const c_char,
+    /// Size in bytes.
+    pub size: u64,
+    /// 0 if the file was hashed, otherwise the OS error code (or -1 if there was none).
+    pub error: c_int,
+    /// False if the file changed while being hashed.
+    pub stable: bool,
+}
+
+/// Called once per file, in walk order. Return non-zero to stop hashing.
+pub type RecursumCallback = Option<
+    unsafe extern "C" fn(result: *const RecursumFileResult, user_data: *mut c_void) -> c_int,
+>;
+
+#[cfg(unix)]
+fn path_from_c(s: &CStr) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(Path::new(std::ffi::OsStr::from_bytes(s.to_bytes())).to_path_buf())
+}
+
+#[cfg(not(unix))]
+fn path_from_c(s: &CStr) -> Option<PathBuf> {
+    s.to_str().ok().map(PathBuf::from)
+}
+
+#[cfg(unix)]
+fn path_to_c(path: &Path) -> CString {
+    use std::os::unix::ffi::OsStrExt;
+    // paths cannot contain NUL
+    CString::new(path.as_os_str().as_bytes()).unwrap()
+}
+
+#[cfg(not(unix))]
+fn path_to_c(path: &Path) -> CString {
+    CString::new(path.to_string_lossy().into_owned()).unwrap()
+}
+
+/// Hash every file under the directory `root` on `threads` threads (0 for one per CPU),
+/// calling `callback` with each result and `user_data`.
+///
+/// Files are walked in sorted depth-first order, including hidden files, without following symlinks.
+///
+/// # Safety
+///
+/// `root` must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn recursum_hash_tree(
+    root: *const c_char,
+    threads: u32,
+    callback: RecursumCallback,
+    user_data: *mut c_void,
+) -> RecursumStatus {
+    let callback = match callback {
+        Some(cb) if !root.is_null() => cb,
+        _ => return RecursumStatus::InvalidArgument,
+    };
+    let root = match path_from_c(CStr::from_ptr(root)) {
+        Some(p) => p,
+        None => return RecursumStatus::InvalidArgument,
+    };
+    if !root.is_dir() {
+        return RecursumStatus::WalkError;
+    }
+
+    let outcome = catch_unwind(AssertUnwindSafe(|| {
+        let jobs = if threads == 0 {
+            num_cpus::get()
+        } else {
+            threads as usize
+        };
+        let walker = WalkerKind::for_order(None)
+            .build(
+                jobs,
+                queue_length(jobs),
+                WalkOptions {
+                    order: None,
+                    sort: true,
+                    skip_hidden: false,
+                    prune: Default::default(),
+                    detect_case_collisions: false,
+                    dir_limits: None,
+                    skipped: None,
+                },
+            )
+            .expect("default walker is always valid");
+        let opts = HashOptions {
+            jobs,
+            ..Default::default()
+        };
+
+        for result in hash_tree(&root, walker.as_ref(), opts) {
+            let (path, digest, file_result) = match result {
+                Ok(hashed) => {
+                    let path = path_to_c(&hashed.path);
+                    let digest = CString::new(hashed.digest).unwrap();
+                    let file_result = RecursumFileResult {
+                        path: path.as_ptr(),
+                        digest: digest.as_ptr(),
+                        size: hashed.size,
+                        error: 0,
+                        stable: hashed.stable,
+                    };
+                    (path, Some(digest), file_result)
+                }
+                Err(e) => {
+                    let path = path_to_c(&e.path);
+                    let file_result = RecursumFileResult {
+                        path: path.as_ptr(),
+                        digest: std::ptr::null(),
+                        size: 0,
+                        error: e.source.raw_os_error().unwrap_or(-1),
+                        stable: false,
+                    };
+                    (path, None, file_result)
+                }
+            };
+            let stop = callback(&file_result, user_data) != 0;
+            drop((path, digest));
+            if stop {
+                return RecursumStatus::Cancelled;
+            }
+        }
+        RecursumStatus::Ok
+    }));
+    outcome.unwrap_or(RecursumStatus::Panic)
+}
+
+/// NUL-terminated version of the library, valid for the lifetime of the program.
+#[no_mangle]
+pub extern "C" fn recursum_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
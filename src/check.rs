@@ -0,0 +1,445 @@
+//! Verify files against a manifest, from recursum or from other tools.
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use structopt::StructOpt;
+use tokio_stream::{iter, StreamExt};
+
+use recursum::walk::PathGlobs;
+use recursum::{hash_stream, hashers, runtime, HashOptions};
+
+use crate::color::{self, Role};
+use crate::cron;
+use crate::manifest::{self, algorithms_for_length, Manifest};
+use crate::normalize::{self, NormalForm};
+use crate::output::UNSTABLE_PREFIX;
+use crate::progress::Progress;
+#[cfg(feature = "redact")]
+use crate::redact::Redactor;
+use crate::{hasher_for, or_num_cpus, seeded_hasher, select_hasher};
+
+#[derive(Debug, StructOpt)]
+pub struct CheckOpt {
+    /// Manifest to check, or - for stdin. Compressed manifests ending in .gz or .zst are decompressed.
+    #[structopt(parse(from_os_str))]
+    manifest: PathBuf,
+    /// Which run to check, counting from 1, if several were appended to the manifest (with --append). Defaults to the last.
+    #[structopt(long = "run")]
+    run: Option<usize>,
+    /// Hashing algorithm, as for hashing (including auto-size:CLASSES). auto reads it from the manifest's header or tags, or guesses it from the length of the digests.
+    #[structopt(long = "hash", default_value = hashers::AUTO)]
+    hash: String,
+    /// Seed for the hashing algorithm, as hex, if the manifest has no header recording it.
+    #[structopt(long = "seed")]
+    seed: Option<String>,
+    /// Hashing threads.
+    #[structopt(short = "t", long = "threads")]
+    threads: Option<usize>,
+    /// Only check files whose paths (as written in the manifest) match this glob, e.g. 'photos/2023/**'. A directory's path selects everything under it. Can be given multiple times.
+    #[structopt(long = "only", number_of_values = 1)]
+    only: Vec<String>,
+    /// File of globs (one per line, as --only; blank lines and lines starting with # are ignored) matching files which are expected to change, like logs and caches. They are still checked, but do not fail if they changed or cannot be read; how many did is reported at the end.
+    #[structopt(long = "ignore-changes", parse(from_os_str))]
+    ignore_changes: Option<PathBuf>,
+    /// Do not fail or report status for files which do not exist. Fails if no file was verified at all.
+    #[structopt(long = "ignore-missing")]
+    ignore_missing: bool,
+    /// Fail if any line of the manifest is improperly formatted.
+    #[structopt(long = "strict")]
+    strict: bool,
+    /// Do not print OK for each file which matches; only failures are printed.
+    #[structopt(long = "quiet-ok")]
+    quiet_ok: bool,
+    /// Do not show progress information.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+    /// Print nothing but one line when finished, for scheduled jobs and log collectors: the time, root, files, bytes, errors, mismatches and duration, as `2020-11-03T17:04:05Z manifest=m.txt root=/data files=3001 bytes=113885 errors=0 mismatches=0 ignored=0 duration=2.051s status=ok`. The exit status is nonzero if the check failed, as usual.
+    #[structopt(long = "cron", conflicts_with_all = &["quiet", "quiet-ok"])]
+    cron: bool,
+    /// Match and print the manifest's paths in this Unicode normalization form, nfc or nfd, as --normalize-paths when hashing; --only and --ignore-changes globs are normalized too. Files are found whichever form their names are stored in, so that e.g. a manifest made on macOS can be checked on Linux.
+    #[structopt(long = "normalize-paths")]
+    normalize_paths: Option<NormalForm>,
+    /// Key the manifest's paths were redacted with (by --redact-paths), to match them to the files under --root.
+    #[cfg(feature = "redact")]
+    #[structopt(long = "redact-key-file", parse(from_os_str))]
+    redact_key_file: Option<PathBuf>,
+    /// Directory a redacted manifest describes.
+    #[cfg(feature = "redact")]
+    #[structopt(long = "root", parse(from_os_str), default_value = ".")]
+    root: PathBuf,
+}
+
+/// How many files had each outcome.
+#[derive(Debug, Default)]
+struct Tally {
+    ok: usize,
+    /// read from files which could be hashed
+    bytes: u64,
+    mismatched: usize,
+    unreadable: usize,
+    /// Files matching --ignore-changes which changed or could not be read.
+    ignored: usize,
+}
+
+/// Work out which algorithm to use, and whether digests may be shorter than it produces.
+/// None if each digest names the algorithm it was made with, as written by --hash auto-size:...
+fn choose_algorithm(opt: &CheckOpt, manifest: &Manifest) -> (Option<String>, bool) {
+    if opt.hash != hashers::AUTO {
+        return (Some(select_hasher(&opt.hash).to_string()), true);
+    }
+    let named: Option<Vec<&str>> = manifest
+        .entries
+        .iter()
+        .map(|e| manifest::split_algorithm(&e.digest).0)
+        .collect();
+    if named.is_some() {
+        for name in named.iter().flatten() {
+            if hashers::get(name).is_none() {
+                panic!(
+                    "the manifest uses {}, which this build of recursum does not support",
+                    name
+                );
+            }
+        }
+        return (None, manifest.digest_length.is_some());
+    }
+    let name = match &manifest.algorithm {
+        Some(a) => a.clone(),
+        None => {
+            let len = manifest
+                .entries
+                .first()
+                .map(|e| e.digest.trim_start_matches(UNSTABLE_PREFIX).len())
+                .unwrap_or(0);
+            let names = algorithms_for_length(len, manifest.format);
+            let (name, others) = names.split_first().unwrap_or_else(|| {
+                panic!(
+                    "cannot tell the algorithm from {}-character digests; use --hash",
+                    len
+                )
+            });
+            if !others.is_empty() {
+                eprintln!(
+                    "{}",
+                    color::stderr(
+                        Role::Warning,
+                        format!(
+                            "WARNING: {}-character digests could be from {} or {}; assuming {} (choose with --hash)",
+                            len,
+                            name,
+                            others.join(" or "),
+                            name
+                        )
+                    )
+                );
+            }
+            name.to_string()
+        }
+    };
+    if !name.starts_with(hashers::BY_SIZE_PREFIX) && hashers::get(&name).is_none() {
+        panic!(
+            "the manifest uses {}, which this build of recursum does not support",
+            name
+        );
+    }
+    (
+        Some(select_hasher(&name).to_string()),
+        manifest.digest_length.is_some(),
+    )
+}
+
+/// Replace the manifest's redacted paths with those of the files under `root` which they redact;
+/// any which match no file are left, and so reported as missing. Returns how many there are.
+#[cfg(feature = "redact")]
+fn unredact(
+    manifest: &mut Manifest,
+    key_file: &Path,
+    root: &Path,
+    form: Option<NormalForm>,
+) -> usize {
+    let redactor = Redactor::load(key_file)
+        .unwrap_or_else(|e| panic!("could not read --redact-key-file {:?}: {}", key_file, e));
+    let index = redactor
+        .index(root, form)
+        .unwrap_or_else(|e| panic!("could not list {:?}: {}", root, e));
+    let mut unmatched = 0;
+    for entry in &mut manifest.entries {
+        match index.get(&entry.path) {
+            Some(path) => entry.path = path.to_string_lossy().into_owned(),
+            None => unmatched += 1,
+        }
+    }
+    manifest.relative_to = Some(root.to_path_buf());
+    unmatched
+}
+
+pub fn run(opt: CheckOpt) {
+    let started = Instant::now();
+    let mut manifest = manifest::load_run(&opt.manifest, opt.run)
+        .unwrap_or_else(|e| panic!("could not read manifest {:?}: {}", opt.manifest, e));
+    #[cfg(feature = "redact")]
+    let redact_key_file = opt.redact_key_file.as_ref();
+    #[cfg(not(feature = "redact"))]
+    let redact_key_file: Option<&PathBuf> = None;
+    match (manifest.is_redacted(), redact_key_file) {
+        (false, None) => (),
+        #[cfg(feature = "redact")]
+        (true, Some(key_file)) => {
+            let unmatched = unredact(&mut manifest, key_file, &opt.root, opt.normalize_paths);
+            if unmatched > 0 && !opt.cron {
+                eprintln!(
+                    "{} redacted path(s) match no file under {:?}",
+                    unmatched, opt.root
+                );
+            }
+        }
+        #[cfg(not(feature = "redact"))]
+        (true, Some(_)) => unreachable!("no key without the redact feature"),
+        (true, None) => panic!(
+            "the manifest's paths are redacted; give the key with --redact-key-file, and the directory with --root"
+        ),
+        (false, Some(_)) => panic!("the manifest's paths are not redacted"),
+    }
+    if let Some(form) = opt.normalize_paths {
+        for entry in &mut manifest.entries {
+            entry.path = form.apply(&entry.path);
+        }
+    }
+    let normalized = |glob: &str| match opt.normalize_paths {
+        Some(form) => form.apply(glob),
+        None => glob.to_string(),
+    };
+    if !opt.only.is_empty() {
+        let only: Vec<_> = opt.only.iter().map(|g| normalized(g)).collect();
+        let globs = PathGlobs::new(&only).unwrap_or_else(|e| panic!("--only: {}", e));
+        manifest
+            .entries
+            .retain(|e| globs.is_match(Path::new(&e.path)));
+        if manifest.entries.is_empty() {
+            panic!("no files in the manifest match --only");
+        }
+    }
+    let volatile = opt.ignore_changes.as_ref().map(|p| {
+        std::fs::read_to_string(p)
+            .map_err(|e| e.to_string())
+            .and_then(|globs| PathGlobs::parse(&normalized(&globs)))
+            .unwrap_or_else(|e| panic!("--ignore-changes {:?}: {}", p, e))
+    });
+    let (algorithm, allow_truncated) = choose_algorithm(&opt, &manifest);
+    // the algorithm each entry was hashed with, and its digest without any name
+    let digests: Vec<(&str, &str)> = manifest
+        .entries
+        .iter()
+        .map(|e| match &algorithm {
+            Some(name) => (name.as_str(), e.digest.as_str()),
+            None => {
+                let (name, hex) = manifest::split_algorithm(&e.digest);
+                (name.expect("every digest names its algorithm"), hex)
+            }
+        })
+        .collect();
+    let mut algorithms: Vec<&str> = digests.iter().map(|(name, _)| *name).collect();
+    algorithms.sort_unstable();
+    algorithms.dedup();
+    if !opt.cron {
+        eprintln!(
+            "checking {} files from a {} manifest with {}",
+            manifest.entries.len(),
+            manifest.format,
+            algorithms.join(", ")
+        );
+    }
+
+    let threads = or_num_cpus(opt.threads);
+    let seed = opt.seed.as_ref().or(manifest.seed.as_ref());
+    let hash_opts = |name: &str| {
+        let (hasher, by_size) = hasher_for(name);
+        HashOptions {
+            hasher,
+            seeded_hasher: seed.map(|s| seeded_hasher(name, s)),
+            by_size,
+            jobs: threads,
+            ..Default::default()
+        }
+    };
+    let paths: Vec<_> = manifest
+        .entries
+        .iter()
+        .map(|e| match &manifest.relative_to {
+            Some(dir) => dir.join(&e.path),
+            None => PathBuf::from(&e.path),
+        })
+        .map(|p| match opt.normalize_paths {
+            Some(_) => normalize::find(&p),
+            None => p,
+        })
+        .collect();
+
+    // a bar by bytes needs every file's size, which only some formats record
+    let total_bytes = manifest.entries.iter().map(|e| e.size).sum::<Option<u64>>();
+    let progress = if opt.quiet || opt.cron {
+        None
+    } else {
+        Some(Progress::bounded(
+            manifest.entries.len() as u64,
+            total_bytes,
+        ))
+    };
+
+    let rt = runtime(threads, false).unwrap();
+    let tally = rt.block_on(async {
+        let stdout = io::stdout();
+        let mut out = BufWriter::new(stdout.lock());
+        let mut tally = Tally::default();
+        // one stream per algorithm, each in manifest order, as entries may name different ones
+        let mut results: HashMap<&str, _> = algorithms
+            .iter()
+            .map(|&name| {
+                let paths: Vec<PathBuf> = paths
+                    .iter()
+                    .zip(&digests)
+                    .filter(|(_, (n, _))| *n == name)
+                    .map(|(p, _)| p.clone())
+                    .collect();
+                (name, hash_stream(iter(paths), hash_opts(name)))
+            })
+            .collect();
+        for (entry, (name, digest)) in manifest.entries.iter().zip(&digests) {
+            let result = results
+                .get_mut(name)
+                .expect("a stream for every algorithm")
+                .next()
+                .await
+                .expect("one result per path");
+            if let Some(p) = &progress {
+                let size = entry
+                    .size
+                    .or_else(|| result.as_ref().ok().map(|h| h.size))
+                    .unwrap_or(0);
+                p.update(Path::new(&entry.path), size);
+            }
+            let ignored = volatile
+                .as_ref()
+                .is_some_and(|g| g.is_match(Path::new(&entry.path)));
+            let status = match result {
+                Ok(hashed) => {
+                    tally.bytes += hashed.size;
+                    let expected = digest.to_ascii_lowercase();
+                    let matches = if allow_truncated {
+                        hashed.digest.starts_with(&expected)
+                    } else {
+                        hashed.digest == expected
+                    };
+                    if matches {
+                        tally.ok += 1;
+                        if opt.quiet_ok {
+                            continue;
+                        }
+                        "OK"
+                    } else if ignored {
+                        tally.ignored += 1;
+                        "CHANGED (ignored)"
+                    } else {
+                        tally.mismatched += 1;
+                        "FAILED"
+                    }
+                }
+                Err(e) if opt.ignore_missing && e.source.kind() == io::ErrorKind::NotFound => {
+                    continue
+                }
+                Err(_) if ignored => {
+                    tally.ignored += 1;
+                    "FAILED open or read (ignored)"
+                }
+                Err(e) => {
+                    let msg = color::stderr(Role::Bad, format!("recursum: {}", e)).to_string();
+                    match &progress {
+                        Some(p) => p.println(msg),
+                        None if opt.cron => (),
+                        None => eprintln!("{}", msg),
+                    }
+                    tally.unreadable += 1;
+                    "FAILED open or read"
+                }
+            };
+            if opt.cron {
+                continue;
+            }
+            let status = color::stdout(Role::of_status(status), status);
+            writeln!(out, "{}: {}", entry.path, status).expect("could not write output");
+        }
+        out.flush().expect("could not write output");
+        tally
+    });
+    if let Some(p) = &progress {
+        p.finish();
+    }
+
+    let verified = tally.ok + tally.mismatched + tally.unreadable + tally.ignored;
+    let failed = tally.mismatched > 0
+        || tally.unreadable > 0
+        || (opt.strict && manifest.malformed > 0)
+        || (opt.ignore_missing && verified == 0);
+    if opt.cron {
+        let root = manifest
+            .root
+            .as_deref()
+            .or(manifest.relative_to.as_deref())
+            .unwrap_or_else(|| Path::new("."));
+        cron::report(
+            &[("manifest", &opt.manifest), ("root", root)],
+            &[
+                ("files", verified as u64),
+                ("bytes", tally.bytes),
+                ("errors", tally.unreadable as u64),
+                ("mismatches", tally.mismatched as u64),
+                ("ignored", tally.ignored as u64),
+                ("malformed", manifest.malformed as u64),
+            ],
+            started,
+            failed,
+        );
+    } else {
+        summarise(&opt, &manifest, &tally, verified);
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Print warnings about anything which went wrong on stderr.
+fn summarise(opt: &CheckOpt, manifest: &Manifest, tally: &Tally, verified: usize) {
+    if manifest.malformed > 0 {
+        let msg = format!(
+            "WARNING: {} line(s) improperly formatted",
+            manifest.malformed
+        );
+        eprintln!("{}", color::stderr(Role::Warning, msg));
+    }
+    if tally.unreadable > 0 {
+        let msg = format!(
+            "WARNING: {} listed file(s) could not be read",
+            tally.unreadable
+        );
+        eprintln!("{}", color::stderr(Role::Bad, msg));
+    }
+    if tally.mismatched > 0 {
+        let msg = format!(
+            "WARNING: {} computed checksum(s) did NOT match",
+            tally.mismatched
+        );
+        eprintln!("{}", color::stderr(Role::Bad, msg));
+    }
+    if tally.ignored > 0 {
+        let msg = format!(
+            "{} file(s) matching --ignore-changes changed or could not be read",
+            tally.ignored
+        );
+        eprintln!("{}", color::stderr(Role::Warning, msg));
+    }
+    if opt.ignore_missing && verified == 0 {
+        eprintln!("{:?}: no file was verified", opt.manifest);
+    }
+}
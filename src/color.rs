@@ -0,0 +1,123 @@
+//! Colouring human-readable output with --color: statuses (OK green, FAILED red),
+//! warnings and errors, and the totals at the end.
+//!
+//! Colour is decided once per stream, so machine-readable output piped elsewhere never gets escape codes.
+use std::fmt;
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorChoice {
+    /// When the stream is a terminal, and neither NO_COLOR is set nor TERM is dumb.
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(format!(
+                "unknown color choice '{}'; expected auto, always or never",
+                s
+            )),
+        }
+    }
+}
+
+/// What some text means, which decides its colour.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Good,
+    Bad,
+    Warning,
+    /// Worth noticing, but neither good nor bad.
+    Highlight,
+}
+
+impl Role {
+    /// SGR parameters.
+    fn code(self) -> &'static str {
+        match self {
+            Self::Good => "32",
+            Self::Bad => "1;31",
+            Self::Warning => "33",
+            Self::Highlight => "1",
+        }
+    }
+
+    /// For a status as printed by check and the verifying subcommands, e.g. `OK` or `FAILED open or read`.
+    pub fn of_status(status: &str) -> Self {
+        if status == "OK" {
+            Self::Good
+        } else if status.ends_with("(ignored)") {
+            Self::Warning
+        } else {
+            Self::Bad
+        }
+    }
+}
+
+/// Whether to colour stdout and stderr.
+static ENABLED: OnceLock<(bool, bool)> = OnceLock::new();
+
+fn wanted(choice: ColorChoice, is_terminal: bool) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            is_terminal
+                && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+                && std::env::var_os("TERM").is_none_or(|t| t != "dumb")
+        }
+    }
+}
+
+/// Decide whether to colour each stream; until then, nothing is coloured.
+pub fn init(choice: ColorChoice) {
+    let _ = ENABLED.set((
+        wanted(choice, io::stdout().is_terminal()),
+        wanted(choice, io::stderr().is_terminal()),
+    ));
+}
+
+/// Text which is coloured by its role when displayed, if its stream is coloured.
+pub struct Painted<T> {
+    value: T,
+    role: Role,
+    enabled: bool,
+}
+
+impl<T: fmt::Display> fmt::Display for Painted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.enabled {
+            write!(f, "\x1b[{}m{}\x1b[0m", self.role.code(), self.value)
+        } else {
+            self.value.fmt(f)
+        }
+    }
+}
+
+/// `value`, coloured for stdout.
+pub fn stdout<T>(role: Role, value: T) -> Painted<T> {
+    Painted {
+        value,
+        role,
+        enabled: ENABLED.get().is_some_and(|e| e.0),
+    }
+}
+
+/// `value`, coloured for stderr.
+pub fn stderr<T>(role: Role, value: T) -> Painted<T> {
+    Painted {
+        value,
+        role,
+        enabled: ENABLED.get().is_some_and(|e| e.1),
+    }
+}
@@ -0,0 +1,178 @@
+//! Parquet output, so that very large manifests can be queried directly with e.g. DuckDB or Spark.
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use parquet::basic::Compression as ParquetCompression;
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, Int64Type};
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+
+use recursum::sink::OutputSink;
+use recursum::{FileHash, HashError};
+
+use crate::encrypt::Recipients;
+use crate::output::{Compression, ManifestWriter};
+
+/// Columns in the order they are written.
+const SCHEMA: &str = "
+message recursum {
+    REQUIRED BYTE_ARRAY path (UTF8);
+    REQUIRED BYTE_ARRAY digest (UTF8);
+    REQUIRED INT64 size;
+    OPTIONAL INT64 mtime (TIMESTAMP(MILLIS, true));
+    REQUIRED BYTE_ARRAY algorithm (UTF8);
+    REQUIRED BOOLEAN stable;
+    REQUIRED BYTE_ARRAY run_id (UTF8);
+}
+";
+/// Rows held in memory before they are written out as a row group.
+const ROW_GROUP_ROWS: usize = 1 << 20;
+
+fn to_io(e: ParquetError) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Columns of the rows not yet written.
+#[derive(Default)]
+struct Rows {
+    paths: Vec<ByteArray>,
+    digests: Vec<ByteArray>,
+    sizes: Vec<i64>,
+    /// Only for files whose modification time could be read.
+    mtimes: Vec<i64>,
+    /// 1 where there is an mtime, 0 where it is null.
+    mtime_levels: Vec<i16>,
+    stable: Vec<bool>,
+}
+
+/// Writes one row per file: path, digest, size, mtime, algorithm, stable and run_id.
+///
+/// Like the text output, the file only appears at its destination once hashing has finished.
+pub struct ParquetOutput {
+    /// None once finished
+    writer: Option<SerializedFileWriter<ManifestWriter>>,
+    algorithm: ByteArray,
+    run_id: ByteArray,
+    rows: Rows,
+}
+
+impl ParquetOutput {
+    pub fn create(
+        path: &Path,
+        algorithm: &str,
+        run_id: &str,
+        recipients: Option<&Recipients>,
+    ) -> io::Result<Self> {
+        let schema = Arc::new(parse_message_type(SCHEMA).expect("schema is valid"));
+        let props = WriterProperties::builder()
+            .set_compression(ParquetCompression::SNAPPY)
+            .build();
+        let file = ManifestWriter::create(path, Compression::None, recipients)?;
+        let writer = SerializedFileWriter::new(file, schema, Arc::new(props)).map_err(to_io)?;
+        Ok(Self {
+            writer: Some(writer),
+            algorithm: ByteArray::from(algorithm),
+            run_id: ByteArray::from(run_id),
+            rows: Rows::default(),
+        })
+    }
+
+    /// Write the buffered rows as a row group.
+    fn write_rows(&mut self) -> io::Result<()> {
+        let rows = std::mem::take(&mut self.rows);
+        if rows.paths.is_empty() {
+            return Ok(());
+        }
+        let n = rows.paths.len();
+        let writer = self.writer.as_mut().expect("output already finished");
+        let mut group = writer.next_row_group().map_err(to_io)?;
+        let mut idx = 0;
+        while let Some(mut column) = group.next_column().map_err(to_io)? {
+            match idx {
+                0 => column
+                    .typed::<ByteArrayType>()
+                    .write_batch(&rows.paths, None, None),
+                1 => column
+                    .typed::<ByteArrayType>()
+                    .write_batch(&rows.digests, None, None),
+                2 => column
+                    .typed::<Int64Type>()
+                    .write_batch(&rows.sizes, None, None),
+                3 => column.typed::<Int64Type>().write_batch(
+                    &rows.mtimes,
+                    Some(&rows.mtime_levels),
+                    None,
+                ),
+                4 => column.typed::<ByteArrayType>().write_batch(
+                    &vec![self.algorithm.clone(); n],
+                    None,
+                    None,
+                ),
+                5 => column
+                    .typed::<BoolType>()
+                    .write_batch(&rows.stable, None, None),
+                6 => column.typed::<ByteArrayType>().write_batch(
+                    &vec![self.run_id.clone(); n],
+                    None,
+                    None,
+                ),
+                _ => unreachable!("schema has 7 columns"),
+            }
+            .map_err(to_io)?;
+            column.close().map_err(to_io)?;
+            idx += 1;
+        }
+        group.close().map_err(to_io)?;
+        Ok(())
+    }
+}
+
+/// Milliseconds since the Unix epoch at which the file was last modified, if that can be read.
+fn mtime_millis(path: &Path) -> Option<i64> {
+    let modified = std::fs::symlink_metadata(path).ok()?.modified().ok()?;
+    let millis = match modified.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    };
+    Some(millis)
+}
+
+impl OutputSink for ParquetOutput {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        let rows = &mut self.rows;
+        rows.paths
+            .push(ByteArray::from(result.path.to_string_lossy().as_ref()));
+        rows.digests.push(ByteArray::from(result.digest.as_str()));
+        rows.sizes.push(result.size as i64);
+        match mtime_millis(&result.path) {
+            Some(t) => {
+                rows.mtimes.push(t);
+                rows.mtime_levels.push(1);
+            }
+            None => rows.mtime_levels.push(0),
+        }
+        rows.stable.push(result.stable);
+        if rows.paths.len() >= ROW_GROUP_ROWS {
+            self.write_rows()?;
+        }
+        Ok(())
+    }
+
+    /// As with the text output, a manifest which silently lacks a file is worse than none.
+    fn on_error(&mut self, error: &HashError) -> io::Result<()> {
+        Err(io::Error::new(error.source.kind(), error.to_string()))
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        if self.writer.is_none() {
+            return Ok(());
+        }
+        self.write_rows()?;
+        let writer = self.writer.take().expect("checked above");
+        writer.into_inner().map_err(to_io)?.finish()
+    }
+}
@@ -0,0 +1,152 @@
+//! Recording results in Redis as they arrive, so that other services can look up
+//! which files have some content (or what a file's digest is) while a long scan runs.
+use std::io;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use recursum::sink::OutputSink;
+use recursum::{FileHash, HashError};
+
+/// Commands held back to be sent together, so that small files are not each waiting on a round trip.
+const BATCH_COMMANDS: usize = 1000;
+/// Send held-back commands at least this often, so that lookups are not far behind a slow scan.
+const BATCH_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_PREFIX: &str = "recursum:";
+
+/// Which way round entries are recorded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexBy {
+    /// `SADD PREFIX<algorithm>:<digest> <path>`, so that every file with the same content is found.
+    Digest,
+    /// `SET PREFIX<path> <digest>`.
+    Path,
+}
+
+impl FromStr for IndexBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "digest" => Ok(Self::Digest),
+            "path" => Ok(Self::Path),
+            _ => Err(format!(
+                "unknown redis index '{}'; expected digest or path",
+                s
+            )),
+        }
+    }
+}
+
+/// Where and how to record results, from `//[:PASSWORD@]HOST[:PORT][/DB][?index=digest|path&prefix=PREFIX]`.
+#[derive(Debug, Clone)]
+pub struct RedisTarget {
+    /// As the redis crate reads it, without recursum's options.
+    url: String,
+    pub index: IndexBy,
+    pub prefix: String,
+}
+
+impl RedisTarget {
+    /// Parse what follows `redis:` in a --sink.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        if !s.starts_with("//") {
+            return Err(format!("expected //HOST[:PORT][/DB], got '{}'", s));
+        }
+        let (address, query) = s.split_once('?').unwrap_or((s, ""));
+        let mut target = Self {
+            url: format!("redis:{}", address),
+            index: IndexBy::Digest,
+            prefix: DEFAULT_PREFIX.to_string(),
+        };
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            match pair.split_once('=') {
+                Some(("index", v)) => target.index = v.parse()?,
+                Some(("prefix", v)) => target.prefix = v.to_string(),
+                _ => return Err(format!("unknown redis option '{}'", pair)),
+            }
+        }
+        redis::Client::open(target.url.as_str()).map_err(|e| e.to_string())?;
+        Ok(target)
+    }
+}
+
+fn to_io(e: redis::RedisError) -> io::Error {
+    io::Error::other(format!("Redis: {}", e))
+}
+
+pub struct RedisIndex {
+    connection: redis::Connection,
+    target: RedisTarget,
+    algorithm: String,
+    batch: redis::Pipeline,
+    batched: usize,
+    last_sent: Instant,
+}
+
+impl RedisIndex {
+    pub fn connect(target: &RedisTarget, algorithm: &str) -> io::Result<Self> {
+        let connection = redis::Client::open(target.url.as_str())
+            .and_then(|c| c.get_connection())
+            .map_err(to_io)?;
+        Ok(Self {
+            connection,
+            target: target.clone(),
+            algorithm: algorithm.to_string(),
+            batch: redis::pipe(),
+            batched: 0,
+            last_sent: Instant::now(),
+        })
+    }
+
+    /// Send the held-back commands, and wait for them to succeed.
+    fn send(&mut self) -> io::Result<()> {
+        if self.batched > 0 {
+            self.batch
+                .query::<()>(&mut self.connection)
+                .map_err(to_io)?;
+            self.batch.clear();
+            self.batched = 0;
+        }
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+}
+
+impl OutputSink for RedisIndex {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        // the file changed while it was read, so its digest describes no content it ever had
+        if !result.stable {
+            return Ok(());
+        }
+        let path = result.path.to_string_lossy();
+        let prefix = &self.target.prefix;
+        match self.target.index {
+            IndexBy::Digest => self
+                .batch
+                .cmd("SADD")
+                .arg(format!("{}{}:{}", prefix, self.algorithm, result.digest))
+                .arg(path.as_ref())
+                .ignore(),
+            IndexBy::Path => self
+                .batch
+                .cmd("SET")
+                .arg(format!("{}{}", prefix, path))
+                .arg(&result.digest)
+                .ignore(),
+        };
+        self.batched += 1;
+        if self.batched >= BATCH_COMMANDS || self.last_sent.elapsed() >= BATCH_INTERVAL {
+            self.send()?;
+        }
+        Ok(())
+    }
+
+    /// Files which could not be read are reported by the other outputs; the index holds what could be.
+    fn on_error(&mut self, _error: &HashError) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
@@ -0,0 +1,444 @@
+//! Find files with identical contents, and optionally replace duplicates with links.
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::hash::Hash;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use structopt::StructOpt;
+use tokio_stream::{iter, StreamExt};
+
+use recursum::extents;
+use recursum::hashers;
+use recursum::walk::{WalkOptions, WalkerKind};
+use recursum::{hash_stream, queue_length, runtime, HashOptions};
+
+use crate::or_num_cpus;
+use crate::progress::{HumanBytes, Progress};
+
+const COMPARE_BUFFER_SIZE: usize = 64 * 1024;
+/// Bytes read from each end of a file by --edge-prefilter.
+const EDGE_SIZE: u64 = 4 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Report,
+    Hardlink,
+    Reflink,
+    Symlink,
+    PrintScript,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "report" => Ok(Self::Report),
+            "hardlink" => Ok(Self::Hardlink),
+            "reflink" => Ok(Self::Reflink),
+            "symlink" => Ok(Self::Symlink),
+            "print-script" => Ok(Self::PrintScript),
+            _ => Err(format!(
+                "unknown action '{}'; expected report, hardlink, reflink, symlink, or print-script",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct DedupeOpt {
+    /// Files and/or directories to search for duplicates. Within each group of identical files, the first one found is kept, so list the inputs whose copies you prefer first.
+    #[structopt(required = true, parse(from_os_str))]
+    input: Vec<PathBuf>,
+    /// What to do with duplicates: report (print groups of identical files, separated by blank lines), hardlink, reflink or symlink (replace each duplicate with a link to the kept file), or print-script (print a shell script which hard-links duplicates, for review).
+    #[structopt(long = "action", default_value = "report")]
+    action: Action,
+    /// Before hashing whole files, compare hashes of the first and last 4 KiB of files with the same size. Saves reading large files which differ near the start or end, at the cost of extra seeks.
+    #[structopt(long = "edge-prefilter")]
+    edge_prefilter: bool,
+    /// Compare the contents of files with matching hashes byte for byte before treating them as duplicates, to rule out hash collisions.
+    #[structopt(long = "verify-bytes")]
+    verify_bytes: bool,
+    /// Hashing threads.
+    #[structopt(short = "t", long = "threads")]
+    threads: Option<usize>,
+    /// Do not show progress information.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+}
+
+/// Files with identical contents, in the order they were found.
+struct Group {
+    digest: String,
+    size: u64,
+    paths: Vec<PathBuf>,
+}
+
+/// Every file in the inputs (once each, however its path is spelled) with its size, in the order found.
+fn find_files(inputs: &[PathBuf], threads: usize) -> Vec<(PathBuf, u64)> {
+    let mut seen = HashSet::new();
+    let mut files = Vec::new();
+    let mut add = |path: PathBuf| match fs::metadata(&path) {
+        // overlapping inputs (e.g. d and ./d) find the same file twice, as do hard links
+        Ok(meta) => match file_id(&path, &meta) {
+            Ok(id) => {
+                if seen.insert(id) {
+                    files.push((path, meta.len()));
+                }
+            }
+            Err(e) => eprintln!("warning: could not resolve {:?}: {}", path, e),
+        },
+        Err(e) => eprintln!("warning: could not read metadata of {:?}: {}", path, e),
+    };
+    for input in inputs {
+        if input.is_dir() {
+            let walker = WalkerKind::for_order(None)
+                .build(
+                    threads,
+                    queue_length(threads),
+                    WalkOptions {
+                        order: None,
+                        sort: true,
+                        skip_hidden: false,
+                        prune: Default::default(),
+                        detect_case_collisions: false,
+                        dir_limits: None,
+                        skipped: None,
+                    },
+                )
+                .unwrap_or_else(|e| panic!("{}", e));
+            walker.files(input).for_each(&mut add);
+        } else {
+            add(input.clone());
+        }
+    }
+    files
+}
+
+/// Keep only files whose key is shared by at least one other file, in the same order.
+/// Files with no key are dropped.
+fn drop_unique<K, F>(files: Vec<(PathBuf, u64)>, mut key: F) -> Vec<(PathBuf, u64)>
+where
+    K: Hash + Eq,
+    F: FnMut(&Path, u64) -> Option<K>,
+{
+    let keys: Vec<_> = files.iter().map(|(path, size)| key(path, *size)).collect();
+    let mut counts = HashMap::new();
+    for k in keys.iter().flatten() {
+        *counts.entry(k).or_insert(0) += 1;
+    }
+    files
+        .into_iter()
+        .zip(keys.iter())
+        .filter(|(_, k)| k.as_ref().is_some_and(|k| counts[k] > 1))
+        .map(|(f, _)| f)
+        .collect()
+}
+
+/// Hash of the first and last few KiB of a file, or an empty string if the file is small enough that this would not save much.
+fn edge_digest(path: &Path, size: u64) -> io::Result<String> {
+    if size <= 2 * EDGE_SIZE {
+        return Ok(String::new());
+    }
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; EDGE_SIZE as usize];
+    let mut hasher = (hashers::default_factory())();
+    file.read_exact(&mut buf)?;
+    hasher.update(&buf);
+    file.seek(SeekFrom::Start(size - EDGE_SIZE))?;
+    file.read_exact(&mut buf)?;
+    hasher.update(&buf);
+    Ok(hex::encode(hasher.finalize()))
+}
+
+async fn find_duplicates(opt: &DedupeOpt, threads: usize) -> Vec<Group> {
+    let hash_opts = HashOptions {
+        jobs: threads,
+        ..Default::default()
+    };
+
+    let files = find_files(&opt.input, threads);
+    let n_files = files.len();
+    // empty files are trivially identical, and linking them saves nothing
+    let mut candidates = drop_unique(files, |_, size| if size > 0 { Some(size) } else { None });
+    if opt.edge_prefilter {
+        candidates = drop_unique(candidates, |path, size| match edge_digest(path, size) {
+            Ok(digest) => Some((size, digest)),
+            Err(e) => {
+                eprintln!("warning: could not read {:?}: {}", path, e);
+                None
+            }
+        });
+    }
+    let n_candidates = candidates.len();
+    let candidate_bytes: u64 = candidates.iter().map(|(_, size)| size).sum();
+
+    let progress = if opt.quiet {
+        None
+    } else {
+        Some(Progress::new())
+    };
+    let mut by_content: HashMap<(u64, String), Vec<PathBuf>> = HashMap::new();
+    let paths: Vec<_> = candidates.into_iter().map(|(path, _)| path).collect();
+    let mut results = hash_stream(iter(paths), hash_opts);
+    while let Some(result) = results.next().await {
+        let result = match result {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!("warning: {}", e);
+                continue;
+            }
+        };
+        if let Some(p) = &progress {
+            p.update(&result.path, result.size);
+        }
+        by_content
+            .entry((result.size, result.digest))
+            .or_default()
+            .push(result.path);
+    }
+    if let Some(p) = progress {
+        p.finish();
+        eprintln!(
+            "{} of {} files ({}) needed to be hashed",
+            n_candidates,
+            n_files,
+            HumanBytes(candidate_bytes)
+        );
+    }
+
+    let mut groups: Vec<_> = by_content
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|((size, digest), paths)| Group {
+            digest,
+            size,
+            paths,
+        })
+        .collect();
+    groups.sort_by(|a, b| a.paths[0].cmp(&b.paths[0]));
+    groups
+}
+
+/// Compare two files byte for byte.
+pub fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut a = BufReader::with_capacity(COMPARE_BUFFER_SIZE, File::open(a)?);
+    let mut b = BufReader::with_capacity(COMPARE_BUFFER_SIZE, File::open(b)?);
+    loop {
+        let (a_buf, b_buf) = (a.fill_buf()?, b.fill_buf()?);
+        if a_buf.is_empty() || b_buf.is_empty() {
+            return Ok(a_buf.is_empty() && b_buf.is_empty());
+        }
+        let len = a_buf.len().min(b_buf.len());
+        if a_buf[..len] != b_buf[..len] {
+            return Ok(false);
+        }
+        a.consume(len);
+        b.consume(len);
+    }
+}
+
+/// Split each group into sets of files which are identical byte for byte.
+fn verify_groups(groups: Vec<Group>) -> Vec<Group> {
+    let mut verified = Vec::with_capacity(groups.len());
+    for Group {
+        digest,
+        size,
+        paths,
+    } in groups
+    {
+        let mut subgroups: Vec<Vec<PathBuf>> = Vec::new();
+        'paths: for path in paths {
+            for sub in subgroups.iter_mut() {
+                match same_contents(&sub[0], &path) {
+                    Ok(true) => {
+                        sub.push(path);
+                        continue 'paths;
+                    }
+                    Ok(false) => (),
+                    Err(e) => {
+                        eprintln!("warning: could not compare {:?}: {}", path, e);
+                        continue 'paths;
+                    }
+                }
+            }
+            if let Some(first) = subgroups.first() {
+                eprintln!(
+                    "warning: hash collision: {:?} and {:?} have the same hash but different contents",
+                    first[0], path
+                );
+            }
+            subgroups.push(vec![path]);
+        }
+        verified.extend(
+            subgroups
+                .into_iter()
+                .filter(|paths| paths.len() > 1)
+                .map(|paths| Group {
+                    digest: digest.clone(),
+                    size,
+                    paths,
+                }),
+        );
+    }
+    verified
+}
+
+/// What identifies the file at `path`, whichever path it is reached by: its device and inode.
+#[cfg(unix)]
+fn file_id(_path: &Path, meta: &fs::Metadata) -> io::Result<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Ok((meta.dev(), meta.ino()))
+}
+
+/// What identifies the file at `path`, whichever path it is reached by: its canonical path.
+#[cfg(not(unix))]
+fn file_id(path: &Path, _meta: &fs::Metadata) -> io::Result<PathBuf> {
+    fs::canonicalize(path)
+}
+
+fn same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    Ok(file_id(a, &fs::metadata(a)?)? == file_id(b, &fs::metadata(b)?)?)
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+/// Replace `dup` with a link to `keep`.
+///
+/// The link is created next to `dup` and renamed over it, so `dup` is never missing.
+fn replace_with_link(keep: &Path, dup: &Path, size: u64, action: Action) -> io::Result<()> {
+    let meta = fs::symlink_metadata(dup)?;
+    if !meta.is_file() || meta.len() != size {
+        return Err(io::Error::other("file changed since it was hashed"));
+    }
+    // replacing a file with a link to itself would lose it
+    if same_file(keep, dup)? {
+        return Ok(());
+    }
+
+    let mut tmp_name = OsStr::new(".").to_os_string();
+    tmp_name.push(dup.file_name().unwrap_or_default());
+    tmp_name.push(format!(".recursum-{}", std::process::id()));
+    let tmp = dup.with_file_name(tmp_name);
+
+    match action {
+        Action::Hardlink => fs::hard_link(keep, &tmp)?,
+        Action::Reflink => extents::reflink(keep, &tmp)?,
+        Action::Symlink => symlink(&fs::canonicalize(keep)?, &tmp)?,
+        Action::Report | Action::PrintScript => unreachable!(),
+    }
+    fs::rename(&tmp, dup).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
+
+/// Quote a path for a POSIX shell.
+fn shell_quote(path: &Path) -> Vec<u8> {
+    #[cfg(unix)]
+    let bytes = {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    };
+    #[cfg(not(unix))]
+    let bytes = path.to_string_lossy().into_owned().into_bytes();
+
+    let mut quoted = vec![b'\''];
+    for b in bytes {
+        if b == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(b);
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+fn write_report(groups: &[Group], out: &mut dyn Write) -> io::Result<()> {
+    for (idx, group) in groups.iter().enumerate() {
+        if idx > 0 {
+            writeln!(out)?;
+        }
+        for path in &group.paths {
+            writeln!(out, "{}", path.to_string_lossy())?;
+        }
+    }
+    Ok(())
+}
+
+fn write_script(groups: &[Group], out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "#!/bin/sh")?;
+    writeln!(out, "# generated by recursum dedupe: review before running")?;
+    writeln!(out, "set -e")?;
+    for group in groups {
+        writeln!(out)?;
+        writeln!(out, "# {} ({} bytes each)", group.digest, group.size)?;
+        let keep = shell_quote(&group.paths[0]);
+        for dup in &group.paths[1..] {
+            out.write_all(b"ln -f -- ")?;
+            out.write_all(&keep)?;
+            out.write_all(b" ")?;
+            out.write_all(&shell_quote(dup))?;
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn run(opt: DedupeOpt) {
+    let threads = or_num_cpus(opt.threads);
+    let rt = runtime(threads, false).unwrap();
+    let mut groups = rt.block_on(find_duplicates(&opt, threads));
+    if opt.verify_bytes {
+        groups = verify_groups(groups);
+    }
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let mut failed = 0;
+    match opt.action {
+        Action::Report => write_report(&groups, &mut out).expect("could not write output"),
+        Action::PrintScript => write_script(&groups, &mut out).expect("could not write output"),
+        action => {
+            for group in &groups {
+                let keep = &group.paths[0];
+                for dup in &group.paths[1..] {
+                    if let Err(e) = replace_with_link(keep, dup, group.size, action) {
+                        eprintln!("warning: could not replace {:?}: {}", dup, e);
+                        failed += 1;
+                    }
+                }
+            }
+        }
+    }
+    out.flush().expect("could not write output");
+
+    if !opt.quiet {
+        let n_dups: usize = groups.iter().map(|g| g.paths.len() - 1).sum();
+        let bytes: u64 = groups
+            .iter()
+            .map(|g| g.size * (g.paths.len() as u64 - 1))
+            .sum();
+        eprintln!(
+            "{} duplicate files in {} groups ({} reclaimable)",
+            n_dups,
+            groups.len(),
+            HumanBytes(bytes)
+        );
+        if failed > 0 {
+            eprintln!("{} duplicates could not be replaced", failed);
+        }
+    }
+}
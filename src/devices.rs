@@ -0,0 +1,127 @@
+//! Limits on how many files are read at once from each device,
+//! so that a spinning disk is not made to seek between dozens of readers while an SSD sits idle.
+//!
+//! Devices are told apart by the device number of each file (`st_dev`),
+//! so each partition or filesystem is limited separately.
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Semaphore;
+
+/// Concurrent reads from each rotational device, with `auto`.
+pub const AUTO_ROTATIONAL_CONCURRENCY: usize = 1;
+
+#[derive(Debug)]
+pub struct DeviceLimits {
+    /// by device number, as given
+    fixed: HashMap<u64, Arc<Semaphore>>,
+    /// whether to limit rotational devices which were not given
+    auto: bool,
+    /// by device number, as detected; None for unlimited devices
+    detected: Mutex<HashMap<u64, Option<Arc<Semaphore>>>>,
+}
+
+impl DeviceLimits {
+    /// From specs like `/mnt/archive=2` (a block device as mounted, or any path on that filesystem,
+    /// and the number of files to read from it at once), or `auto` to limit every rotational device
+    /// to [AUTO_ROTATIONAL_CONCURRENCY].
+    pub fn parse(specs: &[String]) -> Result<Self, String> {
+        let mut fixed = HashMap::new();
+        let mut auto = false;
+        for spec in specs {
+            if spec == "auto" {
+                auto = true;
+                continue;
+            }
+            let (path, n) = spec
+                .rsplit_once('=')
+                .ok_or_else(|| format!("expected PATH=N or auto, got '{}'", spec))?;
+            let n: usize = n.parse().ok().filter(|n| *n > 0).ok_or_else(|| {
+                format!("bad concurrency in '{}'; expected a positive number", spec)
+            })?;
+            let meta =
+                fs::metadata(path).map_err(|e| format!("could not read {:?}: {}", path, e))?;
+            fixed.insert(
+                named_device(Path::new(path), &meta)?,
+                Arc::new(Semaphore::new(n)),
+            );
+        }
+        Ok(Self {
+            fixed,
+            auto,
+            detected: Mutex::default(),
+        })
+    }
+
+    /// The semaphore limiting reads from the device holding the file, if it is limited.
+    pub(crate) fn limit_for(&self, meta: &Metadata) -> Option<Arc<Semaphore>> {
+        let device = device_of(meta)?;
+        if let Some(limit) = self.fixed.get(&device) {
+            return Some(Arc::clone(limit));
+        }
+        if !self.auto {
+            return None;
+        }
+        let mut detected = self.detected.lock().expect("device limits poisoned");
+        detected
+            .entry(device)
+            .or_insert_with(|| {
+                is_rotational(device)
+                    .filter(|r| *r)
+                    .map(|_| Arc::new(Semaphore::new(AUTO_ROTATIONAL_CONCURRENCY)))
+            })
+            .clone()
+    }
+}
+
+#[cfg(unix)]
+fn device_of(meta: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(meta.dev())
+}
+
+#[cfg(not(unix))]
+fn device_of(_meta: &Metadata) -> Option<u64> {
+    None
+}
+
+/// The device a spec refers to: the device itself for a block device node, otherwise the one holding the path.
+#[cfg(unix)]
+fn named_device(_path: &Path, meta: &Metadata) -> Result<u64, String> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    if meta.file_type().is_block_device() {
+        Ok(meta.rdev())
+    } else {
+        Ok(meta.dev())
+    }
+}
+
+#[cfg(not(unix))]
+fn named_device(path: &Path, _meta: &Metadata) -> Result<u64, String> {
+    Err(format!(
+        "cannot limit concurrency for {:?}: device numbers are only available on Unix",
+        path
+    ))
+}
+
+/// Whether the kernel reports the block device as rotational, or None if it is not a block device
+/// (e.g. a network or FUSE filesystem).
+#[cfg(target_os = "linux")]
+fn is_rotational(device: u64) -> Option<bool> {
+    // as glibc's gnu_dev_major and gnu_dev_minor
+    let major = ((device >> 8) & 0xfff) | ((device >> 32) & !0xfff);
+    let minor = (device & 0xff) | ((device >> 12) & !0xff);
+    let base = format!("/sys/dev/block/{}:{}", major, minor);
+    // partitions keep their queue settings in the parent device
+    ["queue/rotational", "../queue/rotational"]
+        .iter()
+        .find_map(|p| fs::read_to_string(Path::new(&base).join(p)).ok())
+        .map(|r| r.trim() == "1")
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_rotational(_device: u64) -> Option<bool> {
+    None
+}
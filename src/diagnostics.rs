@@ -0,0 +1,121 @@
+//! Where the time goes in [hash_stream](crate::hash_stream), for tuning the numbers of threads and walkers.
+//!
+//! Timing every read and hash costs a little, so this is only collected if
+//! [HashOptions::diagnostics](crate::HashOptions::diagnostics) is set.
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Totals, summed over every file and hashing job.
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    files: AtomicU64,
+    /// between a job being spawned and a thread starting it
+    queued: AtomicU64,
+    reading: AtomicU64,
+    hashing: AtomicU64,
+    /// the hashing loop waiting for the next path, i.e. for the walker
+    path_wait: AtomicU64,
+    /// times the hashing loop waited for the oldest job, while it was still running
+    head_stalls: AtomicU64,
+    head_stall: AtomicU64,
+    /// the hashing loop waiting for a result to be taken, i.e. for the output
+    output_wait: AtomicU64,
+}
+
+fn add(counter: &AtomicU64, duration: Duration) {
+    counter.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+}
+
+fn get(counter: &AtomicU64) -> Duration {
+    Duration::from_nanos(counter.load(Ordering::Relaxed))
+}
+
+impl Diagnostics {
+    pub(crate) fn file(&self, queued: Duration) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        add(&self.queued, queued);
+    }
+
+    pub(crate) fn reading(&self, d: Duration) {
+        add(&self.reading, d);
+    }
+
+    pub(crate) fn hashing(&self, d: Duration) {
+        add(&self.hashing, d);
+    }
+
+    pub(crate) fn path_wait(&self, d: Duration) {
+        add(&self.path_wait, d);
+    }
+
+    /// The oldest job finished at `finished`, after the loop started waiting for it at `waited_from`.
+    pub(crate) fn head_wait(&self, waited_from: Instant, finished: Instant) {
+        if finished > waited_from {
+            self.head_stalls.fetch_add(1, Ordering::Relaxed);
+            add(&self.head_stall, finished - waited_from);
+        }
+    }
+
+    pub(crate) fn output_wait(&self, d: Duration) {
+        add(&self.output_wait, d);
+    }
+}
+
+fn percent(part: Duration, whole: Duration) -> f64 {
+    if whole.as_nanos() == 0 {
+        0.0
+    } else {
+        100.0 * part.as_secs_f64() / whole.as_secs_f64()
+    }
+}
+
+/// A breakdown over several lines, with a hint about the likely bottleneck.
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (queued, reading, hashing) =
+            (get(&self.queued), get(&self.reading), get(&self.hashing));
+        let working = reading + hashing;
+        let (path_wait, head_stall, output_wait) = (
+            get(&self.path_wait),
+            get(&self.head_stall),
+            get(&self.output_wait),
+        );
+        writeln!(
+            f,
+            "{} files; time summed over hashing jobs:",
+            self.files.load(Ordering::Relaxed)
+        )?;
+        // jobs are queued a few deep per thread, so this is expected to be large
+        writeln!(f, "  {:<20} {:>10.2?}", "queued for a thread", queued)?;
+        for (label, d) in &[("reading", reading), ("hashing", hashing)] {
+            writeln!(
+                f,
+                "  {:<20} {:>10.2?} ({:.0}%)",
+                label,
+                d,
+                percent(*d, working)
+            )?;
+        }
+        writeln!(f, "time the hashing loop waited:")?;
+        writeln!(f, "  {:<20} {:>10.2?}", "for the walker", path_wait)?;
+        writeln!(
+            f,
+            "  {:<20} {:>10.2?} ({} times)",
+            "for the oldest job",
+            head_stall,
+            self.head_stalls.load(Ordering::Relaxed)
+        )?;
+        writeln!(f, "  {:<20} {:>10.2?}", "for the output", output_wait)?;
+        let hint = if output_wait > path_wait.max(head_stall) {
+            "writing output is the bottleneck"
+        } else if path_wait > head_stall {
+            "walking is the bottleneck; try more --walkers"
+        } else if reading > hashing {
+            "reading is the bottleneck; more --threads may help on SSDs or network storage, but not on one spinning disk"
+        } else {
+            "hashing is the bottleneck; try more --threads, if there are idle cores, or a faster --hash"
+        };
+        write!(f, "{}", hint)
+    }
+}
@@ -0,0 +1,502 @@
+//! Compare two manifests, without reading the files they describe.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+
+use structopt::StructOpt;
+
+use crate::color::{self, Role};
+use crate::manifest::{self, algorithms_for_length, Manifest};
+use crate::normalize::NormalForm;
+use crate::output::UNSTABLE_PREFIX;
+#[cfg(feature = "redact")]
+use crate::redact::Redactor;
+#[cfg(feature = "report")]
+use crate::report::{Report, ReportFormat, Table};
+
+#[derive(Debug, StructOpt)]
+pub struct DiffOpt {
+    /// Earlier manifest, or - for stdin. Compressed manifests ending in .gz or .zst are decompressed.
+    #[structopt(parse(from_os_str))]
+    old: PathBuf,
+    /// Later manifest, or - for stdin.
+    #[structopt(parse(from_os_str))]
+    new: PathBuf,
+    /// Which run of the old manifest to compare, counting from 1, if several were appended to it (with --append). Defaults to the last.
+    #[structopt(long = "old-run")]
+    old_run: Option<usize>,
+    /// Which run of the new manifest to compare, as --old-run. E.g. `diff-manifests --old-run 1 log.tsv log.tsv` compares the first and last runs in one file.
+    #[structopt(long = "new-run")]
+    new_run: Option<usize>,
+    /// Directory to make the old manifest's paths relative to. Defaults to the root recorded in its header, if any.
+    #[structopt(long = "old-root", parse(from_os_str))]
+    old_root: Option<PathBuf>,
+    /// Directory to make the new manifest's paths relative to. Defaults to the root recorded in its header, if any.
+    #[structopt(long = "new-root", parse(from_os_str))]
+    new_root: Option<PathBuf>,
+    /// Report files which moved as removed and added, rather than matching them up by their contents.
+    #[structopt(long = "no-moves")]
+    no_moves: bool,
+    /// Rather than listing each file, print a table of how many files were added, removed, changed and moved under each directory (including its subdirectories) which has any changes.
+    #[structopt(long = "summarize-dirs")]
+    summarize_dirs: bool,
+    /// With --summarize-dirs, only list directories up to this many levels below the root.
+    #[structopt(long = "summary-depth", requires = "summarize-dirs")]
+    summary_depth: Option<usize>,
+    /// Also write a report for people to read, in FORMAT (html or markdown), to FILE: a summary, the changes under each directory (as with --summarize-dirs), then the changed files in each directory.
+    #[cfg(feature = "report")]
+    #[structopt(long = "report", number_of_values = 2, value_names = &["FORMAT", "FILE"])]
+    report: Vec<String>,
+    /// Compare and print paths in this Unicode normalization form, nfc or nfd, so that manifests made on macOS (whose names are NFD) and Linux (usually NFC) match up, as --normalize-paths when hashing.
+    #[structopt(long = "normalize-paths")]
+    normalize_paths: Option<NormalForm>,
+    /// Key one manifest's paths were redacted with (by --redact-paths), to compare the other manifest's paths in the redacted domain.
+    #[cfg(feature = "redact")]
+    #[structopt(long = "redact-key-file", parse(from_os_str))]
+    redact_key_file: Option<PathBuf>,
+}
+
+/// How many files had each kind of change.
+#[derive(Debug, Default)]
+struct Tally {
+    added: usize,
+    removed: usize,
+    changed: usize,
+    moved: usize,
+}
+
+impl Tally {
+    fn count(&mut self, change: &Change) {
+        match change {
+            Change::Added(_) => self.added += 1,
+            Change::Removed(_) => self.removed += 1,
+            Change::Changed(_) => self.changed += 1,
+            Change::Moved(..) => self.moved += 1,
+        }
+    }
+}
+
+/// A difference between the manifests.
+enum Change {
+    Added(PathBuf),
+    Removed(PathBuf),
+    Changed(PathBuf),
+    /// Removed from the first path and added at the second, with the same contents.
+    Moved(PathBuf, PathBuf),
+}
+
+impl Change {
+    fn path(&self) -> &Path {
+        match self {
+            Self::Added(p) | Self::Removed(p) | Self::Changed(p) | Self::Moved(p, _) => p,
+        }
+    }
+
+    /// The file's path, and where it moved to, if it did.
+    fn paths(&self) -> impl Iterator<Item = &Path> {
+        let to = match self {
+            Self::Moved(_, to) => Some(to.as_path()),
+            _ => None,
+        };
+        std::iter::once(self.path()).chain(to)
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Added(_) => "added",
+            Self::Removed(_) => "removed",
+            Self::Changed(_) => "changed",
+            Self::Moved(..) => "moved",
+        }
+    }
+
+    fn write(&self, w: &mut dyn Write) -> io::Result<()> {
+        match self {
+            Self::Moved(from, to) => writeln!(w, "moved\t{}\t{}", from.display(), to.display()),
+            other => writeln!(w, "{}\t{}", other.kind(), other.path().display()),
+        }
+    }
+}
+
+/// The manifest's algorithm, as named in it or guessed from the length of its digests and its format.
+fn algorithm(manifest: &Manifest) -> Option<String> {
+    manifest.algorithm.clone().or_else(|| {
+        manifest
+            .entries
+            .first()
+            .and_then(|e| {
+                let len = e.digest.trim_start_matches(UNSTABLE_PREFIX).len();
+                algorithms_for_length(len, manifest.format).first().copied()
+            })
+            .map(String::from)
+    })
+}
+
+/// Path relative to `root` (if it is under it), without `.` components,
+/// so that the same file is listed under the same path in both manifests.
+fn normalize(path: &Path, root: Option<&Path>) -> PathBuf {
+    let path = root.and_then(|r| path.strip_prefix(r).ok()).unwrap_or(path);
+    path.components()
+        .filter(|c| *c != Component::CurDir)
+        .collect()
+}
+
+/// What a manifest records about a file's contents.
+struct Record {
+    /// Lowercase, and truncated to the length being compared.
+    digest: String,
+    size: Option<u64>,
+}
+
+impl Record {
+    /// Could be the same contents; sizes are only compared if both manifests record them.
+    fn matches(&self, other: &Self) -> bool {
+        self.digest == other.digest
+            && match (self.size, other.size) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            }
+    }
+}
+
+/// Normalized path to record, with digests truncated to `digest_length` if given,
+/// and paths in Unicode normalization form `form` if given.
+fn records(
+    manifest: &Manifest,
+    root: Option<&Path>,
+    digest_length: Option<usize>,
+    form: Option<NormalForm>,
+) -> BTreeMap<PathBuf, Record> {
+    manifest
+        .entries
+        .iter()
+        .map(|e| {
+            let mut digest = e.digest.to_ascii_lowercase();
+            if let Some(len) = digest_length {
+                digest.truncate(len);
+            }
+            let path = match &manifest.relative_to {
+                Some(dir) => dir.join(&e.path),
+                None => PathBuf::from(&e.path),
+            };
+            let path = normalize(&path, root);
+            let path = match form {
+                Some(f) => f.path(&path),
+                None => path,
+            };
+            (
+                path,
+                Record {
+                    digest,
+                    size: e.size,
+                },
+            )
+        })
+        .collect()
+}
+
+/// The records with their paths redacted, to compare with a redacted manifest.
+#[cfg(feature = "redact")]
+fn redact(records: BTreeMap<PathBuf, Record>, key_file: &Path) -> BTreeMap<PathBuf, Record> {
+    let redactor = Redactor::load(key_file)
+        .unwrap_or_else(|e| panic!("could not read --redact-key-file {:?}: {}", key_file, e));
+    records
+        .into_iter()
+        .map(|(path, record)| (PathBuf::from(redactor.redact(&path)), record))
+        .collect()
+}
+
+/// The manifest, and a warning if any of its lines could not be parsed.
+fn load(path: &Path, run: Option<usize>) -> (Manifest, Option<String>) {
+    let manifest = manifest::load_run(path, run)
+        .unwrap_or_else(|e| panic!("could not read manifest {:?}: {}", path, e));
+    let warning = (manifest.malformed > 0).then(|| {
+        format!(
+            "{:?}: {} line(s) improperly formatted",
+            path, manifest.malformed
+        )
+    });
+    (manifest, warning)
+}
+
+/// Of the added files which could be `path` moved, the one with the same file name if any, or else the first.
+fn claim_move(
+    path: &Path,
+    record: &Record,
+    candidates: &mut Vec<(&PathBuf, &Record)>,
+) -> Option<PathBuf> {
+    let idx = candidates
+        .iter()
+        .position(|(p, r)| r.matches(record) && p.file_name() == path.file_name())
+        .or_else(|| candidates.iter().position(|(_, r)| r.matches(record)))?;
+    Some(candidates.remove(idx).0.clone())
+}
+
+fn changes(
+    old: &BTreeMap<PathBuf, Record>,
+    new: &BTreeMap<PathBuf, Record>,
+    detect_moves: bool,
+) -> Vec<Change> {
+    let mut changes = Vec::new();
+    // added files by digest, in order, to be claimed by removed files with the same contents
+    let mut added: HashMap<&str, Vec<(&PathBuf, &Record)>> = HashMap::new();
+    for (path, record) in new {
+        if !old.contains_key(path) {
+            added
+                .entry(&record.digest)
+                .or_default()
+                .push((path, record));
+        }
+    }
+    for (path, record) in old {
+        match new.get(path) {
+            Some(r) if r.matches(record) => (),
+            Some(_) => changes.push(Change::Changed(path.clone())),
+            None => {
+                let moved = added
+                    .get_mut(record.digest.as_str())
+                    .filter(|_| detect_moves)
+                    .and_then(|candidates| claim_move(path, record, candidates));
+                changes.push(match moved {
+                    Some(to) => Change::Moved(path.clone(), to),
+                    None => Change::Removed(path.clone()),
+                });
+            }
+        }
+    }
+    for paths in added.into_values() {
+        changes.extend(paths.into_iter().map(|(p, _)| Change::Added(p.clone())));
+    }
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+    changes
+}
+
+/// Changes under each directory (the root being empty) down to `depth` levels, if given.
+/// A file which moved counts once towards each directory it was in before or after.
+fn summarize(changes: &[Change], depth: Option<usize>) -> BTreeMap<PathBuf, Tally> {
+    let mut dirs: BTreeMap<PathBuf, Tally> = BTreeMap::new();
+    for change in changes {
+        let affected: BTreeSet<_> = change
+            .paths()
+            .flat_map(|p| p.ancestors().skip(1))
+            .filter(|d| depth.is_none_or(|max| d.components().count() <= max))
+            .collect();
+        for dir in affected {
+            dirs.entry(dir.to_path_buf()).or_default().count(change);
+        }
+    }
+    dirs
+}
+
+/// How to show a directory, with the root as `.`.
+fn dir_name(dir: &Path) -> &Path {
+    if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    }
+}
+
+fn write_summary(dirs: &BTreeMap<PathBuf, Tally>, w: &mut dyn Write) -> io::Result<()> {
+    writeln!(w, "directory\tadded\tremoved\tchanged\tmoved")?;
+    for (dir, t) in dirs {
+        writeln!(
+            w,
+            "{}\t{}\t{}\t{}\t{}",
+            dir_name(dir).display(),
+            t.added,
+            t.removed,
+            t.changed,
+            t.moved
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "report")]
+fn report(
+    opt: &DiffOpt,
+    changes: &[Change],
+    tally: &Tally,
+    unchanged: usize,
+    errors: Vec<String>,
+) -> Report {
+    let summary = vec![
+        ("Old manifest", opt.old.display().to_string()),
+        ("New manifest", opt.new.display().to_string()),
+        ("Added", tally.added.to_string()),
+        ("Removed", tally.removed.to_string()),
+        ("Changed", tally.changed.to_string()),
+        ("Moved", tally.moved.to_string()),
+        ("Unchanged", unchanged.to_string()),
+    ];
+    let header = |cells: &[&str]| cells.iter().map(|c| c.to_string()).collect();
+    let mut tables = vec![Table {
+        caption: "Changes by directory".to_string(),
+        header: header(&["Directory", "Added", "Removed", "Changed", "Moved"]),
+        rows: summarize(changes, opt.summary_depth)
+            .iter()
+            .map(|(dir, t)| {
+                vec![
+                    dir_name(dir).display().to_string(),
+                    t.added.to_string(),
+                    t.removed.to_string(),
+                    t.changed.to_string(),
+                    t.moved.to_string(),
+                ]
+            })
+            .collect(),
+    }];
+    // changes are sorted by path, so each directory's are together
+    let mut dir_tables: Vec<Table> = Vec::new();
+    for change in changes {
+        let path = change.path();
+        let caption = dir_name(path.parent().unwrap_or(Path::new("")))
+            .display()
+            .to_string();
+        if dir_tables.last().is_none_or(|t| t.caption != caption) {
+            dir_tables.push(Table {
+                caption,
+                header: header(&["Change", "File", "Moved to"]),
+                rows: Vec::new(),
+            });
+        }
+        let moved_to = match change {
+            Change::Moved(_, to) => to.display().to_string(),
+            _ => String::new(),
+        };
+        dir_tables.last_mut().unwrap().rows.push(vec![
+            change.kind().to_string(),
+            path.file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            moved_to,
+        ]);
+    }
+    tables.extend(dir_tables);
+    Report {
+        title: format!(
+            "Changes from {} to {}",
+            opt.old.display(),
+            opt.new.display()
+        ),
+        summary: summary
+            .into_iter()
+            .map(|(label, value)| (label.to_string(), value))
+            .collect(),
+        tables,
+        errors,
+    }
+}
+
+pub fn run(opt: DiffOpt) {
+    #[cfg(feature = "report")]
+    let report_to = match opt.report.as_slice() {
+        [format, path] => Some((
+            format
+                .parse::<ReportFormat>()
+                .unwrap_or_else(|e| panic!("--report: {}", e)),
+            PathBuf::from(path),
+        )),
+        _ => None,
+    };
+    let (old, old_warning) = load(&opt.old, opt.old_run);
+    let (new, new_warning) = load(&opt.new, opt.new_run);
+    let errors: Vec<String> = old_warning.into_iter().chain(new_warning).collect();
+    for e in &errors {
+        eprintln!(
+            "{}",
+            color::stderr(Role::Warning, format!("WARNING: {}", e))
+        );
+    }
+
+    let (old_alg, new_alg) = (algorithm(&old), algorithm(&new));
+    if let (Some(a), Some(b)) = (&old_alg, &new_alg) {
+        if a != b {
+            panic!("cannot compare a {} manifest with a {} manifest", a, b);
+        }
+    }
+    if old.seed != new.seed {
+        panic!("the manifests were made with different seeds, so their digests cannot be compared");
+    }
+    // if either was truncated, compare as many characters as every digest has
+    let digest_length = if old.digest_length.is_some() || new.digest_length.is_some() {
+        old.entries
+            .iter()
+            .chain(&new.entries)
+            .map(|e| e.digest.len())
+            .min()
+    } else {
+        None
+    };
+    eprintln!(
+        "comparing a {} manifest with a {} manifest ({})",
+        old.format,
+        new.format,
+        old_alg
+            .or(new_alg)
+            .as_deref()
+            .unwrap_or("unknown algorithm")
+    );
+
+    let old_root = opt.old_root.as_ref().or(old.root.as_ref());
+    let new_root = opt.new_root.as_ref().or(new.root.as_ref());
+    let form = opt.normalize_paths;
+    let mut old_records = records(&old, old_root.map(PathBuf::as_path), digest_length, form);
+    let mut new_records = records(&new, new_root.map(PathBuf::as_path), digest_length, form);
+    #[cfg(feature = "redact")]
+    let redact_key_file = opt.redact_key_file.as_deref();
+    #[cfg(not(feature = "redact"))]
+    let redact_key_file: Option<&Path> = None;
+    // a redacted manifest is compared with a plain one by redacting the plain one's paths too
+    let plain = match (old.is_redacted(), new.is_redacted()) {
+        (true, false) => Some(&mut new_records),
+        (false, true) => Some(&mut old_records),
+        _ => None,
+    };
+    match (plain, redact_key_file) {
+        (None, None) => (),
+        (None, Some(_)) => {
+            panic!("--redact-key-file is for comparing a redacted manifest with one which is not")
+        }
+        (Some(_), None) => panic!(
+            "one manifest's paths are redacted; give the key with --redact-key-file to compare them"
+        ),
+        #[cfg(feature = "redact")]
+        (Some(records), Some(key_file)) => *records = redact(std::mem::take(records), key_file),
+        #[cfg(not(feature = "redact"))]
+        (Some(_), Some(_)) => unreachable!("no key without the redact feature"),
+    }
+    let changes = changes(&old_records, &new_records, !opt.no_moves);
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let mut tally = Tally::default();
+    for change in &changes {
+        tally.count(change);
+        if !opt.summarize_dirs {
+            change.write(&mut out).expect("could not write output");
+        }
+    }
+    if opt.summarize_dirs {
+        write_summary(&summarize(&changes, opt.summary_depth), &mut out)
+            .expect("could not write output");
+    }
+    out.flush().expect("could not write output");
+
+    let unchanged = old_records.len() - tally.removed - tally.changed - tally.moved;
+    eprintln!(
+        "{} added, {} removed, {} changed, {} moved, {} unchanged",
+        tally.added, tally.removed, tally.changed, tally.moved, unchanged
+    );
+    #[cfg(feature = "report")]
+    if let Some((format, path)) = report_to {
+        report(&opt, &changes, &tally, unchanged, errors)
+            .write_to(&path, format)
+            .unwrap_or_else(|e| panic!("could not write report {:?}: {}", path, e));
+    }
+    if !changes.is_empty() {
+        std::process::exit(1);
+    }
+}
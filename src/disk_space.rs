@@ -0,0 +1,124 @@
+//! Stopping before the disk an output is written to fills up, for --min-free-space,
+//! rather than failing part of the way through a line or a compressed stream.
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use recursum::platform;
+use recursum::sink::OutputSink;
+use recursum::{FileHash, HashError};
+
+use crate::color::{self, Role};
+use crate::progress::HumanBytes;
+
+/// How often to check the free space while writing.
+const CHECK_EVERY: Duration = Duration::from_secs(1);
+/// How often to check again while waiting for space to be freed.
+const POLL_EVERY: Duration = Duration::from_secs(5);
+
+/// The directory a file at `path` is written to.
+pub fn directory(path: &Path) -> &Path {
+    match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    }
+}
+
+/// The free space on the filesystem holding `dir`, if less than `min_free`.
+pub fn low_space(dir: &Path, min_free: u64) -> Option<u64> {
+    platform::free_space(dir).filter(|free| *free < min_free)
+}
+
+/// Checks the free space in the directory `inner` writes to before passing it each result.
+///
+/// Once it falls below the minimum, waits up to the given time for space to be freed;
+/// if none is, finishes `inner`, so that it holds every result so far and is properly closed, then fails.
+pub struct SpaceGuard {
+    inner: Box<dyn OutputSink>,
+    dir: PathBuf,
+    min_free: u64,
+    wait: Duration,
+    checked: Option<Instant>,
+    /// `inner` was finished early for lack of space
+    stopped: bool,
+}
+
+impl SpaceGuard {
+    pub fn new(inner: Box<dyn OutputSink>, dir: &Path, min_free: u64, wait: Duration) -> Self {
+        Self {
+            inner,
+            dir: dir.to_path_buf(),
+            min_free,
+            wait,
+            checked: None,
+            stopped: false,
+        }
+    }
+
+    fn check(&mut self) -> io::Result<()> {
+        if self.checked.is_some_and(|c| c.elapsed() < CHECK_EVERY) {
+            return Ok(());
+        }
+        self.checked = Some(Instant::now());
+        let mut free = match low_space(&self.dir, self.min_free) {
+            Some(free) => free,
+            None => return Ok(()),
+        };
+        if !self.wait.is_zero() {
+            let msg = format!(
+                "warning: only {} free in {:?}; pausing for up to {} seconds for space to be freed",
+                HumanBytes(free),
+                self.dir,
+                self.wait.as_secs()
+            );
+            eprintln!("{}", color::stderr(Role::Warning, msg));
+            let deadline = Instant::now() + self.wait;
+            while Instant::now() < deadline {
+                thread::sleep(POLL_EVERY.min(deadline.saturating_duration_since(Instant::now())));
+                free = match low_space(&self.dir, self.min_free) {
+                    Some(free) => free,
+                    None => {
+                        eprintln!("space was freed; carrying on");
+                        return Ok(());
+                    }
+                };
+            }
+        }
+        self.stopped = true;
+        self.inner.on_finish()?;
+        Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "only {} free in {:?}, less than --min-free-space {}; stopped writing there, and closed the output with the files hashed so far",
+                HumanBytes(free),
+                self.dir,
+                HumanBytes(self.min_free)
+            ),
+        ))
+    }
+}
+
+impl OutputSink for SpaceGuard {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        self.check()?;
+        self.inner.on_result(result)
+    }
+
+    fn on_error(&mut self, error: &HashError) -> io::Result<()> {
+        self.check()?;
+        self.inner.on_error(error)
+    }
+
+    fn on_skipped(&mut self, path: &Path, reason: &str) -> io::Result<()> {
+        self.check()?;
+        self.inner.on_skipped(path, reason)
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        if self.stopped {
+            return Ok(());
+        }
+        self.inner.on_finish()
+    }
+}
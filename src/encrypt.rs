@@ -0,0 +1,42 @@
+//! Encrypting manifests with age (https://age-encryption.org) as they are written, with --encrypt-output,
+//! since a manifest of a sensitive filesystem reveals its whole structure.
+//!
+//! Without the age feature, [Recipients] cannot be constructed, so nothing is encrypted.
+#[cfg(feature = "age")]
+use std::io::{self, Write};
+
+/// The public keys to encrypt outputs to; any of their private keys can decrypt them.
+#[cfg(feature = "age")]
+#[derive(Debug, Clone)]
+pub struct Recipients(Vec<age::x25519::Recipient>);
+
+#[cfg(not(feature = "age"))]
+#[derive(Debug, Clone)]
+pub enum Recipients {}
+
+/// Parse an --encrypt-output recipient, e.g. `age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p`.
+#[cfg(feature = "age")]
+pub fn parse_recipient(s: &str) -> Result<age::x25519::Recipient, String> {
+    s.parse()
+        .map_err(|e| format!("'{}' is not an age recipient: {}", s, e))
+}
+
+#[cfg(feature = "age")]
+impl Recipients {
+    /// None if there are no recipients, i.e. outputs are not to be encrypted.
+    pub fn new(recipients: &[age::x25519::Recipient]) -> Option<Self> {
+        if recipients.is_empty() {
+            None
+        } else {
+            Some(Self(recipients.to_vec()))
+        }
+    }
+
+    /// Encrypt everything written to the returned writer, and write it to `output`.
+    pub fn encrypt<W: Write>(&self, output: W) -> io::Result<age::stream::StreamWriter<W>> {
+        let encryptor =
+            age::Encryptor::with_recipients(self.0.iter().map(|r| r as &dyn age::Recipient))
+                .map_err(io::Error::other)?;
+        encryptor.wrap_output(output)
+    }
+}
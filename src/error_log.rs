@@ -0,0 +1,173 @@
+//! Logging files which could not be hashed to their own file, for --errors-to, instead of stopping,
+//! so that the manifest holds only digests and every failure is kept in full.
+//!
+//! Each failure is one JSON object per line, written as it happens, e.g.
+//!
+//! ```text
+//! {"path":"my_dir/locked.db","phase":"open","errno":13,"error":"Permission denied (os error 13)","time":"2020-11-03T17:04:05Z","run_id":"9c3e5d1f0a27b884"}
+//! ```
+//!
+//! where phase is what was being done to the file (stat, open or read, or path if --strict-paths refused it),
+//! and errno is the operating system's error code, or null if it gave none.
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use recursum::sink::OutputSink;
+use recursum::{FileHash, HashError};
+
+use crate::color::{self, Role};
+use crate::header::rfc3339;
+use crate::sinks::json_string;
+
+/// Prefix of an --errors-to destination which is a file descriptor, rather than a path.
+const FD_PREFIX: &str = "fd:";
+
+pub struct ErrorLog {
+    /// as given
+    destination: String,
+    writer: Mutex<LineWriter<File>>,
+    count: AtomicU64,
+    run_id: String,
+}
+
+impl ErrorLog {
+    /// Log to `destination`: a path, replacing any file there, or `fd:N` for a descriptor
+    /// which is already open, e.g. `fd:2` for stderr.
+    pub fn open(destination: &str, run_id: &str) -> io::Result<Self> {
+        let file = match destination.strip_prefix(FD_PREFIX) {
+            Some(fd) => {
+                let fd = fd.parse().map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid file descriptor {:?}", fd),
+                    )
+                })?;
+                duplicate(fd)?
+            }
+            None => {
+                // a device or pipe is written to as it is
+                let regular = std::fs::metadata(destination).map_or(true, |m| m.is_file());
+                OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(regular)
+                    .open(destination)?
+            }
+        };
+        Ok(Self {
+            destination: destination.to_string(),
+            writer: Mutex::new(LineWriter::new(file)),
+            count: AtomicU64::new(0),
+            run_id: run_id.to_string(),
+        })
+    }
+
+    /// Write a record of `error`.
+    pub fn record(&self, error: &HashError) -> io::Result<()> {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let errno = error
+            .source
+            .raw_os_error()
+            .map_or("null".to_string(), |e| e.to_string());
+        let line = format!(
+            r#"{{"path":{},"phase":"{}","errno":{},"error":{},"time":"{}","run_id":{}}}"#,
+            json_string(&error.path.to_string_lossy()),
+            error.phase,
+            errno,
+            json_string(&error.source.to_string()),
+            rfc3339(SystemTime::now()),
+            json_string(&self.run_id),
+        );
+        let mut writer = self.writer.lock().expect("error log lock poisoned");
+        writeln!(writer, "{}", line)
+    }
+
+    /// How many files could not be hashed so far.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Flush the log, and warn if anything was logged; returns whether every file was hashed.
+    pub fn finish(&self) -> bool {
+        let flushed = self.writer.lock().expect("error log lock poisoned").flush();
+        if let Err(e) = flushed {
+            let msg = format!(
+                "warning: could not write --errors-to {}: {}",
+                self.destination, e
+            );
+            eprintln!("{}", color::stderr(Role::Warning, msg));
+        }
+        match self.count() {
+            0 => true,
+            n => {
+                let msg = format!(
+                    "{} files could not be hashed, and are listed in {}",
+                    n, self.destination
+                );
+                eprintln!("{}", color::stderr(Role::Bad, msg));
+                false
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn duplicate(fd: i32) -> io::Result<File> {
+    use std::os::fd::BorrowedFd;
+    // SAFETY: only borrowed for as long as it takes to duplicate it; fcntl fails if it is not open
+    let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+    Ok(File::from(borrowed.try_clone_to_owned()?))
+}
+
+#[cfg(not(unix))]
+fn duplicate(_fd: i32) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "file descriptors are only supported on Unix",
+    ))
+}
+
+/// Passes results on to `inner`, and records errors in the log instead,
+/// so that they do not stop the outputs.
+pub struct ErrorsTo {
+    inner: Box<dyn OutputSink>,
+    log: &'static ErrorLog,
+}
+
+impl ErrorsTo {
+    pub fn new(inner: Box<dyn OutputSink>, log: &'static ErrorLog) -> Self {
+        Self { inner, log }
+    }
+}
+
+impl OutputSink for ErrorsTo {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        self.inner.on_result(result)
+    }
+
+    fn on_error(&mut self, error: &HashError) -> io::Result<()> {
+        // a file cut short by cancellation is not a failure to log
+        crate::CANCEL.check()?;
+        self.log.record(error).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "could not write --errors-to {}: {}",
+                    self.log.destination, e
+                ),
+            )
+        })
+    }
+
+    fn on_skipped(&mut self, path: &Path, reason: &str) -> io::Result<()> {
+        self.inner.on_skipped(path, reason)
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        self.inner.on_finish()
+    }
+}
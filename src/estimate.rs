@@ -0,0 +1,130 @@
+//! Count the files and bytes under a directory without hashing them,
+//! and estimate how long hashing would take.
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use structopt::StructOpt;
+
+use recursum::hashers::{self, HasherFactory};
+use recursum::queue_length;
+use recursum::walk::{WalkOptions, WalkerKind, DEFAULT_PRUNE};
+
+use crate::or_num_cpus;
+use crate::progress::{HumanBytes, HumanDuration};
+use crate::stats::SizeHistogram;
+
+/// Bytes hashed by each algorithm to measure its speed.
+const BENCHMARK_LENGTH: usize = 32 * 1024 * 1024;
+/// Pieces the benchmark input is fed in, the same size as the default read buffer when hashing files.
+const BENCHMARK_CHUNK: usize = 8 * 1024;
+#[derive(Debug, StructOpt)]
+pub struct EstimateOpt {
+    /// Directory to survey.
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+    /// Directory-walking threads.
+    #[structopt(short = "w", long = "walkers")]
+    walkers: Option<usize>,
+    /// Hashing threads the estimate is for.
+    #[structopt(short = "t", long = "threads")]
+    threads: Option<usize>,
+    /// Skip hidden files and directories, as recursum --skip-hidden would.
+    #[structopt(long = "skip-hidden")]
+    skip_hidden: bool,
+    /// Skip directories with this name, as recursum --prune would. Can be given multiple times.
+    #[structopt(long = "prune", number_of_values = 1, parse(from_os_str))]
+    prune: Vec<OsString>,
+    /// Skip common version control, dependency, build and cache directories, as recursum --prune-defaults would.
+    #[structopt(long = "prune-defaults")]
+    prune_defaults: bool,
+}
+
+/// Single-threaded throughput of the algorithm, in bytes per second.
+fn benchmark(factory: HasherFactory) -> f64 {
+    let data = vec![0xa5u8; BENCHMARK_LENGTH];
+    let mut hasher = factory();
+    hasher.expect_length(BENCHMARK_LENGTH as u64);
+    let started = Instant::now();
+    for chunk in data.chunks(BENCHMARK_CHUNK) {
+        hasher.update(chunk);
+    }
+    hasher.finalize();
+    BENCHMARK_LENGTH as f64 / started.elapsed().as_secs_f64()
+}
+
+pub fn run(opt: EstimateOpt) {
+    if !opt.input.is_dir() {
+        panic!("{:?} is not a directory", opt.input);
+    }
+    let threads = or_num_cpus(opt.threads);
+    let mut prune: HashSet<OsString> = opt.prune.iter().cloned().collect();
+    if opt.prune_defaults {
+        prune.extend(DEFAULT_PRUNE.iter().map(OsString::from));
+    }
+    let walker = WalkerKind::for_order(None)
+        .build(
+            or_num_cpus(opt.walkers),
+            queue_length(threads),
+            WalkOptions {
+                order: None,
+                // order does not matter for counting
+                sort: false,
+                skip_hidden: opt.skip_hidden,
+                prune,
+                detect_case_collisions: false,
+                dir_limits: None,
+                skipped: None,
+            },
+        )
+        .unwrap_or_else(|e| panic!("{}", e));
+
+    let started = Instant::now();
+    let mut sizes = SizeHistogram::new();
+    let mut unreadable = 0;
+    for path in walker.files(&opt.input) {
+        match fs::metadata(&path) {
+            Ok(meta) => sizes.add(meta.len()),
+            Err(e) => {
+                eprintln!("warning: could not read metadata of {:?}: {}", path, e);
+                unreadable += 1;
+            }
+        }
+    }
+    let walked = started.elapsed();
+    let (files, bytes) = (sizes.files(), sizes.bytes());
+
+    println!("files: {}", files);
+    println!("bytes: {} ({})", bytes, HumanBytes(bytes));
+    if unreadable > 0 {
+        println!("unreadable: {}", unreadable);
+    }
+    println!("walked in: {}", HumanDuration(walked));
+    println!("sizes:");
+    sizes
+        .write(&mut io::stdout().lock())
+        .expect("could not write output");
+
+    println!(
+        "hashing time with {} threads, if storage keeps up (add the walk time above):",
+        threads
+    );
+    for name in hashers::names() {
+        if let Some(reason) = hashers::unsupported(&name) {
+            println!("  {}: unsupported ({})", name, reason);
+            continue;
+        }
+        let factory = hashers::get(&name).expect("name was just listed");
+        let rate = benchmark(factory) * threads as f64;
+        let eta = Duration::from_secs_f64(bytes as f64 / rate);
+        println!(
+            "  {}: {} ({}/s)",
+            name,
+            HumanDuration(eta),
+            HumanBytes(rate as u64)
+        );
+    }
+}
@@ -0,0 +1,261 @@
+//! Running a command for each result (or batch of results) as it arrives, with --exec-per-file,
+//! e.g. to tag objects in another system as they are hashed.
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::process::{Child, ExitStatus, Stdio};
+use std::str::FromStr;
+
+use recursum::sink::OutputSink;
+use recursum::FileHash;
+
+use crate::color::{self, Role};
+use crate::hook::{self, RUN_ID_VAR};
+use crate::output::UNSTABLE_PREFIX;
+
+/// Environment variable holding the file's path, when running the command for each file.
+pub const PATH_VAR: &str = "RECURSUM_PATH";
+/// Environment variable holding the file's digest, when running the command for each file.
+pub const HASH_VAR: &str = "RECURSUM_HASH";
+
+/// What to do when the command fails (or cannot be run).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExecFailure {
+    /// Stop hashing, and fail.
+    Abort,
+    /// Print a warning, and carry on.
+    Warn,
+    /// Carry on.
+    Ignore,
+}
+
+impl FromStr for ExecFailure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "abort" => Ok(Self::Abort),
+            "warn" => Ok(Self::Warn),
+            "ignore" => Ok(Self::Ignore),
+            _ => Err(format!(
+                "unknown failure policy '{}'; expected abort, warn or ignore",
+                s
+            )),
+        }
+    }
+}
+
+struct Record {
+    path: String,
+    /// Prefixed as in the output if the file changed while being hashed.
+    hash: String,
+    size: u64,
+}
+
+pub struct ExecPerFile {
+    /// With `{path}`, `{hash}` and `{size}` replaced for each file, if not batched.
+    command: String,
+    /// Files per command; if more than 1, they are given on stdin.
+    batch: usize,
+    /// Commands to run at once.
+    jobs: usize,
+    on_failure: ExecFailure,
+    run_id: String,
+    pending: Vec<Record>,
+    /// with the files they were run for, for error messages
+    running: VecDeque<(Child, String)>,
+    failures: usize,
+}
+
+impl ExecPerFile {
+    pub fn new(
+        command: &str,
+        batch: usize,
+        jobs: usize,
+        on_failure: ExecFailure,
+        run_id: &str,
+    ) -> Self {
+        Self {
+            command: command.to_string(),
+            batch: batch.max(1),
+            jobs: jobs.max(1),
+            on_failure,
+            run_id: run_id.to_string(),
+            pending: Vec::new(),
+            running: VecDeque::new(),
+            failures: 0,
+        }
+    }
+
+    /// Run the command for the pending files, once fewer than `jobs` commands are running.
+    fn launch(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.reap(self.jobs - 1)?;
+        let records = std::mem::take(&mut self.pending);
+        let mut env: Vec<(&str, OsString)> = vec![(RUN_ID_VAR, self.run_id.clone().into())];
+        let (mut cmd, described) = if self.batch == 1 {
+            let r = &records[0];
+            env.push((PATH_VAR, r.path.clone().into()));
+            env.push((HASH_VAR, r.hash.clone().into()));
+            let command = substitute(
+                &self.command,
+                &[
+                    ("{path}", quote(&r.path)),
+                    ("{hash}", quote(&r.hash)),
+                    ("{size}", r.size.to_string()),
+                ],
+            );
+            (hook::shell(&command), format!("{:?}", r.path))
+        } else {
+            let described = format!(
+                "{} files from {:?} to {:?}",
+                records.len(),
+                records[0].path,
+                records[records.len() - 1].path
+            );
+            (hook::shell(&self.command), described)
+        };
+        cmd.envs(env);
+        // so that it cannot end up in the manifest
+        cmd.stdout(io::stderr());
+        if self.batch > 1 {
+            cmd.stdin(Stdio::piped());
+        } else {
+            cmd.stdin(Stdio::null());
+        }
+        let mut child = match cmd.spawn() {
+            Ok(c) => c,
+            Err(e) => {
+                let e = io::Error::new(
+                    e.kind(),
+                    format!("could not run --exec-per-file command: {}", e),
+                );
+                return self.failed(&described, e);
+            }
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            // as coreutils, so that e.g. `sha256sum -c` could read it
+            let written = records
+                .iter()
+                .try_for_each(|r| writeln!(stdin, "{}  {}", r.hash, r.path));
+            if let Err(e) = written {
+                // the command may not want all of its input; its status decides
+                if e.kind() != io::ErrorKind::BrokenPipe {
+                    return Err(e);
+                }
+            }
+        }
+        self.running.push_back((child, described));
+        Ok(())
+    }
+
+    /// Wait until at most `max_running` commands are running, checking how each finished.
+    fn reap(&mut self, max_running: usize) -> io::Result<()> {
+        let mut idx = 0;
+        while idx < self.running.len() {
+            match self.running[idx].0.try_wait()? {
+                Some(status) => {
+                    let (_, described) = self.running.remove(idx).expect("index is in range");
+                    self.check(status, &described)?;
+                }
+                None => idx += 1,
+            }
+        }
+        while self.running.len() > max_running {
+            let (mut child, described) = self.running.pop_front().expect("more than 0 running");
+            let status = child.wait()?;
+            self.check(status, &described)?;
+        }
+        Ok(())
+    }
+
+    fn check(&mut self, status: ExitStatus, described: &str) -> io::Result<()> {
+        if status.success() {
+            return Ok(());
+        }
+        self.failed(
+            described,
+            io::Error::other(format!("--exec-per-file command failed: {}", status)),
+        )
+    }
+
+    fn failed(&mut self, described: &str, error: io::Error) -> io::Result<()> {
+        self.failures += 1;
+        match self.on_failure {
+            ExecFailure::Abort => Err(io::Error::new(
+                error.kind(),
+                format!("{} (for {})", error, described),
+            )),
+            ExecFailure::Warn => {
+                let msg = format!("warning: {} (for {})", error, described);
+                eprintln!("{}", color::stderr(Role::Warning, msg));
+                Ok(())
+            }
+            ExecFailure::Ignore => Ok(()),
+        }
+    }
+}
+
+impl OutputSink for ExecPerFile {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        let hash = if result.stable {
+            result.digest.clone()
+        } else {
+            format!("{}{}", UNSTABLE_PREFIX, result.digest)
+        };
+        self.pending.push(Record {
+            path: result.path.to_string_lossy().into_owned(),
+            hash,
+            size: result.size,
+        });
+        if self.pending.len() >= self.batch {
+            self.launch()?;
+        }
+        Ok(())
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        self.launch()?;
+        self.reap(0)?;
+        if self.failures > 0 && self.on_failure == ExecFailure::Warn {
+            eprintln!("{} --exec-per-file commands failed", self.failures);
+        }
+        Ok(())
+    }
+}
+
+/// Replace each placeholder in `template` with its value, in one pass,
+/// so that placeholders within the values (e.g. a file named `x{size}`) are left as they are.
+fn substitute(template: &str, values: &[(&str, String)]) -> String {
+    let mut command = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        command.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match values.iter().find(|(p, _)| rest.starts_with(p)) {
+            Some((placeholder, value)) => {
+                command.push_str(value);
+                rest = &rest[placeholder.len()..];
+            }
+            None => {
+                command.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    command.push_str(rest);
+    command
+}
+
+/// Quote a value to be substituted into a shell command.
+#[cfg(not(windows))]
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(windows)]
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\"\""))
+}
@@ -0,0 +1,197 @@
+//! Files which share physical extents, e.g. reflink copies or deduplicated files on btrfs or XFS.
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::sink::OutputSink;
+use crate::FileHash;
+
+#[cfg(target_os = "linux")]
+mod fiemap {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    // _IOWR('f', 11, struct fiemap)
+    const FS_IOC_FIEMAP: u64 = 0xC020_660B;
+    const FIEMAP_FLAG_SYNC: u32 = 0x1;
+    const FIEMAP_EXTENT_LAST: u32 = 0x1;
+    const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+    const BATCH: usize = 64;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct Extent {
+        logical: u64,
+        physical: u64,
+        length: u64,
+        reserved64: [u64; 2],
+        flags: u32,
+        reserved: [u32; 3],
+    }
+
+    #[repr(C)]
+    struct Request {
+        start: u64,
+        length: u64,
+        flags: u32,
+        mapped_extents: u32,
+        extent_count: u32,
+        reserved: u32,
+        extents: [Extent; BATCH],
+    }
+
+    pub fn shared_extents(path: &Path) -> io::Result<Vec<u64>> {
+        let file = File::open(path)?;
+        let mut shared = Vec::new();
+        let mut start = 0;
+        loop {
+            let mut req = Request {
+                start,
+                length: u64::MAX - start,
+                flags: FIEMAP_FLAG_SYNC,
+                mapped_extents: 0,
+                extent_count: BATCH as u32,
+                reserved: 0,
+                extents: [Extent::default(); BATCH],
+            };
+            // SAFETY: req is a valid struct fiemap with room for extent_count extents
+            let ret = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut req) };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let extents = &req.extents[..req.mapped_extents as usize];
+            for e in extents {
+                if e.flags & FIEMAP_EXTENT_SHARED != 0 {
+                    shared.push(e.physical);
+                }
+            }
+            match extents.last() {
+                Some(e) if e.flags & FIEMAP_EXTENT_LAST == 0 => start = e.logical + e.length,
+                _ => return Ok(shared),
+            }
+        }
+    }
+}
+
+/// Create a new file at `dest` which shares all of its extents with `src` (like `cp --reflink=always`).
+#[cfg(target_os = "linux")]
+pub fn reflink(src: &Path, dest: &Path) -> io::Result<()> {
+    use std::fs::{File, OpenOptions};
+    use std::os::unix::io::AsRawFd;
+
+    // _IOW(0x94, 9, int)
+    const FICLONE: u64 = 0x4004_9409;
+
+    let src_file = File::open(src)?;
+    let dest_file = OpenOptions::new().write(true).create_new(true).open(dest)?;
+    // SAFETY: both are open file descriptors
+    let ret = unsafe { libc::ioctl(dest_file.as_raw_fd(), FICLONE as _, src_file.as_raw_fd()) };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        drop(dest_file);
+        let _ = std::fs::remove_file(dest);
+        return Err(err);
+    }
+    dest_file.set_permissions(src_file.metadata()?.permissions())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn reflink(_src: &Path, _dest: &Path) -> io::Result<()> {
+    Err(io::Error::other("reflinks are only supported on Linux"))
+}
+
+/// Physical offsets of the extents of this file which are shared with other files.
+#[cfg(target_os = "linux")]
+pub fn shared_extents(path: &Path) -> io::Result<Vec<u64>> {
+    fiemap::shared_extents(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn shared_extents(_path: &Path) -> io::Result<Vec<u64>> {
+    Err(io::Error::other(
+        "extent queries are only supported on Linux",
+    ))
+}
+
+pub const SUPPORTED: bool = cfg!(target_os = "linux");
+
+/// Collects shared extents across files, to report which files share them.
+#[derive(Default)]
+pub struct SharedExtents {
+    owners: HashMap<u64, Vec<PathBuf>>,
+    failed: u64,
+}
+
+impl SharedExtents {
+    pub fn add(&mut self, path: &Path, extents: &io::Result<Vec<u64>>) {
+        match extents {
+            Ok(offsets) => {
+                for offset in offsets {
+                    self.owners
+                        .entry(*offset)
+                        .or_default()
+                        .push(path.to_path_buf());
+                }
+            }
+            Err(_) => self.failed += 1,
+        }
+    }
+
+    /// Print groups of files which share at least one extent to stderr.
+    pub fn report(&mut self) {
+        let mut groups = BTreeSet::new();
+        let mut grouped = BTreeSet::new();
+        let mut lone = BTreeSet::new();
+        for (_, mut paths) in self.owners.drain() {
+            paths.sort();
+            paths.dedup();
+            if paths.len() > 1 {
+                grouped.extend(paths.iter().cloned());
+                groups.insert(paths);
+            } else {
+                lone.extend(paths);
+            }
+        }
+
+        if !groups.is_empty() {
+            eprintln!("files sharing physical extents:");
+            for (idx, group) in groups.iter().enumerate() {
+                if idx > 0 {
+                    eprintln!();
+                }
+                for path in group {
+                    eprintln!("\t{}", path.to_string_lossy());
+                }
+            }
+        }
+        let outside = lone.difference(&grouped).count();
+        if outside > 0 {
+            eprintln!(
+                "{} files share extents only with files which were not hashed",
+                outside
+            );
+        }
+        if self.failed > 0 {
+            eprintln!(
+                "warning: could not query extents of {} files; the file system may not support FIEMAP",
+                self.failed
+            );
+        }
+    }
+}
+
+impl OutputSink for SharedExtents {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        if let Some(extents) = &result.shared_extents {
+            self.add(&result.path, extents);
+        }
+        Ok(())
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        self.report();
+        Ok(())
+    }
+}
@@ -0,0 +1,417 @@
+//! Reusing digests from earlier runs for files which have not changed, as with recursum --cache:
+//! a file whose size and modification time match its entry is not read again.
+//!
+//! Entries are keyed by path relative to the directory hashed (see [HashCache::set_root]),
+//! so that a cache built on one copy of a dataset can seed runs on identical copies elsewhere.
+//!
+//! The file starts with the line `# recursum-cache 2`, then `# <key>=<value>` lines
+//! (the hasher, and how many files the last run found in the cache and had to hash);
+//! every other line is an entry, `<size>\t<mtime>\t<digest>\t<verified>\t<path>`,
+//! where mtime is `<seconds>.<nanoseconds>` since the Unix epoch, and verified is the time
+//! the file was last read, in seconds since the Unix epoch, or `-` if not known.
+//! Version 1 caches, whose entries have no verified field, are read too.
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, Metadata};
+use std::hash::BuildHasher;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The first line of every cache.
+pub const MAGIC: &str = "# recursum-cache 2";
+/// The first line of caches written before the verified field was added.
+const MAGIC_V1: &str = "# recursum-cache 1";
+const PREFIX: &str = "# ";
+
+/// When a file was last modified, to the nanosecond.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mtime {
+    secs: i64,
+    nanos: u32,
+}
+
+impl Mtime {
+    /// None if the platform or filesystem does not record modification times.
+    pub fn of(meta: &Metadata) -> Option<Self> {
+        let modified = meta.modified().ok()?;
+        Some(match modified.duration_since(UNIX_EPOCH) {
+            Ok(d) => Self {
+                secs: d.as_secs() as i64,
+                nanos: d.subsec_nanos(),
+            },
+            Err(e) => {
+                // before the epoch: count down to it, then back up through the second
+                let d = e.duration();
+                let (secs, nanos) = (d.as_secs() as i64, d.subsec_nanos());
+                if nanos == 0 {
+                    Self { secs: -secs, nanos }
+                } else {
+                    Self {
+                        secs: -secs - 1,
+                        nanos: 1_000_000_000 - nanos,
+                    }
+                }
+            }
+        })
+    }
+}
+
+impl fmt::Display for Mtime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:09}", self.secs, self.nanos)
+    }
+}
+
+impl FromStr for Mtime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid mtime '{}'", s);
+        let (secs, nanos) = s.split_once('.').ok_or_else(invalid)?;
+        let nanos: u32 = nanos.parse().map_err(|_| invalid())?;
+        if nanos >= 1_000_000_000 {
+            return Err(invalid());
+        }
+        Ok(Self {
+            secs: secs.parse().map_err(|_| invalid())?,
+            nanos,
+        })
+    }
+}
+
+/// A file's digest, as it was when hashed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub size: u64,
+    pub mtime: Mtime,
+    pub digest: String,
+    /// When the file's contents were last read, and either matched this digest or gave it,
+    /// in seconds since the Unix epoch; None if not recorded.
+    pub verified: Option<u64>,
+}
+
+impl Entry {
+    /// Whether the file is as it was when cached, going by its metadata.
+    pub fn matches(&self, meta: &Metadata) -> bool {
+        self.size == meta.len() && Some(self.mtime) == Mtime::of(meta)
+    }
+}
+
+#[derive(Debug)]
+pub struct HashCache {
+    /// Identifies the algorithm, and any seed or digest length, which the digests were made with,
+    /// e.g. `sha256` or `meow seed=00ff digest-length=16`.
+    pub hasher: String,
+    /// Directory which entries' paths are relative to; until set, paths are used as they are.
+    root: OnceLock<PathBuf>,
+    entries: Mutex<HashMap<PathBuf, Entry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    /// hits and misses of the run which last saved the cache, if recorded
+    last_run: Option<(u64, u64)>,
+    /// hits to hash anyway, per 10,000, chosen by a hash of the path keyed afresh each run
+    verify_per_10k: u64,
+    sampler: RandomState,
+    verified: AtomicU64,
+    /// files whose cached digests were wrong, with the cached and actual digests
+    mismatches: Mutex<Vec<Mismatch>>,
+}
+
+/// A file which had not changed since it was cached, going by its metadata, but whose contents had.
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+    pub path: PathBuf,
+    pub cached: String,
+    pub actual: String,
+}
+
+impl HashCache {
+    /// An empty cache, for digests made with `hasher`.
+    pub fn new(hasher: &str) -> Self {
+        Self {
+            hasher: hasher.to_string(),
+            root: OnceLock::new(),
+            entries: Mutex::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            last_run: None,
+            verify_per_10k: 0,
+            sampler: RandomState::new(),
+            verified: AtomicU64::new(0),
+            mismatches: Mutex::default(),
+        }
+    }
+
+    /// Hash about `percent`% of files even if they have not changed, picked at random,
+    /// and record any whose digests differ from the cached ones; see [mismatches](Self::mismatches).
+    /// This catches corruption or tampering which kept files' sizes and modification times.
+    pub fn verify_percent(&mut self, percent: f64) {
+        self.verify_per_10k = (percent.clamp(0.0, 100.0) * 100.0).round() as u64;
+    }
+
+    /// Read a cache written by [write](Self::write).
+    pub fn read(r: impl BufRead) -> io::Result<Self> {
+        let invalid = |line: usize, msg: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", line + 1, msg),
+            )
+        };
+        let mut lines = r.lines().enumerate();
+        let has_verified = match lines.next().map(|(_, l)| l).transpose()?.as_deref() {
+            Some(MAGIC) => true,
+            Some(MAGIC_V1) => false,
+            _ => return Err(invalid(0, "not a recursum cache")),
+        };
+        let mut hasher = None;
+        let (mut hits, mut misses) = (None, None);
+        let mut entries = HashMap::new();
+        for (idx, line) in lines {
+            let line = line?;
+            if let Some(field) = line.strip_prefix(PREFIX) {
+                match field.split_once('=') {
+                    Some(("hasher", h)) => hasher = Some(h.to_string()),
+                    Some(("last-run-hits", n)) => hits = n.parse().ok(),
+                    Some(("last-run-misses", n)) => misses = n.parse().ok(),
+                    _ => (),
+                }
+                continue;
+            }
+            let mut fields = line.splitn(if has_verified { 5 } else { 4 }, '\t');
+            let mut field = || fields.next().ok_or_else(|| invalid(idx, "too few fields"));
+            let size = field()?.parse().map_err(|_| invalid(idx, "invalid size"))?;
+            let mtime = field()?.parse().map_err(|e: String| invalid(idx, &e))?;
+            let digest = field()?.to_string();
+            let verified = match has_verified {
+                false => None,
+                true => match field()? {
+                    "-" => None,
+                    secs => Some(
+                        secs.parse()
+                            .map_err(|_| invalid(idx, "invalid verified time"))?,
+                    ),
+                },
+            };
+            let path = PathBuf::from(field()?);
+            entries.insert(
+                path,
+                Entry {
+                    size,
+                    mtime,
+                    digest,
+                    verified,
+                },
+            );
+        }
+        let hasher = hasher.ok_or_else(|| invalid(0, "no hasher recorded"))?;
+        let mut cache = Self::new(&hasher);
+        cache.last_run = hits.zip(misses);
+        *cache.entries.lock().expect("cache lock poisoned") = entries;
+        Ok(cache)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Write every entry, sorted by path.
+    pub fn write(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "{}", MAGIC)?;
+        writeln!(w, "{}hasher={}", PREFIX, self.hasher)?;
+        // this run's, if the cache was used to hash anything
+        let run = match self.hits_and_misses() {
+            (0, 0) => self.last_run,
+            counts => Some(counts),
+        };
+        if let Some((hits, misses)) = run {
+            writeln!(w, "{}last-run-hits={}", PREFIX, hits)?;
+            writeln!(w, "{}last-run-misses={}", PREFIX, misses)?;
+        }
+        for (path, e) in self.entries() {
+            let verified = e.verified.map_or("-".to_string(), |t| t.to_string());
+            writeln!(
+                w,
+                "{}\t{}\t{}\t{}\t{}",
+                e.size,
+                e.mtime,
+                e.digest,
+                verified,
+                path.to_string_lossy()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Write to `path` by replacing it, so that it is never left half-written.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let dir = match path.parent() {
+            Some(d) if !d.as_os_str().is_empty() => d,
+            _ => Path::new("."),
+        };
+        let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+        {
+            let mut w = BufWriter::new(tmp.as_file_mut());
+            self.write(&mut w)?;
+            w.flush()?;
+        }
+        tmp.as_file().sync_all()?;
+        tmp.persist(path).map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    /// Key paths relative to `root` from now on, e.g. the directory being hashed. Only the first root set is used.
+    pub fn set_root(&self, root: PathBuf) {
+        let _ = self.root.set(root);
+    }
+
+    fn key(&self, path: &Path) -> PathBuf {
+        let relative = self
+            .root
+            .get()
+            .and_then(|r| path.strip_prefix(r).ok())
+            .unwrap_or(path);
+        relative.to_path_buf()
+    }
+
+    /// The cached digest of the file at `path`, if it has not changed since, going by its metadata.
+    pub fn lookup(&self, path: &Path, meta: &Metadata) -> Option<String> {
+        let key = self.key(path);
+        let entries = self.entries.lock().expect("cache lock poisoned");
+        match entries.get(&key).filter(|e| e.matches(meta)) {
+            Some(e) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(e.digest.clone())
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Whether to hash the file at `path`, which [lookup](Self::lookup) found, anyway; see [verify_percent](Self::verify_percent).
+    pub fn sample(&self, path: &Path) -> bool {
+        self.verify_per_10k > 0 && self.sampler.hash_one(path) % 10_000 < self.verify_per_10k
+    }
+
+    /// Record the result of hashing a file chosen by [sample](Self::sample).
+    pub fn verified(&self, path: &Path, cached: String, actual: &str) {
+        self.verified.fetch_add(1, Ordering::Relaxed);
+        if cached != actual {
+            self.mismatches
+                .lock()
+                .expect("cache lock poisoned")
+                .push(Mismatch {
+                    path: path.to_path_buf(),
+                    cached,
+                    actual: actual.to_string(),
+                });
+        }
+    }
+
+    /// Files sampled so far, and those whose cached digests were wrong.
+    pub fn verification(&self) -> (u64, Vec<Mismatch>) {
+        (
+            self.verified.load(Ordering::Relaxed),
+            self.mismatches.lock().expect("cache lock poisoned").clone(),
+        )
+    }
+
+    /// When the file at `path` was last read, as recorded in its entry; see [Entry::verified].
+    pub fn verified_at(&self, path: &Path) -> Option<u64> {
+        let key = self.key(path);
+        let entries = self.entries.lock().expect("cache lock poisoned");
+        entries.get(&key).and_then(|e| e.verified)
+    }
+
+    /// Cache the digest of the file at `path`, as it was when `meta` was read before hashing it,
+    /// which was just now.
+    pub fn record(&self, path: &Path, meta: &Metadata, digest: &str) {
+        let mtime = match Mtime::of(meta) {
+            Some(m) => m,
+            None => return,
+        };
+        let key = self.key(path);
+        // would break the line-based format
+        if key.to_string_lossy().contains('\n') {
+            return;
+        }
+        self.entries.lock().expect("cache lock poisoned").insert(
+            key,
+            Entry {
+                size: meta.len(),
+                mtime,
+                digest: digest.to_string(),
+                verified: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .ok()
+                    .map(|d| d.as_secs()),
+            },
+        );
+    }
+
+    /// Files whose cached digests were used, and files which were hashed, so far.
+    pub fn hits_and_misses(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Files found in the cache, and files hashed, by the run which last saved it, if recorded.
+    pub fn last_run(&self) -> Option<(u64, u64)> {
+        self.last_run
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().expect("cache lock poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every entry, sorted by path.
+    pub fn entries(&self) -> Vec<(PathBuf, Entry)> {
+        let mut entries: Vec<_> = self
+            .entries
+            .lock()
+            .expect("cache lock poisoned")
+            .iter()
+            .map(|(p, e)| (p.clone(), e.clone()))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    /// Keep only the entries for which `keep` is true, returning how many were removed.
+    pub fn retain(&self, mut keep: impl FnMut(&Path, &Entry) -> bool) -> usize {
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+        let before = entries.len();
+        entries.retain(|path, entry| keep(path, entry));
+        before - entries.len()
+    }
+
+    /// Add `entries`, keeping any existing entries for the same paths unless `overwrite`.
+    /// Returns how many were added or replaced.
+    pub fn merge(
+        &self,
+        entries: impl IntoIterator<Item = (PathBuf, Entry)>,
+        overwrite: bool,
+    ) -> usize {
+        let mut mine = self.entries.lock().expect("cache lock poisoned");
+        let mut merged = 0;
+        for (path, entry) in entries {
+            if overwrite || !mine.contains_key(&path) {
+                mine.insert(path, entry);
+                merged += 1;
+            }
+        }
+        merged
+    }
+}
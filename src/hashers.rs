@@ -0,0 +1,406 @@
+//! Hashing algorithms, selectable by name at runtime.
+//!
+//! Built-in algorithms are always registered;
+//! downstream crates can add their own with [register] before hashing.
+//! Each built-in algorithm has a cargo feature of the same name, enabled by default
+//! (except sha1, sha256 and sha512, which share the `sha` feature, and git-sha1 and git-sha256, which share `git`).
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{OnceLock, RwLock};
+
+use digest::Digest;
+#[cfg(feature = "meow")]
+use meowhash::MeowHasher;
+
+#[cfg(not(any(
+    feature = "meow",
+    feature = "crc32",
+    feature = "md5",
+    feature = "sha",
+    feature = "git"
+)))]
+compile_error!("at least one hash algorithm feature (meow, crc32, md5, sha, git) must be enabled");
+
+/// Incrementally hashes the contents of a file.
+///
+/// Implemented for every [Digest], and for other hashers (e.g. checksums) by wrapping them.
+pub trait Hasher: Send {
+    /// Called with the length of the data before any is given, if it is known (e.g. the size of a file).
+    /// Most hashers have no use for it.
+    fn expect_length(&mut self, _len: u64) {}
+
+    fn update(&mut self, data: &[u8]);
+
+    /// Fails if the digest would be wrong because the data given was not the length expected,
+    /// for hashers which depend on it; called after the last update.
+    fn check_length(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The raw (not hex-encoded) hash.
+    fn finalize(self: Box<Self>) -> Vec<u8>;
+}
+
+impl<D: Digest + Send> Hasher for D {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        Digest::finalize(*self).to_vec()
+    }
+}
+
+/// CRC-32 (as used by zlib, gzip, PNG etc.), big-endian.
+#[cfg(feature = "crc32")]
+#[derive(Default)]
+pub struct Crc32(crc32fast::Hasher);
+
+#[cfg(feature = "crc32")]
+impl Hasher for Crc32 {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        self.0.finalize().to_be_bytes().to_vec()
+    }
+}
+
+/// Hashes data as git does a blob, i.e. prefixed with `blob <length>\0`,
+/// so that digests match `git hash-object`.
+///
+/// Data of unknown length is held in memory until it is finalized.
+#[cfg(feature = "git")]
+pub struct GitBlob<D> {
+    inner: D,
+    /// None once the header has been hashed
+    buffered: Option<Vec<u8>>,
+    /// the length given in the header, if it was hashed before the data
+    expected: Option<u64>,
+    /// bytes hashed after the header
+    received: u64,
+}
+
+#[cfg(feature = "git")]
+impl<D: Digest> GitBlob<D> {
+    pub fn new() -> Self {
+        Self {
+            inner: D::new(),
+            buffered: Some(Vec::new()),
+            expected: None,
+            received: 0,
+        }
+    }
+
+    fn header(&mut self, len: u64) {
+        Digest::update(&mut self.inner, format!("blob {}\0", len));
+    }
+}
+
+#[cfg(feature = "git")]
+impl<D: Digest> Default for GitBlob<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "git")]
+impl<D: Digest + Send> Hasher for GitBlob<D> {
+    fn expect_length(&mut self, len: u64) {
+        if self.buffered.as_ref().is_some_and(|b| b.is_empty()) {
+            self.buffered = None;
+            self.header(len);
+            self.expected = Some(len);
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match &mut self.buffered {
+            Some(b) => b.extend_from_slice(data),
+            None => {
+                Digest::update(&mut self.inner, data);
+                self.received += data.len() as u64;
+            }
+        }
+    }
+
+    fn check_length(&self) -> io::Result<()> {
+        match self.expected {
+            Some(expected) if expected != self.received => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "short read: expected {} bytes but read {} (the file changed while being read, or misreports its size), so its git blob digest would be wrong",
+                    expected, self.received
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn finalize(mut self: Box<Self>) -> Vec<u8> {
+        if let Some(b) = self.buffered.take() {
+            self.header(b.len() as u64);
+            Digest::update(&mut self.inner, &b);
+        }
+        self.inner.finalize().to_vec()
+    }
+}
+
+/// Creates a fresh hasher for each file.
+pub type HasherFactory = fn() -> Box<dyn Hasher>;
+
+/// Creates a fresh hasher for each file, keyed with a seed of any length,
+/// so that differently-seeded hashers produce unrelated digests.
+pub type SeededHasherFactory = fn(&[u8]) -> Box<dyn Hasher>;
+
+/// Name which selects the fastest built-in algorithm this machine supports; see [auto].
+pub const AUTO: &str = "auto";
+
+/// Prefix of names which choose the algorithm by each file's size, e.g. `auto-size:crc32<64K,meow>=64K`; see [BySize].
+pub const BY_SIZE_PREFIX: &str = "auto-size:";
+
+/// Whether the CPU has the instructions MeowHash is built on.
+#[cfg(feature = "meow")]
+fn meow_supported() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        is_x86_feature_detected!("aes") && is_x86_feature_detected!("sse4.1")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    {
+        false
+    }
+}
+
+#[cfg(feature = "meow")]
+fn meow() -> Box<dyn Hasher> {
+    // fail clearly rather than on an illegal instruction
+    assert!(meow_supported(), "{}", MEOW_UNSUPPORTED);
+    Box::new(MeowHasher::new())
+}
+
+/// Length of MeowHash's seed.
+#[cfg(feature = "meow")]
+const MEOW_SEED_LENGTH: usize = 128;
+
+/// Stretch a seed of any length to MeowHash's, by hashing it (twice, with different prefixes) with the default seed.
+#[cfg(feature = "meow")]
+fn meow_seed(seed: &[u8]) -> [u8; MEOW_SEED_LENGTH] {
+    let mut expanded = [0; MEOW_SEED_LENGTH];
+    for (i, half) in expanded.chunks_mut(MEOW_SEED_LENGTH / 2).enumerate() {
+        let mut hasher = MeowHasher::new();
+        Digest::update(&mut hasher, [i as u8]);
+        Digest::update(&mut hasher, seed);
+        half.copy_from_slice(&Digest::finalize(hasher)[..half.len()]);
+    }
+    expanded
+}
+
+#[cfg(feature = "meow")]
+fn meow_seeded(seed: &[u8]) -> Box<dyn Hasher> {
+    assert!(meow_supported(), "{}", MEOW_UNSUPPORTED);
+    Box::new(MeowHasher::with_seed(meow_seed(seed)))
+}
+
+#[cfg(feature = "meow")]
+const MEOW_UNSUPPORTED: &str = "meow requires a CPU with AES-NI and SSE4.1";
+
+#[cfg(feature = "crc32")]
+fn crc32() -> Box<dyn Hasher> {
+    Box::new(Crc32::default())
+}
+
+#[cfg(feature = "md5")]
+fn md5() -> Box<dyn Hasher> {
+    Box::new(md5::Md5::new())
+}
+
+#[cfg(feature = "sha")]
+fn sha1() -> Box<dyn Hasher> {
+    Box::new(sha1::Sha1::new())
+}
+
+#[cfg(feature = "sha")]
+fn sha256() -> Box<dyn Hasher> {
+    Box::new(sha2::Sha256::new())
+}
+
+#[cfg(feature = "sha")]
+fn sha512() -> Box<dyn Hasher> {
+    Box::new(sha2::Sha512::new())
+}
+
+#[cfg(feature = "git")]
+fn git_sha1() -> Box<dyn Hasher> {
+    Box::new(GitBlob::<sha1::Sha1>::new())
+}
+
+#[cfg(feature = "git")]
+fn git_sha256() -> Box<dyn Hasher> {
+    Box::new(GitBlob::<sha2::Sha256>::new())
+}
+
+type Entry = (HasherFactory, Option<SeededHasherFactory>);
+
+/// Fastest first, for [auto].
+const BUILTIN: &[(&str, Entry)] = &[
+    #[cfg(feature = "meow")]
+    ("meow", (meow, Some(meow_seeded))),
+    #[cfg(feature = "crc32")]
+    ("crc32", (crc32, None)),
+    #[cfg(feature = "md5")]
+    ("md5", (md5, None)),
+    #[cfg(feature = "sha")]
+    ("sha1", (sha1, None)),
+    #[cfg(feature = "sha")]
+    ("sha512", (sha512, None)),
+    #[cfg(feature = "sha")]
+    ("sha256", (sha256, None)),
+    #[cfg(feature = "git")]
+    ("git-sha1", (git_sha1, None)),
+    #[cfg(feature = "git")]
+    ("git-sha256", (git_sha256, None)),
+];
+
+fn registry() -> &'static RwLock<BTreeMap<String, Entry>> {
+    static REGISTRY: OnceLock<RwLock<BTreeMap<String, Entry>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        RwLock::new(
+            BUILTIN
+                .iter()
+                .map(|(name, entry)| (name.to_string(), *entry))
+                .collect(),
+        )
+    })
+}
+
+/// Make an algorithm available under the given name.
+///
+/// Fails if the name is already taken.
+pub fn register(name: &str, factory: HasherFactory) -> Result<(), String> {
+    insert(name, (factory, None))
+}
+
+/// As [register], for an algorithm which can also be seeded.
+pub fn register_seeded(
+    name: &str,
+    factory: HasherFactory,
+    seeded: SeededHasherFactory,
+) -> Result<(), String> {
+    insert(name, (factory, Some(seeded)))
+}
+
+fn insert(name: &str, entry: Entry) -> Result<(), String> {
+    let mut registry = registry().write().unwrap();
+    if registry.contains_key(name) {
+        return Err(format!("hasher '{}' is already registered", name));
+    }
+    registry.insert(name.to_string(), entry);
+    Ok(())
+}
+
+/// The factory registered under the given name.
+pub fn get(name: &str) -> Option<HasherFactory> {
+    registry()
+        .read()
+        .unwrap()
+        .get(name)
+        .map(|(factory, _)| *factory)
+}
+
+/// The seeded factory registered under the given name, if that algorithm can be seeded.
+pub fn get_seeded(name: &str) -> Option<SeededHasherFactory> {
+    registry()
+        .read()
+        .unwrap()
+        .get(name)
+        .and_then(|(_, seeded)| *seeded)
+}
+
+/// Names of all registered algorithms, sorted.
+pub fn names() -> Vec<String> {
+    registry().read().unwrap().keys().cloned().collect()
+}
+
+/// Why the named algorithm cannot run on this machine, if it cannot.
+pub fn unsupported(name: &str) -> Option<String> {
+    match name {
+        #[cfg(feature = "meow")]
+        "meow" if !meow_supported() => Some(MEOW_UNSUPPORTED.to_string()),
+        _ => None,
+    }
+}
+
+/// The fastest built-in algorithm which this machine supports,
+/// and why any faster ones were passed over.
+///
+/// Fails if no built-in algorithm is supported.
+pub fn auto() -> Result<(&'static str, Vec<String>), String> {
+    let mut passed_over = Vec::new();
+    for (name, _) in BUILTIN {
+        match unsupported(name) {
+            Some(reason) => passed_over.push(reason),
+            None => return Ok((name, passed_over)),
+        }
+    }
+    Err(passed_over.join("; "))
+}
+
+/// The default algorithm: the one picked by [auto].
+pub fn default_factory() -> HasherFactory {
+    let (name, _) = auto().unwrap_or_else(|e| panic!("no supported hasher: {}", e));
+    get(name).expect("built-in hashers are registered")
+}
+
+/// Algorithms to hash files with according to their size: cheap ones for the many tiny files
+/// which would otherwise be dominated by the setup cost of SIMD-heavy hashers, and fast ones for the rest.
+#[derive(Debug, Clone)]
+pub struct BySize {
+    /// the algorithm for files smaller than each size, smallest first
+    below: Vec<(u64, &'static str, HasherFactory)>,
+    /// the algorithm for files no smaller than any of those
+    rest: (&'static str, HasherFactory),
+}
+
+impl BySize {
+    /// Hash files smaller than each size with the named algorithm, trying the smallest size first,
+    /// and larger files with `rest`.
+    ///
+    /// Fails if an algorithm is not registered, or the sizes are not increasing.
+    pub fn new(below: Vec<(u64, &'static str)>, rest: &'static str) -> Result<Self, String> {
+        let factory = |name| get(name).ok_or_else(|| format!("unknown hasher '{}'", name));
+        if below.windows(2).any(|w| w[0].0 >= w[1].0) {
+            return Err("sizes must be increasing".to_string());
+        }
+        Ok(Self {
+            below: below
+                .into_iter()
+                .map(|(size, name)| Ok((size, name, factory(name)?)))
+                .collect::<Result<_, String>>()?,
+            rest: (rest, factory(rest)?),
+        })
+    }
+
+    /// Name and factory of the algorithm for a file of `size` bytes.
+    pub fn choose(&self, size: u64) -> (&'static str, HasherFactory) {
+        self.below
+            .iter()
+            .find(|(below, _, _)| size < *below)
+            .map_or(self.rest, |(_, name, factory)| (name, *factory))
+    }
+
+    /// Names of the algorithms used, each once.
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names: Vec<_> = self.below.iter().map(|(_, name, _)| *name).collect();
+        names.push(self.rest.0);
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+
+    /// The factory for the largest files.
+    pub fn largest(&self) -> HasherFactory {
+        self.rest.1
+    }
+}
@@ -0,0 +1,219 @@
+//! Commented header at the top of a manifest, recording how it was made,
+//! so that it can be verified without knowing which options produced it.
+//!
+//! The first line is `# recursum <version>`; each following line is `# <key>=<value>`.
+//! A file appended to by several runs (with --append) has a header at the start of each run.
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Starts the first line of every header.
+pub const MAGIC: &str = "# recursum ";
+/// Starts every other line of a header.
+pub const PREFIX: &str = "# ";
+
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub version: String,
+    pub algorithm: String,
+    /// Hex seed, if the algorithm was seeded.
+    pub seed: Option<String>,
+    /// How digests are encoded; always hex.
+    pub encoding: String,
+    pub separator: String,
+    /// Whether each line is digest then path (as with --compatible), rather than path then digest.
+    pub hash_first: bool,
+    pub digest_length: Option<usize>,
+    /// The directory which was hashed, if any.
+    pub root: Option<PathBuf>,
+    /// Directory the paths are relative to, if not the working directory at the time.
+    pub relative_to: Option<PathBuf>,
+    /// RFC 3339 UTC timestamp.
+    pub created: String,
+    /// The command line which produced the manifest, if recorded (as with --append).
+    pub command: Option<String>,
+    /// Identifies the run which produced the manifest, e.g. to match it with the run's summary.
+    pub run_id: Option<String>,
+    /// How paths were redacted, if they were, e.g. hmac-sha256.
+    pub redact: Option<String>,
+}
+
+impl Header {
+    pub fn new(algorithm: &str, separator: &str, hash_first: bool) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            algorithm: algorithm.to_string(),
+            seed: None,
+            encoding: "hex".to_string(),
+            separator: separator.to_string(),
+            hash_first,
+            digest_length: None,
+            root: None,
+            relative_to: None,
+            created: rfc3339(SystemTime::now()),
+            command: None,
+            run_id: None,
+            redact: None,
+        }
+    }
+
+    /// The same header, for a manifest whose paths are relative to `dir`.
+    pub fn relative_to(&self, dir: &Path) -> Self {
+        Self {
+            relative_to: Some(dir.to_path_buf()),
+            ..self.clone()
+        }
+    }
+
+    /// The same header, for a manifest whose paths were redacted with `method`,
+    /// without the fields which would reveal them.
+    #[cfg(feature = "redact")]
+    pub fn redacted(&self, method: &str) -> Self {
+        Self {
+            root: None,
+            relative_to: None,
+            command: None,
+            redact: Some(method.to_string()),
+            ..self.clone()
+        }
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "{}{}", MAGIC, self.version)?;
+        let mut field = |key: &str, value: &str| writeln!(w, "{}{}={}", PREFIX, key, value);
+        field("algorithm", &self.algorithm)?;
+        if let Some(seed) = &self.seed {
+            field("seed", seed)?;
+        }
+        field("encoding", &self.encoding)?;
+        field("separator", &escape(&self.separator))?;
+        field(
+            "order",
+            if self.hash_first {
+                "digest-path"
+            } else {
+                "path-digest"
+            },
+        )?;
+        if let Some(length) = self.digest_length {
+            field("digest-length", &length.to_string())?;
+        }
+        if let Some(root) = &self.root {
+            field("root", &root.to_string_lossy())?;
+        }
+        if let Some(dir) = &self.relative_to {
+            field("relative-to", &dir.to_string_lossy())?;
+        }
+        if let Some(command) = &self.command {
+            field("command", &escape(command))?;
+        }
+        if let Some(id) = &self.run_id {
+            field("run-id", &escape(id))?;
+        }
+        if let Some(method) = &self.redact {
+            field("redact", method)?;
+        }
+        field("created", &self.created)
+    }
+
+    /// Parse the header at the start of a manifest's lines,
+    /// returning it and how many lines it took up, or None if there is no header.
+    ///
+    /// Unknown keys are ignored, so that older versions can read newer headers.
+    pub fn parse<S: AsRef<str>>(lines: &[S]) -> Option<(Self, usize)> {
+        let version = lines.first()?.as_ref().strip_prefix(MAGIC)?;
+        let mut header = Self {
+            version: version.to_string(),
+            created: String::new(),
+            ..Self::new("", "\t", false)
+        };
+        let mut n_lines = 1;
+        for line in &lines[1..] {
+            let (key, value) = match line
+                .as_ref()
+                .strip_prefix(PREFIX)
+                .and_then(|l| l.split_once('='))
+            {
+                Some(kv) => kv,
+                None => break,
+            };
+            match key {
+                "algorithm" => header.algorithm = value.to_string(),
+                "seed" => header.seed = Some(value.to_string()),
+                "encoding" => header.encoding = value.to_string(),
+                "separator" => header.separator = unescape(value),
+                "order" => header.hash_first = value == "digest-path",
+                "digest-length" => header.digest_length = value.parse().ok(),
+                "root" => header.root = Some(PathBuf::from(value)),
+                "relative-to" => header.relative_to = Some(PathBuf::from(value)),
+                "created" => header.created = value.to_string(),
+                "command" => header.command = Some(unescape(value)),
+                "run-id" => header.run_id = Some(unescape(value)),
+                "redact" => header.redact = Some(value.to_string()),
+                _ => (),
+            }
+            n_lines += 1;
+        }
+        Some((header, n_lines))
+    }
+}
+
+/// Escape tabs, NULs and newlines, as --separator accepts them.
+pub fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\0', "\\0")
+        .replace('\n', "\\n")
+}
+
+pub fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('0') => out.push('\0'),
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// Format as e.g. `2020-11-03T17:04:05Z`.
+pub fn rfc3339(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+// from http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -0,0 +1,65 @@
+use std::ffi::OsString;
+use std::process::Command;
+
+/// Environment variable holding the directory given as <input>.
+pub const INPUT_VAR: &str = "RECURSUM_INPUT";
+/// Environment variable holding the directory which is actually walked, if given with --hash-root.
+pub const HASH_ROOT_VAR: &str = "RECURSUM_HASH_ROOT";
+/// Environment variable holding the run's identifier (see --run-id).
+pub const RUN_ID_VAR: &str = "RECURSUM_RUN_ID";
+
+/// A shell command to run before or after hashing.
+pub struct Hook {
+    command: String,
+    env: Vec<(&'static str, OsString)>,
+}
+
+impl Hook {
+    pub fn new(command: &str, env: Vec<(&'static str, OsString)>) -> Self {
+        Self {
+            command: command.to_string(),
+            env,
+        }
+    }
+
+    /// Run the command to completion, with stdout redirected to stderr so that it cannot end up in the manifest.
+    pub fn run(&self) -> Result<(), String> {
+        let mut cmd = shell(&self.command);
+        cmd.envs(self.env.iter().map(|(k, v)| (k, v)));
+        cmd.stdout(std::io::stderr());
+        let status = cmd
+            .status()
+            .map_err(|e| format!("could not run hook '{}': {}", self.command, e))?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!("hook '{}' failed: {}", self.command, status))
+        }
+    }
+}
+
+/// A command to run `command` with the system shell.
+#[cfg(not(windows))]
+pub fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(windows)]
+pub fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+/// Runs a hook when dropped, so that cleanup (e.g. unmounting a snapshot) happens even if hashing panics.
+pub struct PostHook(pub Hook);
+
+impl Drop for PostHook {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.run() {
+            eprintln!("warning: post-hook: {}", e);
+        }
+    }
+}
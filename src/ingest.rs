@@ -0,0 +1,231 @@
+//! Copy files into a content-addressed store, named by their digests.
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use structopt::StructOpt;
+use tokio_stream::{iter, Stream, StreamExt};
+
+use recursum::extents;
+use recursum::sink::OutputSink;
+use recursum::walk::{WalkOptions, WalkerKind};
+use recursum::{hash_stream, hashers, queue_length, runtime, walk_paths, HashOptions};
+
+use crate::color::{self, Role};
+use crate::output::{Compression, TextOutput};
+use crate::progress::{HumanBytes, Progress};
+use crate::{hasher_names, or_num_cpus, select_hasher};
+
+/// Hex digits of the digest used to name the subdirectory of the store holding each file.
+const FANOUT_DIGITS: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    Copy,
+    Hardlink,
+    Reflink,
+}
+
+impl FromStr for Method {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "copy" => Ok(Self::Copy),
+            "hardlink" => Ok(Self::Hardlink),
+            "reflink" => Ok(Self::Reflink),
+            _ => Err(format!(
+                "unknown method '{}'; expected copy, hardlink or reflink",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct IngestOpt {
+    /// File or directory to ingest.
+    #[structopt(parse(from_os_str))]
+    src: PathBuf,
+    /// Directory of the store; created if necessary. Each file is stored as <store>/<first 2 digits of digest>/<rest of digest>.
+    #[structopt(parse(from_os_str))]
+    store: PathBuf,
+    /// How to put files into the store: copy, hardlink (the store must be on the same file system) or reflink (Linux only, on file systems which support it).
+    #[structopt(long = "method", default_value = "copy")]
+    method: Method,
+    /// Hashing algorithm.
+    #[structopt(long = "hash", default_value = hashers::AUTO, possible_values = hasher_names())]
+    hash: String,
+    /// Write the manifest mapping each ingested path to its digest to this file, rather than stdout. Compressed if the name ends in .gz or .zst.
+    #[structopt(short = "o", long = "output", parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// Hashing threads.
+    #[structopt(short = "t", long = "threads")]
+    threads: Option<usize>,
+    /// Do not show progress information.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+}
+
+/// How many files went where.
+#[derive(Debug, Default)]
+struct Tally {
+    stored: usize,
+    stored_bytes: u64,
+    /// already in the store
+    present: usize,
+    failed: usize,
+}
+
+/// Where the file with this digest lives in the store.
+fn store_path(store: &Path, digest: &str) -> PathBuf {
+    let (dir, rest) = digest.split_at(FANOUT_DIGITS.min(digest.len()));
+    store.join(dir).join(rest)
+}
+
+/// Put `src` into the store at `dest`, unless something is already there.
+///
+/// The file is created under a temporary name and renamed into place,
+/// so the store never holds a partial file under a digest's name.
+/// Returns whether the file was added.
+fn place(src: &Path, dest: &Path, size: u64, method: Method) -> io::Result<bool> {
+    if dest.exists() {
+        return Ok(false);
+    }
+    let dir = dest.parent().expect("store paths have a parent");
+    fs::create_dir_all(dir)?;
+    let mut tmp_name = OsStr::new(".").to_os_string();
+    tmp_name.push(dest.file_name().unwrap_or_default());
+    tmp_name.push(format!(".recursum-{}", std::process::id()));
+    let tmp = dir.join(tmp_name);
+
+    let placed = match method {
+        Method::Copy => fs::copy(src, &tmp).map(|_| ()),
+        Method::Hardlink => fs::hard_link(src, &tmp),
+        Method::Reflink => extents::reflink(src, &tmp),
+    }
+    .and_then(|_| {
+        if fs::metadata(&tmp)?.len() != size {
+            return Err(io::Error::other("file changed since it was hashed"));
+        }
+        fs::rename(&tmp, dest)
+    });
+    placed.inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })?;
+    Ok(true)
+}
+
+pub fn run(opt: IngestOpt) {
+    let threads = or_num_cpus(opt.threads);
+    let hash_name = select_hasher(&opt.hash);
+    let hash_opts = HashOptions {
+        hasher: hashers::get(hash_name).expect("hasher names are checked by clap"),
+        // a file which changes while being hashed would be stored under the wrong digest
+        detect_races: true,
+        jobs: threads,
+        ..Default::default()
+    };
+    fs::create_dir_all(&opt.store)
+        .unwrap_or_else(|e| panic!("could not create store {:?}: {}", opt.store, e));
+
+    let mut output = TextOutput::new(crate::DEFAULT_SEPARATOR, false, None);
+    if let Some(path) = &opt.output {
+        output = output.write_to_file(path, Compression::from_path(path));
+    }
+    let progress = if opt.quiet {
+        None
+    } else {
+        Some(Progress::new())
+    };
+    let warn = |msg: String| match &progress {
+        Some(p) => p.println(msg),
+        None => eprintln!("{}", msg),
+    };
+
+    let rt = runtime(threads, false).unwrap();
+    let tally = rt.block_on(async {
+        let paths: Box<dyn Stream<Item = PathBuf> + Unpin + Send> = if opt.src.is_dir() {
+            let walker = WalkerKind::for_order(None)
+                .build(
+                    threads,
+                    queue_length(threads),
+                    WalkOptions {
+                        order: None,
+                        sort: true,
+                        skip_hidden: false,
+                        prune: Default::default(),
+                        detect_case_collisions: false,
+                        dir_limits: None,
+                        skipped: None,
+                    },
+                )
+                .unwrap_or_else(|e| panic!("{}", e));
+            Box::new(walk_paths(
+                opt.src.clone(),
+                queue_length(threads),
+                walker.as_ref(),
+            ))
+        } else {
+            Box::new(iter(vec![opt.src.clone()]))
+        };
+        let mut results = hash_stream(paths, hash_opts);
+        let mut tally = Tally::default();
+        while let Some(result) = results.next().await {
+            let hashed = match result {
+                Ok(h) => h,
+                Err(e) => {
+                    warn(format!("warning: {}", e));
+                    tally.failed += 1;
+                    continue;
+                }
+            };
+            if let Some(p) = &progress {
+                p.update(&hashed.path, hashed.size);
+            }
+            if !hashed.stable {
+                warn(format!(
+                    "warning: not ingesting {:?}: it changed while being hashed",
+                    hashed.path
+                ));
+                tally.failed += 1;
+                continue;
+            }
+            let dest = store_path(&opt.store, &hashed.digest);
+            match place(&hashed.path, &dest, hashed.size, opt.method) {
+                Ok(true) => {
+                    tally.stored += 1;
+                    tally.stored_bytes += hashed.size;
+                }
+                Ok(false) => tally.present += 1,
+                Err(e) => {
+                    warn(format!(
+                        "warning: could not ingest {:?}: {}",
+                        hashed.path, e
+                    ));
+                    tally.failed += 1;
+                    continue;
+                }
+            }
+            output.on_result(&hashed).expect("could not write manifest");
+        }
+        output.on_finish().expect("could not write manifest");
+        tally
+    });
+    if let Some(p) = progress {
+        p.finish();
+        eprintln!(
+            "{} files ({}) added to the store; {} already there",
+            tally.stored,
+            HumanBytes(tally.stored_bytes),
+            tally.present
+        );
+    }
+    if tally.failed > 0 {
+        let msg = format!("WARNING: {} file(s) could not be ingested", tally.failed);
+        eprintln!("{}", color::stderr(Role::Bad, msg));
+        std::process::exit(1);
+    }
+}
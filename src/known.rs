@@ -0,0 +1,280 @@
+//! Looking up each digest in a set of known digests as files are hashed, for --known-hashes:
+//! e.g. leaving out files in an NSRL-style list of known operating system and application files,
+//! or keeping only files in a list of known contraband.
+//!
+//! Lists too large to hold in memory can be loaded into a bloom filter with --known-bloom instead,
+//! whose matches are confirmed by reading the list again when hashing finishes.
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use recursum::sink::OutputSink;
+use recursum::{FileHash, HashError};
+
+use crate::bloom::Bloom;
+use crate::manifest::split_algorithm;
+use crate::progress::HumanBytes;
+
+/// Shorter hex fields are more likely to be something else, e.g. a year or a size.
+const MIN_DIGEST_CHARS: usize = 8;
+/// Files listed when bloom filter matches turn out to be wrong; the rest are only counted.
+const LISTED: usize = 20;
+
+/// What --known-hashes does with the files it matches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KnownMode {
+    /// Output only files whose digests are known.
+    Include,
+    /// Leave out files whose digests are known.
+    Exclude,
+}
+
+impl FromStr for KnownMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "include" => Ok(Self::Include),
+            "exclude" => Ok(Self::Exclude),
+            _ => Err(format!(
+                "unknown known-hashes mode '{}'; expected include or exclude",
+                s
+            )),
+        }
+    }
+}
+
+/// The bytes of a hex digest, without any algorithm name recursum prefixed it with (e.g. `crc32:`).
+fn digest_bytes(digest: &str) -> Option<Vec<u8>> {
+    let (_, hex) = split_algorithm(digest);
+    hex::decode(hex).ok()
+}
+
+/// Pass every field of a file which is a hex digest (optionally prefixed with its algorithm) to `f`, as bytes,
+/// so that it may be a list of digests one per line, a manifest, or a CSV such as the NSRL's (whose quotes are ignored).
+/// Blank lines and lines starting with `#` are skipped.
+fn read_digests(path: &Path, mut f: impl FnMut(Vec<u8>)) -> io::Result<()> {
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.starts_with('#') {
+            continue;
+        }
+        let fields = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .map(|f| f.trim_matches('"'))
+            .filter(|f| split_algorithm(f).1.len() >= MIN_DIGEST_CHARS);
+        for field in fields {
+            if let Some(bytes) = digest_bytes(field) {
+                f(bytes);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+enum Digests {
+    /// Stored as bytes to halve the memory of a large list.
+    Exact(HashSet<Box<[u8]>>),
+    /// Matches must be confirmed by reading the list again.
+    Bloom(Bloom),
+}
+
+#[derive(Debug)]
+pub struct KnownHashes {
+    digests: Digests,
+    path: PathBuf,
+    /// digests read, including any duplicates if in a bloom filter
+    len: usize,
+}
+
+impl KnownHashes {
+    /// Hold every digest in `path` in memory.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut digests = HashSet::new();
+        read_digests(path, |d| {
+            digests.insert(d.into_boxed_slice());
+        })?;
+        Ok(Self {
+            len: digests.len(),
+            digests: Digests::Exact(digests),
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Load the digests in `path` into a bloom filter with the false-positive rate `fp_rate`,
+    /// reading it twice: once to size the filter, and once to fill it.
+    pub fn load_bloom(path: &Path, fp_rate: f64) -> io::Result<Self> {
+        let mut len = 0;
+        read_digests(path, |_| len += 1)?;
+        let mut bloom = Bloom::new(len, fp_rate);
+        read_digests(path, |d| bloom.insert(&d))?;
+        Ok(Self {
+            digests: Digests::Bloom(bloom),
+            path: path.to_path_buf(),
+            len,
+        })
+    }
+
+    /// e.g. for saying what was loaded.
+    pub fn describe(&self) -> String {
+        match &self.digests {
+            Digests::Exact(_) => format!("{} known digests from {:?}", self.len, self.path),
+            Digests::Bloom(b) => format!(
+                "{} known digests from {:?} into a {} bloom filter",
+                self.len,
+                self.path,
+                HumanBytes(b.size() as u64)
+            ),
+        }
+    }
+
+    /// Whether `digest` is known, or with a bloom filter, may be.
+    pub fn contains(&self, digest: &str) -> bool {
+        let bytes = match digest_bytes(digest) {
+            Some(b) => b,
+            None => return false,
+        };
+        match &self.digests {
+            Digests::Exact(set) => set.contains(bytes.as_slice()),
+            Digests::Bloom(bloom) => bloom.may_contain(&bytes),
+        }
+    }
+
+    /// Which of the `candidates`, digests for which [contains](Self::contains) was true, really are known.
+    /// With a bloom filter, this reads the list again.
+    pub fn confirm<'a>(
+        &self,
+        candidates: impl Iterator<Item = &'a str>,
+    ) -> io::Result<HashSet<String>> {
+        if let Digests::Exact(_) = self.digests {
+            return Ok(candidates.map(str::to_string).collect());
+        }
+        let mut unconfirmed: HashMap<Vec<u8>, Vec<&str>> = HashMap::new();
+        for candidate in candidates {
+            if let Some(bytes) = digest_bytes(candidate) {
+                unconfirmed.entry(bytes).or_default().push(candidate);
+            }
+        }
+        let mut confirmed = HashSet::new();
+        read_digests(&self.path, |d| {
+            if let Some(matched) = unconfirmed.remove(&d) {
+                confirmed.extend(matched.into_iter().map(str::to_string));
+            }
+        })?;
+        Ok(confirmed)
+    }
+}
+
+/// Passes results to another output only if they should be kept.
+pub struct KnownFilter {
+    inner: Box<dyn OutputSink>,
+    known: &'static KnownHashes,
+    mode: KnownMode,
+}
+
+impl KnownFilter {
+    pub fn new(inner: Box<dyn OutputSink>, known: &'static KnownHashes, mode: KnownMode) -> Self {
+        Self { inner, known, mode }
+    }
+}
+
+impl OutputSink for KnownFilter {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        let keep = match self.mode {
+            KnownMode::Include => self.known.contains(&result.digest),
+            KnownMode::Exclude => !self.known.contains(&result.digest),
+        };
+        match (keep, self.mode) {
+            (true, _) => self.inner.on_result(result),
+            (false, KnownMode::Include) => self.inner.on_skipped(&result.path, "unknown"),
+            (false, KnownMode::Exclude) => self.inner.on_skipped(&result.path, "known"),
+        }
+    }
+
+    fn on_error(&mut self, error: &HashError) -> io::Result<()> {
+        self.inner.on_error(error)
+    }
+
+    fn on_skipped(&mut self, path: &Path, reason: &str) -> io::Result<()> {
+        self.inner.on_skipped(path, reason)
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        self.inner.on_finish()
+    }
+}
+
+/// Counts matches, and reports them on stderr when finished,
+/// along with any files wrongly matched by a bloom filter.
+pub struct KnownCount {
+    known: &'static KnownHashes,
+    mode: KnownMode,
+    files: u64,
+    /// files which matched, and their digests
+    matched: Vec<(PathBuf, String)>,
+}
+
+impl KnownCount {
+    pub fn new(known: &'static KnownHashes, mode: KnownMode) -> Self {
+        Self {
+            known,
+            mode,
+            files: 0,
+            matched: Vec::new(),
+        }
+    }
+}
+
+impl OutputSink for KnownCount {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        self.files += 1;
+        if self.known.contains(&result.digest) {
+            self.matched
+                .push((result.path.clone(), result.digest.clone()));
+        }
+        Ok(())
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        let confirmed = self
+            .known
+            .confirm(self.matched.iter().map(|(_, d)| d.as_str()))?;
+        let (right, wrong): (Vec<_>, Vec<_>) = self
+            .matched
+            .iter()
+            .partition(|(_, d)| confirmed.contains(d));
+        let action = match self.mode {
+            KnownMode::Include => "only they were output",
+            KnownMode::Exclude => "they were left out of the output",
+        };
+        eprintln!(
+            "{} of {} files hashed have known digests; {}",
+            right.len(),
+            self.files,
+            action
+        );
+        if wrong.is_empty() {
+            return Ok(());
+        }
+        let action = match self.mode {
+            KnownMode::Include => "output",
+            KnownMode::Exclude => "left out of the output",
+        };
+        eprintln!(
+            "{} files were matched by the bloom filter but are not in {:?}, so were wrongly {}:",
+            wrong.len(),
+            self.known.path,
+            action
+        );
+        for (path, _) in wrong.iter().take(LISTED) {
+            eprintln!("  {}", path.display());
+        }
+        if wrong.len() > LISTED {
+            eprintln!("  ... and {} more", wrong.len() - LISTED);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,737 @@
+//! Hash lots of files fast, in parallel.
+//!
+//! The core of the `recursum` binary: walking directory trees ([walk]),
+//! and hashing streams of files concurrently on a tokio runtime ([hash_stream]),
+//! or on a pool of threads ([blocking]).
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::fs::Metadata;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use activity::Activity;
+use cancel::{CancellableRead, CancellationToken};
+use devices::DeviceLimits;
+use diagnostics::Diagnostics;
+use hash_cache::HashCache;
+use hashers::{BySize, Hasher, HasherFactory, SeededHasherFactory};
+use prefetch::Prefetcher;
+use structure::Structure;
+use tokio::runtime::{self, Runtime};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::{self, JoinHandle};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tune::AutoTune;
+
+pub mod activity;
+pub mod blocking;
+mod buffer;
+pub mod cancel;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod devices;
+pub mod diagnostics;
+pub mod extents;
+pub mod hash_cache;
+pub mod hashers;
+pub mod listing_cache;
+pub mod platform;
+#[cfg(feature = "rayon")]
+pub mod pool;
+mod prefetch;
+pub mod sink;
+pub mod structure;
+pub mod tune;
+pub mod walk;
+use walk::Walker;
+
+/// Bytes read from a file at a time, unless chosen by [HashOptions::buffer_size] or [tune].
+pub const READ_BUFFER_SIZE: usize = 8 * 1024;
+
+const BUFFER_PPN: f64 = 3.0;
+/// Threads driving the async parts of hashing (fetching paths, ordering results, output),
+/// which mostly wait for the blocking threads doing the work.
+const ASYNC_THREADS: usize = 2;
+
+/// Length of the queue of pending paths/results for this many jobs,
+/// large enough that hashing threads should never wait for it to be populated.
+pub fn queue_length(n_jobs: usize) -> usize {
+    (n_jobs as f64 * BUFFER_PPN).ceil() as usize
+}
+
+/// Options for hashing each file.
+#[derive(Debug, Clone, Copy)]
+pub struct HashOptions {
+    /// Creates the hasher for each file; see [hashers].
+    pub hasher: HasherFactory,
+    /// Creates seeded hashers instead of `hasher`, with the given seed;
+    /// see [hashers::get_seeded].
+    pub seeded_hasher: Option<(SeededHasherFactory, &'static [u8])>,
+    /// Maximum length of the hex digest.
+    pub truncate: Option<usize>,
+    /// Check whether each file changed while it was being hashed.
+    pub detect_races: bool,
+    /// Query which of each file's physical extents are shared with other files.
+    pub shared_extents: bool,
+    /// Number of files to hash concurrently.
+    pub jobs: usize,
+    /// Bytes to read from a file at a time, unless `auto_tune` chooses.
+    pub buffer_size: usize,
+    /// Ask the kernel to read ahead aggressively in each file; see [platform::advise_sequential].
+    pub advise_sequential: bool,
+    /// Record where time is spent; see [diagnostics].
+    pub diagnostics: Option<&'static Diagnostics>,
+    /// Vary the number of concurrent jobs (up to `jobs`) and the read buffer size
+    /// to maximise throughput; see [tune]. Only used by [hash_stream].
+    pub auto_tune: Option<&'static AutoTune>,
+    /// Hash files no larger than the read buffer back-to-back in one task, up to this many at a time,
+    /// rather than spawning a task for each; 0 or 1 disables batching.
+    /// Costs a stat of each file, made on the blocking pool ahead of hashing it. Only used by [hash_stream].
+    pub small_file_batch: usize,
+    /// Stop hashing once this is cancelled; see [cancel].
+    pub cancel: Option<&'static CancellationToken>,
+    /// Pin each hashing thread to a NUMA node, taking the nodes in turn; see [platform::pin_to_next_numa_node].
+    /// Used by [blocking] and [pool](crate::pool) threads; for [hash_stream], see [runtime].
+    pub numa: bool,
+    /// Limit how many files are read at once from each device; see [devices].
+    /// Costs a stat of each file, made on the blocking pool ahead of hashing it. Only used by [hash_stream].
+    pub device_limits: Option<&'static DeviceLimits>,
+    /// Record which file each thread is reading; see [activity].
+    pub activity: Option<&'static Activity>,
+    /// Reuse digests of files which have not changed since they were cached, and cache the rest;
+    /// see [hash_cache]. Costs a stat of each file.
+    pub cache: Option<&'static HashCache>,
+    /// Stat each file once, and use that for everything which needs its metadata: batching, device limits,
+    /// the cache, the first check of `detect_races`, and the length hint given to the hasher
+    /// (otherwise taken from the open file). For network filesystems, where every query is a round trip.
+    /// [hash_stream] stats each file on the blocking pool as soon as the walk yields it, several at once,
+    /// so that the round trips overlap and are made while the client may still hold the attributes
+    /// it fetched when listing the file's directory.
+    pub stat_once: bool,
+    /// Hash each file's path, size and modification time instead of its contents; see [structure].
+    pub structure: Option<&'static Structure>,
+    /// Open up to this many files ahead of the ones being hashed, and ask the kernel to start reading them,
+    /// to hide the latency of opening and first reading each file on slow storage; 0 disables prefetching.
+    /// Uses a few threads of its own. Only used by [hash_stream].
+    pub prefetch: usize,
+    /// Hash each file with the algorithm for its size rather than with `hasher`,
+    /// prefixing each digest with the algorithm's name and a colon, e.g. `crc32:d159a670`,
+    /// so that every digest records how it was made. Costs a stat of each file.
+    pub by_size: Option<&'static BySize>,
+}
+
+impl Default for HashOptions {
+    fn default() -> Self {
+        Self {
+            hasher: hashers::default_factory(),
+            seeded_hasher: None,
+            truncate: None,
+            detect_races: false,
+            shared_extents: false,
+            jobs: num_cpus::get(),
+            buffer_size: READ_BUFFER_SIZE,
+            advise_sequential: true,
+            diagnostics: None,
+            auto_tune: None,
+            small_file_batch: 0,
+            cancel: None,
+            numa: false,
+            device_limits: None,
+            activity: None,
+            cache: None,
+            stat_once: false,
+            structure: None,
+            prefetch: 0,
+            by_size: None,
+        }
+    }
+}
+
+impl HashOptions {
+    /// A new hasher for one file: seeded if `seeded_hasher` is set, or from `hasher`.
+    pub fn new_hasher(&self) -> Box<dyn Hasher> {
+        match self.seeded_hasher {
+            Some((factory, seed)) => factory(seed),
+            None => (self.hasher)(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FileHash {
+    pub path: PathBuf,
+    /// Hex digest.
+    pub digest: String,
+    pub size: u64,
+    /// False if the file was seen to change while being hashed, so the digest may be meaningless.
+    pub stable: bool,
+    /// Physical offsets of shared extents, if queried.
+    pub shared_extents: Option<io::Result<Vec<u64>>>,
+    /// Time taken to open, read and hash the file.
+    pub elapsed: Duration,
+}
+
+/// What was being done to a file when it could not be hashed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// reading its size and modification time
+    Stat,
+    Open,
+    /// reading its contents
+    Read,
+    /// writing its path, which an output could not hold as it is (e.g. it is not valid UTF-8)
+    Path,
+}
+
+impl Phase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Stat => "stat",
+            Self::Open => "open",
+            Self::Read => "read",
+            Self::Path => "path",
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A file which could not be hashed.
+#[derive(Debug)]
+pub struct HashError {
+    pub path: PathBuf,
+    pub phase: Phase,
+    pub source: io::Error,
+}
+
+impl fmt::Display for HashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "could not hash {:?}: {}", self.path, self.source)
+    }
+}
+
+impl std::error::Error for HashError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+// adapted from https://stackoverflow.com/a/58825638/2700168
+/// Stream the files found by `walker` under `root`, walking on another thread.
+///
+/// Waiting for the walker occupies one of the runtime's blocking threads until the walk is finished.
+///
+/// Must be called from within a tokio runtime.
+pub fn walk_paths(root: PathBuf, queue_len: usize, walker: &dyn Walker) -> ReceiverStream<PathBuf> {
+    let (sender, receiver) = mpsc::channel(queue_len);
+    let files = walker.files(&root);
+    task::spawn_blocking(move || {
+        for path in files {
+            if sender.blocking_send(path).is_err() {
+                // receiver dropped
+                break;
+            }
+        }
+    });
+
+    ReceiverStream::new(receiver)
+}
+
+fn is_cancelled(opts: HashOptions) -> bool {
+    opts.cancel.is_some_and(|c| c.is_cancelled())
+}
+
+/// Bytes to read from a file at a time.
+fn buffer_size(opts: HashOptions) -> usize {
+    opts.auto_tune
+        .map_or(opts.buffer_size, |t| t.buffer_size())
+        .max(1)
+}
+
+/// Whether the file fits in one read buffer, so is worth batching with others.
+fn is_small(meta: &Metadata, opts: HashOptions) -> bool {
+    meta.is_file() && meta.len() <= buffer_size(opts) as u64
+}
+
+type Job = JoinHandle<(Vec<Result<FileHash, HashError>>, Instant)>;
+
+/// Hash the files one after another in a single task, once a read from their device is allowed by `limit`,
+/// returning their results in order and when the last one finished.
+/// Each path comes with its metadata if already stat'd for [HashOptions::stat_once],
+/// and its file is taken from `prefetcher` if it has been opened already.
+fn spawn_batch(
+    paths: Vec<(PathBuf, Option<Metadata>)>,
+    limit: Option<Arc<Semaphore>>,
+    prefetcher: Option<Arc<Prefetcher>>,
+    opts: HashOptions,
+) -> Job {
+    let spawned = Instant::now();
+    let hash_batch = move || {
+        let queued = spawned.elapsed();
+        let results = paths
+            .into_iter()
+            .take_while(|_| !is_cancelled(opts))
+            .map(|(path, meta)| {
+                if let Some(d) = opts.diagnostics {
+                    d.file(queued);
+                }
+                let file = prefetcher.as_ref().and_then(|p| p.take(&path));
+                hash_path_with(path, meta, file, opts)
+            })
+            .collect();
+        (results, Instant::now())
+    };
+    match limit {
+        // wait for the device without holding up a blocking thread
+        Some(limit) => tokio::spawn(async move {
+            let permit = limit
+                .acquire_owned()
+                .await
+                .expect("device semaphores are never closed");
+            task::spawn_blocking(move || {
+                let _permit = permit;
+                hash_batch()
+            })
+            .await
+            .expect("hashing task panicked")
+        }),
+        None => task::spawn_blocking(hash_batch),
+    }
+}
+
+/// Small files waiting to be hashed together, all limited by the same device semaphore (if any).
+struct Batch {
+    paths: Vec<(PathBuf, Option<Metadata>)>,
+    limit: Option<Arc<Semaphore>>,
+    prefetcher: Option<Arc<Prefetcher>>,
+}
+
+impl Batch {
+    fn accepts(&self, limit: &Option<Arc<Semaphore>>) -> bool {
+        match (&self.limit, limit) {
+            (None, None) => true,
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            _ => self.paths.is_empty(),
+        }
+    }
+
+    /// Spawn the batch so far, if there is one.
+    fn flush(&mut self, pending: &mut VecDeque<Job>, opts: HashOptions) {
+        if !self.paths.is_empty() {
+            let paths = std::mem::take(&mut self.paths);
+            let prefetcher = self.prefetcher.clone();
+            pending.push_back(spawn_batch(paths, self.limit.take(), prefetcher, opts));
+        }
+    }
+}
+
+/// A multi-threaded runtime for [hash_stream] and [walk_paths].
+///
+/// Files are read and hashed on tokio's blocking pool, so that the async threads stay responsive;
+/// the pool is limited to `threads` hashing threads plus one for [walk_paths] or reading stdin.
+/// If `numa`, every thread is pinned to a NUMA node as it starts, as for [HashOptions::numa].
+pub fn runtime(threads: usize, numa: bool) -> io::Result<Runtime> {
+    let threads = threads.max(1);
+    let mut builder = runtime::Builder::new_multi_thread();
+    builder
+        .enable_all()
+        .worker_threads(ASYNC_THREADS)
+        .max_blocking_threads(threads + 1);
+    if numa {
+        builder.on_thread_start(platform::pin_to_next_numa_node);
+    }
+    builder.build()
+}
+
+/// Stat each path on the blocking pool as soon as it arrives, with up to `ahead` stats in flight at once,
+/// yielding the paths in the same order with their metadata (if it could be read).
+fn stat_ahead<S>(paths: S, ahead: usize) -> impl Stream<Item = (PathBuf, Option<Metadata>)> + Send
+where
+    S: Stream<Item = PathBuf> + Unpin + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(ahead.max(1));
+    tokio::spawn(async move {
+        let mut paths = paths;
+        while let Some(path) = paths.next().await {
+            let stat = task::spawn_blocking(move || {
+                let meta = std::fs::metadata(&path).ok();
+                (path, meta)
+            });
+            if sender.send(stat).await.is_err() {
+                // receiver dropped
+                break;
+            }
+        }
+    });
+    ReceiverStream::new(receiver).then(|stat| async { stat.await.expect("stat task panicked") })
+}
+
+/// Hash files concurrently, yielding results in the same order as the paths.
+///
+/// Up to a few times `opts.jobs` jobs, each of one file or of a batch of small files
+/// (see [HashOptions::small_file_batch]), are started ahead of the results being consumed;
+/// beyond that, nothing more is read until results are taken from the returned stream.
+/// Dropping the returned stream stops hashing.
+///
+/// Files are read and hashed on the runtime's blocking pool, which limits how many are hashed at once:
+/// see [runtime] to limit it to `opts.jobs`.
+///
+/// Must be called from within a tokio runtime.
+pub fn hash_stream<S>(
+    paths: S,
+    opts: HashOptions,
+) -> impl Stream<Item = Result<FileHash, HashError>> + Unpin
+where
+    S: Stream<Item = PathBuf> + Unpin + Send + 'static,
+{
+    let queue_len = queue_length(opts.jobs.max(1));
+    let (sender, receiver) = mpsc::channel(queue_len);
+    tokio::spawn(async move {
+        // files' metadata is read on the blocking pool, never on the async threads
+        let needs_meta =
+            opts.small_file_batch > 1 || opts.device_limits.is_some() || opts.stat_once;
+        let mut paths: Pin<Box<dyn Stream<Item = (PathBuf, Option<Metadata>)> + Send>> =
+            if needs_meta {
+                Box::pin(stat_ahead(paths, queue_len))
+            } else {
+                Box::pin(paths.map(|path| (path, None)))
+            };
+        let mut pending = VecDeque::with_capacity(queue_len);
+        let prefetcher = (opts.prefetch > 0).then(|| Arc::new(Prefetcher::new(opts.prefetch)));
+        let mut batch = Batch {
+            paths: Vec::new(),
+            limit: None,
+            prefetcher: prefetcher.clone(),
+        };
+        let mut exhausted = false;
+        loop {
+            if is_cancelled(opts) {
+                // drop the paths and pending jobs, and end the stream of results
+                return;
+            }
+            // keep enough jobs running before waiting on the oldest
+            let in_flight = opts.auto_tune.map_or(queue_len, |t| t.jobs());
+            while !exhausted && pending.len() < in_flight {
+                let waited_from = Instant::now();
+                let next = paths.next().await;
+                if let Some(d) = opts.diagnostics {
+                    d.path_wait(waited_from.elapsed());
+                }
+                let (path, meta) = match next {
+                    Some(next) => next,
+                    None => {
+                        exhausted = true;
+                        batch.flush(&mut pending, opts);
+                        break;
+                    }
+                };
+                // files which cannot be stat'd are hashed alone and unlimited, so the error is reported as usual
+                let small =
+                    opts.small_file_batch > 1 && meta.as_ref().is_some_and(|m| is_small(m, opts));
+                let limit = match (opts.device_limits, &meta) {
+                    (Some(limits), Some(meta)) => limits.limit_for(meta),
+                    _ => None,
+                };
+                // the batch so far came first, so must be spawned first to keep results in order
+                if !small || !batch.accepts(&limit) {
+                    batch.flush(&mut pending, opts);
+                }
+                let meta = if opts.stat_once { meta } else { None };
+                if let Some(p) = &prefetcher {
+                    p.push(path.clone());
+                }
+                if small {
+                    batch.paths.push((path, meta));
+                    batch.limit = limit;
+                    if batch.paths.len() >= opts.small_file_batch {
+                        batch.flush(&mut pending, opts);
+                    }
+                } else {
+                    let job = spawn_batch(vec![(path, meta)], limit, prefetcher.clone(), opts);
+                    pending.push_back(job);
+                }
+            }
+            let waited_from = Instant::now();
+            let (results, finished) = match pending.pop_front() {
+                Some(job) => job.await.expect("hashing task panicked"),
+                None => break,
+            };
+            if let Some(d) = opts.diagnostics {
+                d.head_wait(waited_from, finished);
+            }
+            for result in results {
+                if is_cancelled(opts) {
+                    return;
+                }
+                if let (Some(t), Ok(hashed)) = (opts.auto_tune, &result) {
+                    t.record(hashed.size);
+                }
+                let sending = Instant::now();
+                let sent = sender.send(result).await;
+                if let Some(d) = opts.diagnostics {
+                    d.output_wait(sending.elapsed());
+                }
+                if sent.is_err() {
+                    // receiver dropped
+                    return;
+                }
+            }
+        }
+    });
+    ReceiverStream::new(receiver)
+}
+
+/// Size and modification time, to check whether a file has changed.
+fn file_state(path: &Path) -> io::Result<(u64, Option<SystemTime>)> {
+    let meta = std::fs::metadata(path)?;
+    Ok((meta.len(), meta.modified().ok()))
+}
+
+/// Hash a single file on the current thread.
+pub fn hash_path(path: PathBuf, opts: HashOptions) -> Result<FileHash, HashError> {
+    hash_path_with(path, None, None, opts)
+}
+
+/// As [hash_path], with the file's metadata if it has already been stat'd for [HashOptions::stat_once],
+/// and the file itself if it has already been opened for [HashOptions::prefetch].
+fn hash_path_with(
+    path: PathBuf,
+    meta: Option<Metadata>,
+    file: Option<File>,
+    opts: HashOptions,
+) -> Result<FileHash, HashError> {
+    let started = Instant::now();
+    // as it was before hashing, so that a file changed meanwhile is hashed again next time
+    let meta = match meta {
+        Some(meta) => Some(meta),
+        None if opts.cache.is_some()
+            || opts.stat_once
+            || opts.structure.is_some()
+            || opts.by_size.is_some() =>
+        {
+            match std::fs::metadata(&path) {
+                Ok(meta) => Some(meta),
+                Err(source) => {
+                    return Err(HashError {
+                        path,
+                        phase: Phase::Stat,
+                        source,
+                    })
+                }
+            }
+        }
+        None => None,
+    };
+    if let (Some(structure), Some(meta)) = (opts.structure, &meta) {
+        let mut digest = hex::encode(structure.digest(&path, meta, opts.new_hasher()));
+        if let Some(t) = opts.truncate {
+            digest.truncate(t);
+        }
+        let shared_extents = opts.shared_extents.then(|| extents::shared_extents(&path));
+        return Ok(FileHash {
+            path,
+            digest,
+            size: meta.len(),
+            stable: true,
+            shared_extents,
+            elapsed: started.elapsed(),
+        });
+    }
+    // a cached digest which is being checked by hashing the file anyway
+    let mut verifying = None;
+    if let (Some(cache), Some(meta)) = (opts.cache, &meta) {
+        if let Some(digest) = cache.lookup(&path, meta) {
+            if cache.sample(&path) {
+                verifying = Some(digest);
+            } else {
+                let shared_extents = opts.shared_extents.then(|| extents::shared_extents(&path));
+                return Ok(FileHash {
+                    path,
+                    digest,
+                    size: meta.len(),
+                    stable: true,
+                    shared_extents,
+                    elapsed: started.elapsed(),
+                });
+            }
+        }
+    }
+    let _reading = opts.activity.map(|a| a.start(&path));
+    let hashed = || -> Result<_, (Phase, io::Error)> {
+        let stat = |e| (Phase::Stat, e);
+        let shared_meta = meta.as_ref().filter(|_| opts.stat_once);
+        let before = match (opts.detect_races, shared_meta) {
+            (false, _) => None,
+            (true, Some(meta)) => Some((meta.len(), meta.modified().ok())),
+            (true, None) => Some(file_state(&path).map_err(stat)?),
+        };
+        let (hasher, algorithm) = match (opts.by_size, &meta) {
+            (Some(by_size), Some(meta)) => {
+                let (name, factory) = by_size.choose(meta.len());
+                (factory(), Some(name))
+            }
+            _ => (opts.new_hasher(), None),
+        };
+        let file = match file {
+            Some(file) => file,
+            None => File::open(&path).map_err(|e| (Phase::Open, e))?,
+        };
+        let len = shared_meta.map(Metadata::len);
+        let (digest, size) =
+            hash_file_with(&path, hasher, opts, len, Some(file)).map_err(|e| (Phase::Read, e))?;
+        let digest = match algorithm {
+            Some(name) => format!("{}:{}", name, digest),
+            None => digest,
+        };
+        let stable = match before {
+            Some(state) => state.0 == size && state == file_state(&path).map_err(stat)?,
+            None => true,
+        };
+        Ok((digest, size, stable))
+    };
+    let (digest, size, stable) = match hashed() {
+        Ok(h) => h,
+        Err((phase, source)) => {
+            return Err(HashError {
+                path,
+                phase,
+                source,
+            })
+        }
+    };
+    let elapsed = started.elapsed();
+    if let (Some(cache), Some(meta), true) = (opts.cache, &meta, stable) {
+        if meta.len() == size {
+            if let Some(cached) = verifying {
+                cache.verified(&path, cached, &digest);
+            }
+            cache.record(&path, meta, &digest);
+        }
+    }
+    let shared_extents = if opts.shared_extents {
+        Some(extents::shared_extents(&path))
+    } else {
+        None
+    };
+    Ok(FileHash {
+        path,
+        digest,
+        size,
+        stable,
+        shared_extents,
+        elapsed,
+    })
+}
+
+/// Hex digest and size of a file.
+pub fn hash_file(
+    fpath: &Path,
+    hasher: Box<dyn Hasher>,
+    truncate: Option<usize>,
+) -> io::Result<(String, u64)> {
+    let opts = HashOptions {
+        truncate,
+        ..Default::default()
+    };
+    hash_file_with(fpath, hasher, opts, None, None)
+}
+
+/// As [hash_file], reading with the buffer size, read-ahead advice, diagnostics and cancellation of `opts`,
+/// and telling the hasher to expect `len` bytes if known (otherwise the open file's length).
+/// Reads `file` if it is already open.
+fn hash_file_with(
+    fpath: &Path,
+    mut hasher: Box<dyn Hasher>,
+    opts: HashOptions,
+    len: Option<u64>,
+    file: Option<File>,
+) -> io::Result<(String, u64)> {
+    let file = match file {
+        Some(file) => file,
+        None => File::open(fpath)?,
+    };
+    if opts.advise_sequential {
+        platform::advise_sequential(&file);
+    }
+    let len = match len {
+        Some(len) => len,
+        None => file.metadata()?.len(),
+    };
+    hasher.expect_length(len);
+    let file = CancellableRead {
+        inner: file,
+        cancel: opts.cancel,
+    };
+    let (hash, size) = buffer::with_buffer(buffer_size(opts), |buf| match opts.diagnostics {
+        Some(d) => hash_read_timed(file, hasher, buf, d),
+        None => hash_read(file, hasher, buf),
+    })?;
+    let mut digest = hex::encode(hash);
+    if let Some(t) = opts.truncate {
+        digest.truncate(t);
+    }
+    Ok((digest, size))
+}
+
+// adapted from https://rust-lang-nursery.github.io/rust-cookbook/cryptography/hashing.html#calculate-the-sha-256-digest-of-a-file
+pub fn hash_reader<R: Read>(reader: R, hasher: Box<dyn Hasher>) -> io::Result<(Vec<u8>, u64)> {
+    buffer::with_buffer(READ_BUFFER_SIZE, |buf| hash_read(reader, hasher, buf))
+}
+
+/// Hash everything from `reader`, reading into `buf`.
+fn hash_read<R: Read>(
+    mut reader: R,
+    mut hasher: Box<dyn Hasher>,
+    buf: &mut [u8],
+) -> io::Result<(Vec<u8>, u64)> {
+    let mut size = 0;
+    loop {
+        let count = reader.read(buf)?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buf[..count]);
+        size += count as u64;
+    }
+    hasher.check_length()?;
+    Ok((hasher.finalize(), size))
+}
+
+/// As [hash_read], recording time spent reading and hashing.
+fn hash_read_timed<R: Read>(
+    mut reader: R,
+    mut hasher: Box<dyn Hasher>,
+    buf: &mut [u8],
+    diagnostics: &Diagnostics,
+) -> io::Result<(Vec<u8>, u64)> {
+    let mut size = 0;
+    let (mut reading, mut hashing) = (Duration::default(), Duration::default());
+    loop {
+        let started = Instant::now();
+        let count = reader.read(buf)?;
+        let read = Instant::now();
+        reading += read - started;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buf[..count]);
+        hashing += read.elapsed();
+        size += count as u64;
+    }
+    hasher.check_length()?;
+    let started = Instant::now();
+    let hash = hasher.finalize();
+    hashing += started.elapsed();
+    diagnostics.reading(reading);
+    diagnostics.hashing(hashing);
+    Ok((hash, size))
+}
@@ -0,0 +1,276 @@
+//! Remembering what is in each directory between runs, as with recursum --listing-cache,
+//! so that walking a tree on a slow network filesystem only lists directories which have changed.
+//!
+//! Adding, removing or renaming an entry changes its directory's modification time, so a directory whose
+//! modification time matches the cached one still has the cached entries; its subdirectories are still
+//! checked in turn. Listings are only cached once their directory has been unchanged for a few seconds,
+//! in case another change lands within the filesystem's timestamp granularity.
+//!
+//! The file starts with the line `# recursum-listing-cache 1`; each directory is then a line
+//! `D\t<mtime>\t<path>`, followed by a line `d\t<name>` or `f\t<name>` for each subdirectory or file in it.
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::hash_cache::Mtime;
+use crate::walk::{entry_order, CaseCollisionWalker, Order, WalkOptions, Walker};
+
+/// The first line of every listing cache.
+pub const MAGIC: &str = "# recursum-listing-cache 1";
+/// Directories changed more recently than this when listed are not cached.
+const RACY: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone)]
+struct Listing {
+    mtime: Mtime,
+    /// name, and whether it is a directory; other entries (e.g. symlinks) are left out
+    entries: Vec<(OsString, bool)>,
+}
+
+#[derive(Debug, Default)]
+pub struct ListingCache {
+    dirs: Mutex<HashMap<PathBuf, Listing>>,
+    /// directories walked in this run; only they are saved
+    walked: Mutex<HashSet<PathBuf>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ListingCache {
+    pub fn read(r: impl BufRead) -> io::Result<Self> {
+        let invalid = |line: usize, msg: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", line + 1, msg),
+            )
+        };
+        let mut lines = r.lines().enumerate();
+        if lines.next().map(|(_, l)| l).transpose()?.as_deref() != Some(MAGIC) {
+            return Err(invalid(0, "not a recursum listing cache"));
+        }
+        let mut dirs = HashMap::new();
+        let mut current: Option<(PathBuf, Listing)> = None;
+        for (idx, line) in lines {
+            let line = line?;
+            let (kind, rest) = line
+                .split_once('\t')
+                .ok_or_else(|| invalid(idx, "no kind"))?;
+            match kind {
+                "D" => {
+                    if let Some((path, listing)) = current.take() {
+                        dirs.insert(path, listing);
+                    }
+                    let (mtime, path) = rest
+                        .split_once('\t')
+                        .ok_or_else(|| invalid(idx, "no path"))?;
+                    let mtime = mtime.parse().map_err(|e: String| invalid(idx, &e))?;
+                    current = Some((
+                        PathBuf::from(path),
+                        Listing {
+                            mtime,
+                            entries: Vec::new(),
+                        },
+                    ));
+                }
+                "d" | "f" => match &mut current {
+                    Some((_, listing)) => listing.entries.push((rest.into(), kind == "d")),
+                    None => return Err(invalid(idx, "entry before any directory")),
+                },
+                _ => return Err(invalid(idx, "unknown kind")),
+            }
+        }
+        if let Some((path, listing)) = current {
+            dirs.insert(path, listing);
+        }
+        Ok(Self {
+            dirs: Mutex::new(dirs),
+            ..Self::default()
+        })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Write the listings of directories walked in this run.
+    pub fn write(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "{}", MAGIC)?;
+        let dirs = self.dirs.lock().expect("listing cache lock poisoned");
+        let walked = self.walked.lock().expect("listing cache lock poisoned");
+        let mut paths: Vec<_> = walked.iter().filter(|p| dirs.contains_key(*p)).collect();
+        paths.sort();
+        for path in paths {
+            let listing = &dirs[path];
+            writeln!(w, "D\t{}\t{}", listing.mtime, path.to_string_lossy())?;
+            for (name, is_dir) in &listing.entries {
+                let kind = if *is_dir { "d" } else { "f" };
+                writeln!(w, "{}\t{}", kind, name.to_string_lossy())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write to `path` by replacing it, so that it is never left half-written.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let dir = match path.parent() {
+            Some(d) if !d.as_os_str().is_empty() => d,
+            _ => Path::new("."),
+        };
+        let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+        {
+            let mut w = BufWriter::new(tmp.as_file_mut());
+            self.write(&mut w)?;
+            w.flush()?;
+        }
+        tmp.as_file().sync_all()?;
+        tmp.persist(path).map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    /// Directories whose cached listings were used, and directories which were listed, so far.
+    pub fn hits_and_misses(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    /// The subdirectories and files in `dir`, from the cache if it has not changed.
+    fn list(&self, dir: &Path) -> io::Result<Vec<(OsString, bool)>> {
+        let meta = fs::metadata(dir)?;
+        let mtime = Mtime::of(&meta);
+        self.walked
+            .lock()
+            .expect("listing cache lock poisoned")
+            .insert(dir.to_path_buf());
+        if let Some(listing) = self
+            .dirs
+            .lock()
+            .expect("listing cache lock poisoned")
+            .get(dir)
+            .filter(|l| Some(l.mtime) == mtime)
+        {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(listing.entries.clone());
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let listed_at = SystemTime::now();
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() || file_type.is_file() {
+                entries.push((entry.file_name(), file_type.is_dir()));
+            }
+        }
+        let settled = meta
+            .modified()
+            .is_ok_and(|m| listed_at.duration_since(m).is_ok_and(|d| d > RACY));
+        // names which would not survive the line-based format are listed afresh every time
+        let storable = entries.iter().all(|(name, _)| {
+            name.to_str()
+                .is_some_and(|n| !n.contains('\n') && !n.contains('\t'))
+        });
+        let mut dirs = self.dirs.lock().expect("listing cache lock poisoned");
+        match mtime {
+            Some(mtime) if settled && storable => {
+                dirs.insert(
+                    dir.to_path_buf(),
+                    Listing {
+                        mtime,
+                        entries: entries.clone(),
+                    },
+                );
+            }
+            _ => {
+                dirs.remove(dir);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// Serial depth-first walk (or by directory), listing directories through a [ListingCache].
+pub struct CachedListingWalker {
+    cache: &'static ListingCache,
+    options: WalkOptions,
+}
+
+impl CachedListingWalker {
+    /// As [WalkerKind::build](crate::walk::WalkerKind::build); breadth-first order is not supported.
+    pub fn build(
+        cache: &'static ListingCache,
+        options: WalkOptions,
+    ) -> Result<Box<dyn Walker>, String> {
+        if options.order == Some(Order::Breadth) {
+            return Err("--listing-cache does not support breadth-first order".to_string());
+        }
+        let detect_case_collisions = options.detect_case_collisions;
+        let walker: Box<dyn Walker> = Box::new(Self { cache, options });
+        if detect_case_collisions {
+            Ok(Box::new(CaseCollisionWalker { inner: walker }))
+        } else {
+            Ok(walker)
+        }
+    }
+}
+
+impl Walker for CachedListingWalker {
+    fn files(&self, root: &Path) -> Box<dyn Iterator<Item = PathBuf> + Send> {
+        let mut walk = CachedWalk {
+            cache: self.cache,
+            options: self.options.clone(),
+            stack: Vec::new(),
+        };
+        walk.descend(root);
+        Box::new(walk)
+    }
+}
+
+struct CachedWalk {
+    cache: &'static ListingCache,
+    options: WalkOptions,
+    /// entries still to visit in each directory being walked, innermost last
+    stack: Vec<std::vec::IntoIter<(PathBuf, bool)>>,
+}
+
+impl CachedWalk {
+    fn descend(&mut self, dir: &Path) {
+        let mut entries = match self.cache.list(dir) {
+            Ok(e) => e,
+            Err(e) => {
+                eprintln!("warning: could not list {:?}: {}", dir, e);
+                return;
+            }
+        };
+        let (order, sort) = (self.options.order(), self.options.sort);
+        entries.sort_by(|(a, a_dir), (b, b_dir)| entry_order(order, sort, a, *a_dir, b, *b_dir));
+        let children: Vec<_> = entries
+            .into_iter()
+            .map(|(name, is_dir)| (dir.join(name), is_dir))
+            .filter(|(path, is_dir)| !self.options.skip(path, *is_dir))
+            .collect();
+        self.stack.push(children.into_iter());
+    }
+}
+
+impl Iterator for CachedWalk {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<PathBuf> {
+        loop {
+            match self.stack.last_mut()?.next() {
+                Some((path, true)) => self.descend(&path),
+                Some((path, false)) => return Some(path),
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Advisory locks which stop two runs from using the same cache, or hashing the same directory, at once,
+//! for --lock: two runs saving the same cache would each overwrite the other's digests.
+//!
+//! Each lock is a file, which is left in place afterwards; only the lock on it is released,
+//! by the operating system if need be, so a crashed run never leaves anything to clean up.
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often to try again while waiting for a lock.
+const POLL_EVERY: Duration = Duration::from_millis(500);
+
+/// Held until dropped.
+#[derive(Debug)]
+pub struct RunLock {
+    _file: File,
+}
+
+impl RunLock {
+    /// Lock the file at `path`, creating it if need be.
+    /// If another process holds it, wait up to `wait` for it to be released, saying so once.
+    pub fn acquire(path: &Path, wait: Duration) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .map_err(|e| format!("could not open lock file {:?}: {}", path, e))?;
+        let deadline = Instant::now() + wait;
+        let mut waiting = false;
+        loop {
+            match file.try_lock() {
+                Ok(()) => return Ok(Self { _file: file }),
+                Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                    if !waiting {
+                        eprintln!(
+                            "waiting up to {} seconds for another run to release {:?}",
+                            wait.as_secs(),
+                            path
+                        );
+                        waiting = true;
+                    }
+                    thread::sleep(
+                        POLL_EVERY.min(deadline.saturating_duration_since(Instant::now())),
+                    );
+                }
+                Err(TryLockError::WouldBlock) => {
+                    return Err(format!(
+                        "another run holds the lock {:?}; try again once it has finished",
+                        path
+                    ))
+                }
+                Err(TryLockError::Error(e)) => {
+                    return Err(format!("could not lock {:?}: {}", path, e))
+                }
+            }
+        }
+    }
+}
+
+/// The lock file for a cache: beside it, with `.lock` appended to its name.
+pub fn beside(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".lock");
+    PathBuf::from(name)
+}
+
+/// The lock file for hashing the directory `root`, in the temporary directory,
+/// named after a digest of its canonical path (FNV-1a, so that every build agrees).
+pub fn for_root(root: &Path) -> PathBuf {
+    let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+    let digest = root
+        .as_os_str()
+        .to_string_lossy()
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, b| {
+            (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+        });
+    std::env::temp_dir().join(format!("recursum-{:016x}.lock", digest))
+}
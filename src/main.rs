@@ -1,240 +1,593 @@
-use std::collections::VecDeque;
+use std::collections::HashSet;
 use std::ffi::OsString;
-use std::fs::File;
-use std::io::Read;
 use std::path::{Path, PathBuf};
 
-use digest::{Digest, Output};
-use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
-use jwalk::{Parallelism, WalkDir};
-use meowhash::MeowHasher;
-use std::time::Instant;
+use std::io;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use structopt::clap::{AppSettings, ErrorKind};
 use structopt::StructOpt;
 use tokio::io::AsyncBufReadExt;
-use tokio::runtime;
-use tokio::stream::{iter, Stream, StreamExt};
 use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::{iter, Stream, StreamExt};
+
+use recursum::activity::Activity;
+use recursum::cancel::CancellationToken;
+use recursum::devices::DeviceLimits;
+use recursum::extents::{self, SharedExtents};
+use recursum::hash_cache::HashCache;
+use recursum::hashers::{self, BySize, HasherFactory};
+use recursum::listing_cache::{CachedListingWalker, ListingCache};
+use recursum::platform;
+use recursum::sink::{write_results_blocking, write_results_until, OutputSink};
+use recursum::structure::Structure;
+use recursum::tune::AutoTune;
+use recursum::walk::{
+    DirLimits, Order, PriorityList, PriorityWalker, RotatedWalker, WalkOptions, Walker, WalkerKind,
+    DEFAULT_PRUNE,
+};
+use recursum::{
+    hash_path, hash_stream, queue_length, runtime, walk_paths, FileHash, HashOptions,
+    READ_BUFFER_SIZE,
+};
+
+mod aggregate;
+mod bloom;
+mod budget;
+mod cache;
+mod check;
+mod color;
+#[cfg(feature = "parquet")]
+mod columnar;
+#[cfg(feature = "redis")]
+mod content_index;
+mod cron;
+mod dedupe;
+mod diff;
+mod disk_space;
+mod encrypt;
+mod error_log;
+mod estimate;
+mod exec;
+mod header;
+mod hook;
+mod ingest;
+mod known;
+mod lock;
+mod manifest;
+mod mtree;
+mod normalize;
+#[cfg(feature = "oci")]
+mod oci;
+mod output;
+mod pg_copy;
+mod prioritize;
+mod profile;
+mod progress;
+#[cfg(any(feature = "kafka", feature = "nats"))]
+mod publish;
+#[cfg(feature = "redact")]
+mod redact;
+#[cfg(feature = "report")]
+mod report;
+mod resume;
+mod rollup;
+mod rotation;
+mod selftest;
+mod sinks;
+mod skipped;
+mod smart_check;
+mod stats;
+mod strict_paths;
+mod suspicious;
+#[cfg(target_os = "linux")]
+mod systemd;
+#[cfg(feature = "timestamp")]
+mod timestamp;
+#[cfg(feature = "tui")]
+mod tui;
+mod verify_pkg;
+#[cfg(feature = "webhook")]
+mod webhook;
+use aggregate::{Aggregate, Aggregator};
+use budget::Budget;
+use color::Role;
+use disk_space::{directory, low_space, SpaceGuard};
+use error_log::{ErrorLog, ErrorsTo};
+use exec::{ExecFailure, ExecPerFile};
+use header::Header;
+use hook::{Hook, PostHook};
+use known::{KnownFilter, KnownHashes, KnownMode};
+use lock::RunLock;
+use output::{Compression, TextOutput, UNSTABLE_PREFIX};
+use prioritize::{LeastRecentlyVerified, Prioritize};
+use progress::{HumanBytes, HumanDuration, Progress};
+use resume::Resume;
+use rotation::Rotation;
+use sinks::{SinkContext, SinkSpec};
+use skipped::ReportSkipped;
+use strict_paths::StrictPaths;
 
-const READ_BUFFER_SIZE: usize = 8 * 1024; // BufReader default, may want to increase
-const HASH_BUFFER_SIZE: usize = 1024;
 const DEFAULT_SEPARATOR: &str = "\t";
-const COMPATIBLE_SEPARATOR: &str = "  ";
+pub const COMPATIBLE_SEPARATOR: &str = "  ";
+const SFV_SEPARATOR: &str = " ";
+/// SFV files only ever hold CRC32s.
+const SFV_HASHER: &str = "crc32";
+/// Cancelled by Ctrl-C, so that hashing stops without finishing (and so publishing) the output.
+static CANCEL: CancellationToken = CancellationToken::new();
+/// From --run-id, or generated; set at the start of main.
+static RUN_ID: OnceLock<String> = OnceLock::new();
+/// From --max-duration, if given; set at the start of main.
+static BUDGET: OnceLock<Budget> = OnceLock::new();
+/// From --rotate-state, if given; set once the input directory is known.
+static ROTATION: OnceLock<Rotation> = OnceLock::new();
+/// Set once, for --errors-to.
+static ERROR_LOG: OnceLock<ErrorLog> = OnceLock::new();
 
-const BUFFER_PPN: f64 = 3.0;
+/// A new identifier for this run: 16 hex digits, unique with overwhelming probability.
+fn new_run_id() -> String {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    use std::time::SystemTime;
+
+    // RandomState is randomly keyed; mix in the time and process as well
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0),
+    );
+    hasher.write_u32(std::process::id());
+    format!("{:016x}", hasher.finish())
+}
+/// Hashing threads per CPU for --auto-tune to choose up to, if --threads is not given.
+const AUTO_TUNE_THREADS_PER_CPU: usize = 4;
+/// Default read size with --network-fs, so that each round trip fetches a lot.
+const NETWORK_FS_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+/// Default walkers with --network-fs; more only pile up listing requests on the server.
+const NETWORK_FS_WALKERS: usize = 2;
 
-fn queue_length(n_jobs: usize) -> usize {
-    (n_jobs as f64 * BUFFER_PPN).ceil() as usize
+fn canonicalize(path: PathBuf) -> PathBuf {
+    std::fs::canonicalize(&path)
+        .unwrap_or_else(|e| panic!("could not canonicalize {:?}: {}", path, e))
 }
 
-fn stdin_paths() -> mpsc::UnboundedReceiver<PathBuf> {
+fn stdin_paths(canonical: bool) -> UnboundedReceiverStream<PathBuf> {
     let (sender, receiver) = mpsc::unbounded_channel();
     tokio::spawn(async move {
         let stdin = tokio::io::BufReader::new(tokio::io::stdin());
-        let mut line_stream = stdin.lines();
+        let mut lines = stdin.lines();
 
-        while let Some(path_result) = line_stream.next().await {
-            sender.send(PathBuf::from(&path_result.unwrap())).unwrap();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            let mut path = PathBuf::from(&line);
+            if canonical {
+                path = canonicalize(path);
+            }
+            sender.send(path).unwrap();
         }
     });
-    receiver
+    UnboundedReceiverStream::new(receiver)
 }
 
-// adapted from https://stackoverflow.com/a/58825638/2700168
-fn walk_paths(
-    root: PathBuf,
-    queue_len: usize,
-    parallelism: Parallelism,
-) -> mpsc::Receiver<PathBuf> {
-    let (mut sender, receiver) = mpsc::channel(queue_len);
-    tokio::spawn(async move {
-        for entry in WalkDir::new(root)
-            .parallelism(parallelism)
-            .follow_links(false)
-            .sort(true)
-        {
-            let e = entry.unwrap();
-            if e.file_type().is_file() {
-                sender.send(e.path()).await.unwrap();
-            }
-        }
-    });
+/// Warnings listed in --summary-file; any more are only counted.
+const SUMMARY_FILE_WARNINGS: usize = 10;
+
+/// Write the plain-text summary for --summary-file:
+/// `key: value` lines starting with the status and run ID, then the first few warnings.
+fn write_summary_file(
+    path: &Path,
+    status: &str,
+    fields: &[(&str, String)],
+    warnings: &[String],
+) -> io::Result<()> {
+    let mut text = format!("status: {}\n", status);
+    if let Some(id) = RUN_ID.get() {
+        text.push_str(&format!("run-id: {}\n", id));
+    }
+    for (key, value) in fields {
+        text.push_str(&format!("{}: {}\n", key, value));
+    }
+    text.push_str(&format!("warnings: {}\n", warnings.len()));
+    for w in warnings.iter().take(SUMMARY_FILE_WARNINGS) {
+        text.push_str(&format!("  {}\n", w));
+    }
+    if warnings.len() > SUMMARY_FILE_WARNINGS {
+        text.push_str(&format!(
+            "  ... and {} more\n",
+            warnings.len() - SUMMARY_FILE_WARNINGS
+        ));
+    }
+    std::fs::write(path, text)
+}
 
-    receiver
+/// How the totals are printed at the end.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SummaryFormat {
+    /// e.g. `3001 files (111.22KB) hashed in 2 seconds (55.61KB/s)`
+    Human,
+    /// e.g. `files=3001 bytes=113885 seconds=2.000 bytes_per_second=56942 unstable=0`
+    Raw,
+    /// e.g. `{"run_id":"...","files":3001,"bytes":113885,"seconds":2.0,"bytes_per_second":56942,"unstable":0}`
+    Json,
 }
 
-struct ResultOutput {
+impl FromStr for SummaryFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Self::Human),
+            "raw" => Ok(Self::Raw),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "unknown summary format '{}'; expected human, raw or json",
+                s
+            )),
+        }
+    }
+}
+
+/// Totals and warnings, on stderr, and in --summary-file.
+struct Summary {
     started: Instant,
     total_files: u64,
     total_bytes: u64,
-    progress: Option<ProgressBar>,
-    quiet: bool,
-    separator: String,
-    hash_first: bool,
-}
-
-impl ResultOutput {
-    // fn default() -> Self {
-    //     Self {
-    //         started: Instant::now(),
-    //         total_files: 0,
-    //         total_bytes: 0,
-    //         progress: None,
-    //     }
-    // }
-
-    // fn with_progress(pbar: ProgressBar) -> Self {
-    //     Self {
-    //         started: Instant::now(),
-    //         total_files: 0,
-    //         total_bytes: 0,
-    //         progress: Some(pbar),
-    //     }
-    // }
-
-    fn new(separator: &str, hash_first: bool) -> Self {
-        Self {
-            started: Instant::now(),
-            total_files: 0,
-            total_bytes: 0,
-            progress: None,
-            quiet: true,
-            separator: separator.to_string(),
-            hash_first,
-        }
-    }
+    unstable_files: u64,
+    /// how to print the totals, if at all
+    totals: Option<SummaryFormat>,
+    /// to print warnings without clobbering the spinner
+    progress: Option<Progress>,
+    summary_file: Option<PathBuf>,
+    /// kept for --summary-file
+    warnings: Vec<String>,
+}
 
-    fn with_default_progress(sep: &str, hash_first: bool) -> Self {
-        let spinner_style = ProgressStyle::default_spinner()
-            .template("{bytes} | {elapsed} | {bytes_per_sec} | {msg}");
-        let spinner = ProgressBar::new_spinner().with_style(spinner_style);
+impl Summary {
+    fn new(
+        totals: Option<SummaryFormat>,
+        progress: Option<Progress>,
+        summary_file: Option<PathBuf>,
+    ) -> Self {
         Self {
             started: Instant::now(),
             total_files: 0,
             total_bytes: 0,
-            progress: Some(spinner),
-            quiet: false,
-            separator: sep.to_string(),
-            hash_first,
+            unstable_files: 0,
+            totals,
+            progress,
+            summary_file,
+            warnings: Vec::new(),
         }
     }
 
-    fn handle_output(&mut self, path: &Path, hash: &str, size: u64) {
-        let path_as_str = path.as_os_str().to_string_lossy();
-
-        if self.hash_first {
-            println!("{}{}{}", hash, self.separator, path_as_str);
-        } else {
-            println!("{}{}{}", path_as_str, self.separator, hash);
+    fn warn(&mut self, msg: String) {
+        if self.summary_file.is_some() {
+            self.warnings.push(msg.clone());
         }
-
-        if let Some(ref mut p) = self.progress {
-            p.set_message(&format!("{} {:?}", HumanBytes(size), path_as_str));
-            p.inc(size);
+        let msg = color::stderr(Role::Warning, msg).to_string();
+        match &self.progress {
+            Some(p) => p.println(msg),
+            None => eprintln!("{}", msg),
         }
+    }
+}
 
-        if !self.quiet {
-            self.total_files += 1;
-            self.total_bytes += size;
+impl OutputSink for Summary {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        self.total_files += 1;
+        self.total_bytes += result.size;
+        if !result.stable {
+            self.unstable_files += 1;
+            self.warn(format!(
+                "warning: file changed while being hashed: {:?}",
+                result.path
+            ));
         }
+        Ok(())
     }
 
-    fn finish(&mut self) {
-        if let Some(ref mut p) = self.progress {
-            p.finish_and_clear();
+    fn on_finish(&mut self) -> io::Result<()> {
+        let elapsed = Instant::now().duration_since(self.started);
+        let rate = (self.total_bytes as f64 / elapsed.as_secs_f64()).floor() as u64;
+        if let Some(path) = &self.summary_file {
+            let mut fields = vec![
+                ("files", self.total_files.to_string()),
+                ("bytes", self.total_bytes.to_string()),
+                ("duration", HumanDuration(elapsed).to_string()),
+                ("rate", format!("{}/s", HumanBytes(rate))),
+            ];
+            let errors = ERROR_LOG.get().map(ErrorLog::count);
+            if let Some(n) = errors {
+                fields.push(("errors", n.to_string()));
+            }
+            let status = match BUDGET.get() {
+                Some(b) if b.exhausted() => {
+                    fields.push(("remaining", b.remaining().to_string()));
+                    "out-of-time"
+                }
+                _ if errors.is_some_and(|n| n > 0) => "errors",
+                _ => "ok",
+            };
+            write_summary_file(path, status, &fields, &self.warnings).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("could not write summary file {:?}: {}", path, e),
+                )
+            })?;
         }
-        if !self.quiet {
-            let elapsed = Instant::now().duration_since(self.started);
-            let rate = (self.total_bytes as f64 / elapsed.as_secs_f64()).floor() as u64;
-            eprintln!(
+        match self.totals {
+            Some(SummaryFormat::Human) => eprintln!(
                 "{} files ({}) hashed in {} ({}/s)",
-                self.total_files,
-                HumanBytes(self.total_bytes),
+                color::stderr(Role::Highlight, self.total_files),
+                color::stderr(Role::Highlight, HumanBytes(self.total_bytes)),
                 HumanDuration(elapsed),
-                HumanBytes(rate),
+                color::stderr(Role::Highlight, HumanBytes(rate)),
+            ),
+            Some(SummaryFormat::Raw) => eprintln!(
+                "files={} bytes={} seconds={:.3} bytes_per_second={} unstable={}",
+                self.total_files,
+                self.total_bytes,
+                elapsed.as_secs_f64(),
+                rate,
+                self.unstable_files
+            ),
+            Some(SummaryFormat::Json) => eprintln!(
+                r#"{{"run_id":{},"files":{},"bytes":{},"seconds":{:.3},"bytes_per_second":{},"unstable":{}}}"#,
+                sinks::json_string(RUN_ID.get().map_or("", String::as_str)),
+                self.total_files,
+                self.total_bytes,
+                elapsed.as_secs_f64(),
+                rate,
+                self.unstable_files
+            ),
+            None => (),
+        }
+        if self.unstable_files > 0 && self.totals != Some(SummaryFormat::Json) {
+            let msg = format!(
+                "{} files changed while being hashed; their digests are marked with '{}'",
+                self.unstable_files, UNSTABLE_PREFIX
             );
+            eprintln!("{}", color::stderr(Role::Warning, msg));
         }
+        Ok(())
     }
 }
 
-async fn hash_from_stream<S: Stream<Item = PathBuf> + Unpin>(
-    mut path_stream: S,
-    truncate_to: Option<usize>,
-    n_jobs: usize,
-    quiet: bool,
-    separator: &str,
-    hash_first: bool,
-) {
-    let mut output;
-    if quiet {
-        output = ResultOutput::new(separator, hash_first);
+/// Everything which happens to each result, in order:
+/// the text output comes first so that it is complete before anything is reported.
+/// `reports` are printed after the totals.
+fn output_sinks(
+    main: Box<dyn OutputSink>,
+    progress: bool,
+    totals: Option<SummaryFormat>,
+    shared_extents: bool,
+    summary_file: Option<PathBuf>,
+    reports: Vec<Box<dyn OutputSink>>,
+    extra: Vec<Box<dyn OutputSink>>,
+) -> Vec<Box<dyn OutputSink>> {
+    let progress = if progress {
+        Some(Progress::new())
     } else {
-        output = ResultOutput::with_default_progress(separator, hash_first);
+        None
+    };
+    let mut sinks: Vec<Box<dyn OutputSink>> = vec![main];
+    sinks.extend(extra);
+    if let Some(p) = &progress {
+        sinks.push(Box::new(p.clone()));
+    }
+    sinks.push(Box::new(Summary::new(totals, progress, summary_file)));
+    sinks.extend(reports);
+    if shared_extents {
+        sinks.push(Box::new(SharedExtents::default()));
     }
+    sinks
+}
+
+/// How recursum was invoked, for the header of an appended run.
+fn command_line() -> String {
+    std::env::args_os()
+        .map(|a| a.to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
 
-    let mut fut_queue = VecDeque::with_capacity(n_jobs);
-    let mut is_finished = false;
+/// Report paths under the first directory as if they were under the second.
+type Rewrite = Option<(PathBuf, PathBuf)>;
 
-    let queue_len = queue_length(n_jobs);
+/// Files to leave out because an earlier run already hashed them.
+type Skip = Option<&'static Resume>;
 
-    // make sure there are n_jobs running before looking at results
-    for _ in 0..queue_len {
-        if let Some(path) = path_stream.next().await {
-            // todo: factor out
-            fut_queue.push_back(tokio::spawn(async move {
-                let (hash, size) = hash_file(path.as_path(), MeowHasher::new(), truncate_to);
-                (path, hash, size)
-            }));
-        } else {
-            // there were fewer than n_jobs to begin with
-            is_finished = true;
-            break;
-        }
+fn rewrite_path(path: PathBuf, rewrite: &Rewrite) -> PathBuf {
+    match rewrite {
+        Some((from, to)) => match path.strip_prefix(from) {
+            Ok(rel) => to.join(rel),
+            Err(_) => path,
+        },
+        None => path,
     }
+}
 
-    if !is_finished {
-        // pop the first job off the queue when completed, spawn another and append to queue
-        while let Some(path) = path_stream.next().await {
-            let result = fut_queue.pop_front().unwrap().await.unwrap();
-            output.handle_output(result.0.as_path(), result.1.as_str(), result.2 as u64);
-            fut_queue.push_back(tokio::spawn(async move {
-                let (hash, size) = hash_file(path.as_path(), MeowHasher::new(), truncate_to);
-                (path, hash, size)
-            }));
+/// Names of registered hashers, and auto, for --hash's help.
+fn hasher_names() -> &'static [&'static str] {
+    static NAMES: OnceLock<Vec<&'static str>> = OnceLock::new();
+    NAMES.get_or_init(|| {
+        let mut names = vec![hashers::AUTO];
+        names.extend(
+            hashers::names()
+                .into_iter()
+                .map(|n| &*Box::leak(n.into_boxed_str())),
+        );
+        names
+    })
+}
+
+/// --hash's help, listing the registered hashers; not as possible values, which would refuse auto-size: classes.
+fn hash_help() -> &'static str {
+    static HELP: OnceLock<String> = OnceLock::new();
+    HELP.get_or_init(|| {
+        format!(
+            "Hashing algorithm: {}, or auto-size:CLASSES. auto picks the fastest one which this CPU supports, warning if it had to pass over any. auto-size:CLASSES picks one by each file's size, e.g. auto-size:crc32<64K,meow>=64K hashes files under 64 KiB with crc32, as cheaper hashers win on tiny files, and the rest with meow (a class NAME<SIZE for files smaller than SIZE not in an earlier class, and a last class NAME>=SIZE or NAME for the rest); each digest is then prefixed with its algorithm and a colon",
+            hasher_names().join(", ")
+        )
+    })
+}
+
+/// Resolve --hash to the name of a registered algorithm, refusing those this machine cannot run.
+fn select_hasher(name: &str) -> &str {
+    if name == hashers::AUTO {
+        let (name, passed_over) = hashers::auto().unwrap_or_else(|e| panic!("{}", e));
+        for reason in passed_over {
+            let msg = format!("warning: {}; using {}", reason, name);
+            eprintln!("{}", color::stderr(Role::Warning, msg));
         }
+        return name;
     }
-
-    for fut in fut_queue.into_iter() {
-        let result = fut.await.unwrap();
-        output.handle_output(result.0.as_path(), result.1.as_str(), result.2 as u64);
+    if name.starts_with(hashers::BY_SIZE_PREFIX) {
+        let by_size = parse_by_size(name).unwrap_or_else(|e| panic!("--hash {}: {}", name, e));
+        for name in by_size.names() {
+            if let Some(reason) = hashers::unsupported(name) {
+                panic!("{}", reason);
+            }
+        }
+        return name;
+    }
+    if hashers::get(name).is_none() {
+        panic!(
+            "unknown hashing algorithm '{}'; choose from {}",
+            name,
+            hasher_names().join(", ")
+        );
+    }
+    if let Some(reason) = hashers::unsupported(name) {
+        panic!("{}", reason);
     }
-    output.finish();
+    name
+}
+
+/// Parse an `auto-size:` --hash, e.g. `auto-size:crc32<64K,meow>=64K`: classes separated by commas,
+/// each `NAME<SIZE` for files smaller than SIZE (and not in an earlier class),
+/// then for the rest `NAME>=SIZE` with the last SIZE again, or just `NAME`.
+fn parse_by_size(name: &str) -> Result<BySize, String> {
+    let spec = name
+        .strip_prefix(hashers::BY_SIZE_PREFIX)
+        .ok_or_else(|| format!("expected {}", hashers::BY_SIZE_PREFIX))?;
+    let leak = |s: &str| -> &'static str { Box::leak(s.to_string().into_boxed_str()) };
+    let classes: Vec<&str> = spec.split(',').collect();
+    let (rest, below) = classes.split_last().expect("split yields at least one");
+    let below = below
+        .iter()
+        .map(|class| match class.split_once('<') {
+            Some((name, size)) => Ok((parse_size(size)?, leak(name))),
+            None => Err(format!(
+                "'{}' should be NAME<SIZE; only the last class is for the rest",
+                class
+            )),
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let largest_below = match below.last() {
+        Some((size, _)) => *size,
+        None => return Err("give at least two classes, e.g. crc32<64K,meow>=64K".to_string()),
+    };
+    let rest = match rest.split_once(">=") {
+        Some((name, size)) if parse_size(size)? == largest_below => name,
+        Some(_) => {
+            return Err(
+                "the last class must start at the size where the one before ends".to_string(),
+            )
+        }
+        None if rest.contains('<') => {
+            return Err("the last class is for the rest: NAME>=SIZE or NAME".to_string())
+        }
+        None => rest,
+    };
+    BySize::new(below, leak(rest))
 }
 
-fn hash_file<D: Digest>(fpath: &Path, hasher: D, truncate: Option<usize>) -> (String, usize) {
-    let file = File::open(fpath).unwrap();
-    let (hash, size) = hash_reader(file, hasher);
-    let mut digest = hex::encode(hash);
-    if let Some(t) = truncate {
-        digest.truncate(t);
+/// The algorithm named by [select_hasher], and for `auto-size:` names, the algorithm for each size
+/// (the one for the largest files standing in where a single algorithm is needed).
+fn hasher_for(name: &str) -> (HasherFactory, Option<&'static BySize>) {
+    if name.starts_with(hashers::BY_SIZE_PREFIX) {
+        let by_size = parse_by_size(name).unwrap_or_else(|e| panic!("--hash {}: {}", name, e));
+        let by_size: &'static BySize = Box::leak(Box::new(by_size));
+        return (by_size.largest(), Some(by_size));
     }
-    (digest, size)
+    let factory = hashers::get(name).expect("hasher names are checked by select_hasher");
+    (factory, None)
 }
 
-// adapted from https://rust-lang-nursery.github.io/rust-cookbook/cryptography/hashing.html#calculate-the-sha-256-digest-of-a-file
-fn hash_reader<R: Read, D: Digest>(reader: R, mut hasher: D) -> (Output<D>, usize) {
-    let mut buf_reader = std::io::BufReader::with_capacity(READ_BUFFER_SIZE, reader);
-    let mut size = 0;
+/// Parse --seed, and find the seeded version of the algorithm.
+fn seeded_hasher(name: &str, seed: &str) -> (hashers::SeededHasherFactory, &'static [u8]) {
+    let factory = hashers::get_seeded(name)
+        .unwrap_or_else(|| panic!("the {} hasher does not take a seed", name));
+    let seed = hex::decode(seed).unwrap_or_else(|e| panic!("--seed is not valid hex: {}", e));
+    if seed.is_empty() {
+        panic!("--seed must not be empty");
+    }
+    (factory, Box::leak(seed.into_boxed_slice()))
+}
 
-    let mut buf = [0; HASH_BUFFER_SIZE];
-    loop {
-        let count = buf_reader.read(&mut buf).expect("could not read file");
-        if count == 0 {
-            break;
+/// A number of bytes, optionally with a binary unit: K, M, G or T (also as KiB, or KB, etc.).
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", s))?;
+    let shift = match unit.trim_end_matches("iB").trim_end_matches('B') {
+        "" => 0,
+        "K" | "k" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => {
+            return Err(format!(
+                "unknown unit in size '{}'; expected K, M, G or T",
+                s
+            ))
         }
-        hasher.update(&buf[..count]);
-        size += count;
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size '{}' is too large", s))
+}
+
+/// A duration, as numbers with units: s, m, h or d, e.g. 4h or 1h30m. A number alone is in seconds.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration '{}'; expected e.g. 4h, 90m or 1h30m", s);
+    let mut rest = s.trim();
+    let mut total: u64 = 0;
+    if rest.is_empty() {
+        return Err(invalid());
     }
-    (hasher.finalize(), size)
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (number, tail) = rest.split_at(split);
+        let number: u64 = number.parse().map_err(|_| invalid())?;
+        let unit_len = tail
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_len);
+        let seconds = match unit {
+            "" | "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        total = number
+            .checked_mul(seconds)
+            .and_then(|n| total.checked_add(n))
+            .ok_or_else(invalid)?;
+        rest = tail;
+    }
+    Ok(Duration::from_secs(total))
 }
 
 fn or_num_cpus(opt: Option<usize>) -> usize {
@@ -242,120 +595,1133 @@ fn or_num_cpus(opt: Option<usize>) -> usize {
 }
 
 #[derive(Debug, StructOpt)]
-#[structopt(name = "recursum", about = "Hash lots of files fast, in parallel.")]
+#[structopt(
+    name = "recursum",
+    about = "Hash lots of files fast, in parallel.",
+    setting = AppSettings::SubcommandsNegateReqs
+)]
 struct Opt {
-    /// One or more file names, one directory name (every file recursively will be hashed, in depth first order), or '-' for getting list of files from stdin (order is conserved).
+    #[structopt(subcommand)]
+    command: Option<Command>,
+    /// One or more file names, one directory name (every file recursively will be hashed, in depth first order), or '-' for getting list of files from stdin (order is conserved). Give a path named like a subcommand as ./NAME, e.g. ./check, or after --.
     #[structopt(required = true)]
     input: Vec<OsString>,
     /// Directory-walking threads, if <input> is a directory.
     #[structopt(short = "w", long = "walkers")]
     walkers: Option<usize>,
+    /// Directory-walking backend, if <input> is a directory: jwalk (parallel, sorted), ignore (parallel, unordered), walkdir (serial, sorted), or stream (serial, unordered, depth first only; reads directories as it goes rather than holding all their entries, so memory does not grow with the size of a directory). Defaults to jwalk, or walkdir for breadth-first order or if built without parallel walkers.
+    #[structopt(long = "walker")]
+    walker: Option<WalkerKind>,
+    /// Order in which to hash files, if <input> is a directory: depth (depth first, default), breadth (breadth first; walkdir only), or by-directory (depth first, but all files in a directory before its subdirectories). Not supported by the ignore walker.
+    #[structopt(long = "order")]
+    order: Option<Order>,
+    /// Do not sort directory entries, if <input> is a directory. Faster, but the order of output may differ between runs and platforms. By default, depth-first output is sorted byte-wise by path.
+    #[structopt(long = "no-sort")]
+    no_sort: bool,
+    /// Skip hidden files and directories, if <input> is a directory. Hidden files are those whose names start with ".", and on Windows and macOS, those with the hidden attribute/flag.
+    #[structopt(long = "skip-hidden")]
+    skip_hidden: bool,
+    /// Skip directories with this name (and everything in them), if <input> is a directory. Can be given multiple times.
+    #[structopt(long = "prune", number_of_values = 1, parse(from_os_str))]
+    prune: Vec<OsString>,
+    /// Skip common version control, dependency, build and cache directories, if <input> is a directory: .git, .hg, .svn, .bzr, CVS, node_modules, __pycache__, .pytest_cache, .mypy_cache, .tox, .venv, target, .cache.
+    #[structopt(long = "prune-defaults")]
+    prune_defaults: bool,
+    /// Skip directories (and everything in them) holding more than this many bytes of files at any depth, if <input> is a directory, e.g. 10G. Units are K, M, G and T, in powers of 1024. Each directory is counted (stopping once over the limit) before it is walked, and those skipped are listed on stderr. Every directory containing a skipped one is larger still, so the limit must be above the largest directory to keep; <input> itself is never skipped.
+    #[structopt(long = "skip-dirs-larger-than", parse(try_from_str = parse_size))]
+    skip_dirs_larger_than: Option<u64>,
+    /// Skip directories (and everything in them) holding more than this many files at any depth, if <input> is a directory, as --skip-dirs-larger-than. For e.g. caches of millions of small files in a home directory.
+    #[structopt(long = "skip-dirs-with-more-than")]
+    skip_dirs_with_more_than: Option<u64>,
+    /// Hash the files and directories listed in this file (one per line, relative to <input> if it is a directory) first, in the order listed, then everything else in the usual order. For verifying the most important data early in a long run. Not supported for stdin.
+    #[structopt(long = "priority-list", parse(from_os_str))]
+    priority_list: Option<PathBuf>,
+    /// Warn on stderr about files whose paths differ only by case, if <input> is a directory. These would clash when copied to a case-insensitive file system. Every path is kept in memory.
+    #[structopt(long = "detect-case-collisions")]
+    detect_case_collisions: bool,
+    /// Resolve symlinks and relative components so that every path is absolute. If <input> is a directory, the directory itself is resolved; otherwise, each file path is.
+    #[structopt(long = "canonicalize")]
+    canonicalize: bool,
+    /// Check each file's size and modification time before and after hashing. Files which changed have their digest prefixed with "!", and a warning is printed.
+    #[structopt(long = "detect-races")]
+    detect_races: bool,
+    /// Report groups of files which share physical extents on disk (e.g. reflink copies on btrfs or XFS) on stderr. Linux only.
+    #[structopt(long = "detect-shared-extents")]
+    detect_shared_extents: bool,
+    /// Lower the I/O priority of hashing to idle (like `ionice -c 3`), so that it only uses the disk when nothing else needs it. Linux only; if unavailable, a warning is printed and hashing continues at normal priority.
+    #[structopt(long = "idle-io")]
+    idle_io: bool,
     /// Hashing threads.
     #[structopt(short = "t", long = "threads")]
     threads: Option<usize>,
+    /// Defaults for --threads, --buffer-size, --fadvise and --order suited to the storage being hashed, each of which can still be given to override it: nvme (4 threads per CPU, 256 KiB buffers), hdd (1 thread, 1 MiB buffers, by-directory order unless --walker is given), network (8 threads per CPU and at least 32, 1 MiB buffers, no read-ahead advice) or laptop (2 threads, 128 KiB buffers, and --idle-io).
+    #[structopt(long = "profile")]
+    profile: Option<profile::Profile>,
+    /// Bytes to read from each file at a time. Defaults to 8 KiB (or as chosen by --profile); ignored with --auto-tune.
+    #[structopt(long = "buffer-size")]
+    buffer_size: Option<usize>,
+    /// Read-ahead advice to give the kernel for each file: sequential (read ahead aggressively; the default, except with --profile network) or none. Linux only.
+    #[structopt(long = "fadvise")]
+    fadvise: Option<profile::Advice>,
+    /// Keep metadata requests down, for high-latency network filesystems (NFS, SMB) where many small requests swamp the server: stat each file only once, right after its directory is listed, and use that for everything which needs its size or modification time; read 4 MiB at a time; and walk with 2 threads. --buffer-size and --walkers still override these, and it can be combined with --profile network.
+    #[structopt(long = "network-fs")]
+    network_fs: bool,
+    /// Open up to K files ahead of the ones being hashed, and ask the kernel to start reading each (Linux only), so that opening and first reading them overlaps with hashing. Hides latency on network filesystems and other high-latency storage, especially with few --threads. 0 disables it. Needs --engine tokio.
+    #[structopt(long = "prefetch", value_name = "K", default_value = "0")]
+    prefetch: usize,
+    /// Start with 2 concurrent jobs and a 64 KiB read buffer, and adjust both while hashing to maximise throughput, keeping the best settings found once --auto-tune-for has passed. Jobs are limited by --threads, which defaults to 4 per CPU with this option. The settings chosen are printed at the end.
+    #[structopt(long = "auto-tune")]
+    auto_tune: bool,
+    /// Read at most N files at once from the device holding PATH (a mounted block device like /dev/sdb1, or any path on its filesystem), given as PATH=N; or "auto" to read one file at a time from each spinning disk, detected by the kernel's rotational flag (Linux only). Can be given multiple times. Needs --engine tokio.
+    #[structopt(long = "per-device-concurrency", number_of_values = 1)]
+    per_device_concurrency: Vec<String>,
+    /// Pin hashing threads to NUMA nodes in turn, so that each reads into memory local to its node. Helps on multi-socket servers with many --threads. Linux only; if unavailable, a warning is printed and threads are not pinned.
+    #[structopt(long = "numa")]
+    numa: bool,
+    /// What drives hashing: tokio (an async runtime, default) or rayon (a rayon thread pool and channels, with no async runtime; only if built with the rayon feature). --auto-tune and --small-file-batch need tokio.
+    #[structopt(long = "engine", default_value = "tokio")]
+    engine: Engine,
+    /// Seconds to tune for with --auto-tune.
+    #[structopt(long = "auto-tune-for", default_value = "10")]
+    auto_tune_for: u64,
+    /// Hash files which fit in one read buffer (--buffer-size, or as chosen by --auto-tune) up to this many at a time in a single job, rather than one job each, which is much faster for trees of many tiny files. Each file is stat'd before hashing to find its size. 0 or 1 hashes every file in its own job.
+    #[structopt(long = "small-file-batch", default_value = "32")]
+    small_file_batch: usize,
+    #[structopt(long = "hash", default_value = hashers::AUTO, help = hash_help())]
+    hash: String,
+    /// Seed for the hashing algorithm, as hex of any length, so that different deployments produce unrelated digests for the same files. The same seed is needed to reproduce the digests. Only supported by meow.
+    #[structopt(long = "seed")]
+    seed: Option<String>,
     /// Maximum length of output hash digests.
     #[structopt(short = "d", long = "digest-length")]
     digest_length: Option<usize>,
     /// Do not show progress information.
     #[structopt(short = "q", long = "quiet")]
     quiet: bool,
+    /// How to print the totals at the end on stderr: human (e.g. "3001 files (111.22KB) hashed in 2 seconds (55.61KB/s)", the default), raw (exact numbers, as `files=3001 bytes=113885 seconds=2.000 bytes_per_second=56942 unstable=0`) or json (the same, and the run ID, as a JSON object). Printed even with --quiet, if given.
+    #[structopt(long = "summary-format")]
+    summary_format: Option<SummaryFormat>,
+    /// Instead of the progress spinner, show a live dashboard on the terminal: totals, a graph of throughput, the file each hashing thread is reading, and the latest results and messages. Anything printed on stderr meanwhile is shown in it, and printed once it closes. Needs stderr to be a terminal.
+    #[cfg(feature = "tui")]
+    #[structopt(long = "tui", conflicts_with = "quiet")]
+    tui: bool,
     /// Separator. Defaults to tab unless --compatible is given. Use "\t" for tab and "\0" for null (cannot be mixed with other characters).
     #[structopt(short = "s", long = "separator")]
     separator: Option<String>,
     /// "Compatible mode", which prints the hash first and changes the default separator to double-space, as used by system utilities like md5sum.
     #[structopt(short = "c", long = "compatible")]
     compatible: bool,
+    /// Output format: text (a path and digest per line, as set by --separator and --compatible) or mtree (an mtree(8) spec giving each file's type, size, mode and digest, with paths relative to <input> if it is a directory), sfv (path, a space and the upper-case CRC32, as read by SFV tools; implies --hash crc32), pg-copy (PostgreSQL's COPY text format, with columns path, digest, size and stable, after a line naming them), or parquet (a row per file with its path, digest, size, mtime, algorithm, whether it was stable and the run ID; needs --output, and the parquet feature). The mtree digest keyword is e.g. sha256digest for --hash sha256.
+    #[structopt(long = "format", default_value = "text")]
+    format: OutputFormat,
+    /// Flush output after every N records. By default, output is flushed only when the buffer fills.
+    #[structopt(short = "f", long = "flush-every", conflicts_with = "line-buffered")]
+    flush_every: Option<usize>,
+    /// Flush output after every record (equivalent to --flush-every 1).
+    #[structopt(short = "l", long = "line-buffered")]
+    line_buffered: bool,
+    /// Write output to this file rather than stdout. The file only appears once hashing has finished. Compressed if the name ends in .gz or .zst, unless --compress is given.
+    #[structopt(short = "o", long = "output", conflicts_with = "split-output-by-dir")]
+    output: Option<PathBuf>,
+    /// Reuse digests from this cache for files whose size and modification time have not changed since they were cached, instead of reading them, then save the digests of every file hashed to it. Created if it does not exist. Paths are cached relative to <input> if it is a directory, so that a cache can be shared between identical copies of a dataset with `recursum cache export` and `import`. Only use it with the algorithm, --seed and --digest-length it was made with.
+    #[structopt(long = "cache", parse(from_os_str))]
+    cache: Option<PathBuf>,
+    /// Hash this percentage of files found unchanged in the --cache anyway, picked at random, and if any digest differs from the cached one (i.e. the file was corrupted or tampered with without changing its size or modification time), list them on stderr when finished and exit with status 1. The new digests are output and cached.
+    #[structopt(long = "cache-verify-percent", requires = "cache")]
+    cache_verify_percent: Option<f64>,
+    /// Which files to hash first, if <input> is a directory: least-recently-verified takes first the files which the --cache records as read longest ago, after any it has no record of reading (e.g. new files), and reads every file even if the cache holds its digest, checking it as --cache-verify-percent 100 would. With --max-duration, each run then verifies the files which have gone longest without it, rather than the same ones every time. The whole directory is walked before hashing starts.
+    #[structopt(
+        long = "prioritize",
+        requires = "cache",
+        conflicts_with_all = &["cache-verify-percent", "rotate-state", "priority-list"]
+    )]
+    prioritize: Option<Prioritize>,
+    /// Hash each file's path (relative to <input> if it is a directory), size and modification time instead of its contents, and print a digest of them all on stderr when finished. Much faster than reading every file, for finding out whether anything has changed at all; if the digest is the same as last time, nothing was added, removed, resized or modified (barring modification times being reset). The digests are not of the files' contents, so do not mix them with, or check them against, ordinary manifests.
+    #[structopt(long = "structure-only", conflicts_with = "cache")]
+    structure_only: bool,
+    /// Remember the contents of each directory walked in this file, and on later runs, use them for directories whose modification time has not changed instead of listing them again; e.g. for network filesystems, where listing is slow. Created if it does not exist. Walks serially, so cannot be used with --walker, nor breadth-first --order. Independent of --cache, which skips reading unchanged files.
+    #[structopt(long = "listing-cache", parse(from_os_str), conflicts_with = "walker")]
+    listing_cache: Option<PathBuf>,
+    /// Check the free space where --output, --split-output-by-dir and file --sink outputs are written every second, and once it falls below this (e.g. 500M; units are K, M, G and T, in powers of 1024), stop cleanly, closing each output with the files hashed so far rather than failing part of the way through writing one, and exit with an error. --cache and --listing-cache are not saved if there is less than this free, leaving any earlier cache as it was. Linux only.
+    #[structopt(long = "min-free-space", parse(try_from_str = parse_size))]
+    min_free_space: Option<u64>,
+    /// Seconds to pause for when --min-free-space is reached, in case space is freed, before stopping.
+    #[structopt(long = "low-space-wait", default_value = "0")]
+    low_space_wait: u64,
+    /// Stop starting new files once most of this time (e.g. 4h, 90m or 1h30m; units are s, m, h and d) has passed since starting, keeping back a twentieth of it (up to 10 minutes) to finish the files being hashed and close the outputs, which then hold every file hashed so far. The paths not hashed are listed in --checkpoint, and the exit status is 3.
+    #[structopt(long = "max-duration", parse(try_from_str = parse_duration), requires = "checkpoint")]
+    max_duration: Option<Duration>,
+    /// File to list the paths which were not hashed in, one per line, when --max-duration runs out, so that a later run can hash them with `recursum - < FILE`. Replaced at the end of every run, even one which hashed every file, when it is left empty; so a run may read the paths from this file and write the next checkpoint to it.
+    #[structopt(long = "checkpoint", parse(from_os_str), requires = "max-duration")]
+    checkpoint: Option<PathBuf>,
+    /// Hash <input> (a directory) a slice at a time over successive runs, recording in this file where each run stopped: the next run starts just after the last file hashed, and carries on from the beginning once it reaches the end, so that runs limited by --max-duration together cover the whole tree, over and over. Created if it does not exist; only saved if the run succeeds. Needs the default sorted, depth-first order.
+    #[structopt(long = "rotate-state", parse(from_os_str))]
+    rotate_state: Option<PathBuf>,
+    /// Hold an advisory lock on <input> (if a directory) while running, so that a second run over the same directory with --lock refuses to start, or waits (see --lock-wait). Runs with --cache, --listing-cache or --rotate-state always lock those files (as FILE.lock, beside them) unless --no-lock is given, as two runs saving the same cache would overwrite each other's digests.
+    #[structopt(long = "lock", conflicts_with = "no-lock")]
+    lock: bool,
+    /// Do not lock the --cache, --listing-cache and --rotate-state files.
+    #[structopt(long = "no-lock")]
+    no_lock: bool,
+    /// Seconds to wait for another run to release a lock before giving up, so that runs queue up rather than fail.
+    #[structopt(long = "lock-wait", default_value = "0")]
+    lock_wait: u64,
+    /// Skip files already listed in this output of an earlier, interrupted run, and hash only the rest. If --output is the same file, the new results are appended to it (after removing any incomplete last line); otherwise, only the new results are written, e.g. to append with `>>`.
+    #[structopt(
+        long = "resume-from",
+        parse(from_os_str),
+        conflicts_with = "split-output-by-dir"
+    )]
+    resume_from: Option<PathBuf>,
+    /// Append to the --output file rather than replacing it, starting with a header (as --header, plus the command line) which marks the start of this run. For accumulating periodic runs in one log; check and diff-manifests read the last run unless given --run (or --old-run and --new-run).
+    #[structopt(long = "append", requires = "output")]
+    append: bool,
+    /// Start the output with commented lines recording the recursum version, algorithm, seed, separator, line format, digest length, root directory, run ID and creation time, so that it can be checked without knowing how it was made. With --split-output-by-dir, every manifest gets a header.
+    #[structopt(long = "header")]
+    header: bool,
+    /// Compression for --output or --split-output-by-dir files: zstd, gzip, or none.
+    #[structopt(long = "compress")]
+    compress: Option<Compression>,
+    /// Write one manifest per top-level subdirectory of <input> into this directory, named after the subdirectory, instead of printing to stdout. Paths are relative to the subdirectory. Files directly under <input> go into _root.txt. Only valid if <input> is a directory.
+    #[structopt(long = "split-output-by-dir")]
+    split_output_by_dir: Option<PathBuf>,
+    /// Time how long hashing jobs spend queued, reading and hashing, and how long the hashing loop waits for the walker, the oldest job and the output, and print a breakdown on stderr when finished. For tuning --threads and --walkers. Makes hashing slightly slower.
+    #[structopt(long = "diagnostics")]
+    diagnostics: bool,
+    /// When finished, print a histogram of file sizes and percentiles of the time taken to hash each file on stderr, e.g. to spot slow storage.
+    #[structopt(long = "stats")]
+    stats: bool,
+    /// When finished, print the number of files, bytes and time spent hashing on stderr for each file extension (ext) or directory (dir, or dir:<depth> to group deeper than the input directory's immediate subdirectories), largest first.
+    #[structopt(long = "aggregate")]
+    aggregate: Option<Aggregate>,
+    /// When finished, list this many of the largest files hashed, with their sizes and digests, on stderr.
+    #[structopt(long = "report-largest")]
+    report_largest: Option<usize>,
+    /// When finished, warn on stderr about empty files, e.g. left by a truncated copy or restore.
+    #[structopt(long = "flag-empty")]
+    flag_empty: bool,
+    /// When finished, warn on stderr about files with the same digest as another file of a different size, which cannot both be right (unless the digests are shortened with --digest-length). Keeps every digest in memory.
+    #[structopt(long = "flag-same-hash-different-size")]
+    flag_same_hash_different_size: bool,
+    /// File of known digests, e.g. a list with one per line, a manifest or an NSRL-style CSV: every field which is entirely hex digits, or an algorithm name, a colon and hex digits (as written with auto-size: hashing), is read as a digest. Files with these digests are left out of the output (and sinks), or with --known-hashes-mode include, only they are output. The number of matches is printed when finished.
+    #[structopt(long = "known-hashes", parse(from_os_str))]
+    known_hashes: Option<PathBuf>,
+    /// As --known-hashes, but for lists too large to hold in memory: the digests are loaded into a bloom filter, so a few files with unknown digests (see --known-bloom-fp-rate) are treated as known. When finished, the list is read again to confirm the matches, and those files are listed.
+    #[structopt(
+        long = "known-bloom",
+        parse(from_os_str),
+        conflicts_with = "known-hashes"
+    )]
+    known_bloom: Option<PathBuf>,
+    /// Fraction of files with unknown digests which --known-bloom treats as known. Smaller rates need more memory: about 1.8 bytes per digest for 0.001.
+    #[structopt(long = "known-bloom-fp-rate", default_value = "0.001")]
+    known_bloom_fp_rate: f64,
+    /// What to do with files in --known-hashes or --known-bloom: exclude them, or include only them.
+    #[structopt(long = "known-hashes-mode", default_value = "exclude")]
+    known_hashes_mode: KnownMode,
+    /// Also write a line for each file left out on purpose, so that every file is accounted for: `# skipped`, the reason and the path, separated as the digests are. The reasons are hidden (--skip-hidden), pruned (--prune), too-large (--skip-dirs-larger-than, --skip-dirs-with-more-than), symlink, special (devices, FIFOs and sockets), unreadable (directories which could not be listed), known or unknown (--known-hashes-mode), and error, with the details, for files which could not be hashed (with --errors-to). A directory skipped whole is listed instead of everything in it. `check` ignores these lines. Only for --format text.
+    #[structopt(long = "report-skipped", conflicts_with = "listing-cache")]
+    report_skipped: bool,
+    /// Also write a short plain-text summary to this file when finished: status (ok, errors, out-of-time, failed or cancelled), numbers of files and bytes, duration, throughput and the first few warnings, or the error which stopped hashing. For cron mail or chat notifications.
+    #[structopt(long = "summary-file", parse(from_os_str))]
+    summary_file: Option<PathBuf>,
+    /// Log files which could not be hashed to this file, or fd:N for an open file descriptor (e.g. fd:2 for stderr), one JSON object per line with the path, phase (stat, open, read, or path for --strict-paths), errno and error message, and carry on hashing the rest, rather than stopping at the first. The outputs then hold only the files which were hashed; the exit status is 1 if any could not be.
+    #[structopt(long = "errors-to")]
+    errors_to: Option<String>,
+    /// Treat files whose paths the output cannot hold as they are as errors, rather than writing an altered path: paths which are not valid UTF-8 (otherwise written with replacement characters), and, for --format text and sfv, paths containing line breaks or which would be read back as comments (starting with # in text without --compatible, or ; in SFV), or, for sfv, ending with whitespace. Such files stop the run, or are logged by --errors-to.
+    #[structopt(long = "strict-paths")]
+    strict_paths: bool,
+    /// Walk and hash this directory instead of <input>, but report paths as if they were under <input>. Intended for hashing a snapshot (e.g. LVM, btrfs, ZFS) of a live directory, created by --pre-hook. Only valid if <input> is a directory.
+    #[structopt(long = "hash-root")]
+    hash_root: Option<PathBuf>,
+    /// Shell command to run before hashing starts, e.g. to create and mount a snapshot. Hashing is aborted if it fails. RECURSUM_INPUT, RECURSUM_HASH_ROOT and RECURSUM_RUN_ID are set in its environment.
+    #[structopt(long = "pre-hook")]
+    pre_hook: Option<String>,
+    /// Shell command to run after hashing finishes (or fails), e.g. to unmount and remove a snapshot. It has the same environment as --pre-hook.
+    #[structopt(long = "post-hook")]
+    post_hook: Option<String>,
+    /// Also write the results to another file, as FORMAT:PATH, where FORMAT is text (as the main output, including any header), coreutils (as md5sum etc.), jsonl (JSON lines, including the algorithm and run ID) or parquet (as --format parquet). Results can also be published as they arrive, one JSON lines record per message, with kafka://BROKER[,BROKER...]/TOPIC or nats://SERVER[:PORT]/SUBJECT, or recorded in Redis for lookups with redis://HOST[:PORT][/DB][?index=digest|path&prefix=PREFIX] (if compiled in). Text formats are compressed if PATH ends in .gz or .zst. Can be given multiple times, so that several formats are produced from one read of the data.
+    #[structopt(long = "sink", number_of_values = 1)]
+    sinks: Vec<SinkSpec>,
+    /// Run this shell command for each file as it is hashed (after it is written to the output), like find's -exec. {path}, {hash} and {size} are replaced by the file's (quoted) path, digest and size, which are also in the environment as RECURSUM_PATH and RECURSUM_HASH. Its stdout is redirected to stderr.
+    #[structopt(long = "exec-per-file")]
+    exec_per_file: Option<String>,
+    /// Run the --exec-per-file command once for up to this many files, given on its stdin as `<digest>  <path>` lines, rather than once per file (placeholders are not replaced).
+    #[structopt(long = "exec-batch", default_value = "1")]
+    exec_batch: usize,
+    /// Number of --exec-per-file commands to run at once.
+    #[structopt(long = "exec-jobs", default_value = "1")]
+    exec_jobs: usize,
+    /// What to do if an --exec-per-file command fails: abort (stop hashing and fail, without publishing --output), warn (print a warning and carry on), or ignore.
+    #[structopt(long = "exec-on-failure", default_value = "abort")]
+    exec_on_failure: ExecFailure,
+    /// Run as a systemd service (Type=notify): tell systemd when hashing starts, keep the service's status line up to date with the files and bytes hashed and the rate, send watchdog keep-alives as files are hashed if WatchdogSec= is set, and log unreadable files, files which changed while being read, and a summary to the journal with fields like RECURSUM_PATH, RECURSUM_FILES and RECURSUM_RUN_ID. Linux only.
+    #[structopt(long = "systemd")]
+    systemd: bool,
+    /// POST the results to this URL in JSON batches, as {"run_id": ..., "sequence": N, "results": [...]} with a JSON lines record per file. Failed requests are retried with backoff (up to 6 attempts), and hashing waits if delivery falls behind.
+    #[cfg(feature = "webhook")]
+    #[structopt(long = "webhook")]
+    webhook: Option<String>,
+    /// Results per --webhook request.
+    #[cfg(feature = "webhook")]
+    #[structopt(long = "webhook-batch", default_value = "1000")]
+    webhook_batch: usize,
+    /// Add this header to --webhook requests, as "NAME: VALUE", e.g. for an authorization token. Can be given multiple times.
+    #[cfg(feature = "webhook")]
+    #[structopt(long = "webhook-header", number_of_values = 1, parse(try_from_str = webhook::parse_header))]
+    webhook_headers: Vec<(String, String)>,
+    /// Write paths in the output (and --sink outputs) in this Unicode normalization form, nfc or nfd, so that manifests of the same files made on macOS (whose names are NFD) and Linux (usually NFC) list them alike. Only the part under <input> is changed, if it is a directory. `check` and `diff-manifests` take the same option to match paths written in either form.
+    #[structopt(long = "normalize-paths")]
+    normalize_paths: Option<normalize::NormalForm>,
+    /// Replace each path in the output (and --sink outputs) with a keyed hash of it, relative to <input> if it is a directory, so that the results can be shared for comparison without revealing file or directory names. hmac is a hex HMAC-SHA256, keyed with --redact-key-file. The header, if any, records this rather than the root or command. `check` and `diff-manifests` compare redacted manifests given the same key.
+    #[cfg(feature = "redact")]
+    #[structopt(long = "redact-paths", requires = "redact-key-file")]
+    redact_paths: Option<redact::RedactMode>,
+    /// File holding the secret key for --redact-paths (without its trailing newline). Whoever has it can match redacted paths to files.
+    #[cfg(feature = "redact")]
+    #[structopt(
+        long = "redact-key-file",
+        parse(from_os_str),
+        requires = "redact-paths"
+    )]
+    redact_key_file: Option<PathBuf>,
+    /// Encrypt output files (--output, --split-output-by-dir manifests and --sink files) to this age recipient (age1...) as they are written, after any compression, e.g. for manifests of sensitive filesystems. Can be given multiple times; any recipient's key can decrypt them. Name files e.g. hashes.tsv.gz.age.
+    #[cfg(feature = "age")]
+    #[structopt(long = "encrypt-output", number_of_values = 1, parse(try_from_str = encrypt::parse_recipient))]
+    encrypt_output: Vec<age::x25519::Recipient>,
+    /// Once --output is written, get an RFC 3161 timestamp token for its SHA-256 digest from the time-stamping authority at this URL, and store the response next to it as <output>.tsr, proving that the manifest existed at that time. It can be checked with e.g. `openssl ts -verify -data <output> -in <output>.tsr -CAfile tsa.pem`.
+    #[cfg(feature = "timestamp")]
+    #[structopt(long = "timestamp-url")]
+    timestamp_url: Option<String>,
+    /// Identifier for this run, recorded in the --header, --summary-file and hooks' environment (as RECURSUM_RUN_ID), to correlate the outputs of sharded or repeated runs. By default, a random one is generated.
+    #[structopt(long = "run-id")]
+    run_id: Option<String>,
+    /// Colour statuses, warnings, errors and totals: auto (only on a terminal, unless NO_COLOR is set), always or never. Applies to every subcommand.
+    #[structopt(long = "color", default_value = "auto", global = true)]
+    color: color::ColorChoice,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Verify files against a manifest. Manifests from recursum (with or without --header), GNU coreutils (md5sum, sha256sum etc.), BSD-style tags, hashdeep, JSON lines, mtree and SFV are detected automatically. Exits with status 1 if any file does not match or cannot be read.
+    Check(check::CheckOpt),
+    /// Check a directory against a baseline made with --cache, quickly: compare every file's size and modification time to the baseline's, then read only new files, files whose metadata changed, and a random sample of the rest (to catch corruption which leaves metadata alone). Prints NEW, CHANGED, TOUCHED (modified, but with the same contents), MISSING, or for sampled files OK or FAILED. Exits with status 1 if anything differs from the baseline or cannot be read; with --update, the baseline takes the changes, and only FAILED and unreadable files fail.
+    SmartCheck(smart_check::SmartCheckOpt),
+    /// Compare two manifests, listing files which were added, removed, changed or moved between them, without reading the files themselves. Manifests in any format `check` accepts can be compared, e.g. on a machine without access to the data. Exits with status 1 if there are any differences.
+    DiffManifests(diff::DiffOpt),
+    /// Count the files and bytes under a directory, with a histogram of file sizes, without hashing anything. Also estimates how long hashing them would take with each algorithm, from a quick benchmark.
+    Estimate(estimate::EstimateOpt),
+    /// Find files with identical contents, and optionally replace duplicates with links.
+    Dedupe(dedupe::DedupeOpt),
+    /// Copy (or link) every file into a content-addressed store, as <store>/ab/cdef... for a file whose digest is abcdef..., and print a manifest of the ingested paths and their digests. Files already in the store are not copied again. Exits with status 1 if any file could not be ingested.
+    Ingest(ingest::IngestOpt),
+    /// Verify files installed by rpm or dpkg against the digests their packages recorded, like `rpm -V` or debsums but in parallel. Only files which are missing or do not match are listed. Exits with status 1 if there are any.
+    VerifyPkg(verify_pkg::VerifyPkgOpt),
+    /// Verify a container image, as an OCI image layout or `docker save` tarball: every blob must match its digest, and every blob the image's manifests refer to must be present. Optionally hashes every file inside each layer. Exits with status 1 if any blob is missing or does not match.
+    #[cfg(feature = "oci")]
+    Oci(oci::OciOpt),
+    /// Check every compiled-in hashing algorithm against known answers, and the output of hashing a generated directory tree. Exits with status 1 if any check fails.
+    Selftest(selftest::SelftestOpt),
+    /// Inspect and manage a --cache: show its statistics, remove stale entries or entries matching globs, or export it for, and import it from, other copies of the dataset.
+    Cache(cache::CacheOpt),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Engine {
+    Tokio,
+    Rayon,
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tokio" => Ok(Self::Tokio),
+            "rayon" if cfg!(feature = "rayon") => Ok(Self::Rayon),
+            "rayon" => Err("the rayon engine was not compiled in (feature rayon)".to_string()),
+            _ => Err(format!("unknown engine '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Text,
+    Mtree,
+    Sfv,
+    PgCopy,
+    Parquet,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "mtree" => Ok(Self::Mtree),
+            "sfv" => Ok(Self::Sfv),
+            "pg-copy" => Ok(Self::PgCopy),
+            "parquet" if cfg!(feature = "parquet") => Ok(Self::Parquet),
+            "parquet" => Err("parquet output was not compiled in (feature parquet)".to_string()),
+            _ => Err(format!(
+                "unknown format '{}'; expected text, mtree, sfv, pg-copy or parquet",
+                s
+            )),
+        }
+    }
 }
 
 enum InputConfig {
     /// number of hashing threads, file paths
     Files((usize, Vec<PathBuf>)),
-    /// number of hashing threads, root directory, number of walker threads
-    Directory((usize, PathBuf, usize)),
-    /// number of hashing threads
-    Stdin(usize),
+    /// number of hashing threads, root directory, walker
+    Directory((usize, PathBuf, Box<dyn Walker>)),
+    /// number of hashing threads, whether to canonicalize paths
+    Stdin((usize, bool)),
 }
 
 impl InputConfig {
     async fn hash(
         &self,
-        truncate_to: Option<usize>,
-        quiet: bool,
-        separator: &str,
-        hash_first: bool,
-    ) {
+        opts: HashOptions,
+        rewrite: &Rewrite,
+        skip: Skip,
+        sink: &mut dyn OutputSink,
+    ) -> io::Result<()> {
         match self {
             Self::Files((n_jobs, paths)) => {
-                let stream = iter(paths.clone());
-                hash_from_stream(stream, truncate_to, *n_jobs, quiet, separator, hash_first).await;
+                let opts = HashOptions {
+                    jobs: *n_jobs,
+                    ..opts
+                };
+                hash_all(iter(paths.clone()), opts, rewrite, skip, sink).await
             }
-            Self::Directory((n_jobs, root, walkers)) => {
-                let stream = walk_paths(
-                    root.clone(),
-                    queue_length(*n_jobs),
-                    Parallelism::RayonNewPool(*walkers),
-                );
-                hash_from_stream(stream, truncate_to, *n_jobs, quiet, separator, hash_first).await;
+            Self::Directory((n_jobs, root, walker)) => {
+                let opts = HashOptions {
+                    jobs: *n_jobs,
+                    ..opts
+                };
+                let stream = walk_paths(root.clone(), queue_length(*n_jobs), walker.as_ref());
+                hash_all(stream, opts, rewrite, skip, sink).await
             }
-            Self::Stdin(n_jobs) => {
-                let stream = stdin_paths();
-                hash_from_stream(stream, truncate_to, *n_jobs, quiet, separator, hash_first).await;
+            Self::Stdin((n_jobs, canonical)) => {
+                let opts = HashOptions {
+                    jobs: *n_jobs,
+                    ..opts
+                };
+                hash_all(stdin_paths(*canonical), opts, rewrite, skip, sink).await
             }
         }
     }
 }
 
-fn handle_single_file(
-    path: &Path,
-    truncate: Option<usize>,
-    quiet: bool,
-    separator: &str,
-    hash_first: bool,
-) {
-    let started = Instant::now();
-    let (digest, size) = hash_file(path, MeowHasher::new(), truncate);
-    let path_as_str = path.as_os_str().to_string_lossy();
+#[cfg(feature = "rayon")]
+impl InputConfig {
+    /// As [InputConfig::hash], on a rayon thread pool rather than an async runtime.
+    fn hash_on_pool(
+        &self,
+        opts: HashOptions,
+        rewrite: &Rewrite,
+        skip: Skip,
+        sink: &mut dyn OutputSink,
+    ) -> io::Result<()> {
+        let (n_jobs, paths): (usize, Box<dyn Iterator<Item = PathBuf> + Send>) = match self {
+            Self::Files((n_jobs, paths)) => (*n_jobs, Box::new(paths.clone().into_iter())),
+            Self::Directory((n_jobs, root, walker)) => (*n_jobs, walker.files(root)),
+            Self::Stdin((n_jobs, canonical)) => {
+                let canonical = *canonical;
+                let lines = io::BufRead::lines(io::BufReader::new(io::stdin()));
+                let paths = lines.map(move |line| {
+                    let path = PathBuf::from(line.unwrap());
+                    if canonical {
+                        canonicalize(path)
+                    } else {
+                        path
+                    }
+                });
+                (*n_jobs, Box::new(paths))
+            }
+        };
+        let paths: Box<dyn Iterator<Item = PathBuf> + Send> = match skip {
+            Some(resume) => {
+                let rewrite = rewrite.clone();
+                Box::new(paths.filter(move |p| !resume.is_done(&rewrite_path(p.clone(), &rewrite))))
+            }
+            None => paths,
+        };
+        let paths = paths.filter(|p| admit(p));
+        let opts = HashOptions {
+            jobs: n_jobs,
+            ..opts
+        };
+        let results = recursum::pool::hash_iter(paths, opts)
+            .map_err(io::Error::other)?
+            .map(|result| {
+                result.map(|mut hashed| {
+                    hashed.path = rewrite_path(hashed.path, rewrite);
+                    hashed
+                })
+            });
+        recursum::sink::write_results_blocking_until(results, sink, opts.cancel)
+    }
+}
 
-    if hash_first {
-        println!("{}{}{}", digest, separator, path_as_str);
-    } else {
-        println!("{}{}{}", path_as_str, separator, digest);
+/// Whether to hash a path from the input, as far as --max-duration allows, noting it for --rotate-state if so.
+fn admit(path: &Path) -> bool {
+    let admitted = BUDGET.get().is_none_or(|b| b.admit(path));
+    if let (true, Some(r)) = (admitted, ROTATION.get()) {
+        r.record(path);
     }
+    admitted
+}
 
-    if !quiet {
-        let elapsed = Instant::now().duration_since(started);
-        let rate = (size as f64 / elapsed.as_secs_f64()).floor() as u64;
-        eprintln!(
-            "{} files ({}) hashed in {} ({}/s)",
-            1,
-            HumanBytes(size as u64),
-            HumanDuration(elapsed),
-            HumanBytes(rate),
-        );
+/// Hash every path in the stream (except those to skip), passing the results to `sink` in the same order.
+async fn hash_all<S>(
+    paths: S,
+    opts: HashOptions,
+    rewrite: &Rewrite,
+    skip: Skip,
+    sink: &mut dyn OutputSink,
+) -> io::Result<()>
+where
+    S: Stream<Item = PathBuf> + Unpin + Send + 'static,
+{
+    let rewrite_skipped = rewrite.clone();
+    let paths = paths
+        .filter(move |p| {
+            skip.is_none_or(|resume| !resume.is_done(&rewrite_path(p.clone(), &rewrite_skipped)))
+        })
+        .filter(|p| admit(p));
+    let results = hash_stream(paths, opts).map(|result| {
+        result.map(|mut hashed| {
+            hashed.path = rewrite_path(hashed.path, rewrite);
+            hashed
+        })
+    });
+    write_results_until(results, sink, opts.cancel).await
+}
+
+/// Parse the command line.
+/// clap takes a first <input> which looks like a mistyped subcommand (e.g. ./check, or in for ingest) for one,
+/// even after --, so if it is an existing path, parse again without looking for subcommand typos.
+fn parse_args() -> Opt {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    match Opt::from_iter_safe(&args) {
+        Ok(opt) => opt,
+        Err(e)
+            if e.kind == ErrorKind::InvalidSubcommand
+                && e.info
+                    .as_ref()
+                    .and_then(|info| info.first())
+                    .is_some_and(|arg| Path::new(arg).symlink_metadata().is_ok()) =>
+        {
+            // with AllowExternalSubcommands alone, clap recurses forever writing the usage
+            let matches = Opt::clap()
+                .setting(AppSettings::AllowExternalSubcommands)
+                .setting(AppSettings::ArgsNegateSubcommands)
+                .get_matches_from(&args);
+            Opt::from_clap(&matches)
+        }
+        Err(e) => e.exit(),
     }
 }
 
 fn main() {
-    let opt = Opt::from_args();
-    let threads = or_num_cpus(opt.threads);
+    let opt = parse_args();
+    color::init(opt.color);
+    match opt.command {
+        Some(Command::Check(check_opt)) => return check::run(check_opt),
+        Some(Command::SmartCheck(smart_opt)) => return smart_check::run(smart_opt),
+        Some(Command::DiffManifests(diff_opt)) => return diff::run(diff_opt),
+        Some(Command::Estimate(estimate_opt)) => return estimate::run(estimate_opt),
+        Some(Command::Dedupe(dedupe_opt)) => return dedupe::run(dedupe_opt),
+        Some(Command::Ingest(ingest_opt)) => return ingest::run(ingest_opt),
+        Some(Command::VerifyPkg(verify_opt)) => return verify_pkg::run(verify_opt),
+        #[cfg(feature = "oci")]
+        Some(Command::Oci(oci_opt)) => return oci::run(oci_opt),
+        Some(Command::Selftest(selftest_opt)) => return selftest::run(selftest_opt),
+        Some(Command::Cache(cache_opt)) => return cache::run(cache_opt),
+        None => (),
+    }
+    // only once run has returned, so that the post-hook has run and the locks are released
+    let code = run(opt);
+    if code != 0 {
+        std::process::exit(code);
+    }
+}
+
+/// Hash the inputs, returning the exit status.
+fn run(opt: Opt) -> i32 {
+    let started = Instant::now();
+    let totals = match (opt.summary_format, opt.quiet) {
+        (Some(format), _) => Some(format),
+        (None, false) => Some(SummaryFormat::Human),
+        (None, true) => None,
+    };
+    if opt.auto_tune && opt.engine != Engine::Tokio {
+        panic!("--auto-tune requires --engine tokio");
+    }
+    if !opt.per_device_concurrency.is_empty() && opt.engine != Engine::Tokio {
+        panic!("--per-device-concurrency requires --engine tokio");
+    }
+    if opt.prefetch > 0 && opt.engine != Engine::Tokio {
+        panic!("--prefetch requires --engine tokio");
+    }
+    let preset = opt.profile.map(profile::Profile::settings);
+    let threads = match (opt.threads.or(preset.map(|p| p.threads)), opt.auto_tune) {
+        (Some(t), _) => t,
+        // a ceiling for tuning; slow storage may want many more jobs than cores
+        (None, true) => AUTO_TUNE_THREADS_PER_CPU * num_cpus::get(),
+        (None, false) => num_cpus::get(),
+    };
     let mut path_strs = opt.input.clone();
+    let run_id = RUN_ID.get_or_init(|| opt.run_id.clone().unwrap_or_else(new_run_id));
+    if let Some(destination) = &opt.errors_to {
+        let log = ErrorLog::open(destination, run_id)
+            .unwrap_or_else(|e| panic!("could not open --errors-to {}: {}", destination, e));
+        // set once, here
+        let _ = ERROR_LOG.set(log);
+    }
+    if let (Some(max), Some(path)) = (opt.max_duration, &opt.checkpoint) {
+        let budget = Budget::new(started, max, path)
+            .unwrap_or_else(|e| panic!("could not create --checkpoint {:?}: {}", path, e));
+        // set once, here
+        let _ = BUDGET.set(budget);
+    }
 
-    let hash_first = opt.compatible.clone();
+    let mut hook_env = vec![(hook::RUN_ID_VAR, OsString::from(run_id))];
+    if path_strs.len() == 1 {
+        hook_env.push((hook::INPUT_VAR, path_strs[0].clone()));
+    }
+    if let Some(hash_root) = &opt.hash_root {
+        hook_env.push((hook::HASH_ROOT_VAR, hash_root.clone().into_os_string()));
+    }
+    // before the pre-hook, and reading any cache; released after the post-hook
+    let _locks = take_locks(&opt);
+    if let Some(cmd) = &opt.pre_hook {
+        Hook::new(cmd, hook_env.clone())
+            .run()
+            .unwrap_or_else(|e| panic!("pre-hook: {}", e));
+    }
+    // run when this returns or unwinds
+    let _post_hook = opt
+        .post_hook
+        .as_ref()
+        .map(|cmd| PostHook(Hook::new(cmd, hook_env)));
+
+    // before any hashing threads are spawned, so that they inherit it
+    if opt.idle_io || preset.is_some_and(|p| p.idle_io) {
+        platform::set_idle_io_priority();
+    }
+    platform::cancel_on_interrupt(&CANCEL);
+
+    let single_dir = path_strs.len() == 1 && Path::new(&path_strs[0]).is_dir();
+    if opt.split_output_by_dir.is_some() && !single_dir {
+        panic!("--split-output-by-dir requires a single directory as input");
+    }
+    if opt.hash_root.is_some() && !single_dir {
+        panic!("--hash-root requires a single directory as input");
+    }
+    if opt.prioritize.is_some() && !single_dir {
+        panic!("--prioritize requires a single directory as input");
+    }
+    if opt.rotate_state.is_some() {
+        if !single_dir {
+            panic!("--rotate-state requires a single directory as input");
+        }
+        let sorted_depth_first = !opt.no_sort
+            && opt.order.is_none_or(|o| o == Order::Depth)
+            && opt.priority_list.is_none()
+            && !matches!(opt.walker, Some(WalkerKind::Ignore | WalkerKind::Stream));
+        if !sorted_depth_first {
+            panic!("--rotate-state needs the default sorted, depth-first order, so cannot be used with --no-sort, --order, --priority-list or the ignore and stream walkers");
+        }
+    }
+    if opt.hash.starts_with(hashers::BY_SIZE_PREFIX) {
+        if opt.compatible || opt.format == OutputFormat::Mtree {
+            panic!("--hash auto-size prefixes digests with their algorithm, which --compatible and --format mtree cannot hold");
+        }
+        if opt.seed.is_some() || opt.structure_only {
+            panic!("--hash auto-size cannot be combined with --seed or --structure-only");
+        }
+    }
+    if opt.format == OutputFormat::Mtree {
+        if opt.compatible || opt.separator.is_some() {
+            panic!("--format mtree cannot be combined with --compatible or --separator");
+        }
+        if opt.split_output_by_dir.is_some() || opt.resume_from.is_some() {
+            panic!("--format mtree does not support --split-output-by-dir or --resume-from");
+        }
+    }
+    if opt.report_skipped && opt.format != OutputFormat::Text {
+        panic!("--report-skipped requires --format text");
+    }
+    if opt.format == OutputFormat::Sfv {
+        if opt.compatible || opt.separator.is_some() {
+            panic!("--format sfv cannot be combined with --compatible or --separator");
+        }
+        if opt.header || opt.append || opt.digest_length.is_some() {
+            panic!("SFV files cannot hold a header or shortened digests (--header, --append, --digest-length)");
+        }
+        if opt.hash != hashers::AUTO && opt.hash != SFV_HASHER {
+            panic!("SFV files hold CRC32 digests; use --hash {}", SFV_HASHER);
+        }
+        if hashers::get(SFV_HASHER).is_none() {
+            panic!(
+                "--format sfv needs the crc32 hasher, which was not compiled in (feature crc32)"
+            );
+        }
+    }
+    if opt.format == OutputFormat::PgCopy {
+        if opt.compatible || opt.separator.is_some() {
+            panic!("--format pg-copy cannot be combined with --compatible or --separator");
+        }
+        if opt.header
+            || opt.append
+            || opt.resume_from.is_some()
+            || opt.split_output_by_dir.is_some()
+        {
+            panic!("--format pg-copy does not support --header, --append, --resume-from or --split-output-by-dir");
+        }
+    }
+    if opt.format == OutputFormat::Parquet {
+        if opt.output.is_none() {
+            panic!("--format parquet needs --output");
+        }
+        if opt.header
+            || opt.append
+            || opt.resume_from.is_some()
+            || opt.split_output_by_dir.is_some()
+        {
+            panic!("--format parquet does not support --header, --append, --resume-from or --split-output-by-dir");
+        }
+    }
+
+    #[cfg(feature = "age")]
+    let recipients = encrypt::Recipients::new(&opt.encrypt_output);
+    #[cfg(not(feature = "age"))]
+    let recipients: Option<encrypt::Recipients> = None;
+    if recipients.is_some() {
+        if opt.output.is_none() && opt.split_output_by_dir.is_none() {
+            panic!("--encrypt-output needs --output or --split-output-by-dir; it does not encrypt stdout");
+        }
+        if opt.append || opt.resume_from.is_some() {
+            panic!("encrypted outputs cannot be appended to (--append, --resume-from)");
+        }
+    }
+    #[cfg(feature = "redact")]
+    let redactor: Option<&'static redact::Redactor> = opt.redact_paths.map(|mode| {
+        let redact::RedactMode::Hmac = mode;
+        if opt.split_output_by_dir.is_some() || opt.resume_from.is_some() {
+            panic!("--redact-paths does not support --split-output-by-dir or --resume-from");
+        }
+        let p = opt
+            .redact_key_file
+            .as_ref()
+            .expect("--redact-paths requires --redact-key-file");
+        let redactor = redact::Redactor::load(p)
+            .unwrap_or_else(|e| panic!("could not read --redact-key-file {:?}: {}", p, e));
+        &*Box::leak(Box::new(redactor))
+    });
+    #[cfg(feature = "timestamp")]
+    if opt.timestamp_url.is_some() && (opt.output.is_none() || opt.split_output_by_dir.is_some()) {
+        panic!("--timestamp-url needs --output, and does not support --split-output-by-dir");
+    }
+
+    let hash_first = opt.compatible;
     let separator = opt
         .separator
+        .as_ref()
         .map(|s| match s.as_str() {
             "\\t" => "\t".to_string(),
             "\\0" => "\0".to_string(),
-            _ => s,
+            _ => s.clone(),
         })
         .unwrap_or_else(|| {
-            if hash_first {
+            if opt.format == OutputFormat::Sfv {
+                SFV_SEPARATOR.to_string()
+            } else if hash_first {
                 COMPATIBLE_SEPARATOR.to_string()
             } else {
                 DEFAULT_SEPARATOR.to_string()
             }
         });
 
+    let hash_name = if opt.format == OutputFormat::Sfv {
+        SFV_HASHER
+    } else {
+        select_hasher(&opt.hash)
+    };
+    #[cfg(feature = "tui")]
+    let activity: Option<&'static Activity> = opt.tui.then(|| {
+        if !io::IsTerminal::is_terminal(&io::stderr()) {
+            panic!("--tui needs stderr to be a terminal");
+        }
+        &*Box::leak(Box::default())
+    });
+    #[cfg(not(feature = "tui"))]
+    let activity: Option<&'static Activity> = None;
+    let seeded = opt.seed.as_ref().map(|s| seeded_hasher(hash_name, s));
+    let cache = opt.cache.as_ref().map(|p| {
+        let mut hasher = hash_name.to_string();
+        if let Some((_, seed)) = seeded {
+            hasher += &format!(" seed={}", hex::encode(seed));
+        }
+        if let Some(length) = opt.digest_length {
+            hasher += &format!(" digest-length={}", length);
+        }
+        let mut cache = match HashCache::load(p) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashCache::new(&hasher),
+            Err(e) => panic!("could not read cache {:?}: {}", p, e),
+        };
+        if cache.hasher != hasher {
+            panic!(
+                "cache {:?} holds digests made with {}, not {}; use another --cache file",
+                p, cache.hasher, hasher
+            );
+        }
+        if let Some(percent) = opt.cache_verify_percent {
+            if !(0.0..=100.0).contains(&percent) {
+                panic!("--cache-verify-percent must be between 0 and 100");
+            }
+            cache.verify_percent(percent);
+        }
+        if opt.prioritize == Some(Prioritize::LeastRecentlyVerified) {
+            cache.verify_percent(100.0);
+        }
+        &*Box::leak(Box::new(cache))
+    });
+    let listing_cache = opt.listing_cache.as_ref().map(|p| {
+        let cache = match ListingCache::load(p) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => ListingCache::default(),
+            Err(e) => panic!("could not read listing cache {:?}: {}", p, e),
+        };
+        &*Box::leak(Box::new(cache))
+    });
+    let (hasher, by_size) = hasher_for(hash_name);
+    let hash_opts = HashOptions {
+        hasher,
+        seeded_hasher: seeded,
+        truncate: opt.digest_length,
+        detect_races: opt.detect_races,
+        shared_extents: opt.detect_shared_extents,
+        jobs: threads,
+        buffer_size: opt
+            .buffer_size
+            .or(opt.network_fs.then_some(NETWORK_FS_BUFFER_SIZE))
+            .or(preset.map(|p| p.buffer_size))
+            .unwrap_or(READ_BUFFER_SIZE),
+        advise_sequential: opt.fadvise.or(preset.map(|p| p.advice)) != Some(profile::Advice::None),
+        diagnostics: if opt.diagnostics {
+            Some(Box::leak(Box::default()))
+        } else {
+            None
+        },
+        auto_tune: if opt.auto_tune {
+            Some(Box::leak(Box::new(AutoTune::new(
+                threads,
+                Duration::from_secs(opt.auto_tune_for),
+            ))))
+        } else {
+            None
+        },
+        small_file_batch: opt.small_file_batch,
+        cancel: Some(&CANCEL),
+        numa: opt.numa,
+        device_limits: if opt.per_device_concurrency.is_empty() {
+            None
+        } else {
+            let limits = DeviceLimits::parse(&opt.per_device_concurrency)
+                .unwrap_or_else(|e| panic!("--per-device-concurrency: {}", e));
+            Some(Box::leak(Box::new(limits)))
+        },
+        activity,
+        cache,
+        stat_once: opt.network_fs,
+        structure: opt
+            .structure_only
+            .then(|| &*Box::leak(Box::new(Structure::new()))),
+        prefetch: opt.prefetch,
+        by_size,
+    };
+    if opt.detect_shared_extents && !extents::SUPPORTED {
+        panic!("--detect-shared-extents is only supported on Linux");
+    }
+    if opt.systemd && !cfg!(target_os = "linux") {
+        panic!("--systemd is only supported on Linux");
+    }
+
+    let flush_every = if opt.line_buffered {
+        Some(1)
+    } else {
+        opt.flush_every
+    };
+    let mut output =
+        TextOutput::new(&separator, hash_first, flush_every).encrypt_to(recipients.clone());
+    if opt.format == OutputFormat::Sfv {
+        output = output.upper_case();
+    }
+    if opt.report_skipped {
+        output = output.report_skipped();
+    }
+    // the walk reports what it leaves out, for --report-skipped
+    let (skip_sender, mut skip_receiver) = match opt.report_skipped {
+        true => {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            (Some(sender), Some(receiver))
+        }
+        false => (None, None),
+    };
+    if !(opt.known_bloom_fp_rate > 0.0 && opt.known_bloom_fp_rate < 1.0) {
+        panic!("--known-bloom-fp-rate must be between 0 and 1");
+    }
+    let known_hashes = match (&opt.known_hashes, &opt.known_bloom) {
+        (Some(p), _) => Some(KnownHashes::load(p).map_err(|e| (p, e))),
+        (None, Some(p)) => {
+            Some(KnownHashes::load_bloom(p, opt.known_bloom_fp_rate).map_err(|e| (p, e)))
+        }
+        (None, None) => None,
+    }
+    .map(|loaded| {
+        let known =
+            loaded.unwrap_or_else(|(p, e)| panic!("could not read known hashes {:?}: {}", p, e));
+        if !opt.quiet {
+            eprintln!("Loaded {}", known.describe());
+        }
+        &*Box::leak(Box::new(known))
+    });
+    // outputs only get the files chosen by --known-hashes
+    let known = |sink: Box<dyn OutputSink>| -> Box<dyn OutputSink> {
+        match known_hashes {
+            Some(k) => Box::new(KnownFilter::new(sink, k, opt.known_hashes_mode)),
+            None => sink,
+        }
+    };
+    // outputs which may be shared have their paths redacted, relative to the input directory
+    let redact = |sink: Box<dyn OutputSink>, root: Option<&Path>| -> Box<dyn OutputSink> {
+        #[cfg(feature = "redact")]
+        if let Some(r) = redactor {
+            return Box::new(redact::RedactPaths::new(
+                sink,
+                r,
+                root.map(Path::to_path_buf),
+            ));
+        }
+        let _ = root;
+        sink
+    };
+    // and their paths normalized first, so that the same name is always redacted alike
+    let normalize = |sink: Box<dyn OutputSink>, root: Option<&Path>| -> Box<dyn OutputSink> {
+        match opt.normalize_paths {
+            Some(form) => Box::new(normalize::NormalizePaths::new(
+                sink,
+                form,
+                root.map(Path::to_path_buf),
+            )),
+            None => sink,
+        }
+    };
+    let redact_header = |header: Header| -> Header {
+        #[cfg(feature = "redact")]
+        if redactor.is_some() {
+            return header.redacted(redact::HMAC_SHA256);
+        }
+        header
+    };
+    // outputs written to disk stop cleanly before it fills up
+    let guard = |sink: Box<dyn OutputSink>, dir: Option<&Path>| -> Box<dyn OutputSink> {
+        match (opt.min_free_space, dir) {
+            (Some(min_free), Some(dir)) => Box::new(SpaceGuard::new(
+                sink,
+                dir,
+                min_free,
+                Duration::from_secs(opt.low_space_wait),
+            )),
+            _ => sink,
+        }
+    };
+    let open_sinks = |header: Option<&Header>, root: Option<&Path>| -> Vec<Box<dyn OutputSink>> {
+        let header = header.cloned().map(redact_header);
+        let ctx = SinkContext {
+            separator: &separator,
+            hash_first,
+            header: header.as_ref(),
+            algorithm: hash_name,
+            run_id,
+            recipients: recipients.as_ref(),
+        };
+        let mut extra: Vec<Box<dyn OutputSink>> = opt
+            .sinks
+            .iter()
+            .map(|spec| {
+                let sink = spec
+                    .open(&ctx)
+                    .unwrap_or_else(|e| panic!("could not create sink {:?}: {}", spec.path, e));
+                let dir = spec.is_file().then(|| directory(&spec.path));
+                guard(normalize(redact(sink, root), root), dir)
+            })
+            .collect();
+        if let Some(cmd) = &opt.exec_per_file {
+            extra.push(Box::new(ExecPerFile::new(
+                cmd,
+                opt.exec_batch,
+                opt.exec_jobs,
+                opt.exec_on_failure,
+                run_id,
+            )));
+        }
+        #[cfg(feature = "webhook")]
+        if let Some(url) = &opt.webhook {
+            extra.push(Box::new(webhook::Webhook::new(
+                url,
+                &opt.webhook_headers,
+                opt.webhook_batch,
+                hash_name,
+                run_id,
+            )));
+        }
+        #[cfg(target_os = "linux")]
+        if opt.systemd {
+            let input: Vec<_> = opt.input.iter().map(|i| i.to_string_lossy()).collect();
+            extra.push(Box::new(systemd::Systemd::new(&input.join(" "))));
+        }
+        // after every other output, so that the manifest is complete
+        #[cfg(feature = "timestamp")]
+        if let (Some(url), Some(path)) = (&opt.timestamp_url, &opt.output) {
+            extra.push(Box::new(timestamp::Timestamp::new(url, path)));
+        }
+        extra.into_iter().map(known).collect()
+    };
+    // errors (including paths refused by --strict-paths) are logged apart from the outputs,
+    // which would otherwise stop at the first, and then reported along with everything else skipped
+    let mut around = |sinks: Vec<Box<dyn OutputSink>>, rewrite: &Rewrite| -> Box<dyn OutputSink> {
+        let sinks: Box<dyn OutputSink> = match ERROR_LOG.get() {
+            Some(log) => Box::new(ErrorsTo::new(Box::new(sinks), log)),
+            None => Box::new(sinks),
+        };
+        let sinks: Box<dyn OutputSink> = match opt.report_skipped {
+            true => Box::new(ReportSkipped::new(
+                sinks,
+                skip_receiver.take(),
+                rewrite.clone(),
+            )),
+            false => sinks,
+        };
+        // first, so that refused paths are errors to everything else
+        match opt.strict_paths {
+            true => Box::new(StrictPaths::new(sinks, opt.format, hash_first)),
+            false => sinks,
+        }
+    };
+    // directories are grouped relative to `root`, the input directory, if there is one
+    let reports = |root: Option<PathBuf>| -> Vec<Box<dyn OutputSink>> {
+        let mut reports: Vec<Box<dyn OutputSink>> = Vec::new();
+        if opt.stats {
+            reports.push(Box::new(stats::Stats::new()));
+        }
+        if let Some(a) = opt.aggregate {
+            reports.push(Box::new(Aggregator::new(a, root.clone())));
+        }
+        if let Some(n) = opt.report_largest.filter(|n| *n > 0) {
+            reports.push(Box::new(stats::Largest::new(n)));
+        }
+        if opt.flag_empty || opt.flag_same_hash_different_size {
+            reports.push(Box::new(suspicious::Suspicious::new(
+                opt.flag_empty,
+                opt.flag_same_hash_different_size,
+            )));
+        }
+        if let Some(k) = known_hashes {
+            reports.push(Box::new(known::KnownCount::new(k, opt.known_hashes_mode)));
+        }
+        if opt.structure_only {
+            reports.push(Box::new(rollup::Rollup::new(hash_opts.new_hasher(), root)));
+        }
+        reports
+    };
+    // the main output, in the chosen format, once it knows where it is written
+    let main_output = |mut output: TextOutput,
+                       header: Option<Header>,
+                       mtree_root: Option<PathBuf>|
+     -> Box<dyn OutputSink> {
+        #[cfg(feature = "parquet")]
+        if opt.format == OutputFormat::Parquet {
+            let path = opt.output.as_ref().expect("checked above");
+            let parquet =
+                columnar::ParquetOutput::create(path, hash_name, run_id, recipients.as_ref())
+                    .unwrap_or_else(|e| panic!("could not create {:?}: {}", path, e));
+            let parquet = redact(Box::new(parquet), mtree_root.as_deref());
+            let parquet = known(normalize(parquet, mtree_root.as_deref()));
+            return guard(parquet, Some(directory(path)));
+        }
+        if opt.format == OutputFormat::Mtree {
+            output = output.mtree(mtree::keyword(hash_name), mtree_root.clone());
+        }
+        if opt.format == OutputFormat::PgCopy {
+            output = output.pg_copy();
+        }
+        if let Some(h) = header {
+            output = output.with_header(redact_header(match &mtree_root {
+                Some(root) if opt.format == OutputFormat::Mtree => h.relative_to(root),
+                _ => h,
+            }));
+        }
+        let dir = match &opt.split_output_by_dir {
+            Some(outdir) => Some(outdir.as_path()),
+            None => opt.output.as_deref().map(directory),
+        };
+        let output = redact(Box::new(output), mtree_root.as_deref());
+        guard(known(normalize(output, mtree_root.as_deref())), dir)
+    };
+    let mut rewrite = None;
+    // paths in an mtree spec are relative to the input directory
+    let mut mtree_root = None;
+    let mut header = if opt.header || opt.append {
+        Some(Header {
+            seed: hash_opts.seeded_hasher.map(|(_, seed)| hex::encode(seed)),
+            digest_length: opt.digest_length,
+            command: opt.append.then(command_line),
+            run_id: Some(run_id.clone()),
+            ..Header::new(hash_name, &separator, hash_first)
+        })
+    } else {
+        None
+    };
+
+    let resume = opt.resume_from.as_ref().map(|p| {
+        let resume = Resume::load(p)
+            .unwrap_or_else(|e| panic!("could not read output to resume from {:?}: {}", p, e));
+        if !opt.quiet {
+            eprintln!("Skipping {} files already in {:?}", resume.len(), p);
+        }
+        &*Box::leak(Box::new(resume))
+    });
+    // parquet output is written by its own sink, created below
+    if let Some(path) = opt
+        .output
+        .as_ref()
+        .filter(|_| opt.format != OutputFormat::Parquet)
+    {
+        let compression = opt.compress.unwrap_or_else(|| Compression::from_path(path));
+        if opt.resume_from.as_ref() == Some(path) {
+            // the earlier run's header is already there
+            header = None;
+            output = output.append_to_file(path, compression);
+        } else if opt.append {
+            output = output.append_to_file(path, compression);
+        } else {
+            output = output.write_to_file(path, compression);
+        }
+    }
+
+    let priority = opt.priority_list.as_ref().map(|p| {
+        PriorityList::load(p)
+            .unwrap_or_else(|e| panic!("could not read priority list {:?}: {}", p, e))
+    });
     let input;
 
     if path_strs.is_empty() {
@@ -363,30 +1729,356 @@ fn main() {
     } else if path_strs.len() == 1 {
         let inp = path_strs.pop().unwrap();
         if inp == "-" {
-            input = InputConfig::Stdin(threads);
+            if priority.is_some() {
+                panic!("--priority-list is not supported for stdin; sort the paths before piping them in");
+            }
+            input = InputConfig::Stdin((threads, opt.canonicalize));
         } else {
-            let path = PathBuf::from(inp);
+            let mut path = PathBuf::from(inp);
+            if opt.canonicalize {
+                path = canonicalize(path);
+            }
             if path.is_dir() {
-                let walkers = or_num_cpus(opt.walkers);
-                input = InputConfig::Directory((threads, path, walkers));
+                if let Some(h) = &mut header {
+                    h.root = Some(path.clone());
+                }
+                mtree_root = Some(path.clone());
+                let mut prune: HashSet<OsString> = opt.prune.iter().cloned().collect();
+                if opt.prune_defaults {
+                    prune.extend(DEFAULT_PRUNE.iter().map(OsString::from));
+                }
+                // a profile's order is only a preference, so gives way to a walker which cannot follow it
+                let order = match (opt.order, opt.walker) {
+                    (Some(order), _) => Some(order),
+                    (None, None) => preset.and_then(|p| p.order),
+                    (None, Some(_)) => None,
+                };
+                let walk_options = WalkOptions {
+                    order,
+                    sort: !opt.no_sort,
+                    skip_hidden: opt.skip_hidden,
+                    prune,
+                    detect_case_collisions: opt.detect_case_collisions,
+                    dir_limits: match (opt.skip_dirs_with_more_than, opt.skip_dirs_larger_than) {
+                        (None, None) => None,
+                        (files, bytes) => Some(DirLimits::new(files, bytes)),
+                    },
+                    skipped: skip_sender.clone(),
+                };
+                let walker = match listing_cache {
+                    Some(c) => CachedListingWalker::build(c, walk_options),
+                    None => opt.walker.unwrap_or(WalkerKind::for_order(order)).build(
+                        or_num_cpus(opt.walkers.or(opt.network_fs.then_some(NETWORK_FS_WALKERS))),
+                        queue_length(threads),
+                        walk_options,
+                    ),
+                }
+                .unwrap_or_else(|e| panic!("{}", e));
+                let walker: Box<dyn Walker> = match priority {
+                    Some(list) => Box::new(PriorityWalker::new(walker, list)),
+                    None => walker,
+                };
+                if let Some(outdir) = &opt.split_output_by_dir {
+                    output = output.split_by_dir(
+                        &path,
+                        outdir,
+                        opt.compress.unwrap_or(Compression::None),
+                    );
+                }
+                let root = match &opt.hash_root {
+                    Some(hash_root) => {
+                        let mut hash_root = hash_root.clone();
+                        if opt.canonicalize {
+                            hash_root = canonicalize(hash_root);
+                        }
+                        if !hash_root.is_dir() {
+                            panic!("--hash-root {:?} is not a directory", hash_root);
+                        }
+                        rewrite = Some((hash_root.clone(), path));
+                        hash_root
+                    }
+                    None => path,
+                };
+                if let Some(c) = cache {
+                    c.set_root(root.clone());
+                }
+                let walker: Box<dyn Walker> = match (opt.prioritize, cache) {
+                    (Some(Prioritize::LeastRecentlyVerified), Some(c)) => {
+                        Box::new(LeastRecentlyVerified::new(walker, c))
+                    }
+                    _ => walker,
+                };
+                let walker: Box<dyn Walker> = match &opt.rotate_state {
+                    Some(state) => {
+                        let rotation =
+                            Rotation::load(state, &root).unwrap_or_else(|e| panic!("{}", e));
+                        let start = rotation.start().map(Path::to_path_buf);
+                        // set once, here
+                        let _ = ROTATION.set(rotation);
+                        match start {
+                            Some(start) => {
+                                eprintln!("continuing after {:?}", start);
+                                Box::new(RotatedWalker::new(walker, start))
+                            }
+                            None => walker,
+                        }
+                    }
+                    None => walker,
+                };
+                if let Some(s) = hash_opts.structure {
+                    s.set_root(root.clone());
+                }
+                input = InputConfig::Directory((threads, root, walker));
             } else if path.is_file() {
-                handle_single_file(&path, opt.digest_length, opt.quiet, &separator, hash_first);
-                return;
+                // no progress bar for a single file, but keep the summary
+                let extra = open_sinks(header.as_ref(), None);
+                let mut sinks = around(
+                    output_sinks(
+                        main_output(output, header, None),
+                        false,
+                        totals,
+                        opt.detect_shared_extents,
+                        opt.summary_file.clone(),
+                        reports(path.parent().map(Path::to_path_buf)),
+                        extra,
+                    ),
+                    &rewrite,
+                );
+                let result = match resume {
+                    Some(r) if r.is_done(&path) => None,
+                    _ => Some(hash_path(path, hash_opts)),
+                };
+                write_results_blocking(result, &mut sinks)
+                    .unwrap_or_else(|e| fail(&opt, started, e));
+                let cache_ok = cache.is_none_or(|c| save_cache(&opt, c));
+                let errors_ok = ERROR_LOG.get().is_none_or(ErrorLog::finish);
+                return if cache_ok && errors_ok { 0 } else { 1 };
             } else {
                 panic!("Given input is not a directory, file, or - for stdin");
             }
         }
     } else {
-        let paths = path_strs.into_iter().map(PathBuf::from).collect();
+        let mut paths: Vec<_> = path_strs
+            .into_iter()
+            .map(PathBuf::from)
+            .map(|p| if opt.canonicalize { canonicalize(p) } else { p })
+            .collect();
+        if let Some(list) = &priority {
+            list.sort(&mut paths);
+        }
         input = InputConfig::Files((threads, paths))
     }
 
-    let mut rt = runtime::Builder::new()
-        .enable_all()
-        .threaded_scheduler()
-        .core_threads(threads)
-        .build()
-        .unwrap();
+    #[allow(unused_mut)]
+    let mut extra = open_sinks(header.as_ref(), mtree_root.as_deref());
+    // last, so that it shows any messages from finishing the others
+    #[cfg(feature = "tui")]
+    if let Some(a) = activity {
+        let dashboard =
+            tui::Dashboard::open(a).unwrap_or_else(|e| panic!("could not open --tui: {}", e));
+        extra.push(Box::new(dashboard));
+    }
+    let reports = reports(mtree_root.clone());
+    let mut sinks = around(
+        output_sinks(
+            main_output(output, header, mtree_root),
+            !opt.quiet && activity.is_none(),
+            totals,
+            opt.detect_shared_extents,
+            opt.summary_file.clone(),
+            reports,
+            extra,
+        ),
+        &rewrite,
+    );
+    let hashed = match opt.engine {
+        Engine::Tokio => runtime(threads, opt.numa)
+            .unwrap()
+            .block_on(input.hash(hash_opts, &rewrite, resume, &mut sinks)),
+        #[cfg(feature = "rayon")]
+        Engine::Rayon => input.hash_on_pool(hash_opts, &rewrite, resume, &mut sinks),
+        #[cfg(not(feature = "rayon"))]
+        Engine::Rayon => unreachable!("rejected when parsing --engine"),
+    };
+    // digests cached before a failure are still good
+    let cache_ok = cache.is_none_or(|c| save_cache(&opt, c));
+    if let Some(c) = listing_cache {
+        save_listing_cache(&opt, c);
+    }
+    hashed.unwrap_or_else(|e| fail(&opt, started, e));
+    if let Some(d) = hash_opts.diagnostics {
+        eprintln!("{}", d);
+    }
+    if let (Some(t), false) = (hash_opts.auto_tune, opt.quiet) {
+        eprintln!("auto-tune chose {}", t);
+    }
+    let rotation_ok = ROTATION.get().is_none_or(|r| {
+        let finished = BUDGET.get().is_none_or(|b| !b.exhausted());
+        r.save(finished)
+            .map_err(|e| {
+                let msg = format!(
+                    "warning: could not save --rotate-state {:?}: {}",
+                    r.path(),
+                    e
+                );
+                eprintln!("{}", color::stderr(Role::Warning, msg));
+            })
+            .is_ok()
+    });
+    let remaining = BUDGET.get().map(|b| {
+        b.finish()
+            .unwrap_or_else(|e| panic!("could not write --checkpoint {:?}: {}", b.path(), e))
+    });
+    let errors_ok = ERROR_LOG.get().is_none_or(ErrorLog::finish);
+    if !cache_ok || !rotation_ok || !errors_ok {
+        return 1;
+    }
+    if let (Some(n @ 1..), Some(b)) = (remaining, BUDGET.get()) {
+        let msg = format!(
+            "--max-duration reached: {} files were not hashed, and are listed in {:?}",
+            n,
+            b.path()
+        );
+        eprintln!("{}", color::stderr(Role::Warning, msg));
+        return budget::EXIT_OUT_OF_TIME;
+    }
+    0
+}
 
-    rt.block_on(input.hash(opt.digest_length, opt.quiet, &separator, hash_first));
+/// Lock <input> for --lock, and the --cache and --listing-cache files unless --no-lock.
+fn take_locks(opt: &Opt) -> Vec<RunLock> {
+    let mut paths = Vec::new();
+    if opt.lock {
+        match opt.input.as_slice() {
+            [input] if Path::new(input).is_dir() => paths.push(lock::for_root(Path::new(input))),
+            _ if opt.cache.is_none() && opt.listing_cache.is_none() => {
+                panic!("--lock needs a directory as input, or --cache or --listing-cache, to lock")
+            }
+            _ => (),
+        }
+    }
+    if !opt.no_lock {
+        paths.extend(
+            opt.cache
+                .iter()
+                .chain(&opt.listing_cache)
+                .chain(&opt.rotate_state)
+                .map(|p| lock::beside(p)),
+        );
+    }
+    paths
+        .iter()
+        .map(|p| {
+            RunLock::acquire(p, Duration::from_secs(opt.lock_wait))
+                .unwrap_or_else(|e| panic!("{}", e))
+        })
+        .collect()
+}
+
+/// Write out the --listing-cache, and say how many directories it saved listing.
+fn save_listing_cache(opt: &Opt, cache: &ListingCache) {
+    let path = opt
+        .listing_cache
+        .as_ref()
+        .expect("only called with --listing-cache");
+    if let Some(free) = opt
+        .min_free_space
+        .and_then(|min| low_space(directory(path), min))
+    {
+        let msg = format!(
+            "warning: not saving listing cache {:?}, as only {} is free there",
+            path,
+            HumanBytes(free)
+        );
+        eprintln!("{}", color::stderr(Role::Warning, msg));
+    } else if let Err(e) = cache.save(path) {
+        let msg = format!("warning: could not save listing cache {:?}: {}", path, e);
+        eprintln!("{}", color::stderr(Role::Warning, msg));
+    }
+    if !opt.quiet {
+        let (hits, misses) = cache.hits_and_misses();
+        eprintln!(
+            "Reused cached listings of {} of {} directories",
+            hits,
+            hits + misses
+        );
+    }
+}
+
+/// Write out the --cache, and say how much it saved and whether any sampled digests were wrong.
+/// Returns false if any were.
+fn save_cache(opt: &Opt, cache: &HashCache) -> bool {
+    let path = opt.cache.as_ref().expect("only called with --cache");
+    if let Some(free) = opt
+        .min_free_space
+        .and_then(|min| low_space(directory(path), min))
+    {
+        let msg = format!(
+            "warning: not saving cache {:?}, as only {} is free there; it is left as it was",
+            path,
+            HumanBytes(free)
+        );
+        eprintln!("{}", color::stderr(Role::Warning, msg));
+    } else if let Err(e) = cache.save(path) {
+        let msg = format!("warning: could not save cache {:?}: {}", path, e);
+        eprintln!("{}", color::stderr(Role::Warning, msg));
+    }
+    if !opt.quiet {
+        let (hits, misses) = cache.hits_and_misses();
+        eprintln!(
+            "Reused cached digests of {} of {} files",
+            hits,
+            hits + misses
+        );
+    }
+    let (verified, mismatches) = cache.verification();
+    if !opt.quiet && (opt.cache_verify_percent.is_some() || opt.prioritize.is_some()) {
+        eprintln!(
+            "Hashed {} unchanged files anyway to verify the cache",
+            verified
+        );
+    }
+    if mismatches.is_empty() {
+        return true;
+    }
+    let msg = format!(
+        "{} files had different contents from when they were cached, without their size or modification time changing:",
+        mismatches.len()
+    );
+    eprintln!("{}", color::stderr(Role::Bad, msg));
+    for m in mismatches {
+        eprintln!(
+            "  {} (cached {}, now {})",
+            m.path.display(),
+            m.cached,
+            m.actual
+        );
+    }
+    false
+}
+
+/// Record the error in --summary-file, if given, then abort with it.
+fn fail(opt: &Opt, started: Instant, error: io::Error) -> ! {
+    #[cfg(target_os = "linux")]
+    if opt.systemd {
+        systemd::failed(&error);
+    }
+    if let Some(path) = &opt.summary_file {
+        let elapsed = Instant::now().duration_since(started);
+        write_summary_file(
+            path,
+            if CANCEL.is_cancelled() {
+                "cancelled"
+            } else {
+                "failed"
+            },
+            &[
+                ("error", error.to_string()),
+                ("duration", HumanDuration(elapsed).to_string()),
+            ],
+            &[],
+        )
+        .unwrap_or_else(|e| eprintln!("could not write summary file {:?}: {}", path, e));
+    }
+    panic!("{}", error);
 }
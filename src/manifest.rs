@@ -0,0 +1,666 @@
+//! Parsing manifests written by recursum or by other tools, detecting their format.
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use recursum::hashers;
+
+use crate::header::{self, Header};
+use crate::mtree;
+use crate::output::{Compression, UNSTABLE_PREFIX};
+
+/// Prefixed to GNU coreutils lines whose path has been escaped.
+const ESCAPED_PREFIX: char = '\\';
+const HASHDEEP_MAGIC: &str = "%%%% HASHDEEP-";
+const HASHDEEP_COLUMNS: &str = "%%%% ";
+/// Starts comment lines in SFV files.
+const SFV_COMMENT: char = ';';
+/// Hex digits of the CRC32 in each line of an SFV file.
+const SFV_DIGEST_LENGTH: usize = 8;
+
+/// Manifest layouts which can be detected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// recursum's own output, with or without a header: path, separator, digest (or the reverse, per the header).
+    Recursum,
+    /// GNU coreutils (md5sum, sha256sum etc.) and recursum --compatible: digest, two characters, path.
+    Coreutils,
+    /// BSD and `--tag`: `ALGORITHM (path) = digest`.
+    BsdTag,
+    /// hashdeep: CSV of size, one column per algorithm, then path.
+    Hashdeep,
+    /// One JSON object per line, with "path" and "digest" (or "hash") members.
+    JsonLines,
+    /// mtree(8) specifications, starting with `#mtree`.
+    Mtree,
+    /// Simple File Verification: path, a space, and its CRC32; comments start with `;`.
+    Sfv,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Recursum => "recursum",
+            Self::Coreutils => "coreutils",
+            Self::BsdTag => "BSD tag",
+            Self::Hashdeep => "hashdeep",
+            Self::JsonLines => "JSON lines",
+            Self::Mtree => "mtree",
+            Self::Sfv => "SFV",
+        })
+    }
+}
+
+/// A file listed in the manifest, and its expected digest.
+pub struct Entry {
+    /// As written in the manifest.
+    pub path: String,
+    pub digest: String,
+    /// Size in bytes, if the manifest records it.
+    pub size: Option<u64>,
+}
+
+pub struct Manifest {
+    pub format: Format,
+    /// Algorithm named in the manifest, if any.
+    pub algorithm: Option<String>,
+    pub seed: Option<String>,
+    /// Digests may be truncated to this length.
+    pub digest_length: Option<usize>,
+    /// Directory which was hashed, if the header records it.
+    pub root: Option<PathBuf>,
+    /// Directory paths are relative to.
+    pub relative_to: Option<PathBuf>,
+    /// How paths were redacted, if the header records it.
+    pub redact: Option<String>,
+    pub entries: Vec<Entry>,
+    /// Lines which could not be parsed.
+    pub malformed: usize,
+}
+
+/// Length of a path redacted by recursum --redact-paths: a hex HMAC-SHA256.
+const REDACTED_PATH_LENGTH: usize = 64;
+
+impl Manifest {
+    /// Whether the paths were redacted, as the header records or (without one) as every path looks.
+    pub fn is_redacted(&self) -> bool {
+        let looks_redacted = |p: &str| {
+            p.len() == REDACTED_PATH_LENGTH
+                && p.bytes()
+                    .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+        };
+        self.redact.is_some()
+            || (!self.entries.is_empty() && self.entries.iter().all(|e| looks_redacted(&e.path)))
+    }
+}
+
+fn is_hex(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Hex digest, possibly marked as unstable by recursum --detect-races,
+/// and possibly prefixed with its algorithm and a colon by recursum --hash auto-size.
+fn is_digest(s: &str) -> bool {
+    let s = s.strip_prefix(UNSTABLE_PREFIX).unwrap_or(s);
+    let s = match s.split_once(':') {
+        Some((name, hex))
+            if !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') =>
+        {
+            hex
+        }
+        _ => s,
+    };
+    is_hex(s)
+}
+
+/// The algorithm a digest is prefixed with (by recursum --hash auto-size:...), if any, and the rest of the digest,
+/// without any mark of --detect-races.
+pub fn split_algorithm(digest: &str) -> (Option<&str>, &str) {
+    let digest = digest.strip_prefix(UNSTABLE_PREFIX).unwrap_or(digest);
+    match digest.split_once(':') {
+        Some((algorithm, hex)) => (Some(algorithm), hex),
+        None => (None, digest),
+    }
+}
+
+/// Canonical name of an algorithm as other tools write it, e.g. SHA-256 -> sha256.
+fn algorithm_name(s: &str) -> String {
+    s.to_ascii_lowercase().replace('-', "")
+}
+
+/// Algorithms which make hex digests of this length, the likeliest first for a manifest in `format`
+/// which does not name its algorithm.
+///
+/// Several algorithms share some lengths: other tools' formats are taken to use the standard one
+/// (e.g. sha512 for 128 characters, as from sha512sum), and recursum's own output its usual default, meow.
+/// Algorithms compiled into this build come before any which are not.
+pub fn algorithms_for_length(len: usize, format: Format) -> Vec<&'static str> {
+    let mut names = match len {
+        8 => vec!["crc32"],
+        32 => vec!["md5"],
+        40 => vec!["sha1", "git-sha1"],
+        64 => vec!["sha256", "git-sha256"],
+        128 if format == Format::Recursum => vec!["meow", "sha512"],
+        128 => vec!["sha512", "meow"],
+        _ => Vec::new(),
+    };
+    names.sort_by_key(|name| hashers::get(name).is_none());
+    names
+}
+
+/// Undo GNU coreutils' escaping of backslashes and newlines in paths.
+fn unescape_coreutils(path: &str) -> String {
+    path.replace("\\\\", "\0")
+        .replace("\\n", "\n")
+        .replace('\0', "\\")
+}
+
+fn parse_coreutils(line: &str) -> Option<Entry> {
+    let (escaped, line) = match line.strip_prefix(ESCAPED_PREFIX) {
+        Some(l) => (true, l),
+        None => (false, line),
+    };
+    let (digest, rest) = line.split_once(' ')?;
+    // second character is ' ' for text mode or '*' for binary mode
+    let path = rest.strip_prefix(' ').or_else(|| rest.strip_prefix('*'))?;
+    if !is_digest(digest) || path.is_empty() {
+        return None;
+    }
+    Some(Entry {
+        path: if escaped {
+            unescape_coreutils(path)
+        } else {
+            path.to_string()
+        },
+        digest: digest.to_string(),
+        size: None,
+    })
+}
+
+/// Algorithm and entry.
+fn parse_bsd_tag(line: &str) -> Option<(String, Entry)> {
+    let (algorithm, rest) = line.split_once(" (")?;
+    let (path, digest) = rest.rsplit_once(") = ")?;
+    if algorithm.is_empty() || algorithm.contains(' ') || !is_hex(digest) {
+        return None;
+    }
+    Some((
+        algorithm_name(algorithm),
+        Entry {
+            path: path.to_string(),
+            digest: digest.to_string(),
+            size: None,
+        },
+    ))
+}
+
+fn parse_separated(line: &str, separator: &str, hash_first: bool) -> Option<Entry> {
+    let (path, digest) = if hash_first {
+        let (d, p) = line.split_once(separator)?;
+        (p, d)
+    } else {
+        line.rsplit_once(separator)?
+    };
+    if !is_digest(digest) || path.is_empty() {
+        return None;
+    }
+    Some(Entry {
+        path: path.to_string(),
+        digest: digest.to_string(),
+        size: None,
+    })
+}
+
+fn parse_sfv(line: &str) -> Option<Entry> {
+    let (path, digest) = line.trim_end().rsplit_once(' ')?;
+    let path = path.trim_end();
+    if digest.len() != SFV_DIGEST_LENGTH || !is_hex(digest) || path.is_empty() {
+        return None;
+    }
+    Some(Entry {
+        path: path.to_string(),
+        digest: digest.to_string(),
+        size: None,
+    })
+}
+
+fn parse_json_line(line: &str) -> Option<(Option<String>, Entry)> {
+    let object = parse_json_object(line)?;
+    let path = object.get("path")?;
+    let digest = object.get("digest").or_else(|| object.get("hash"))?;
+    if !is_digest(digest) {
+        return None;
+    }
+    Some((
+        object.get("algorithm").map(|a| algorithm_name(a)),
+        Entry {
+            path: path.clone(),
+            digest: digest.clone(),
+            size: object.get("size").and_then(|s| s.parse().ok()),
+        },
+    ))
+}
+
+/// Members of a flat JSON object whose values are strings, numbers, booleans or null,
+/// with non-string values as written.
+fn parse_json_object(s: &str) -> Option<HashMap<String, String>> {
+    let mut chars = s.trim().chars().peekable();
+    let mut object = HashMap::new();
+    let skip_ws = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+    };
+    if chars.next()? != '{' {
+        return None;
+    }
+    skip_ws(&mut chars);
+    if chars.peek() == Some(&'}') {
+        chars.next();
+        return chars.next().is_none().then_some(object);
+    }
+    loop {
+        skip_ws(&mut chars);
+        let key = parse_json_string(&mut chars)?;
+        skip_ws(&mut chars);
+        if chars.next()? != ':' {
+            return None;
+        }
+        skip_ws(&mut chars);
+        let value = if chars.peek() == Some(&'"') {
+            parse_json_string(&mut chars)?
+        } else {
+            let mut scalar = String::new();
+            while let Some(c) = chars
+                .peek()
+                .filter(|c| !matches!(c, ',' | '}') && !c.is_whitespace())
+            {
+                scalar.push(*c);
+                chars.next();
+            }
+            if scalar.is_empty() {
+                return None;
+            }
+            scalar
+        };
+        object.insert(key, value);
+        skip_ws(&mut chars);
+        match chars.next()? {
+            ',' => continue,
+            '}' => break,
+            _ => return None,
+        }
+    }
+    chars.next().is_none().then_some(object)
+}
+
+/// Four hex digits of a `\u` escape.
+fn json_code_unit(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<u32> {
+    let hex: String = chars.by_ref().take(4).collect();
+    u32::from_str_radix(&hex, 16).ok()
+}
+
+fn parse_json_string(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<String> {
+    if chars.next()? != '"' {
+        return None;
+    }
+    let mut s = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(s),
+            '\\' => match chars.next()? {
+                'n' => s.push('\n'),
+                't' => s.push('\t'),
+                'r' => s.push('\r'),
+                'b' => s.push('\u{8}'),
+                'f' => s.push('\u{c}'),
+                'u' => {
+                    let high = json_code_unit(chars)?;
+                    let code = if (0xd800..0xdc00).contains(&high) {
+                        // surrogate pair
+                        if chars.next()? != '\\' || chars.next()? != 'u' {
+                            return None;
+                        }
+                        let low = json_code_unit(chars)?;
+                        0x10000 + ((high - 0xd800) << 10) + (low.checked_sub(0xdc00)?)
+                    } else {
+                        high
+                    };
+                    s.push(char::from_u32(code)?);
+                }
+                other => s.push(other),
+            },
+            c => s.push(c),
+        }
+    }
+}
+
+/// Work out the format from the first line of content.
+fn detect(line: &str) -> Option<Format> {
+    if line.starts_with(HASHDEEP_MAGIC) {
+        Some(Format::Hashdeep)
+    } else if line.starts_with('{') {
+        Some(Format::JsonLines)
+    } else if line.starts_with(SFV_COMMENT) {
+        Some(Format::Sfv)
+    } else if parse_bsd_tag(line).is_some() {
+        Some(Format::BsdTag)
+    } else if parse_coreutils(line).is_some() {
+        Some(Format::Coreutils)
+    } else if parse_separated(line, crate::DEFAULT_SEPARATOR, false).is_some() {
+        Some(Format::Recursum)
+    } else if parse_sfv(line).is_some() {
+        Some(Format::Sfv)
+    } else {
+        None
+    }
+}
+
+fn open(path: &Path) -> io::Result<Box<dyn Read>> {
+    if path.as_os_str() == "-" {
+        return Ok(Box::new(io::stdin()));
+    }
+    let file = File::open(path)?;
+    Ok(match Compression::from_path(path) {
+        Compression::None => Box::new(file),
+        #[cfg(feature = "gzip")]
+        Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(file)),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Box::new(zstd::Decoder::new(file)?),
+        #[allow(unreachable_patterns)]
+        other => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{:?} compression was not compiled in", other),
+            ))
+        }
+    })
+}
+
+/// The lines of one run of a file appended to by several runs, each starting with a header:
+/// the `run`th (counting from 1), or the last.
+/// Lines before the first header (from a run without one) count as a run of their own.
+fn select_run(mut lines: Vec<String>, run: Option<usize>) -> Result<Vec<String>, String> {
+    let mut starts: Vec<_> = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, l)| l.starts_with(header::MAGIC))
+        // an mtree signature belongs with the header after it
+        .map(|(i, _)| match i.checked_sub(1) {
+            Some(prev) if lines[prev].starts_with(mtree::SIGNATURE) => prev,
+            _ => i,
+        })
+        .collect();
+    if starts.first() != Some(&0) {
+        starts.insert(0, 0);
+    }
+    let n_runs = starts.len();
+    let idx = match run {
+        None => n_runs - 1,
+        Some(n) if n >= 1 && n <= n_runs => n - 1,
+        Some(n) => return Err(format!("there is no run {}; the file has {}", n, n_runs)),
+    };
+    if let Some(&end) = starts.get(idx + 1) {
+        lines.truncate(end);
+    }
+    Ok(lines.split_off(starts[idx]))
+}
+
+fn read_manifest(reader: impl Read, run: Option<usize>) -> Result<Manifest, String> {
+    let lines = BufReader::new(reader)
+        .lines()
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+    let lines = select_run(lines, run)?;
+
+    let mut manifest = Manifest {
+        format: Format::Recursum,
+        algorithm: None,
+        seed: None,
+        digest_length: None,
+        root: None,
+        relative_to: None,
+        redact: None,
+        entries: Vec::new(),
+        malformed: 0,
+    };
+    let is_mtree = lines
+        .first()
+        .is_some_and(|l| l.starts_with(mtree::SIGNATURE));
+    let signature_lines = if is_mtree { 1 } else { 0 };
+    let (header, start) = match Header::parse(&lines[signature_lines..]) {
+        Some((h, n)) => (Some(h), n + signature_lines),
+        None => (None, signature_lines),
+    };
+    let body = &lines[start..];
+    if is_mtree {
+        manifest.format = Format::Mtree;
+    }
+    let is_content = |l: &&String| !l.is_empty() && !l.starts_with('#');
+
+    let separated = match &header {
+        Some(h) => {
+            manifest.algorithm = Some(h.algorithm.clone());
+            manifest.seed = h.seed.clone();
+            manifest.digest_length = h.digest_length;
+            manifest.root = h.root.clone();
+            manifest.relative_to = h.relative_to.clone();
+            manifest.redact = h.redact.clone();
+            (h.separator.clone(), h.hash_first)
+        }
+        None if is_mtree => (String::new(), false),
+        None => {
+            let first = body
+                .iter()
+                .find(is_content)
+                .ok_or("manifest lists no files")?;
+            manifest.format = detect(first).ok_or("unrecognised manifest format")?;
+            if manifest.format == Format::Sfv {
+                manifest.algorithm = Some("crc32".to_string());
+            }
+            (crate::DEFAULT_SEPARATOR.to_string(), false)
+        }
+    };
+
+    if is_mtree {
+        let (entries, algorithm, malformed) = mtree::parse(body);
+        manifest.entries = entries;
+        manifest.malformed = malformed;
+        if manifest.algorithm.is_none() {
+            manifest.algorithm = algorithm;
+        }
+        return Ok(manifest);
+    }
+
+    // hashdeep: indices of the column for the chosen algorithm and of the size column, and number of columns
+    let mut hashdeep_columns: Option<(usize, Option<usize>, usize)> = None;
+    for line in body.iter().filter(is_content) {
+        let entry = match manifest.format {
+            Format::Recursum => parse_separated(line, &separated.0, separated.1),
+            Format::Coreutils => parse_coreutils(line),
+            Format::BsdTag => {
+                parse_bsd_tag(line).and_then(|(algorithm, entry)| match &manifest.algorithm {
+                    Some(a) if *a != algorithm => None,
+                    _ => {
+                        manifest.algorithm = Some(algorithm);
+                        Some(entry)
+                    }
+                })
+            }
+            Format::Mtree => unreachable!("mtree specs are parsed as a whole"),
+            Format::Sfv => {
+                if line.starts_with(SFV_COMMENT) {
+                    continue;
+                }
+                parse_sfv(line)
+            }
+            Format::JsonLines => parse_json_line(line).and_then(|(algorithm, entry)| {
+                match (&manifest.algorithm, algorithm) {
+                    (Some(a), Some(b)) if *a != b => None,
+                    (_, Some(b)) => {
+                        manifest.algorithm = Some(b);
+                        Some(entry)
+                    }
+                    _ => Some(entry),
+                }
+            }),
+            Format::Hashdeep => {
+                if line.starts_with(HASHDEEP_MAGIC) {
+                    continue;
+                }
+                if let Some(columns) = line.strip_prefix(HASHDEEP_COLUMNS) {
+                    // e.g. size,md5,sha256,filename
+                    let columns: Vec<_> = columns.split(',').map(algorithm_name).collect();
+                    let column = columns
+                        .iter()
+                        .position(|c| hashers::get(c).is_some())
+                        .ok_or_else(|| {
+                            format!(
+                                "none of the algorithms in this hashdeep manifest ({}) are supported",
+                                columns[1..columns.len().saturating_sub(1)].join(", ")
+                            )
+                        })?;
+                    manifest.algorithm = Some(columns[column].clone());
+                    let size_column = columns.iter().position(|c| c == "size");
+                    hashdeep_columns = Some((column, size_column, columns.len()));
+                    continue;
+                }
+                let (column, size_column, n_columns) =
+                    hashdeep_columns.ok_or("hashdeep manifest has no column names")?;
+                let fields: Vec<_> = line.splitn(n_columns, ',').collect();
+                match fields.get(column) {
+                    Some(digest) if fields.len() == n_columns && is_hex(digest) => Some(Entry {
+                        path: fields[n_columns - 1].to_string(),
+                        digest: digest.to_string(),
+                        size: size_column.and_then(|i| fields[i].parse().ok()),
+                    }),
+                    _ => None,
+                }
+            }
+        };
+        match entry {
+            Some(e) => manifest.entries.push(e),
+            None => manifest.malformed += 1,
+        }
+    }
+    Ok(manifest)
+}
+
+/// Read and parse the manifest at `path`, or stdin if it is `-`.
+///
+/// If several runs were appended to it, only the last is read.
+pub fn load(path: &Path) -> Result<Manifest, String> {
+    load_run(path, None)
+}
+
+/// As [load], but reading the `run`th run (counting from 1) of a file appended to by several runs.
+pub fn load_run(path: &Path, run: Option<usize>) -> Result<Manifest, String> {
+    open(path)
+        .map_err(|e| e.to_string())
+        .and_then(|r| read_manifest(r, run))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(len: usize) -> String {
+        "0123456789abcdef".repeat(len / 16 + 1)[..len].to_string()
+    }
+
+    #[test]
+    fn splits_algorithm_prefixes() {
+        assert_eq!(
+            split_algorithm("crc32:d159a670"),
+            (Some("crc32"), "d159a670")
+        );
+        assert_eq!(
+            split_algorithm("!crc32:d159a670"),
+            (Some("crc32"), "d159a670")
+        );
+        assert_eq!(split_algorithm("d159a670"), (None, "d159a670"));
+        assert_eq!(split_algorithm("!d159a670"), (None, "d159a670"));
+    }
+
+    #[test]
+    fn detects_formats() {
+        let sha512 = digest(128);
+        assert_eq!(
+            detect(&format!("{}  a.txt", sha512)),
+            Some(Format::Coreutils)
+        );
+        assert_eq!(
+            detect(&format!("{} *a.txt", sha512)),
+            Some(Format::Coreutils)
+        );
+        assert_eq!(
+            detect(&format!("a.txt\t{}", sha512)),
+            Some(Format::Recursum)
+        );
+        assert_eq!(
+            detect(&format!("a.txt\tcrc32:{}", digest(8))),
+            Some(Format::Recursum)
+        );
+        assert_eq!(
+            detect(&format!("SHA512 (a.txt) = {}", sha512)),
+            Some(Format::BsdTag)
+        );
+        assert_eq!(detect("a.txt d159a670"), Some(Format::Sfv));
+        assert_eq!(detect("; made by cksfv"), Some(Format::Sfv));
+        assert_eq!(
+            detect(r#"{"path": "a.txt", "digest": "d159a670"}"#),
+            Some(Format::JsonLines)
+        );
+        assert_eq!(detect("%%%% HASHDEEP-1.0"), Some(Format::Hashdeep));
+        assert_eq!(detect("not a manifest"), None);
+    }
+
+    #[test]
+    fn unique_lengths() {
+        for format in [Format::Recursum, Format::Coreutils] {
+            assert_eq!(algorithms_for_length(8, format), ["crc32"]);
+            assert_eq!(algorithms_for_length(32, format), ["md5"]);
+            assert!(algorithms_for_length(14, format).is_empty());
+        }
+    }
+
+    #[test]
+    fn shared_lengths_are_ambiguous() {
+        for (len, names) in [
+            (40, ["sha1", "git-sha1"]),
+            (64, ["sha256", "git-sha256"]),
+            (128, ["sha512", "meow"]),
+        ] {
+            for format in [Format::Recursum, Format::Coreutils, Format::JsonLines] {
+                let mut guessed = algorithms_for_length(len, format);
+                guessed.sort_unstable();
+                let mut expected = names.to_vec();
+                expected.sort_unstable();
+                assert_eq!(guessed, expected, "{} characters in {}", len, format);
+            }
+        }
+    }
+
+    #[cfg(all(feature = "sha", feature = "git", feature = "meow"))]
+    #[test]
+    fn shared_lengths_prefer_the_standard_algorithm_for_other_tools() {
+        assert_eq!(algorithms_for_length(40, Format::Coreutils)[0], "sha1");
+        assert_eq!(algorithms_for_length(64, Format::Coreutils)[0], "sha256");
+        assert_eq!(algorithms_for_length(128, Format::Coreutils)[0], "sha512");
+        assert_eq!(algorithms_for_length(128, Format::JsonLines)[0], "sha512");
+        assert_eq!(algorithms_for_length(128, Format::Recursum)[0], "meow");
+    }
+
+    #[test]
+    fn reads_a_sha512sum_manifest() {
+        let text = format!("{}  a.txt\n{}  b.txt\n", digest(128), digest(128));
+        let manifest = read_manifest(text.as_bytes(), None).unwrap();
+        assert_eq!(manifest.format, Format::Coreutils);
+        assert_eq!(manifest.algorithm, None);
+        assert_eq!(manifest.entries.len(), 2);
+        assert_eq!(manifest.entries[1].path, "b.txt");
+        assert_eq!(manifest.malformed, 0);
+    }
+}
@@ -0,0 +1,252 @@
+//! The mtree(8) specification format, as used by BSD and pkg tooling:
+//! one line per file, with its path and `keyword=value` pairs for its type, size, mode and digest.
+//!
+//! Paths are encoded as by vis(3), so that spaces, `#`, `=` and non-printable bytes cannot break the line up.
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use recursum::hashers;
+
+use crate::manifest::Entry;
+
+/// First line of every mtree file.
+pub const SIGNATURE: &str = "#mtree";
+/// Ends the name of the keyword holding each digest, e.g. sha256digest.
+const DIGEST_SUFFIX: &str = "digest";
+
+/// The keyword for digests from the algorithm, e.g. sha256 -> sha256digest, git-sha1 -> gitsha1digest.
+///
+/// Those for md5, sha1, sha256 and sha512 are standard; the rest are only understood by recursum.
+pub fn keyword(algorithm: &str) -> String {
+    format!("{}{}", algorithm.replace('-', ""), DIGEST_SUFFIX)
+}
+
+/// The algorithm whose digests a keyword holds, if it is a digest keyword,
+/// given the names of the registered algorithms.
+fn algorithm(keyword: &str, names: &[String]) -> Option<String> {
+    let name = keyword.strip_suffix(DIGEST_SUFFIX)?;
+    if name.is_empty() {
+        return None;
+    }
+    Some(
+        names
+            .iter()
+            .find(|n| n.replace('-', "") == name)
+            .cloned()
+            .unwrap_or_else(|| name.to_string()),
+    )
+}
+
+/// Encode a path as by vis(3) with VIS_WHITE | VIS_OCTAL, as mtree does.
+fn encode_path(path: &Path) -> String {
+    #[cfg(unix)]
+    let bytes = {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    };
+    #[cfg(not(unix))]
+    let bytes = path.to_string_lossy().into_owned().into_bytes();
+
+    let mut out = String::with_capacity(bytes.len());
+    for b in bytes {
+        if b.is_ascii_graphic() && b != b'\\' && b != b'#' && b != b'=' {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("\\{:03o}", b));
+        }
+    }
+    out
+}
+
+/// Undo [encode_path], and the other escapes other mtree writers use.
+fn decode_path(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' || i + 1 == bytes.len() {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let octal = bytes[i + 1..]
+            .iter()
+            .take(3)
+            .all(|b| (b'0'..=b'7').contains(b))
+            && bytes.len() >= i + 4;
+        if octal {
+            let digits = std::str::from_utf8(&bytes[i + 1..i + 4]).expect("octal digits are ASCII");
+            out.push(u8::from_str_radix(digits, 8).unwrap_or(b'?'));
+            i += 4;
+            continue;
+        }
+        out.push(match bytes[i + 1] {
+            b's' => b' ',
+            b't' => b'\t',
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b'\\' => b'\\',
+            other => other,
+        });
+        i += 2;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Permission bits of the file, as mtree's mode keyword.
+#[cfg(unix)]
+fn mode(path: &Path) -> io::Result<String> {
+    use std::os::unix::fs::PermissionsExt;
+    let meta = fs::symlink_metadata(path)?;
+    Ok(format!("{:04o}", meta.permissions().mode() & 0o7777))
+}
+
+#[cfg(not(unix))]
+fn mode(path: &Path) -> io::Result<String> {
+    let meta = fs::symlink_metadata(path)?;
+    Ok(if meta.permissions().readonly() {
+        "0444"
+    } else {
+        "0644"
+    }
+    .to_string())
+}
+
+/// The line describing a hashed file, found at `path`, and described as `relative` (to the root of the spec).
+pub fn line(
+    path: &Path,
+    relative: &Path,
+    size: u64,
+    keyword: &str,
+    digest: &str,
+) -> io::Result<String> {
+    let mut name = encode_path(relative);
+    if !relative.is_absolute() && !name.starts_with("./") {
+        name.insert_str(0, "./");
+    }
+    Ok(format!(
+        "{} type=file size={} mode={} {}={}",
+        name,
+        size,
+        mode(path)?,
+        keyword,
+        digest
+    ))
+}
+
+/// `keyword=value` pairs, and any bare words (e.g. `ignore`).
+fn keywords<'a>(words: impl Iterator<Item = &'a str>) -> HashMap<&'a str, &'a str> {
+    words
+        .map(|w| w.split_once('=').unwrap_or((w, "")))
+        .collect()
+}
+
+/// The files in an mtree spec, the algorithm of their digests, and how many lines could not be read.
+///
+/// Understands both full paths (as from `bsdtar --format mtree` and recursum)
+/// and the hierarchical form (as from `mtree -c`), where names without a slash are relative to
+/// the last directory entered, and `..` leaves it; `/set` defaults are applied to later entries.
+/// Only entries of type file with a digest are returned.
+pub fn parse<S: AsRef<str>>(lines: &[S]) -> (Vec<Entry>, Option<String>, usize) {
+    let mut entries = Vec::new();
+    let mut algorithm_found: Option<String> = None;
+    let mut malformed = 0;
+    let mut defaults: HashMap<String, String> = HashMap::new();
+    let mut cwd: Vec<String> = Vec::new();
+    let names = hashers::names();
+
+    // join lines continued with a trailing backslash
+    let mut joined = Vec::new();
+    let mut current = String::new();
+    for line in lines {
+        let line = line.as_ref();
+        match line.strip_suffix('\\') {
+            Some(start) => current.push_str(start),
+            None => {
+                current.push_str(line);
+                joined.push(std::mem::take(&mut current));
+            }
+        }
+    }
+
+    for line in &joined {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let name = words.next().expect("line is not empty");
+        match name {
+            "/set" => {
+                for (k, v) in keywords(words) {
+                    defaults.insert(k.to_string(), v.to_string());
+                }
+                continue;
+            }
+            "/unset" => {
+                for (k, _) in keywords(words) {
+                    defaults.remove(k);
+                }
+                continue;
+            }
+            ".." => {
+                cwd.pop();
+                continue;
+            }
+            _ => (),
+        }
+        let own = keywords(words);
+        let get = |k: &str| {
+            own.get(k)
+                .map(|v| v.to_string())
+                .or_else(|| defaults.get(k).cloned())
+        };
+        let name = decode_path(name);
+        let full_path = name.contains('/');
+        let path = if full_path {
+            name.clone()
+        } else {
+            cwd.iter()
+                .chain(std::iter::once(&name))
+                .cloned()
+                .collect::<Vec<_>>()
+                .join("/")
+        };
+        let kind = get("type").unwrap_or_else(|| "file".to_string());
+        if kind == "dir" {
+            if !full_path {
+                cwd.push(name);
+            }
+            continue;
+        }
+        if kind != "file" {
+            continue;
+        }
+        // any digest of the algorithm already found; otherwise, preferably one recursum supports
+        let mut digests: Vec<(String, String)> = own
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .chain(defaults.iter().map(|(k, v)| (k.clone(), v.clone())))
+            .filter_map(|(k, v)| Some((algorithm(&k, &names)?, v)))
+            .collect();
+        digests.sort_by_key(|(a, _)| (!names.contains(a), a.clone()));
+        let digest = digests
+            .into_iter()
+            .find(|(a, _)| algorithm_found.as_ref().is_none_or(|found| found == a));
+        match digest {
+            Some((a, digest)) => {
+                algorithm_found = Some(a);
+                let path = path.strip_prefix("./").unwrap_or(&path).to_string();
+                entries.push(Entry {
+                    path,
+                    digest,
+                    size: get("size").and_then(|s| s.parse().ok()),
+                });
+            }
+            None => malformed += 1,
+        }
+    }
+    (entries, algorithm_found, malformed)
+}
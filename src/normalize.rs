@@ -0,0 +1,132 @@
+//! Writing and matching paths in one Unicode normalization form, for --normalize-paths,
+//! so that manifests made on macOS (which stores names decomposed, as NFD) and on Linux
+//! (where names are usually composed, as NFC) agree on accented file names.
+//!
+//! Paths which are not valid UTF-8 are left as they are.
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use unicode_normalization::UnicodeNormalization;
+
+use recursum::sink::OutputSink;
+use recursum::{FileHash, HashError};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NormalForm {
+    /// Canonical composition, e.g. é as one character.
+    Nfc,
+    /// Canonical decomposition, e.g. é as e and a combining acute accent.
+    Nfd,
+}
+
+impl FromStr for NormalForm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nfc" => Ok(Self::Nfc),
+            "nfd" => Ok(Self::Nfd),
+            _ => Err(format!(
+                "unknown normalization '{}'; expected nfc or nfd",
+                s
+            )),
+        }
+    }
+}
+
+impl NormalForm {
+    pub fn apply(self, s: &str) -> String {
+        match self {
+            Self::Nfc => s.nfc().collect(),
+            Self::Nfd => s.nfd().collect(),
+        }
+    }
+
+    pub fn path(self, path: &Path) -> PathBuf {
+        match path.to_str() {
+            Some(p) => PathBuf::from(self.apply(p)),
+            None => path.to_path_buf(),
+        }
+    }
+
+    /// The path with the part under `root` normalized, and `root` itself left as given,
+    /// so that outputs which write paths relative to it still find it.
+    fn path_under(self, path: &Path, root: Option<&Path>) -> PathBuf {
+        match root.and_then(|r| path.strip_prefix(r).ok().map(|rel| (r, rel))) {
+            Some((root, relative)) => root.join(self.path(relative)),
+            None => self.path(path),
+        }
+    }
+}
+
+/// The file `path` names: `path` itself if it exists, or else the same path in either form if that does,
+/// for reading a manifest made on a system which stores names in the other form.
+pub fn find(path: &Path) -> PathBuf {
+    if path.symlink_metadata().is_ok() {
+        return path.to_path_buf();
+    }
+    [NormalForm::Nfc, NormalForm::Nfd]
+        .iter()
+        .map(|form| form.path(path))
+        .find(|p| p.symlink_metadata().is_ok())
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// The same error, for another path.
+fn copy_error(e: &io::Error) -> io::Error {
+    match e.raw_os_error() {
+        Some(code) => io::Error::from_raw_os_error(code),
+        None => io::Error::new(e.kind(), e.to_string()),
+    }
+}
+
+/// Passes results to another output with their paths normalized.
+pub struct NormalizePaths {
+    inner: Box<dyn OutputSink>,
+    form: NormalForm,
+    /// Only the part of paths under this directory is normalized, if given.
+    root: Option<PathBuf>,
+}
+
+impl NormalizePaths {
+    pub fn new(inner: Box<dyn OutputSink>, form: NormalForm, root: Option<PathBuf>) -> Self {
+        Self { inner, form, root }
+    }
+
+    fn normalize(&self, path: &Path) -> PathBuf {
+        self.form.path_under(path, self.root.as_deref())
+    }
+}
+
+impl OutputSink for NormalizePaths {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        self.inner.on_result(&FileHash {
+            path: self.normalize(&result.path),
+            digest: result.digest.clone(),
+            size: result.size,
+            stable: result.stable,
+            shared_extents: result
+                .shared_extents
+                .as_ref()
+                .map(|r| r.as_ref().cloned().map_err(copy_error)),
+            elapsed: result.elapsed,
+        })
+    }
+
+    fn on_error(&mut self, error: &HashError) -> io::Result<()> {
+        self.inner.on_error(&HashError {
+            path: self.normalize(&error.path),
+            phase: error.phase,
+            source: copy_error(&error.source),
+        })
+    }
+
+    fn on_skipped(&mut self, path: &Path, reason: &str) -> io::Result<()> {
+        self.inner.on_skipped(&self.normalize(path), reason)
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        self.inner.on_finish()
+    }
+}
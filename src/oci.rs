@@ -0,0 +1,557 @@
+//! Verify container images, as OCI image layouts or `docker save` tarballs:
+//! each blob must match the digest it is named by and referred to with,
+//! and optionally every file inside each layer is hashed too.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use serde_json::Value;
+use structopt::StructOpt;
+use tokio_stream::{iter, StreamExt};
+
+use recursum::sink::OutputSink;
+use recursum::{hash_reader, hash_stream, hashers, runtime, FileHash, HashOptions};
+
+use crate::color::{self, Role};
+use crate::output::{Compression, TextOutput};
+use crate::{hasher_names, or_num_cpus, select_hasher};
+
+/// Files in a tarball up to this size are kept in memory, in case they are manifests or configs.
+const MAX_DOCUMENT_SIZE: u64 = 4 << 20;
+const OCI_INDEX: &str = "index.json";
+const DOCKER_MANIFEST: &str = "manifest.json";
+const INDEX_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+const MANIFEST_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.docker.distribution.manifest.v2+json",
+];
+
+#[derive(Debug, StructOpt)]
+pub struct OciOpt {
+    /// OCI image layout directory, or tarball from `docker save` (or of an OCI image layout).
+    #[structopt(parse(from_os_str))]
+    image: PathBuf,
+    /// Also hash every file inside each layer, writing a manifest of them to FILE, with paths as <layer digest>/<path in layer>. Compressed if the name ends in .gz or .zst.
+    #[structopt(long = "files", parse(from_os_str), value_name = "FILE")]
+    files: Option<PathBuf>,
+    /// Hashing algorithm for --files. Blobs are always checked with the algorithm of their digests.
+    #[structopt(long = "hash", default_value = hashers::AUTO, possible_values = hasher_names())]
+    hash: String,
+    /// Hashing threads.
+    #[structopt(short = "t", long = "threads")]
+    threads: Option<usize>,
+    /// Do not print OK for each blob which matches; only failures are printed.
+    #[structopt(long = "quiet-ok")]
+    quiet_ok: bool,
+}
+
+/// Where the image's files are.
+enum Layout {
+    Dir(PathBuf),
+    Tar(PathBuf),
+}
+
+/// A file in the image.
+struct Blob {
+    /// e.g. sha256:abc...
+    digest: String,
+    size: u64,
+}
+
+/// A layer whose files are to be hashed.
+struct Layer {
+    /// Path within the image.
+    name: String,
+    /// Prefixed to the paths of its files.
+    label: String,
+}
+
+struct Image {
+    layout: Layout,
+    /// By path within the image.
+    blobs: BTreeMap<String, Blob>,
+    /// Contents of small files from a tarball, by path within it.
+    documents: HashMap<String, Vec<u8>>,
+}
+
+/// The algorithm of the digest a blob should have, from its path within the image.
+fn blob_algorithm(name: &str) -> &'static str {
+    if name.starts_with("blobs/sha512/") {
+        "sha512"
+    } else {
+        "sha256"
+    }
+}
+
+/// The digest a blob is named by, e.g. blobs/sha256/abc... -> sha256:abc...
+fn named_digest(name: &str) -> Option<String> {
+    let (algorithm, hex) = name.strip_prefix("blobs/")?.split_once('/')?;
+    Some(format!("{}:{}", algorithm, hex))
+}
+
+/// Where a blob with this digest is in an OCI image layout.
+fn blob_name(digest: &str) -> Option<String> {
+    let (algorithm, hex) = digest.split_once(':')?;
+    Some(format!("blobs/{}/{}", algorithm, hex))
+}
+
+fn hash_bytes(data: impl Read, algorithm: &str) -> io::Result<(String, u64)> {
+    let factory = hashers::get(algorithm).expect("blob algorithms are compiled in");
+    let (hash, size) = hash_reader(data, factory())?;
+    Ok((format!("{}:{}", algorithm, hex::encode(hash)), size))
+}
+
+impl Image {
+    /// Hash every file in the image.
+    fn load(path: &Path, threads: usize) -> io::Result<Self> {
+        if path.is_dir() {
+            Self::load_dir(path, threads)
+        } else {
+            Self::load_tar(path)
+        }
+    }
+
+    fn load_dir(dir: &Path, threads: usize) -> io::Result<Self> {
+        let mut by_algorithm: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for entry in walkdir::WalkDir::new(dir).sort_by_file_name() {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let relative = entry
+                .path()
+                .strip_prefix(dir)
+                .expect("walked paths are under the root");
+            let name = relative
+                .iter()
+                .map(|c| c.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            by_algorithm
+                .entry(blob_algorithm(&name))
+                .or_default()
+                .push(name);
+        }
+
+        let mut blobs = BTreeMap::new();
+        let rt = runtime(threads, false)?;
+        for (algorithm, names) in by_algorithm {
+            let hash_opts = HashOptions {
+                hasher: hashers::get(algorithm).expect("blob algorithms are compiled in"),
+                jobs: threads,
+                ..Default::default()
+            };
+            let paths: Vec<_> = names.iter().map(|n| dir.join(n)).collect();
+            let hashed = rt.block_on(async {
+                hash_stream(iter(paths), hash_opts)
+                    .collect::<Vec<_>>()
+                    .await
+            });
+            for (name, result) in names.into_iter().zip(hashed) {
+                let h = result.map_err(|e| io::Error::new(e.source.kind(), e.to_string()))?;
+                blobs.insert(
+                    name,
+                    Blob {
+                        digest: format!("{}:{}", algorithm, h.digest),
+                        size: h.size,
+                    },
+                );
+            }
+        }
+        Ok(Self {
+            layout: Layout::Dir(dir.to_path_buf()),
+            blobs,
+            documents: HashMap::new(),
+        })
+    }
+
+    /// A tarball can only be read in order, so its files are hashed one at a time as they are reached.
+    fn load_tar(path: &Path) -> io::Result<Self> {
+        let mut blobs = BTreeMap::new();
+        let mut documents = HashMap::new();
+        let mut archive = tar::Archive::new(File::open(path)?);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let name = tar_name(&entry)?;
+            let algorithm = blob_algorithm(&name);
+            let (digest, size) = if entry.size() <= MAX_DOCUMENT_SIZE {
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                let hashed = hash_bytes(data.as_slice(), algorithm)?;
+                documents.insert(name.clone(), data);
+                hashed
+            } else {
+                hash_bytes(&mut entry, algorithm)?
+            };
+            blobs.insert(name, Blob { digest, size });
+        }
+        Ok(Self {
+            layout: Layout::Tar(path.to_path_buf()),
+            blobs,
+            documents,
+        })
+    }
+
+    /// Parse a JSON file in the image, if it exists.
+    fn json(&self, name: &str) -> Result<Option<Value>, String> {
+        let data = match &self.layout {
+            Layout::Dir(dir) => match fs::read(dir.join(name)) {
+                Ok(d) => d,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(format!("could not read {}: {}", name, e)),
+            },
+            Layout::Tar(_) => match self.documents.get(name) {
+                Some(d) => d.clone(),
+                None => return Ok(None),
+            },
+        };
+        serde_json::from_slice(&data)
+            .map(Some)
+            .map_err(|e| format!("could not parse {}: {}", name, e))
+    }
+
+    /// Call `f` with the contents of each of the named files.
+    fn for_each_file(
+        &self,
+        names: &HashSet<&str>,
+        mut f: impl FnMut(&str, &mut dyn Read) -> io::Result<()>,
+    ) -> io::Result<()> {
+        match &self.layout {
+            Layout::Dir(dir) => {
+                for (name, _) in self
+                    .blobs
+                    .iter()
+                    .filter(|(n, _)| names.contains(n.as_str()))
+                {
+                    f(name, &mut File::open(dir.join(name))?)?;
+                }
+            }
+            Layout::Tar(path) => {
+                let mut archive = tar::Archive::new(File::open(path)?);
+                for entry in archive.entries()? {
+                    let mut entry = entry?;
+                    let name = tar_name(&entry)?;
+                    if names.contains(name.as_str()) {
+                        f(&name, &mut entry)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Path of a tarball entry, without any leading ./
+fn tar_name<R: Read>(entry: &tar::Entry<R>) -> io::Result<String> {
+    let path = entry.path()?;
+    let name = path.to_string_lossy();
+    Ok(name.strip_prefix("./").unwrap_or(&name).to_string())
+}
+
+/// Prints the outcome of each check, and counts failures.
+struct Checker<'a> {
+    image: &'a Image,
+    out: BufWriter<io::Stdout>,
+    quiet_ok: bool,
+    failures: usize,
+    /// Blobs whose references have been checked.
+    seen: HashSet<String>,
+    layers: Vec<Layer>,
+}
+
+impl Checker<'_> {
+    fn report(&mut self, name: &str, status: &str) {
+        let ok = status == "OK";
+        if !ok {
+            self.failures += 1;
+        }
+        if !ok || !self.quiet_ok {
+            let status = color::stdout(Role::of_status(status), status);
+            writeln!(self.out, "{}: {}", name, status).expect("could not write output");
+        }
+    }
+
+    /// Every blob in an OCI layout is named by its digest.
+    fn check_named_blobs(&mut self) {
+        let image = self.image;
+        for (name, blob) in &image.blobs {
+            if let Some(expected) = named_digest(name) {
+                let status = if blob.digest == expected {
+                    "OK"
+                } else {
+                    "FAILED"
+                };
+                self.report(name, status);
+            }
+        }
+    }
+
+    /// Check that the blob a descriptor refers to exists and has the right size, and check what it refers to in turn.
+    fn check_descriptor(&mut self, descriptor: &Value, from: &str) -> Result<(), String> {
+        let digest = descriptor["digest"]
+            .as_str()
+            .ok_or_else(|| format!("a descriptor in {} has no digest", from))?;
+        let name =
+            blob_name(digest).ok_or_else(|| format!("invalid digest {} in {}", digest, from))?;
+        if !self.seen.insert(name.clone()) {
+            return Ok(());
+        }
+        let size = match self.image.blobs.get(&name) {
+            Some(b) => b.size,
+            None => {
+                self.report(&name, "MISSING");
+                return Ok(());
+            }
+        };
+        if descriptor["size"].as_u64().is_some_and(|s| s != size) {
+            self.report(&name, "FAILED size");
+        }
+        let media_type = descriptor["mediaType"].as_str().unwrap_or_default();
+        if INDEX_MEDIA_TYPES.contains(&media_type) {
+            self.check_index(&name)
+        } else if MANIFEST_MEDIA_TYPES.contains(&media_type) {
+            self.check_manifest(&name)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_index(&mut self, name: &str) -> Result<(), String> {
+        let index = self
+            .image
+            .json(name)?
+            .ok_or(format!("{} is missing", name))?;
+        for descriptor in index["manifests"].as_array().into_iter().flatten() {
+            self.check_descriptor(descriptor, name)?;
+        }
+        Ok(())
+    }
+
+    fn check_manifest(&mut self, name: &str) -> Result<(), String> {
+        let manifest = self
+            .image
+            .json(name)?
+            .ok_or(format!("{} is missing", name))?;
+        if !manifest["config"].is_null() {
+            self.check_descriptor(&manifest["config"], name)?;
+        }
+        for descriptor in manifest["layers"].as_array().into_iter().flatten() {
+            self.check_descriptor(descriptor, name)?;
+            if let Some(digest) = descriptor["digest"].as_str() {
+                self.layers.push(Layer {
+                    name: blob_name(digest).expect("digest was checked"),
+                    label: digest.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// `docker save` lists each image's config and layers, which may not be named by their digests;
+    /// the config records the digest of each (uncompressed) layer.
+    fn check_docker_manifest(&mut self, manifest: &Value) -> Result<(), String> {
+        for image in manifest.as_array().into_iter().flatten() {
+            let config_name = image["Config"]
+                .as_str()
+                .ok_or_else(|| format!("an image in {} has no Config", DOCKER_MANIFEST))?;
+            let config = match self.image.json(config_name)? {
+                Some(c) => c,
+                None => {
+                    self.report(config_name, "MISSING");
+                    continue;
+                }
+            };
+            // e.g. abc....json, named by its digest
+            if let Some(hex) = config_name.strip_suffix(".json") {
+                let blob = &self.image.blobs[config_name];
+                let status = if blob.digest == format!("sha256:{}", hex) {
+                    "OK"
+                } else {
+                    "FAILED"
+                };
+                self.report(config_name, status);
+            }
+            let diff_ids: Vec<_> = config["rootfs"]["diff_ids"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                .collect();
+            let layers: Vec<_> = image["Layers"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                .collect();
+            for (i, layer) in layers.into_iter().enumerate() {
+                if !self.seen.insert(layer.to_string()) {
+                    continue;
+                }
+                let blob = match self.image.blobs.get(layer) {
+                    Some(b) => b,
+                    None => {
+                        self.report(layer, "MISSING");
+                        continue;
+                    }
+                };
+                // layers named by their digests were checked with the other blobs
+                if named_digest(layer).is_none() {
+                    let status = match diff_ids.get(i) {
+                        Some(&id) if id == blob.digest => "OK",
+                        _ => "FAILED",
+                    };
+                    self.report(layer, status);
+                }
+                self.layers.push(Layer {
+                    name: layer.to_string(),
+                    label: blob.digest.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Undo any compression of a layer, detected from its first bytes.
+fn decompress<'a>(reader: &'a mut dyn Read) -> io::Result<Box<dyn Read + 'a>> {
+    let mut reader = BufReader::new(reader);
+    let magic = reader.fill_buf()?.to_vec();
+    Ok(if magic.starts_with(&[0x1f, 0x8b]) {
+        #[cfg(feature = "gzip")]
+        {
+            Box::new(flate2::read::MultiGzDecoder::new(reader))
+        }
+        #[cfg(not(feature = "gzip"))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "gzip compression was not compiled in",
+        ));
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        #[cfg(feature = "zstd")]
+        {
+            Box::new(zstd::Decoder::new(reader)?)
+        }
+        #[cfg(not(feature = "zstd"))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "zstd compression was not compiled in",
+        ));
+    } else {
+        Box::new(reader)
+    })
+}
+
+/// Hash every regular file in a layer.
+fn hash_layer(
+    layer: &mut dyn Read,
+    label: &str,
+    algorithm: &str,
+    output: &mut TextOutput,
+) -> io::Result<usize> {
+    let factory = hashers::get(algorithm).expect("hasher names are checked by clap");
+    let mut archive = tar::Archive::new(decompress(layer)?);
+    let mut count = 0;
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = PathBuf::from(label).join(tar_name(&entry)?);
+        let started = Instant::now();
+        let (hash, size) = hash_reader(entry, factory())?;
+        output.on_result(&FileHash {
+            path,
+            digest: hex::encode(hash),
+            size,
+            stable: true,
+            shared_extents: None,
+            elapsed: started.elapsed(),
+        })?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+pub fn run(opt: OciOpt) {
+    for algorithm in ["sha256", "sha512"] {
+        if hashers::get(algorithm).is_none() {
+            panic!(
+                "the oci subcommand needs {}, which was not compiled in (feature sha)",
+                algorithm
+            );
+        }
+    }
+    let threads = or_num_cpus(opt.threads);
+    let image = Image::load(&opt.image, threads)
+        .unwrap_or_else(|e| panic!("could not read image {:?}: {}", opt.image, e));
+
+    let mut checker = Checker {
+        image: &image,
+        out: BufWriter::new(io::stdout()),
+        quiet_ok: opt.quiet_ok,
+        failures: 0,
+        seen: HashSet::new(),
+        layers: Vec::new(),
+    };
+    checker.check_named_blobs();
+    let index = image.json(OCI_INDEX).unwrap_or_else(|e| panic!("{}", e));
+    let docker_manifest = image
+        .json(DOCKER_MANIFEST)
+        .unwrap_or_else(|e| panic!("{}", e));
+    if index.is_none() && docker_manifest.is_none() {
+        panic!(
+            "{:?} has neither an {} nor a {}; is it an OCI image layout or docker save tarball?",
+            opt.image, OCI_INDEX, DOCKER_MANIFEST
+        );
+    }
+    let checked = match (&index, &docker_manifest) {
+        // newer docker versions save an OCI layout with a manifest.json as well
+        (Some(_), _) => checker.check_index(OCI_INDEX),
+        (None, Some(m)) => checker.check_docker_manifest(m),
+        (None, None) => unreachable!("checked above"),
+    };
+    checked.unwrap_or_else(|e| panic!("{}", e));
+    checker.out.flush().expect("could not write output");
+    let failures = checker.failures;
+    let layers = checker.layers;
+    eprintln!(
+        "{} files in the image and {} layers checked",
+        image.blobs.len(),
+        layers.len()
+    );
+
+    if let Some(path) = &opt.files {
+        let algorithm = select_hasher(&opt.hash);
+        let mut output = TextOutput::new(crate::DEFAULT_SEPARATOR, false, None)
+            .write_to_file(path, Compression::from_path(path));
+        let labels: HashMap<_, _> = layers
+            .iter()
+            .map(|l| (l.name.as_str(), l.label.as_str()))
+            .collect();
+        let names: HashSet<_> = labels.keys().copied().collect();
+        let mut n_files = 0;
+        image
+            .for_each_file(&names, |name, reader| {
+                n_files += hash_layer(reader, labels[name], algorithm, &mut output)
+                    .map_err(|e| io::Error::new(e.kind(), format!("layer {}: {}", name, e)))?;
+                Ok(())
+            })
+            .and_then(|_| output.on_finish())
+            .unwrap_or_else(|e| panic!("could not hash files in layers: {}", e));
+        eprintln!("{} files in layers hashed", n_files);
+    }
+
+    if failures > 0 {
+        let msg = format!("WARNING: {} blob(s) missing or NOT matching", failures);
+        eprintln!("{}", color::stderr(Role::Bad, msg));
+        std::process::exit(1);
+    }
+}
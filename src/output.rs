@@ -0,0 +1,681 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, StdoutLock, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[cfg(feature = "gzip")]
+use flate2::write::GzEncoder;
+use recursum::sink::OutputSink;
+use recursum::{FileHash, HashError};
+use tempfile::NamedTempFile;
+
+use crate::encrypt::Recipients;
+use crate::header::Header;
+use crate::mtree;
+use crate::pg_copy;
+
+pub const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+/// Manifest name for files directly under the root when splitting output by directory.
+const ROOT_MANIFEST_NAME: &str = "_root";
+const MANIFEST_EXTENSION: &str = ".txt";
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+/// Conventional extension of age-encrypted files.
+const ENCRYPTED_EXTENSION: &str = "age";
+/// Prefixed to the digests of files which changed while being hashed.
+pub const UNSTABLE_PREFIX: &str = "!";
+/// Starts the line for a file not hashed on purpose, with --report-skipped; `check` ignores lines starting with `#`.
+const SKIPPED: &str = "# skipped";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Infer compression from a file extension, defaulting to none.
+    /// An encrypted file's extension is skipped, e.g. `hashes.tsv.gz.age` is compressed with gzip.
+    pub fn from_path(path: &Path) -> Self {
+        let path = match path.extension() {
+            Some(e) if e == ENCRYPTED_EXTENSION => Path::new(path.file_stem().unwrap_or_default()),
+            _ => path,
+        };
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("gz") => Self::Gzip,
+            Some("zst") | Some("zstd") => Self::Zstd,
+            _ => Self::None,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::None => "",
+            Self::Gzip => ".gz",
+            Self::Zstd => ".zst",
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "gzip" | "gz" => Ok(Self::Gzip),
+            "zstd" | "zst" => Ok(Self::Zstd),
+            _ => Err(format!("unknown compression '{}'", s)),
+        }
+    }
+}
+
+/// Where a manifest is written: a temporary file to move into place, the end of an existing file,
+/// or a device or pipe (e.g. `/dev/null`), which is written to directly rather than replaced.
+enum Target {
+    Temporary(NamedTempFile),
+    Append(File),
+    Special(File),
+}
+
+impl Write for Target {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Temporary(f) => f.write(buf),
+            Self::Append(f) | Self::Special(f) => f.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Temporary(f) => f.flush(),
+            Self::Append(f) | Self::Special(f) => f.flush(),
+        }
+    }
+}
+
+/// What the compressed stream is written to: the file, or an encrypted stream written to it.
+enum Layer {
+    Plain(BufWriter<Target>),
+    #[cfg(feature = "age")]
+    Encrypted(age::stream::StreamWriter<BufWriter<Target>>),
+}
+
+impl Layer {
+    fn new(target: Target, recipients: Option<&Recipients>) -> io::Result<Self> {
+        let buffered = BufWriter::with_capacity(WRITE_BUFFER_SIZE, target);
+        Ok(match recipients {
+            None => Self::Plain(buffered),
+            #[cfg(feature = "age")]
+            Some(r) => Self::Encrypted(r.encrypt(buffered)?),
+            #[cfg(not(feature = "age"))]
+            Some(r) => match *r {},
+        })
+    }
+
+    /// Finish any encrypted stream.
+    fn finish(self) -> io::Result<BufWriter<Target>> {
+        match self {
+            Self::Plain(w) => Ok(w),
+            #[cfg(feature = "age")]
+            Self::Encrypted(w) => w.finish(),
+        }
+    }
+}
+
+impl Write for Layer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(w) => w.write(buf),
+            #[cfg(feature = "age")]
+            Self::Encrypted(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(w) => w.flush(),
+            #[cfg(feature = "age")]
+            Self::Encrypted(w) => w.flush(),
+        }
+    }
+}
+
+enum Encoder {
+    Plain(Layer),
+    #[cfg(feature = "gzip")]
+    Gzip(GzEncoder<Layer>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<Layer>),
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(w) => w.write(buf),
+            #[cfg(feature = "gzip")]
+            Self::Gzip(w) => w.write(buf),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(w) => w.flush(),
+            #[cfg(feature = "gzip")]
+            Self::Gzip(w) => w.flush(),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+/// A (possibly compressed) manifest file which only appears at its destination once finished.
+///
+/// Records are written to a temporary file in the destination directory,
+/// which is renamed into place by `finish`.
+/// If the writer is dropped without finishing, the temporary file is deleted.
+///
+/// Alternatively, records can be appended to an existing manifest, as they are written.
+/// Destinations which are not regular files, like `/dev/null`, are written to directly.
+///
+/// New manifests can be encrypted to age recipients (after any compression).
+pub struct ManifestWriter {
+    destination: PathBuf,
+    encoder: Encoder,
+}
+
+impl ManifestWriter {
+    pub fn create(
+        destination: &Path,
+        compression: Compression,
+        recipients: Option<&Recipients>,
+    ) -> io::Result<Self> {
+        if std::fs::metadata(destination).is_ok_and(|m| !m.is_file()) {
+            let file = OpenOptions::new().write(true).open(destination)?;
+            return Self::new(destination, Target::Special(file), compression, recipients);
+        }
+        let dir = match destination.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        Self::new(
+            destination,
+            Target::Temporary(temporary_in(dir)?),
+            compression,
+            recipients,
+        )
+    }
+
+    /// Append to the manifest at `destination`, creating it if necessary.
+    ///
+    /// An uncompressed manifest which does not end with a newline (e.g. from a run which was killed)
+    /// first has its incomplete last line removed. Compressed records are appended as a new
+    /// gzip member or zstd frame, which decoders read as a continuation of the same stream.
+    pub fn append(destination: &Path, compression: Compression) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(destination)?;
+        if compression == Compression::None {
+            truncate_incomplete_line(&mut file)?;
+        }
+        Self::new(destination, Target::Append(file), compression, None)
+    }
+
+    fn new(
+        destination: &Path,
+        target: Target,
+        compression: Compression,
+        recipients: Option<&Recipients>,
+    ) -> io::Result<Self> {
+        let tmp = Layer::new(target, recipients)?;
+        let encoder = match compression {
+            Compression::None => Encoder::Plain(tmp),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Encoder::Gzip(GzEncoder::new(tmp, flate2::Compression::default())),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(tmp, ZSTD_LEVEL)?),
+            #[allow(unreachable_patterns)]
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("{:?} compression was not compiled in", other),
+                ))
+            }
+        };
+        Ok(Self {
+            destination: destination.to_path_buf(),
+            encoder,
+        })
+    }
+
+    /// Finish any compressed stream and move the manifest into place (or just sync it, if appending).
+    pub fn finish(self) -> io::Result<()> {
+        // only Plain exists if no compression is compiled in
+        #[allow(clippy::infallible_destructuring_match)]
+        let layer = match self.encoder {
+            Encoder::Plain(w) => w,
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(w) => w.finish()?,
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(w) => w.finish()?,
+        };
+        match layer.finish()?.into_inner().map_err(|e| e.into_error())? {
+            Target::Temporary(tmp) => {
+                // a manifest being replaced keeps its permissions, as it would if it were rewritten
+                if let Ok(existing) = std::fs::metadata(&self.destination) {
+                    tmp.as_file().set_permissions(existing.permissions())?;
+                }
+                tmp.as_file().sync_all()?;
+                tmp.persist(&self.destination).map_err(|e| e.error)?;
+            }
+            Target::Append(file) => file.sync_all()?,
+            Target::Special(_) => (),
+        }
+        Ok(())
+    }
+}
+
+/// A temporary file in `dir` with the permissions a newly created file would have,
+/// rather than the owner-only ones temporary files are usually given.
+fn temporary_in(dir: &Path) -> io::Result<NamedTempFile> {
+    let mut builder = tempfile::Builder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // narrowed by the umask, as for File::create
+        builder.permissions(std::fs::Permissions::from_mode(0o666));
+    }
+    builder.tempfile_in(dir)
+}
+
+/// Cut the file after its last newline, if it does not end with one.
+fn truncate_incomplete_line(file: &mut File) -> io::Result<()> {
+    let len = file.seek(SeekFrom::End(0))?;
+    let mut end = len;
+    let mut buf = [0; 4096];
+    while end > 0 {
+        let start = end.saturating_sub(buf.len() as u64);
+        let chunk = &mut buf[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+        if let Some(i) = chunk.iter().rposition(|b| *b == b'\n') {
+            end = start + i as u64 + 1;
+            break;
+        }
+        end = start;
+    }
+    if end < len {
+        eprintln!(
+            "warning: removing an incomplete last line ({} bytes) before appending",
+            len - end
+        );
+        file.set_len(end)?;
+    }
+    Ok(())
+}
+
+impl Write for ManifestWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+/// Destination for formatted records.
+pub enum Sink {
+    Stdout(BufWriter<StdoutLock<'static>>),
+    File(ManifestWriter),
+    /// One manifest per top-level subdirectory of the root,
+    /// containing paths relative to that subdirectory.
+    SplitByDir {
+        root: PathBuf,
+        outdir: PathBuf,
+        compression: Compression,
+        recipients: Option<Recipients>,
+        writers: HashMap<OsString, ManifestWriter>,
+        /// written at the top of each manifest
+        header: Option<Box<Header>>,
+    },
+}
+
+impl Sink {
+    pub fn stdout() -> Self {
+        Self::Stdout(BufWriter::with_capacity(
+            WRITE_BUFFER_SIZE,
+            io::stdout().lock(),
+        ))
+    }
+
+    pub fn file(path: &Path, compression: Compression, recipients: Option<&Recipients>) -> Self {
+        Self::File(
+            ManifestWriter::create(path, compression, recipients)
+                .expect("could not create output file"),
+        )
+    }
+
+    pub fn append_to_file(path: &Path, compression: Compression) -> Self {
+        Self::File(ManifestWriter::append(path, compression).expect("could not open output file"))
+    }
+
+    pub fn split_by_dir(
+        root: &Path,
+        outdir: &Path,
+        compression: Compression,
+        recipients: Option<Recipients>,
+    ) -> Self {
+        // its manifest would be the same file as that of the files directly under the root
+        assert!(
+            !root.join(ROOT_MANIFEST_NAME).is_dir(),
+            "--split-output-by-dir: {:?} has a subdirectory named {}, whose manifest would be mixed up with that of the files directly inside it",
+            root,
+            ROOT_MANIFEST_NAME
+        );
+        std::fs::create_dir_all(outdir).expect("could not create output directory");
+        Self::SplitByDir {
+            root: root.to_path_buf(),
+            outdir: outdir.to_path_buf(),
+            compression,
+            recipients,
+            writers: HashMap::new(),
+            header: None,
+        }
+    }
+
+    /// Write the header, or for split output, write it at the top of each manifest as it is created.
+    pub fn set_header(&mut self, header: Header) -> io::Result<()> {
+        match self {
+            Self::Stdout(w) => header.write(w),
+            Self::File(w) => header.write(w),
+            Self::SplitByDir { header: h, .. } => {
+                *h = Some(Box::new(header));
+                Ok(())
+            }
+        }
+    }
+
+    /// Find the writer for the given path, and the form of the path to write.
+    pub fn writer_for<'a>(&mut self, path: &'a Path) -> (&mut dyn Write, &'a Path) {
+        match self {
+            Self::Stdout(w) => (w, path),
+            Self::File(w) => (w, path),
+            Self::SplitByDir {
+                root,
+                outdir,
+                compression,
+                recipients,
+                writers,
+                header,
+            } => {
+                let rel = path.strip_prefix(&root).unwrap_or(path);
+                let mut components = rel.components();
+                let first = components.next().map(|c| c.as_os_str());
+                let rest = components.as_path();
+                let (name, displayed, relative_to) = match first {
+                    Some(dir) if !rest.as_os_str().is_empty() => {
+                        (dir.to_os_string(), rest, root.join(dir))
+                    }
+                    // files directly under the root
+                    _ => (OsString::from(ROOT_MANIFEST_NAME), rel, root.clone()),
+                };
+                let compression = *compression;
+                let writer = writers.entry(name).or_insert_with_key(|name| {
+                    let mut fname = name.clone();
+                    fname.push(MANIFEST_EXTENSION);
+                    fname.push(compression.extension());
+                    if recipients.is_some() {
+                        fname.push(".");
+                        fname.push(ENCRYPTED_EXTENSION);
+                    }
+                    let mut writer = ManifestWriter::create(
+                        &outdir.join(fname),
+                        compression,
+                        recipients.as_ref(),
+                    )
+                    .expect("could not create manifest");
+                    if let Some(h) = header {
+                        h.relative_to(&relative_to)
+                            .write(&mut writer)
+                            .expect("could not write manifest header");
+                    }
+                    writer
+                });
+                (writer, displayed)
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Stdout(w) => w.flush(),
+            Self::File(w) => w.flush(),
+            Self::SplitByDir { writers, .. } => {
+                for w in writers.values_mut() {
+                    w.flush()?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Flush everything and move any output files into place.
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            Self::Stdout(mut w) => w.flush(),
+            Self::File(w) => w.finish(),
+            Self::SplitByDir { writers, .. } => {
+                for (_, w) in writers {
+                    w.finish()?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Settings for writing mtree specs rather than separated fields.
+struct MtreeFormat {
+    /// e.g. sha256digest
+    keyword: String,
+    /// Paths are written relative to this directory, if given.
+    root: Option<PathBuf>,
+}
+
+/// Writes one line per file, as path and digest.
+pub struct TextOutput {
+    separator: String,
+    hash_first: bool,
+    /// as in SFV files
+    upper_case: bool,
+    mtree: Option<MtreeFormat>,
+    /// as PostgreSQL's COPY reads
+    pg_copy: bool,
+    /// write a line for each file not hashed on purpose
+    report_skipped: bool,
+    /// encrypt output files to these
+    recipients: Option<Recipients>,
+    /// None once finished
+    sink: Option<Sink>,
+    /// flush the sink after this many records; None leaves it to the buffer
+    flush_every: Option<usize>,
+    unflushed: usize,
+}
+
+impl TextOutput {
+    pub fn new(separator: &str, hash_first: bool, flush_every: Option<usize>) -> Self {
+        Self {
+            separator: separator.to_string(),
+            hash_first,
+            upper_case: false,
+            mtree: None,
+            pg_copy: false,
+            report_skipped: false,
+            recipients: None,
+            sink: Some(Sink::stdout()),
+            flush_every,
+            unflushed: 0,
+        }
+    }
+
+    /// Write to the given file, rather than to stdout.
+    pub fn write_to_file(mut self, path: &Path, compression: Compression) -> Self {
+        self.sink = Some(Sink::file(path, compression, self.recipients.as_ref()));
+        self
+    }
+
+    /// Append to the given file as results arrive, rather than writing to stdout.
+    pub fn append_to_file(mut self, path: &Path, compression: Compression) -> Self {
+        self.sink = Some(Sink::append_to_file(path, compression));
+        self
+    }
+
+    /// Write one manifest per top-level subdirectory of `root` into `outdir`, rather than to stdout.
+    pub fn split_by_dir(mut self, root: &Path, outdir: &Path, compression: Compression) -> Self {
+        self.sink = Some(Sink::split_by_dir(
+            root,
+            outdir,
+            compression,
+            self.recipients.clone(),
+        ));
+        self
+    }
+
+    /// Encrypt output files to these recipients, if given; call before choosing where to write.
+    /// Output to stdout is not encrypted.
+    pub fn encrypt_to(mut self, recipients: Option<Recipients>) -> Self {
+        self.recipients = recipients;
+        self
+    }
+
+    /// Write digests in upper case, as SFV files do.
+    pub fn upper_case(mut self) -> Self {
+        self.upper_case = true;
+        self
+    }
+
+    /// Write an mtree(8) spec (starting now, with its signature line) rather than separated fields,
+    /// with digests under `keyword` and paths relative to `root`, if given.
+    pub fn mtree(mut self, keyword: String, root: Option<PathBuf>) -> Self {
+        if let Some(sink) = self.sink.as_mut() {
+            let (writer, _) = sink.writer_for(Path::new(""));
+            writeln!(writer, "{}", mtree::SIGNATURE).expect("could not write mtree signature");
+        }
+        self.mtree = Some(MtreeFormat { keyword, root });
+        self
+    }
+
+    /// Write a line for each file (or directory) deliberately not hashed: [SKIPPED], the reason and the path,
+    /// separated as the digests are.
+    pub fn report_skipped(mut self) -> Self {
+        self.report_skipped = true;
+        self
+    }
+
+    /// Write rows for PostgreSQL's COPY (starting now, with a line naming the columns) rather than separated fields.
+    pub fn pg_copy(mut self) -> Self {
+        if let Some(sink) = self.sink.as_mut() {
+            let (writer, _) = sink.writer_for(Path::new(""));
+            writeln!(writer, "{}", pg_copy::header()).expect("could not write column names");
+        }
+        self.pg_copy = true;
+        self
+    }
+
+    /// Start each manifest with a header; call after choosing where to write.
+    pub fn with_header(mut self, header: Header) -> Self {
+        if let Some(sink) = self.sink.as_mut() {
+            sink.set_header(header)
+                .expect("could not write manifest header");
+        }
+        self
+    }
+}
+
+impl OutputSink for TextOutput {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        let sink = self.sink.as_mut().expect("output already finished");
+        let marked;
+        let hash = if result.stable && !self.upper_case {
+            result.digest.as_str()
+        } else {
+            let prefix = if result.stable { "" } else { UNSTABLE_PREFIX };
+            let digest = if self.upper_case {
+                result.digest.to_ascii_uppercase()
+            } else {
+                result.digest.clone()
+            };
+            marked = format!("{}{}", prefix, digest);
+            marked.as_str()
+        };
+
+        let (writer, displayed) = sink.writer_for(&result.path);
+        if let Some(m) = &self.mtree {
+            let relative = m
+                .root
+                .as_ref()
+                .and_then(|r| displayed.strip_prefix(r).ok())
+                .unwrap_or(displayed);
+            let line = mtree::line(&result.path, relative, result.size, &m.keyword, hash)?;
+            writeln!(writer, "{}", line)?;
+        } else if self.pg_copy {
+            // whether the digest is stable has its own column
+            let line = pg_copy::line(result, &displayed.to_string_lossy(), &result.digest);
+            writeln!(writer, "{}", line)?;
+        } else {
+            let displayed = displayed.as_os_str().to_string_lossy();
+            if self.hash_first {
+                writeln!(writer, "{}{}{}", hash, self.separator, displayed)?;
+            } else {
+                writeln!(writer, "{}{}{}", displayed, self.separator, hash)?;
+            }
+        }
+
+        if let Some(n) = self.flush_every {
+            self.unflushed += 1;
+            if self.unflushed >= n {
+                sink.flush()?;
+                self.unflushed = 0;
+            }
+        }
+        Ok(())
+    }
+
+    /// A manifest which silently lacks a file is worse than none, so stop.
+    fn on_error(&mut self, error: &HashError) -> io::Result<()> {
+        Err(io::Error::new(error.source.kind(), error.to_string()))
+    }
+
+    fn on_skipped(&mut self, path: &Path, reason: &str) -> io::Result<()> {
+        if !self.report_skipped {
+            return Ok(());
+        }
+        let sink = self.sink.as_mut().expect("output already finished");
+        let (writer, displayed) = sink.writer_for(path);
+        let displayed = match displayed.to_str() {
+            Some(p) if !p.contains(['\n', '\r']) => p.to_string(),
+            // quoted and escaped, so that the line stays whole and the path is not altered
+            _ => format!("{:?}", displayed),
+        };
+        writeln!(
+            writer,
+            "{}{}{}{}{}",
+            SKIPPED, self.separator, reason, self.separator, displayed
+        )
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        match self.sink.take() {
+            Some(sink) => sink.finish(),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,45 @@
+//! PostgreSQL's COPY text format: one row per line, with tab-separated columns,
+//! and backslash escapes for characters which would otherwise break up the row.
+//!
+//! The first line names the columns, as COPY's HEADER option (PostgreSQL 15 and later) expects, e.g.
+//! `\copy files (path, digest, size, stable) FROM 'hashes.tsv' WITH (HEADER MATCH)`.
+use recursum::FileHash;
+
+/// Columns in the order they are written.
+pub const COLUMNS: [&str; 4] = ["path", "digest", "size", "stable"];
+const DELIMITER: char = '\t';
+
+/// The line naming the columns.
+pub fn header() -> String {
+    COLUMNS.join(&DELIMITER.to_string())
+}
+
+/// Escape a value as COPY's text format reads it.
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\x08' => out.push_str("\\b"),
+            '\x0c' => out.push_str("\\f"),
+            '\x0b' => out.push_str("\\v"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// The row for a hashed file, given as it is displayed and with its digest as it is written.
+pub fn line(result: &FileHash, displayed: &str, digest: &str) -> String {
+    [
+        escape(displayed),
+        escape(digest),
+        result.size.to_string(),
+        // as PostgreSQL writes booleans
+        if result.stable { "t" } else { "f" }.to_string(),
+    ]
+    .join(&DELIMITER.to_string())
+}
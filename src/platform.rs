@@ -0,0 +1,315 @@
+//! Optional platform features, which may be unavailable at runtime
+//! (e.g. on older kernels, under seccomp in containers, or off Linux).
+//!
+//! Each feature is used until it first fails; that failure is reported once on stderr,
+//! and the feature is skipped from then on.
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::cancel::CancellationToken;
+
+/// A platform feature which is disabled the first time it fails.
+pub struct Feature {
+    name: &'static str,
+    available: AtomicBool,
+}
+
+impl Feature {
+    const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            available: AtomicBool::new(true),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// False once the feature has failed.
+    pub fn is_available(&self) -> bool {
+        self.available.load(Ordering::Relaxed)
+    }
+
+    /// Stop using the feature, reporting why on stderr if it was not already disabled.
+    pub fn disable(&self, reason: &io::Error) {
+        if self.available.swap(false, Ordering::Relaxed) {
+            eprintln!(
+                "warning: {} is unavailable, continuing without it: {}",
+                self.name, reason
+            );
+        }
+    }
+
+    /// Run `f` if the feature is still available, disabling it if `f` fails.
+    pub fn attempt<F: FnOnce() -> io::Result<()>>(&self, f: F) {
+        if self.is_available() {
+            if let Err(e) = f() {
+                self.disable(&e);
+            }
+        }
+    }
+}
+
+/// `sigaction`, used to cancel hashing cleanly on Ctrl-C.
+pub static SIGNALS: Feature = Feature::new("signal handling");
+
+/// `sched_setaffinity` to the CPUs of NUMA nodes, used by `--numa`.
+pub static NUMA: Feature = Feature::new("NUMA pinning");
+
+/// `posix_fadvise`, used to request aggressive read-ahead for files being hashed.
+pub static FADVISE: Feature = Feature::new("fadvise");
+/// `ioprio_set`, used to lower the I/O priority of the process.
+pub static IONICE: Feature = Feature::new("ionice");
+/// `statvfs`, used to find how much space is left where output is written.
+pub static STATVFS: Feature = Feature::new("statvfs");
+
+/// Tell the kernel that `file` will be read sequentially from start to end.
+pub fn advise_sequential(file: &File) {
+    FADVISE.attempt(|| sys::advise_sequential(file));
+}
+
+/// Ask the kernel to start reading the first `len` bytes of `file` in the background.
+pub fn advise_willneed(file: &File, len: u64) {
+    FADVISE.attempt(|| sys::advise_willneed(file, len));
+}
+
+/// Bytes available to unprivileged processes on the filesystem holding `path`, if that can be found out.
+pub fn free_space(path: &Path) -> Option<u64> {
+    let mut free = None;
+    STATVFS.attempt(|| {
+        free = Some(sys::free_space(path)?);
+        Ok(())
+    });
+    free
+}
+
+/// Put this thread, and threads it spawns from now on, in the idle I/O scheduling class,
+/// so that they only get disk time when no other process needs it.
+pub fn set_idle_io_priority() {
+    IONICE.attempt(sys::set_idle_io_priority);
+}
+
+/// Cancel `token` on the first SIGINT or SIGTERM; a second one kills the process as usual.
+/// Without signal handling (off Linux), the first one kills the process.
+pub fn cancel_on_interrupt(token: &'static CancellationToken) {
+    SIGNALS.attempt(|| sys::cancel_on_interrupt(token));
+}
+
+/// Pin this thread to the CPUs of one NUMA node, taking the nodes in turn for each thread pinned.
+///
+/// Linux allocates memory on the node of the thread which first touches it,
+/// so buffers a thread allocates after this (e.g. its read buffer) are local to it.
+pub fn pin_to_next_numa_node() {
+    NUMA.attempt(sys::pin_to_next_numa_node);
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::OnceLock;
+
+    use crate::cancel::CancellationToken;
+
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    pub fn advise_sequential(file: &File) -> io::Result<()> {
+        // returns the error number rather than setting errno
+        match unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_SEQUENTIAL) } {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+
+    pub fn free_space(path: &Path) -> io::Result<u64> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+        if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let stat = unsafe { stat.assume_init() };
+        // the fields are narrower on some targets
+        #[allow(clippy::useless_conversion)]
+        Ok(u64::from(stat.f_bavail) * u64::from(stat.f_frsize))
+    }
+
+    pub fn advise_willneed(file: &File, len: u64) -> io::Result<()> {
+        let len = len.min(libc::off_t::MAX as u64) as libc::off_t;
+        match unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, len, libc::POSIX_FADV_WILLNEED) } {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+
+    /// Parse a kernel CPU list, like "0-3,8-11".
+    fn parse_cpulist(list: &str) -> io::Result<Vec<usize>> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad CPU list {:?}", list),
+            )
+        };
+        let mut cpus = Vec::new();
+        for range in list.split(',').filter(|r| !r.is_empty()) {
+            let (first, last) = range.split_once('-').unwrap_or((range, range));
+            let first: usize = first.parse().map_err(|_| invalid())?;
+            let last: usize = last.parse().map_err(|_| invalid())?;
+            cpus.extend(first..=last);
+        }
+        Ok(cpus)
+    }
+
+    /// CPUs of each NUMA node which has any, in order of node number.
+    fn numa_nodes() -> io::Result<Vec<Vec<usize>>> {
+        let mut nodes = Vec::new();
+        for entry in std::fs::read_dir("/sys/devices/system/node")? {
+            let entry = entry?;
+            let id = match entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix("node"))
+                .and_then(|n| n.parse::<usize>().ok())
+            {
+                Some(id) => id,
+                None => continue,
+            };
+            let cpus =
+                parse_cpulist(std::fs::read_to_string(entry.path().join("cpulist"))?.trim())?;
+            if !cpus.is_empty() {
+                nodes.push((id, cpus));
+            }
+        }
+        nodes.sort();
+        Ok(nodes.into_iter().map(|(_, cpus)| cpus).collect())
+    }
+
+    static NUMA_NODES: OnceLock<Vec<Vec<usize>>> = OnceLock::new();
+    static NEXT_NODE: AtomicUsize = AtomicUsize::new(0);
+
+    pub fn pin_to_next_numa_node() -> io::Result<()> {
+        let nodes = match NUMA_NODES.get() {
+            Some(nodes) => nodes,
+            None => {
+                let nodes = numa_nodes()?;
+                if nodes.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "no NUMA nodes with CPUs",
+                    ));
+                }
+                NUMA_NODES.get_or_init(|| nodes)
+            }
+        };
+        let cpus = &nodes[NEXT_NODE.fetch_add(1, Ordering::Relaxed) % nodes.len()];
+        let ret = unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            for cpu in cpus {
+                libc::CPU_SET(*cpu, &mut set);
+            }
+            libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+        };
+        if ret == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    static INTERRUPTED: OnceLock<&'static CancellationToken> = OnceLock::new();
+
+    extern "C" fn on_signal(_signal: libc::c_int) {
+        // only atomic loads and stores, so safe in a signal handler
+        if let Some(token) = INTERRUPTED.get() {
+            token.cancel();
+        }
+    }
+
+    pub fn cancel_on_interrupt(token: &'static CancellationToken) -> io::Result<()> {
+        if INTERRUPTED.set(token).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "interrupts already cancel another token",
+            ));
+        }
+        for signal in [libc::SIGINT, libc::SIGTERM] {
+            let ret = unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+                // restore the default, so that a second signal kills the process
+                action.sa_flags = libc::SA_RESETHAND | libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+                libc::sigaction(signal, &action, std::ptr::null_mut())
+            };
+            if ret == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_idle_io_priority() -> io::Result<()> {
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_ioprio_set,
+                IOPRIO_WHO_PROCESS,
+                0,
+                IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+            )
+        };
+        if ret == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::path::Path;
+
+    use crate::cancel::CancellationToken;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Unsupported, "only supported on Linux")
+    }
+
+    /// Read-ahead hints are an optimisation; silently do without them.
+    pub fn advise_sequential(_file: &File) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn advise_willneed(_file: &File, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn free_space(_path: &Path) -> io::Result<u64> {
+        Err(unsupported())
+    }
+
+    pub fn pin_to_next_numa_node() -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    /// Ctrl-C kills the process as it always has; output is still never left half-written.
+    pub fn cancel_on_interrupt(_token: &'static CancellationToken) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn set_idle_io_priority() -> io::Result<()> {
+        Err(unsupported())
+    }
+}
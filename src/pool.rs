@@ -0,0 +1,60 @@
+//! Hashing on a rayon thread pool, with no async runtime at all.
+//!
+//! For comparison with [hash_stream](crate::hash_stream), and for environments where tokio's scheduler behaves poorly;
+//! like it, yields results in the same order as the paths, with backpressure.
+use std::path::PathBuf;
+use std::thread;
+use std::time::Instant;
+
+use crossbeam_channel::bounded;
+use rayon::{ThreadPoolBuildError, ThreadPoolBuilder};
+
+use crate::blocking::HashIter;
+use crate::{hash_path, platform, queue_length, HashOptions};
+
+/// Hash files on a pool of `opts.jobs` rayon threads, yielding results in the same order as the paths.
+///
+/// Paths are pulled from `paths` on another thread, which spawns a job for each in a rayon scope,
+/// up to a few times `opts.jobs` ahead of the results being consumed.
+pub fn hash_iter<I>(paths: I, opts: HashOptions) -> Result<HashIter, ThreadPoolBuildError>
+where
+    I: IntoIterator<Item = PathBuf>,
+    I::IntoIter: Send + 'static,
+{
+    let jobs = opts.jobs.max(1);
+    let mut builder = ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .thread_name(|i| format!("recursum-hash-{}", i));
+    if opts.numa {
+        builder = builder.start_handler(|_| platform::pin_to_next_numa_node());
+    }
+    let pool = builder.build()?;
+    let (pending_sender, pending) = bounded(queue_length(jobs));
+
+    let paths = paths.into_iter();
+    thread::spawn(move || {
+        // the scope runs here, so that waiting for room in the queue never holds up a rayon thread
+        pool.in_place_scope(|scope| {
+            for path in paths {
+                if opts.cancel.is_some_and(|c| c.is_cancelled()) {
+                    break;
+                }
+                let (result_sender, result_receiver) = bounded(1);
+                if pending_sender.send(result_receiver).is_err() {
+                    // iterator dropped
+                    break;
+                }
+                let spawned = Instant::now();
+                scope.spawn(move |_| {
+                    if let Some(d) = opts.diagnostics {
+                        d.file(spawned.elapsed());
+                    }
+                    // the receiver is only gone if the iterator was dropped
+                    let _ = result_sender.send(hash_path(path, opts));
+                });
+            }
+        });
+    });
+
+    Ok(HashIter::new(pending))
+}
@@ -0,0 +1,139 @@
+//! Opening files, and asking the kernel to start reading them, shortly before they are hashed,
+//! for [HashOptions::prefetch](crate::HashOptions::prefetch).
+//!
+//! [hash_stream](crate::hash_stream) passes every path to the prefetcher in the order it will be hashed.
+//! The prefetcher's threads open them in turn, keeping no more than the given number open and waiting,
+//! and the hashing jobs take the open files rather than opening them again.
+//! A job which gets to its file first opens it itself, and the prefetcher skips it.
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+
+use crate::platform;
+
+/// Bytes at the start of each file to ask the kernel to read ahead.
+const READ_AHEAD: u64 = 2 * 1024 * 1024;
+/// Most threads to open files with; opening is mostly waiting on the storage, so a few can overlap.
+const MAX_THREADS: usize = 4;
+
+enum Slot {
+    /// opened ahead of time, waiting to be hashed
+    Open(File),
+    /// being hashed already, by a job which opened it itself
+    Claimed,
+}
+
+#[derive(Default)]
+struct State {
+    slots: HashMap<PathBuf, Slot>,
+    /// files opened, or being opened, and not yet taken
+    open: usize,
+    closed: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    taken: Condvar,
+    ahead: usize,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("prefetch lock poisoned")
+    }
+}
+
+pub struct Prefetcher {
+    shared: Arc<Shared>,
+    paths: Sender<PathBuf>,
+}
+
+impl Prefetcher {
+    /// Keep up to `ahead` files open ahead of the hashing jobs.
+    pub fn new(ahead: usize) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::default(),
+            taken: Condvar::new(),
+            ahead: ahead.max(1),
+        });
+        let (paths, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..ahead.clamp(1, MAX_THREADS) {
+            let shared = shared.clone();
+            let receiver = receiver.clone();
+            thread::spawn(move || prefetch(&shared, &receiver));
+        }
+        Self { shared, paths }
+    }
+
+    /// Open `path` once fewer than `ahead` files are waiting; paths are opened in the order they are pushed.
+    pub fn push(&self, path: PathBuf) {
+        // the threads only stop once this is dropped
+        let _ = self.paths.send(path);
+    }
+
+    /// The file at `path`, if it has been opened ahead of time; otherwise, `None`, and it will not be.
+    pub fn take(&self, path: &Path) -> Option<File> {
+        let mut state = self.shared.lock();
+        match state.slots.remove(path) {
+            Some(Slot::Open(file)) => {
+                state.open -= 1;
+                self.shared.taken.notify_one();
+                Some(file)
+            }
+            _ => {
+                state.slots.insert(path.to_path_buf(), Slot::Claimed);
+                None
+            }
+        }
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.taken.notify_all();
+    }
+}
+
+fn prefetch(shared: &Shared, paths: &Mutex<Receiver<PathBuf>>) {
+    loop {
+        let path = match paths.lock().expect("prefetch lock poisoned").recv() {
+            Ok(path) => path,
+            Err(_) => return,
+        };
+        {
+            let mut state = shared.lock();
+            while state.open >= shared.ahead && !state.closed {
+                state = shared.taken.wait(state).expect("prefetch lock poisoned");
+            }
+            if state.closed {
+                return;
+            }
+            if let Some(Slot::Claimed) = state.slots.get(&path) {
+                state.slots.remove(&path);
+                continue;
+            }
+            state.open += 1;
+        }
+        // files which cannot be opened are left for the hashing job to report
+        let file = File::open(&path).ok();
+        if let Some(file) = &file {
+            platform::advise_willneed(file, READ_AHEAD);
+        }
+        let mut state = shared.lock();
+        match (state.slots.remove(&path), file) {
+            (None, Some(file)) => {
+                state.slots.insert(path, Slot::Open(file));
+            }
+            // not opened, or taken meanwhile
+            _ => {
+                state.open -= 1;
+                shared.taken.notify_one();
+            }
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! Choosing which files to hash first from what earlier runs recorded, for --prioritize,
+//! so that runs cut short by --max-duration spend their time where it is most needed.
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use recursum::hash_cache::HashCache;
+use recursum::walk::Walker;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Prioritize {
+    /// Files which the cache records as read longest ago first, and those it has no record of reading before them.
+    LeastRecentlyVerified,
+}
+
+impl FromStr for Prioritize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "least-recently-verified" => Ok(Self::LeastRecentlyVerified),
+            _ => Err(format!(
+                "unknown priority '{}'; expected least-recently-verified",
+                s
+            )),
+        }
+    }
+}
+
+/// Wraps another walker, yielding its files in order of when `cache` records them as last read,
+/// oldest first, and otherwise in the order they were walked.
+///
+/// Every file is found before any is yielded, so the whole tree is walked before hashing starts,
+/// and every path is held in memory.
+pub struct LeastRecentlyVerified {
+    inner: Box<dyn Walker>,
+    cache: &'static HashCache,
+}
+
+impl LeastRecentlyVerified {
+    pub fn new(inner: Box<dyn Walker>, cache: &'static HashCache) -> Self {
+        Self { inner, cache }
+    }
+}
+
+impl Walker for LeastRecentlyVerified {
+    fn files(&self, root: &Path) -> Box<dyn Iterator<Item = PathBuf> + Send> {
+        let mut files: Vec<_> = self.inner.files(root).collect();
+        // None, for files never read, sorts first; the sort is stable
+        files.sort_by_cached_key(|p| self.cache.verified_at(p));
+        Box::new(files.into_iter())
+    }
+}
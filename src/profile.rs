@@ -0,0 +1,112 @@
+//! Bundles of tuning settings for common kinds of storage, chosen with --profile,
+//! for those who know what they are hashing but not which of the many options matter on it.
+//!
+//! A profile only fills in defaults: --threads, --buffer-size, --fadvise and --order still override it.
+use std::str::FromStr;
+
+use recursum::walk::Order;
+
+const KIB: usize = 1024;
+const MIB: usize = 1024 * KIB;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Profile {
+    /// Local flash, which is fastest with many requests in flight.
+    Nvme,
+    /// Spinning disks, which are fastest reading one file at a time, in the order they were written.
+    Hdd,
+    /// NFS, SMB and other network filesystems, where each request waits on a round trip.
+    Network,
+    /// Keeping the machine responsive, at the cost of speed.
+    Laptop,
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nvme" => Ok(Self::Nvme),
+            "hdd" => Ok(Self::Hdd),
+            "network" => Ok(Self::Network),
+            "laptop" => Ok(Self::Laptop),
+            _ => Err(format!(
+                "unknown profile '{}'; expected nvme, hdd, network or laptop",
+                s
+            )),
+        }
+    }
+}
+
+/// What --fadvise asks of the kernel for each file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Advice {
+    /// The file will be read from start to end, so read ahead aggressively.
+    Sequential,
+    /// No advice, leaving read-ahead to the kernel's defaults.
+    None,
+}
+
+impl FromStr for Advice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sequential" => Ok(Self::Sequential),
+            "none" => Ok(Self::None),
+            _ => Err(format!(
+                "unknown advice '{}'; expected sequential or none",
+                s
+            )),
+        }
+    }
+}
+
+/// The settings a profile chooses.
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    pub threads: usize,
+    pub buffer_size: usize,
+    pub advice: Advice,
+    pub order: Option<Order>,
+    /// Hash at idle I/O priority, as --idle-io.
+    pub idle_io: bool,
+}
+
+impl Profile {
+    pub fn settings(self) -> Settings {
+        let cpus = num_cpus::get();
+        match self {
+            Self::Nvme => Settings {
+                threads: 4 * cpus,
+                buffer_size: 256 * KIB,
+                advice: Advice::Sequential,
+                order: None,
+                idle_io: false,
+            },
+            // files in a directory tend to be near each other on the platter
+            Self::Hdd => Settings {
+                threads: 1,
+                buffer_size: MIB,
+                advice: Advice::Sequential,
+                order: Some(Order::ByDirectory),
+                idle_io: false,
+            },
+            // read-ahead hints stop at the client's page cache, so only cost it memory
+            Self::Network => Settings {
+                threads: (8 * cpus).max(32),
+                buffer_size: MIB,
+                advice: Advice::None,
+                order: None,
+                idle_io: false,
+            },
+            Self::Laptop => Settings {
+                threads: 2,
+                buffer_size: 128 * KIB,
+                advice: Advice::Sequential,
+                order: None,
+                idle_io: true,
+            },
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Progress spinner on stderr, and human-readable sizes and durations.
+//!
+//! Without the `progress` feature, there is no spinner and formatting is plainer.
+use std::io;
+use std::path::Path;
+
+use recursum::sink::OutputSink;
+use recursum::FileHash;
+
+#[cfg(feature = "progress")]
+pub use indicatif::{HumanBytes, HumanDuration};
+
+#[cfg(not(feature = "progress"))]
+pub use plain::{HumanBytes, HumanDuration};
+
+/// Spinner showing bytes hashed, elapsed time, throughput and the latest file,
+/// or a bar towards a known total with an ETA.
+///
+/// Cloning gives another handle to the same spinner.
+#[cfg(feature = "progress")]
+#[derive(Clone)]
+pub struct Progress {
+    bar: indicatif::ProgressBar,
+    /// the bar's total is a number of files, rather than bytes
+    counts_files: bool,
+}
+
+#[cfg(feature = "progress")]
+impl Progress {
+    pub fn new() -> Self {
+        let spinner_style = indicatif::ProgressStyle::default_spinner()
+            .template("{bytes} | {elapsed} | {bytes_per_sec} | {msg}");
+        Self {
+            bar: indicatif::ProgressBar::new_spinner().with_style(spinner_style),
+            counts_files: false,
+        }
+    }
+
+    /// A bar towards a total number of bytes, if known, or otherwise of files.
+    pub fn bounded(files: u64, bytes: Option<u64>) -> Self {
+        let (len, template) = match bytes {
+            Some(b) => (
+                b,
+                "[{bar:30}] {bytes}/{total_bytes} ({percent}%) | {bytes_per_sec} | ETA {eta} | {msg}",
+            ),
+            None => (
+                files,
+                "[{bar:30}] {pos}/{len} files ({percent}%) | ETA {eta} | {msg}",
+            ),
+        };
+        let style = indicatif::ProgressStyle::default_bar()
+            .template(template)
+            .progress_chars("=> ");
+        Self {
+            bar: indicatif::ProgressBar::new(len).with_style(style),
+            counts_files: bytes.is_none(),
+        }
+    }
+
+    pub fn update(&self, path: &Path, size: u64) {
+        self.bar.set_message(&format!(
+            "{} {:?}",
+            HumanBytes(size),
+            path.as_os_str().to_string_lossy()
+        ));
+        self.bar.inc(if self.counts_files { 1 } else { size });
+    }
+
+    /// Print a line on stderr without clobbering the spinner.
+    pub fn println(&self, msg: String) {
+        if self.bar.is_hidden() {
+            // indicatif drops messages if it is not drawing
+            eprintln!("{}", msg);
+        } else {
+            self.bar.println(msg);
+        }
+    }
+
+    pub fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+/// Stand-in for the spinner when the `progress` feature is disabled.
+#[cfg(not(feature = "progress"))]
+#[derive(Clone)]
+pub struct Progress;
+
+#[cfg(not(feature = "progress"))]
+impl Progress {
+    pub fn new() -> Self {
+        Self
+    }
+
+    pub fn bounded(_files: u64, _bytes: Option<u64>) -> Self {
+        Self
+    }
+
+    pub fn update(&self, _path: &Path, _size: u64) {}
+
+    pub fn println(&self, msg: String) {
+        eprintln!("{}", msg);
+    }
+
+    pub fn finish(&self) {}
+}
+
+impl OutputSink for Progress {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        self.update(&result.path, result.size);
+        Ok(())
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        self.finish();
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "progress"))]
+mod plain {
+    use std::fmt;
+    use std::time::Duration;
+
+    const UNITS: &[&str] = &["B", "KB", "MB", "GB", "TB", "PB"];
+
+    /// Size in binary units, e.g. 1.50MB.
+    pub struct HumanBytes(pub u64);
+
+    impl fmt::Display for HumanBytes {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let mut size = self.0 as f64;
+            let mut unit = 0;
+            while size >= 1024.0 && unit < UNITS.len() - 1 {
+                size /= 1024.0;
+                unit += 1;
+            }
+            if unit == 0 {
+                write!(f, "{}B", self.0)
+            } else {
+                write!(f, "{:.2}{}", size, UNITS[unit])
+            }
+        }
+    }
+
+    /// Duration in seconds.
+    pub struct HumanDuration(pub Duration);
+
+    impl fmt::Display for HumanDuration {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{:.1} seconds", self.0.as_secs_f64())
+        }
+    }
+}
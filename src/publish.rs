@@ -0,0 +1,337 @@
+//! Publishing each result as a JSON message to a Kafka topic or NATS subject as it arrives,
+//! so that other systems can follow a scan while it runs.
+use std::io;
+
+use recursum::sink::OutputSink;
+use recursum::{FileHash, HashError};
+
+use crate::sinks::json_record;
+
+/// Where to publish: brokers (or server) and topic (or subject), from `//HOST[:PORT][,HOST...]/TOPIC`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoint {
+    pub servers: String,
+    pub topic: String,
+}
+
+impl Endpoint {
+    /// Parse what follows `kafka:` or `nats:` in a --sink.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let rest = s
+            .strip_prefix("//")
+            .ok_or_else(|| format!("expected //HOST/TOPIC, got '{}'", s))?;
+        let (servers, topic) = rest
+            .split_once('/')
+            .ok_or_else(|| format!("no topic given in '{}'", s))?;
+        if servers.is_empty() || topic.is_empty() {
+            return Err(format!("expected //HOST/TOPIC, got '{}'", s));
+        }
+        Ok(Self {
+            servers: servers.to_string(),
+            topic: topic.to_string(),
+        })
+    }
+}
+
+/// Anything which can deliver messages to a topic.
+trait Publisher {
+    /// Queue a message, keyed by the file's path.
+    fn publish(&mut self, key: &str, payload: &str) -> io::Result<()>;
+
+    /// Wait until every message has been delivered.
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Publishes the same records as `--sink jsonl`, one message per file.
+pub struct PublishOutput {
+    publisher: Box<dyn Publisher>,
+    algorithm: String,
+    run_id: String,
+}
+
+impl PublishOutput {
+    #[cfg(feature = "kafka")]
+    pub fn kafka(endpoint: &Endpoint, algorithm: &str, run_id: &str) -> io::Result<Self> {
+        Ok(Self {
+            publisher: Box::new(kafka::KafkaPublisher::connect(endpoint)?),
+            algorithm: algorithm.to_string(),
+            run_id: run_id.to_string(),
+        })
+    }
+
+    #[cfg(feature = "nats")]
+    pub fn nats(endpoint: &Endpoint, algorithm: &str, run_id: &str) -> io::Result<Self> {
+        Ok(Self {
+            publisher: Box::new(nats::NatsPublisher::connect(endpoint)?),
+            algorithm: algorithm.to_string(),
+            run_id: run_id.to_string(),
+        })
+    }
+}
+
+impl OutputSink for PublishOutput {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        let record = json_record(result, &self.algorithm, &self.run_id);
+        self.publisher
+            .publish(&result.path.to_string_lossy(), &record)
+    }
+
+    /// As with the other outputs, a stream which silently lacks a file is worse than none.
+    fn on_error(&mut self, error: &HashError) -> io::Result<()> {
+        Err(io::Error::new(error.source.kind(), error.to_string()))
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        self.publisher.flush()
+    }
+}
+
+#[cfg(feature = "kafka")]
+mod kafka {
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use rdkafka::config::ClientConfig;
+    use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+    use rdkafka::message::DeliveryResult;
+    use rdkafka::producer::{BaseProducer, BaseRecord, Producer, ProducerContext};
+    use rdkafka::util::Timeout;
+    use rdkafka::ClientContext;
+
+    use super::{Endpoint, Publisher};
+
+    /// How long to wait for a full queue to drain, between attempts to send.
+    const QUEUE_FULL_WAIT: Duration = Duration::from_millis(100);
+    /// How long to wait for outstanding messages to be delivered, once hashing has finished.
+    const FLUSH_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// Counts messages which could not be delivered.
+    #[derive(Default)]
+    struct Deliveries {
+        failed: AtomicUsize,
+    }
+
+    impl ClientContext for Deliveries {}
+
+    impl ProducerContext for Deliveries {
+        type DeliveryOpaque = ();
+
+        fn delivery(&self, result: &DeliveryResult<'_>, _: Self::DeliveryOpaque) {
+            if let Err((e, _)) = result {
+                if self.failed.fetch_add(1, Ordering::Relaxed) == 0 {
+                    eprintln!("warning: could not deliver to Kafka: {}", e);
+                }
+            }
+        }
+    }
+
+    pub struct KafkaPublisher {
+        producer: BaseProducer<Deliveries>,
+        topic: String,
+    }
+
+    fn to_io(e: KafkaError) -> io::Error {
+        io::Error::other(format!("Kafka: {}", e))
+    }
+
+    impl KafkaPublisher {
+        pub fn connect(endpoint: &Endpoint) -> io::Result<Self> {
+            let producer = ClientConfig::new()
+                .set("bootstrap.servers", &endpoint.servers)
+                .set("client.id", "recursum")
+                .create_with_context(Deliveries::default())
+                .map_err(to_io)?;
+            Ok(Self {
+                producer,
+                topic: endpoint.topic.clone(),
+            })
+        }
+    }
+
+    impl Publisher for KafkaPublisher {
+        fn publish(&mut self, key: &str, payload: &str) -> io::Result<()> {
+            let mut record = BaseRecord::to(&self.topic).key(key).payload(payload);
+            loop {
+                match self.producer.send(record) {
+                    Ok(()) => break,
+                    // hashing is outpacing the brokers; wait for some messages to be delivered
+                    Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), r)) => {
+                        record = r;
+                        self.producer.poll(QUEUE_FULL_WAIT);
+                    }
+                    Err((e, _)) => return Err(to_io(e)),
+                }
+            }
+            // run delivery callbacks
+            self.producer.poll(Duration::ZERO);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.producer
+                .flush(Timeout::After(FLUSH_TIMEOUT))
+                .map_err(to_io)?;
+            match self.producer.context().failed.load(Ordering::Relaxed) {
+                0 => Ok(()),
+                n => Err(io::Error::other(format!(
+                    "{} messages could not be delivered to Kafka",
+                    n
+                ))),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "nats")]
+mod nats {
+    //! Just enough of the NATS client protocol to publish, over plain TCP:
+    //! https://docs.nats.io/reference/reference-protocols/nats-protocol
+    use std::io::{self, BufRead, BufReader, BufWriter, Write};
+    use std::net::TcpStream;
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{Endpoint, Publisher};
+
+    const DEFAULT_PORT: u16 = 4222;
+    /// How long to wait for the server to confirm it has processed everything, once hashing has finished.
+    const FLUSH_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// What the server said, as read by the background thread.
+    enum Reply {
+        Pong,
+        Error(String),
+        Closed,
+    }
+
+    pub struct NatsPublisher {
+        /// Shared with the thread reading from the server, which answers its PINGs.
+        writer: Arc<Mutex<BufWriter<TcpStream>>>,
+        replies: mpsc::Receiver<Reply>,
+        subject: String,
+    }
+
+    fn protocol_error(msg: impl Into<String>) -> io::Error {
+        io::Error::other(format!("NATS: {}", msg.into()))
+    }
+
+    impl NatsPublisher {
+        pub fn connect(endpoint: &Endpoint) -> io::Result<Self> {
+            // only one server is needed to publish
+            let server = endpoint.servers.split(',').next().unwrap_or_default();
+            let stream = if server.contains(':') {
+                TcpStream::connect(server)?
+            } else {
+                TcpStream::connect((server, DEFAULT_PORT))?
+            };
+            let mut reader = BufReader::new(stream.try_clone()?);
+            let mut info = String::new();
+            reader.read_line(&mut info)?;
+            if !info.starts_with("INFO ") {
+                return Err(protocol_error(format!(
+                    "unexpected greeting: {}",
+                    info.trim_end()
+                )));
+            }
+            if info.contains(r#""tls_required":true"#) || info.contains(r#""auth_required":true"#) {
+                return Err(protocol_error(
+                    "the server requires TLS or authentication, which are not supported",
+                ));
+            }
+            let mut writer = BufWriter::new(stream);
+            write!(
+                writer,
+                "CONNECT {{\"verbose\":false,\"pedantic\":false,\"name\":\"recursum\",\"lang\":\"rust\",\"version\":\"{}\"}}\r\n",
+                env!("CARGO_PKG_VERSION")
+            )?;
+            writer.flush()?;
+
+            let writer = Arc::new(Mutex::new(writer));
+            let (tx, replies) = mpsc::channel();
+            let pong_writer = Arc::clone(&writer);
+            thread::spawn(move || {
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    let reply = match reader.read_line(&mut line) {
+                        Ok(0) | Err(_) => Reply::Closed,
+                        Ok(_) if line.starts_with("PING") => {
+                            let mut w = pong_writer.lock().unwrap();
+                            if w.write_all(b"PONG\r\n").and_then(|_| w.flush()).is_err() {
+                                Reply::Closed
+                            } else {
+                                continue;
+                            }
+                        }
+                        Ok(_) if line.starts_with("PONG") => Reply::Pong,
+                        Ok(_) if line.starts_with("-ERR") => {
+                            Reply::Error(line.trim_end().to_string())
+                        }
+                        // +OK, INFO updates
+                        Ok(_) => continue,
+                    };
+                    let closed = matches!(reply, Reply::Closed);
+                    if tx.send(reply).is_err() || closed {
+                        return;
+                    }
+                }
+            });
+            Ok(Self {
+                writer,
+                replies,
+                subject: endpoint.topic.clone(),
+            })
+        }
+
+        /// Fail if the server has reported an error or hung up.
+        fn check_replies(&self) -> io::Result<()> {
+            while let Ok(reply) = self.replies.try_recv() {
+                match reply {
+                    Reply::Pong => (),
+                    Reply::Error(e) => return Err(protocol_error(e)),
+                    Reply::Closed => {
+                        return Err(protocol_error("the server closed the connection"))
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl Publisher for NatsPublisher {
+        /// NATS messages have no key; the path is in the payload.
+        fn publish(&mut self, _key: &str, payload: &str) -> io::Result<()> {
+            self.check_replies()?;
+            let mut w = self.writer.lock().unwrap();
+            write!(
+                w,
+                "PUB {} {}\r\n{}\r\n",
+                self.subject,
+                payload.len(),
+                payload
+            )
+        }
+
+        /// A PONG in reply to a PING means the server has processed everything sent before it.
+        fn flush(&mut self) -> io::Result<()> {
+            {
+                let mut w = self.writer.lock().unwrap();
+                w.write_all(b"PING\r\n")?;
+                w.flush()?;
+            }
+            match self.replies.recv_timeout(FLUSH_TIMEOUT) {
+                Ok(Reply::Pong) => Ok(()),
+                Ok(Reply::Error(e)) => Err(protocol_error(e)),
+                Ok(Reply::Closed) | Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    Err(protocol_error("the server closed the connection"))
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    Err(protocol_error("timed out waiting for the server"))
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Replacing paths with their HMACs, so that fixity data can be shared for comparison
+//! without revealing the names of files and directories.
+//!
+//! A path is redacted relative to the hashed directory, so that whoever holds the key can
+//! match a redacted manifest to the files under any copy of that directory.
+use std::collections::HashMap;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+use recursum::sink::OutputSink;
+use recursum::{FileHash, HashError};
+
+use crate::normalize::NormalForm;
+
+/// Recorded in the header of redacted manifests.
+pub const HMAC_SHA256: &str = "hmac-sha256";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RedactMode {
+    /// HMAC-SHA256 of the path, keyed with a secret.
+    Hmac,
+}
+
+impl FromStr for RedactMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hmac" => Ok(Self::Hmac),
+            _ => Err(format!("unknown redaction '{}'; expected hmac", s)),
+        }
+    }
+}
+
+/// The path's bytes, without `.` components, so that e.g. `./a/b` and `a/b` are redacted alike.
+fn normalized_bytes(path: &Path) -> Vec<u8> {
+    let normalized: PathBuf = path
+        .components()
+        .filter(|c| *c != Component::CurDir)
+        .collect();
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        normalized.as_os_str().as_bytes().to_vec()
+    }
+    #[cfg(not(unix))]
+    {
+        normalized.to_string_lossy().replace('\\', "/").into_bytes()
+    }
+}
+
+pub struct Redactor {
+    key: Vec<u8>,
+}
+
+impl Redactor {
+    /// Read the key from a file; a trailing newline is not part of it.
+    pub fn load(key_file: &Path) -> io::Result<Self> {
+        let mut key = std::fs::read(key_file)?;
+        while key.last().is_some_and(|b| *b == b'\n' || *b == b'\r') {
+            key.pop();
+        }
+        if key.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the key file is empty",
+            ));
+        }
+        Ok(Self { key })
+    }
+
+    /// Hex HMAC-SHA256 of the path.
+    pub fn redact(&self, path: &Path) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.key).expect("HMAC takes keys of any length");
+        mac.update(&normalized_bytes(path));
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Redacted path to real path, for the files under `root`, relative to it;
+    /// also keyed by the redacted path in `form`, if given, as for a manifest made with --normalize-paths.
+    pub fn index(
+        &self,
+        root: &Path,
+        form: Option<NormalForm>,
+    ) -> io::Result<HashMap<String, PathBuf>> {
+        let mut index = HashMap::new();
+        for entry in walkdir::WalkDir::new(root) {
+            let entry = entry.map_err(io::Error::from)?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let relative = entry
+                .path()
+                .strip_prefix(root)
+                .expect("walked paths are under the root")
+                .to_path_buf();
+            if let Some(form) = form {
+                index.insert(self.redact(&form.path(&relative)), relative.clone());
+            }
+            index.insert(self.redact(&relative), relative);
+        }
+        Ok(index)
+    }
+}
+
+/// Passes results to another output with their paths redacted.
+pub struct RedactPaths {
+    inner: Box<dyn OutputSink>,
+    redactor: &'static Redactor,
+    /// Paths are redacted relative to this directory, if given.
+    root: Option<PathBuf>,
+}
+
+impl RedactPaths {
+    pub fn new(
+        inner: Box<dyn OutputSink>,
+        redactor: &'static Redactor,
+        root: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            inner,
+            redactor,
+            root,
+        }
+    }
+
+    fn redact(&self, path: &Path) -> PathBuf {
+        let relative = self
+            .root
+            .as_ref()
+            .and_then(|r| path.strip_prefix(r).ok())
+            .unwrap_or(path);
+        PathBuf::from(self.redactor.redact(relative))
+    }
+}
+
+impl OutputSink for RedactPaths {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        self.inner.on_result(&FileHash {
+            path: self.redact(&result.path),
+            digest: result.digest.clone(),
+            size: result.size,
+            stable: result.stable,
+            shared_extents: None,
+            elapsed: result.elapsed,
+        })
+    }
+
+    fn on_error(&mut self, error: &HashError) -> io::Result<()> {
+        self.inner.on_error(&HashError {
+            path: self.redact(&error.path),
+            phase: error.phase,
+            source: io::Error::new(error.source.kind(), error.source.to_string()),
+        })
+    }
+
+    fn on_skipped(&mut self, path: &Path, reason: &str) -> io::Result<()> {
+        self.inner.on_skipped(&self.redact(path), reason)
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        self.inner.on_finish()
+    }
+}
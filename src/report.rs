@@ -0,0 +1,153 @@
+//! Human-readable reports, in HTML or markdown, for people who will not read TSV.
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportFormat {
+    Html,
+    Markdown,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "html" => Ok(Self::Html),
+            "markdown" | "md" => Ok(Self::Markdown),
+            _ => Err(format!(
+                "unknown report format '{}'; expected html or markdown",
+                s
+            )),
+        }
+    }
+}
+
+pub struct Table {
+    pub caption: String,
+    pub header: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+pub struct Report {
+    pub title: String,
+    /// Label and value of each headline number.
+    pub summary: Vec<(String, String)>,
+    pub tables: Vec<Table>,
+    pub errors: Vec<String>,
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Escape characters which would end a table cell or start formatting.
+fn escape_markdown(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(
+            c,
+            '\\' | '|' | '*' | '_' | '`' | '[' | ']' | '<' | '>' | '#'
+        ) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.replace('\n', " ")
+}
+
+impl Report {
+    pub fn write_to(&self, path: &Path, format: ReportFormat) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        match format {
+            ReportFormat::Html => self.write_html(&mut w)?,
+            ReportFormat::Markdown => self.write_markdown(&mut w)?,
+        }
+        w.flush()
+    }
+
+    fn write_html(&self, w: &mut dyn Write) -> io::Result<()> {
+        let title = escape_html(&self.title);
+        writeln!(w, "<!DOCTYPE html>")?;
+        writeln!(
+            w,
+            "<html><head><meta charset=\"utf-8\"><title>{}</title>",
+            title
+        )?;
+        writeln!(
+            w,
+            "<style>body {{ font-family: sans-serif; }} table {{ border-collapse: collapse; }} \
+             td, th {{ border: 1px solid #ccc; padding: 0.2em 0.6em; text-align: left; }}</style>"
+        )?;
+        writeln!(w, "</head><body>\n<h1>{}</h1>", title)?;
+        writeln!(w, "<h2>Summary</h2>\n<table>")?;
+        for (label, value) in &self.summary {
+            writeln!(
+                w,
+                "<tr><th>{}</th><td>{}</td></tr>",
+                escape_html(label),
+                escape_html(value)
+            )?;
+        }
+        writeln!(w, "</table>")?;
+        for table in &self.tables {
+            writeln!(w, "<h2>{}</h2>\n<table>\n<tr>", escape_html(&table.caption))?;
+            for cell in &table.header {
+                write!(w, "<th>{}</th>", escape_html(cell))?;
+            }
+            writeln!(w, "</tr>")?;
+            for row in &table.rows {
+                write!(w, "<tr>")?;
+                for cell in row {
+                    write!(w, "<td>{}</td>", escape_html(cell))?;
+                }
+                writeln!(w, "</tr>")?;
+            }
+            writeln!(w, "</table>")?;
+        }
+        if !self.errors.is_empty() {
+            writeln!(w, "<h2>Errors</h2>\n<ul>")?;
+            for e in &self.errors {
+                writeln!(w, "<li>{}</li>", escape_html(e))?;
+            }
+            writeln!(w, "</ul>")?;
+        }
+        writeln!(w, "</body></html>")
+    }
+
+    fn write_markdown(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "# {}\n\n## Summary\n", escape_markdown(&self.title))?;
+        for (label, value) in &self.summary {
+            writeln!(
+                w,
+                "- {}: {}",
+                escape_markdown(label),
+                escape_markdown(value)
+            )?;
+        }
+        for table in &self.tables {
+            writeln!(w, "\n## {}\n", escape_markdown(&table.caption))?;
+            let row = |cells: &[String]| {
+                let cells: Vec<_> = cells.iter().map(|c| escape_markdown(c)).collect();
+                format!("| {} |", cells.join(" | "))
+            };
+            writeln!(w, "{}", row(&table.header))?;
+            writeln!(w, "|{}", " --- |".repeat(table.header.len()))?;
+            for cells in &table.rows {
+                writeln!(w, "{}", row(cells))?;
+            }
+        }
+        if !self.errors.is_empty() {
+            writeln!(w, "\n## Errors\n")?;
+            for e in &self.errors {
+                writeln!(w, "- {}", escape_markdown(e))?;
+            }
+        }
+        Ok(())
+    }
+}
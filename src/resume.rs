@@ -0,0 +1,63 @@
+//! Picking up an interrupted run where it left off, from its output so far.
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use crate::manifest;
+
+/// Paths which an earlier run already hashed.
+pub struct Resume {
+    done: HashSet<String>,
+}
+
+impl Resume {
+    /// Read the earlier run's output.
+    ///
+    /// A run which was killed may have written only part of its last line,
+    /// so entries whose digest is shorter than the others are not counted as done.
+    /// With `--hash auto-size:...`, digests are compared with those of the same algorithm,
+    /// which is given as their `algo:` prefix.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let manifest = manifest::load(path)?;
+        if manifest.malformed > 0 {
+            eprintln!(
+                "warning: {} lines of {:?} could not be read; those files will be hashed again",
+                manifest.malformed, path
+            );
+        }
+        // the algorithm, if the digest names one, and the digest's length
+        let digest_length = |e: &manifest::Entry| {
+            let (algorithm, hex) = manifest::split_algorithm(&e.digest);
+            (algorithm.map(str::to_string), hex.len())
+        };
+        let mut longest = HashMap::new();
+        for (algorithm, len) in manifest.entries.iter().map(digest_length) {
+            let max = longest.entry(algorithm).or_insert(len);
+            *max = len.max(*max);
+        }
+        // a digest cut short before its algorithm's name was finished has no prefix
+        let named = longest.keys().any(Option::is_some);
+        let relative_to = manifest.relative_to;
+        let done = manifest
+            .entries
+            .into_iter()
+            .filter(|e| {
+                let (algorithm, len) = digest_length(e);
+                (algorithm.is_some() || !named) && longest.get(&algorithm) == Some(&len)
+            })
+            .map(|e| match &relative_to {
+                Some(dir) => dir.join(&e.path).to_string_lossy().into_owned(),
+                None => e.path,
+            })
+            .collect();
+        Ok(Self { done })
+    }
+
+    /// Whether the file was hashed by the earlier run, given its path as it would be output.
+    pub fn is_done(&self, displayed: &Path) -> bool {
+        self.done.contains(displayed.to_string_lossy().as_ref())
+    }
+
+    pub fn len(&self) -> usize {
+        self.done.len()
+    }
+}
@@ -0,0 +1,72 @@
+//! One digest for a whole run, for --structure-only, so that two runs can be compared at a glance.
+use std::io;
+use std::path::PathBuf;
+
+use recursum::hashers::Hasher;
+use recursum::sink::OutputSink;
+use recursum::{FileHash, HashError};
+
+/// Collects every file's digest, and when finished, prints on stderr a digest of them all.
+///
+/// Files are sorted by path first, so that the rollup does not depend on the order they were walked in.
+/// Every path and digest is kept in memory.
+pub struct Rollup {
+    hasher: Option<Box<dyn Hasher>>,
+    root: Option<PathBuf>,
+    files: Vec<(PathBuf, String)>,
+    errors: u64,
+}
+
+impl Rollup {
+    /// Paths are made relative to `root`, if given, as for the per-file digests.
+    pub fn new(hasher: Box<dyn Hasher>, root: Option<PathBuf>) -> Self {
+        Self {
+            hasher: Some(hasher),
+            root,
+            files: Vec::new(),
+            errors: 0,
+        }
+    }
+}
+
+impl OutputSink for Rollup {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        let path = match &self.root {
+            Some(root) => result.path.strip_prefix(root).unwrap_or(&result.path),
+            None => &result.path,
+        };
+        self.files.push((path.to_path_buf(), result.digest.clone()));
+        Ok(())
+    }
+
+    fn on_error(&mut self, _error: &HashError) -> io::Result<()> {
+        self.errors += 1;
+        Ok(())
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        let mut hasher = match self.hasher.take() {
+            Some(h) => h,
+            None => return Ok(()),
+        };
+        self.files.sort();
+        for (path, digest) in &self.files {
+            hasher.update(path.to_string_lossy().as_bytes());
+            hasher.update(b"\0");
+            hasher.update(digest.as_bytes());
+            hasher.update(b"\n");
+        }
+        eprintln!(
+            "structure digest of {} files: {}",
+            self.files.len(),
+            hex::encode(hasher.finalize())
+        );
+        if self.errors > 0 {
+            eprintln!(
+                "{} files could not be stat'd, and are not included",
+                self.errors
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+//! Checking a tree too large for any one run a slice at a time, for --rotate-state:
+//! each run starts just after the last file the previous run hashed, and carries on from the beginning
+//! once it reaches the end, so that together, successive runs (each limited by --max-duration) cover the whole tree.
+//!
+//! The state file records the directory, the path (relative to it) of the last file hashed,
+//! and how many times runs have reached the end of the tree, e.g.
+//!
+//! ```text
+//! # recursum rotation state
+//! root=/srv/archive
+//! position=2019/scans/0451.tiff
+//! passes=3
+//! updated=2020-11-03T17:04:05Z
+//! ```
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use tempfile::NamedTempFile;
+
+use crate::disk_space::directory;
+use crate::header::{escape, rfc3339, unescape};
+
+const MAGIC: &str = "# recursum rotation state";
+
+pub struct Rotation {
+    path: PathBuf,
+    /// the directory walked, as given
+    root: PathBuf,
+    /// canonical, to recognise it however it is given
+    canonical_root: PathBuf,
+    /// relative path of the last file the previous run hashed
+    start: Option<PathBuf>,
+    passes: u64,
+    /// relative path of the last file this run has hashed so far
+    last: Mutex<Option<PathBuf>>,
+}
+
+impl Rotation {
+    /// Read the state from `path`, if it exists, for a walk of `root`;
+    /// fails if it was made for a different directory.
+    pub fn load(path: &Path, root: &Path) -> Result<Self, String> {
+        let canonical_root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+        let mut rotation = Self {
+            path: path.to_path_buf(),
+            root: root.to_path_buf(),
+            canonical_root,
+            start: None,
+            passes: 0,
+            last: Mutex::new(None),
+        };
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(rotation),
+            Err(e) => return Err(format!("could not read {:?}: {}", path, e)),
+        };
+        let mut lines = text.lines();
+        if lines.next() != Some(MAGIC) {
+            return Err(format!("{:?} is not a rotation state file", path));
+        }
+        for line in lines {
+            match line.split_once('=') {
+                Some(("root", value)) => {
+                    let recorded = PathBuf::from(unescape(value));
+                    if recorded != rotation.canonical_root {
+                        return Err(format!(
+                            "{:?} is the rotation state for {:?}, not {:?}",
+                            path, recorded, rotation.canonical_root
+                        ));
+                    }
+                }
+                Some(("position", value)) => rotation.start = Some(PathBuf::from(unescape(value))),
+                Some(("passes", value)) => {
+                    rotation.passes = value
+                        .parse()
+                        .map_err(|_| format!("invalid passes in {:?}: {}", path, value))?
+                }
+                _ => (),
+            }
+        }
+        Ok(rotation)
+    }
+
+    /// Relative path of the file to start after, if an earlier run stopped somewhere.
+    pub fn start(&self) -> Option<&Path> {
+        self.start.as_deref()
+    }
+
+    /// Note that the file at `path` is about to be hashed.
+    pub fn record(&self, path: &Path) {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        *self.last.lock().expect("rotation lock poisoned") = Some(relative.to_path_buf());
+    }
+
+    /// Whether this run went past the end of the tree: it either hashed everything after where it started
+    /// (`finished`, i.e. did not run out of time) or started again from the beginning.
+    fn reached_end(&self, finished: bool) -> bool {
+        let last = self.last.lock().expect("rotation lock poisoned");
+        let bytes = |p: &Path| p.as_os_str().as_encoded_bytes().to_vec();
+        match (&self.start, &*last) {
+            _ if finished => true,
+            (Some(start), Some(last)) => bytes(last) <= bytes(start),
+            _ => false,
+        }
+    }
+
+    /// Save where this run stopped, replacing the state file, and say where the next run will start.
+    /// `finished` is whether every file was hashed, rather than the run stopping for lack of time.
+    pub fn save(&self, finished: bool) -> io::Result<()> {
+        let passes = self.passes + u64::from(self.reached_end(finished));
+        if passes > self.passes {
+            eprintln!(
+                "reached the end of {:?}: {} full pass(es) completed",
+                self.root, passes
+            );
+        }
+        let last = self.last.lock().expect("rotation lock poisoned").clone();
+        let position = last.as_ref().or(self.start.as_ref());
+        let mut text = format!(
+            "{}\nroot={}\n",
+            MAGIC,
+            escape(&self.canonical_root.to_string_lossy())
+        );
+        if let Some(p) = position {
+            text.push_str(&format!("position={}\n", escape(&p.to_string_lossy())));
+            eprintln!("the next run with --rotate-state will start after {:?}", p);
+        }
+        text.push_str(&format!(
+            "passes={}\nupdated={}\n",
+            passes,
+            rfc3339(SystemTime::now())
+        ));
+        let mut tmp = NamedTempFile::new_in(directory(&self.path))?;
+        tmp.write_all(text.as_bytes())?;
+        tmp.as_file().sync_all()?;
+        tmp.persist(&self.path).map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
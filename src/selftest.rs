@@ -0,0 +1,482 @@
+//! Check that every compiled-in algorithm, and the output around it, is correct on this machine.
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use structopt::StructOpt;
+
+use recursum::blocking::hash_tree;
+use recursum::hashers::{self, HasherFactory, SeededHasherFactory};
+use recursum::sink::write_results_blocking;
+use recursum::walk::{WalkOptions, WalkerKind};
+use recursum::HashOptions;
+
+use crate::output::{Compression, TextOutput};
+use crate::progress::HumanBytes;
+
+/// Length of the generated input for checking that chunking does not change digests.
+const CHUNKED_LENGTH: usize = 1024 * 1024 + 13;
+/// Sizes of the pieces the chunked input is fed in, cycled through.
+const CHUNK_SIZES: &[usize] = &[1, 3, 64, 1000, 4096, 65537];
+/// Files in the directory the stream walker is checked on.
+const STREAM_FILES: usize = 50_000;
+/// How much walking them may add to peak memory; walkers which hold a whole directory need several times this.
+const STREAM_MEMORY_LIMIT: u64 = 2 * 1024 * 1024;
+
+/// Input repeated some number of times, fed in one repeat at a time, and its hex digest,
+/// seeded if the algorithm takes a seed.
+struct Vector {
+    hasher: &'static str,
+    seed: Option<&'static [u8]>,
+    input: &'static [u8],
+    repeat: usize,
+    digest: &'static str,
+}
+
+/// Published test vectors, or for algorithms which publish none (Meow Hash),
+/// regression vectors from the version pinned in Cargo.toml.
+/// Every compiled-in algorithm needs some, or the self-test fails.
+const VECTORS: &[Vector] = &[
+    Vector {
+        hasher: "crc32",
+        seed: None,
+        input: b"",
+        repeat: 1,
+        digest: "00000000",
+    },
+    Vector {
+        hasher: "crc32",
+        seed: None,
+        input: b"a",
+        repeat: 1,
+        digest: "e8b7be43",
+    },
+    Vector {
+        hasher: "crc32",
+        seed: None,
+        input: b"abc",
+        repeat: 1,
+        digest: "352441c2",
+    },
+    Vector {
+        hasher: "crc32",
+        seed: None,
+        input: b"123456789",
+        repeat: 1,
+        digest: "cbf43926",
+    },
+    Vector {
+        hasher: "crc32",
+        seed: None,
+        input: b"The quick brown fox jumps over the lazy dog",
+        repeat: 1,
+        digest: "414fa339",
+    },
+    Vector {
+        hasher: "crc32",
+        seed: None,
+        input: b"a",
+        repeat: 1_000_000,
+        digest: "dc25bfbc",
+    },
+    // from RFC 1321
+    Vector {
+        hasher: "md5",
+        seed: None,
+        input: b"",
+        repeat: 1,
+        digest: "d41d8cd98f00b204e9800998ecf8427e",
+    },
+    Vector {
+        hasher: "md5",
+        seed: None,
+        input: b"abc",
+        repeat: 1,
+        digest: "900150983cd24fb0d6963f7d28e17f72",
+    },
+    // from FIPS 180-2
+    Vector {
+        hasher: "sha1",
+        seed: None,
+        input: b"abc",
+        repeat: 1,
+        digest: "a9993e364706816aba3e25717850c26c9cd0d89d",
+    },
+    Vector {
+        hasher: "sha256",
+        seed: None,
+        input: b"abc",
+        repeat: 1,
+        digest: "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+    },
+    Vector {
+        hasher: "sha256",
+        seed: None,
+        input: b"a",
+        repeat: 1_000_000,
+        digest: "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0",
+    },
+    Vector {
+        hasher: "sha512",
+        seed: None,
+        input: b"abc",
+        repeat: 1,
+        digest: "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f",
+    },
+    // from git hash-object
+    Vector {
+        hasher: "git-sha1",
+        seed: None,
+        input: b"",
+        repeat: 1,
+        digest: "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391",
+    },
+    Vector {
+        hasher: "git-sha1",
+        seed: None,
+        input: b"abc",
+        repeat: 1,
+        digest: "f2ba8f84ab5c1bce84a7b441cb1959cfc7093b7f",
+    },
+    Vector {
+        hasher: "git-sha256",
+        seed: None,
+        input: b"",
+        repeat: 1,
+        digest: "473a0f4c3be8a93681a267e3b1e9a7dcda1185436fe141f7749120a303721813",
+    },
+    Vector {
+        hasher: "git-sha256",
+        seed: None,
+        input: b"abc",
+        repeat: 1,
+        digest: "c1cf6e465077930e88dc5136641d402f72a229ddd996f627d60e9639eaba35a6",
+    },
+];
+
+/// Relative path and contents of each file in the generated tree.
+/// `sub-x` and `sub.d/` come before `sub/` in byte-wise path order, but after `sub` in name order,
+/// so walkers which sort each directory by name alone produce them in the wrong order.
+fn tree_files() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        (".hidden", b"abc".to_vec()),
+        ("a.txt", b"123456789".to_vec()),
+        ("empty", Vec::new()),
+        ("sub-x", b"x".to_vec()),
+        ("sub.d/y", b"y".to_vec()),
+        ("sub/big", pseudorandom(100_003)),
+        ("sub/deeper/z", b"a".to_vec()),
+    ]
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SelftestOpt {
+    /// Only print failures.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+}
+
+/// Deterministic bytes which exercise every bit pattern.
+fn pseudorandom(len: usize) -> Vec<u8> {
+    // xorshift64
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 56) as u8
+        })
+        .collect()
+}
+
+fn digest_of<'a, I: IntoIterator<Item = &'a [u8]>>(factory: HasherFactory, chunks: I) -> String {
+    let mut hasher = factory();
+    for chunk in chunks {
+        hasher.update(chunk);
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Check the algorithm against its known-answer vectors, returning how many there were.
+fn known_answers(name: &str, factory: HasherFactory) -> Result<usize, String> {
+    let vectors: Vec<_> = VECTORS.iter().filter(|v| v.hasher == name).collect();
+    if vectors.is_empty() {
+        return Err("no known-answer vectors".to_string());
+    }
+    for v in &vectors {
+        let mut hasher = match v.seed {
+            Some(seed) => {
+                hashers::get_seeded(name)
+                    .ok_or("vector has a seed, but the algorithm takes none")?(seed)
+            }
+            None => factory(),
+        };
+        for _ in 0..v.repeat {
+            hasher.update(v.input);
+        }
+        let digest = hex::encode(hasher.finalize());
+        if digest != v.digest {
+            return Err(format!(
+                "{:?} x {}{}: expected {}, got {}",
+                String::from_utf8_lossy(v.input),
+                v.repeat,
+                v.seed
+                    .map(|s| format!(" seeded with {:?}", String::from_utf8_lossy(s)))
+                    .unwrap_or_default(),
+                v.digest,
+                digest
+            ));
+        }
+    }
+    Ok(vectors.len())
+}
+
+/// Check that feeding input in pieces of various sizes gives the same digest as all at once.
+fn chunking(factory: HasherFactory) -> Result<(), String> {
+    let data = pseudorandom(CHUNKED_LENGTH);
+    let whole = digest_of(factory, Some(data.as_slice()));
+    let mut chunks = Vec::new();
+    let mut rest = data.as_slice();
+    for size in CHUNK_SIZES.iter().cycle() {
+        if rest.is_empty() {
+            break;
+        }
+        let (chunk, r) = rest.split_at((*size).min(rest.len()));
+        chunks.push(chunk);
+        rest = r;
+    }
+    let chunked = digest_of(factory, chunks);
+    if whole == chunked {
+        Ok(())
+    } else {
+        Err(format!("all at once {}, in chunks {}", whole, chunked))
+    }
+}
+
+/// Check that seeding is reproducible, and that different seeds (or none) give different digests.
+fn seeding(factory: HasherFactory, seeded: SeededHasherFactory) -> Result<(), String> {
+    let data = pseudorandom(1000);
+    let seeded_digest = |seed: &[u8]| {
+        let mut hasher = seeded(seed);
+        hasher.update(&data);
+        hex::encode(hasher.finalize())
+    };
+    let unseeded = digest_of(factory, Some(data.as_slice()));
+    let (a, a_again, b) = (
+        seeded_digest(b"a"),
+        seeded_digest(b"a"),
+        seeded_digest(b"b"),
+    );
+    if a != a_again {
+        Err(format!("same seed gave {} then {}", a, a_again))
+    } else if a == b || a == unseeded {
+        Err(format!("seeds a, b and none all gave {}", a))
+    } else {
+        Ok(())
+    }
+}
+
+/// Hash the tree under `root` with the given walker, and compare the manifest with one built in memory.
+fn end_to_end(
+    name: &str,
+    root: &Path,
+    manifest: &Path,
+    walker: WalkerKind,
+    hash_first: bool,
+) -> Result<(), String> {
+    let walker = walker.build(
+        2,
+        recursum::queue_length(2),
+        WalkOptions {
+            order: None,
+            sort: true,
+            skip_hidden: false,
+            prune: Default::default(),
+            detect_case_collisions: false,
+            dir_limits: None,
+            skipped: None,
+        },
+    )?;
+    let factory = hashers::get(name).ok_or("not registered")?;
+    let opts = HashOptions {
+        hasher: factory,
+        jobs: 2,
+        ..Default::default()
+    };
+    let separator = if hash_first {
+        crate::COMPATIBLE_SEPARATOR
+    } else {
+        crate::DEFAULT_SEPARATOR
+    };
+    let mut output =
+        TextOutput::new(separator, hash_first, None).write_to_file(manifest, Compression::None);
+    write_results_blocking(hash_tree(root, walker.as_ref(), opts), &mut output)
+        .map_err(|e| e.to_string())?;
+    let actual = fs::read_to_string(manifest).map_err(|e| e.to_string())?;
+
+    let mut files = tree_files();
+    files.sort_by_key(|(rel, _)| *rel);
+    let expected: String = files
+        .iter()
+        .map(|(rel, contents)| {
+            let path = root.join(rel);
+            let digest = digest_of(factory, Some(contents.as_slice()));
+            if hash_first {
+                format!("{}{}{}\n", digest, separator, path.to_string_lossy())
+            } else {
+                format!("{}{}{}\n", path.to_string_lossy(), separator, digest)
+            }
+        })
+        .collect();
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(format!("expected\n{}got\n{}", expected, actual))
+    }
+}
+
+/// The process's peak resident memory, in bytes; Linux only.
+fn peak_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let kib: u64 = status
+        .lines()
+        .find_map(|l| l.strip_prefix("VmHWM:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+/// Walk a directory of many files with the stream walker, which must find them all
+/// without its peak memory growing with their number.
+fn stream_walk(dir: &Path) -> Result<String, String> {
+    fs::create_dir(dir).map_err(|e| e.to_string())?;
+    for i in 0..STREAM_FILES {
+        File::create(dir.join(i.to_string())).map_err(|e| e.to_string())?;
+    }
+    let walker = WalkerKind::Stream.build(
+        1,
+        1,
+        WalkOptions {
+            order: None,
+            sort: false,
+            skip_hidden: false,
+            prune: Default::default(),
+            detect_case_collisions: false,
+            dir_limits: None,
+            skipped: None,
+        },
+    )?;
+    let before = peak_memory();
+    let found = walker.files(dir).count();
+    let after = peak_memory();
+    if found != STREAM_FILES {
+        return Err(format!("found {} of {} files", found, STREAM_FILES));
+    }
+    match before.zip(after) {
+        Some((b, a)) if a - b > STREAM_MEMORY_LIMIT => Err(format!(
+            "peak memory grew by {} walking {} files",
+            HumanBytes(a - b),
+            STREAM_FILES
+        )),
+        Some((b, a)) => Ok(format!(
+            " ({} files; peak memory grew by {})",
+            STREAM_FILES,
+            HumanBytes(a - b)
+        )),
+        None => Ok(format!(" ({} files; memory not measured)", STREAM_FILES)),
+    }
+}
+
+fn walker_name(walker: WalkerKind) -> &'static str {
+    match walker {
+        WalkerKind::JWalk => "jwalk",
+        WalkerKind::Ignore => "ignore",
+        WalkerKind::Serial => "walkdir",
+        WalkerKind::Stream => "stream",
+    }
+}
+
+fn create_tree(root: &Path) -> io::Result<()> {
+    for (rel, contents) in tree_files() {
+        let path = root.join(rel);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, contents)?;
+    }
+    Ok(())
+}
+
+pub fn run(opt: SelftestOpt) {
+    let tmp = tempfile::tempdir().expect("could not create temporary directory");
+    let root: PathBuf = tmp.path().join("tree");
+    let manifest = tmp.path().join("manifest.txt");
+    create_tree(&root).expect("could not create test tree");
+
+    let mut walkers = vec![WalkerKind::for_order(None), WalkerKind::Serial];
+    walkers.dedup();
+
+    let mut failed = 0;
+    let mut report = |check: String, result: Result<String, String>| match result {
+        Ok(detail) => {
+            if !opt.quiet {
+                println!("ok    {}{}", check, detail);
+            }
+        }
+        Err(e) => {
+            failed += 1;
+            println!("FAIL  {}: {}", check, e);
+        }
+    };
+
+    // before hashing raises the peak
+    report(
+        "stream walker: bounded memory".to_string(),
+        stream_walk(&tmp.path().join("flat")),
+    );
+
+    for name in hashers::names() {
+        if let Some(reason) = hashers::unsupported(&name) {
+            if !opt.quiet {
+                println!("skip  {}: {}", name, reason);
+            }
+            continue;
+        }
+        let factory = hashers::get(&name).expect("name was just listed");
+        report(
+            format!("{}: known answers", name),
+            known_answers(&name, factory).map(|n| format!(" ({} vectors)", n)),
+        );
+        report(
+            format!("{}: chunked input", name),
+            chunking(factory).map(|_| String::new()),
+        );
+        if let Some(seeded) = hashers::get_seeded(&name) {
+            report(
+                format!("{}: seeding", name),
+                seeding(factory, seeded).map(|_| String::new()),
+            );
+        }
+        for walker in &walkers {
+            for hash_first in &[false, true] {
+                report(
+                    format!(
+                        "{}: end to end ({} walker{})",
+                        name,
+                        walker_name(*walker),
+                        if *hash_first { ", compatible" } else { "" }
+                    ),
+                    end_to_end(&name, &root, &manifest, *walker, *hash_first)
+                        .map(|_| String::new()),
+                );
+            }
+        }
+    }
+
+    if failed > 0 {
+        eprintln!("{} checks failed", failed);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,148 @@
+//! Consumers of hashing results.
+use std::io;
+use std::path::Path;
+
+use tokio_stream::{Stream, StreamExt};
+
+use crate::cancel::CancellationToken;
+use crate::{FileHash, HashError};
+
+/// Receives the results of hashing, in order.
+///
+/// Formatting, progress reporting and statistics can each be a separate sink;
+/// combine several in a `Vec<Box<dyn OutputSink>>`.
+pub trait OutputSink {
+    /// Called with each file which was hashed.
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()>;
+
+    /// Called with each file which could not be hashed. Ignored by default.
+    fn on_error(&mut self, _error: &HashError) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called with each file (or directory, along with everything in it) which was deliberately not hashed,
+    /// and a short reason, e.g. `hidden`. Ignored by default.
+    fn on_skipped(&mut self, _path: &Path, _reason: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called once, after the last result.
+    fn on_finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T: OutputSink + ?Sized> OutputSink for Box<T> {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        (**self).on_result(result)
+    }
+
+    fn on_error(&mut self, error: &HashError) -> io::Result<()> {
+        (**self).on_error(error)
+    }
+
+    fn on_skipped(&mut self, path: &Path, reason: &str) -> io::Result<()> {
+        (**self).on_skipped(path, reason)
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        (**self).on_finish()
+    }
+}
+
+/// Passes everything to each sink in turn.
+impl<T: OutputSink> OutputSink for Vec<T> {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        self.iter_mut().try_for_each(|s| s.on_result(result))
+    }
+
+    fn on_error(&mut self, error: &HashError) -> io::Result<()> {
+        self.iter_mut().try_for_each(|s| s.on_error(error))
+    }
+
+    fn on_skipped(&mut self, path: &Path, reason: &str) -> io::Result<()> {
+        self.iter_mut().try_for_each(|s| s.on_skipped(path, reason))
+    }
+
+    /// Every sink is finished, even if an earlier one fails; the first error is returned.
+    fn on_finish(&mut self) -> io::Result<()> {
+        let mut result = Ok(());
+        for sink in self.iter_mut() {
+            let finished = sink.on_finish();
+            if result.is_ok() {
+                result = finished;
+            }
+        }
+        result
+    }
+}
+
+/// Pass each result (e.g. from [hash_stream](crate::hash_stream)) to the sink, then finish it.
+///
+/// Stops at the first error returned by the sink, without finishing it.
+pub async fn write_results<S>(results: S, sink: &mut dyn OutputSink) -> io::Result<()>
+where
+    S: Stream<Item = Result<FileHash, HashError>> + Unpin,
+{
+    write_results_until(results, sink, None).await
+}
+
+/// As [write_results], but if `cancel` is cancelled, stops without finishing the sink
+/// and returns a [cancelled](crate::cancel::cancelled) error.
+///
+/// A cancelled [hash_stream](crate::hash_stream) just ends early, so this is how to tell its results are incomplete.
+pub async fn write_results_until<S>(
+    mut results: S,
+    sink: &mut dyn OutputSink,
+    cancel: Option<&CancellationToken>,
+) -> io::Result<()>
+where
+    S: Stream<Item = Result<FileHash, HashError>> + Unpin,
+{
+    while let Some(result) = results.next().await {
+        if let Some(c) = cancel {
+            c.check()?;
+        }
+        handle(result, sink)?;
+    }
+    if let Some(c) = cancel {
+        c.check()?;
+    }
+    sink.on_finish()
+}
+
+/// As [write_results], for results from an iterator (e.g. from [hash_iter](crate::blocking::hash_iter)).
+pub fn write_results_blocking<I>(results: I, sink: &mut dyn OutputSink) -> io::Result<()>
+where
+    I: IntoIterator<Item = Result<FileHash, HashError>>,
+{
+    write_results_blocking_until(results, sink, None)
+}
+
+/// As [write_results_until], for results from an iterator.
+pub fn write_results_blocking_until<I>(
+    results: I,
+    sink: &mut dyn OutputSink,
+    cancel: Option<&CancellationToken>,
+) -> io::Result<()>
+where
+    I: IntoIterator<Item = Result<FileHash, HashError>>,
+{
+    for result in results {
+        if let Some(c) = cancel {
+            c.check()?;
+        }
+        handle(result, sink)?;
+    }
+    if let Some(c) = cancel {
+        c.check()?;
+    }
+    sink.on_finish()
+}
+
+fn handle(result: Result<FileHash, HashError>, sink: &mut dyn OutputSink) -> io::Result<()> {
+    match result {
+        Ok(hashed) => sink.on_result(&hashed),
+        Err(e) => sink.on_error(&e),
+    }
+}
@@ -0,0 +1,251 @@
+//! Extra outputs given with --sink, each written from the same results as the main output,
+//! so that several formats can be produced from one read of the data.
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use recursum::sink::OutputSink;
+use recursum::{FileHash, HashError};
+
+#[cfg(feature = "redis")]
+use crate::content_index::{RedisIndex, RedisTarget};
+use crate::encrypt::Recipients;
+use crate::header::Header;
+use crate::output::{Compression, ManifestWriter, TextOutput};
+#[cfg(any(feature = "kafka", feature = "nats"))]
+use crate::publish::{Endpoint, PublishOutput};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SinkFormat {
+    /// As the main output, with the same separator, line format and header.
+    Text,
+    /// As md5sum and friends: digest, two spaces, path.
+    Coreutils,
+    /// One JSON object per line.
+    JsonLines,
+    /// Parquet, with a row per file.
+    Parquet,
+    /// A message per file, as the JSON lines records, to a Kafka topic.
+    Kafka,
+    /// A message per file, as the JSON lines records, to a NATS subject.
+    Nats,
+    /// Entries in Redis, from digest to paths or from path to digest.
+    Redis,
+}
+
+impl FromStr for SinkFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "coreutils" => Ok(Self::Coreutils),
+            "jsonl" => Ok(Self::JsonLines),
+            "parquet" if cfg!(feature = "parquet") => Ok(Self::Parquet),
+            "parquet" => Err("parquet output was not compiled in (feature parquet)".to_string()),
+            "kafka" if cfg!(feature = "kafka") => Ok(Self::Kafka),
+            "kafka" => Err("publishing to Kafka was not compiled in (feature kafka)".to_string()),
+            "nats" if cfg!(feature = "nats") => Ok(Self::Nats),
+            "nats" => Err("publishing to NATS was not compiled in (feature nats)".to_string()),
+            "redis" if cfg!(feature = "redis") => Ok(Self::Redis),
+            "redis" => Err("the Redis index was not compiled in (feature redis)".to_string()),
+            _ => Err(format!(
+                "unknown sink format '{}'; expected text, coreutils, jsonl, parquet, kafka, nats or redis",
+                s
+            )),
+        }
+    }
+}
+
+/// An output given as `FORMAT:PATH`, or `kafka://BROKER[,BROKER...]/TOPIC` `nats://SERVER/SUBJECT`
+/// or `redis://HOST[:PORT][/DB][?OPTIONS]`.
+#[derive(Debug, Clone)]
+pub struct SinkSpec {
+    pub format: SinkFormat,
+    pub path: PathBuf,
+}
+
+impl FromStr for SinkSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (format, path) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected FORMAT:PATH, got '{}'", s))?;
+        if path.is_empty() {
+            return Err(format!("no path given in '{}'", s));
+        }
+        let format = format.parse()?;
+        #[cfg(any(feature = "kafka", feature = "nats"))]
+        if matches!(format, SinkFormat::Kafka | SinkFormat::Nats) {
+            Endpoint::parse(path)?;
+        }
+        #[cfg(feature = "redis")]
+        if format == SinkFormat::Redis {
+            RedisTarget::parse(path)?;
+        }
+        Ok(Self {
+            format,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+impl SinkSpec {
+    /// Whether the output is written to a file, rather than sent over the network.
+    pub fn is_file(&self) -> bool {
+        matches!(
+            self.format,
+            SinkFormat::Text | SinkFormat::Coreutils | SinkFormat::JsonLines | SinkFormat::Parquet
+        )
+    }
+}
+
+/// What every sink may need to know about the run.
+pub struct SinkContext<'a> {
+    pub separator: &'a str,
+    pub hash_first: bool,
+    pub header: Option<&'a Header>,
+    pub algorithm: &'a str,
+    pub run_id: &'a str,
+    /// encrypt output files to these
+    pub recipients: Option<&'a Recipients>,
+}
+
+impl SinkSpec {
+    /// Create the output file (compressed according to its extension), which appears once hashing has finished,
+    /// or connect to the message broker.
+    pub fn open(&self, ctx: &SinkContext) -> io::Result<Box<dyn OutputSink>> {
+        let compression = Compression::from_path(&self.path);
+        Ok(match self.format {
+            SinkFormat::Text => {
+                let mut text = TextOutput::new(ctx.separator, ctx.hash_first, None)
+                    .encrypt_to(ctx.recipients.cloned())
+                    .write_to_file(&self.path, compression);
+                if let Some(h) = ctx.header {
+                    text = text.with_header(h.clone());
+                }
+                Box::new(text)
+            }
+            SinkFormat::Coreutils => Box::new(
+                TextOutput::new(crate::COMPATIBLE_SEPARATOR, true, None)
+                    .encrypt_to(ctx.recipients.cloned())
+                    .write_to_file(&self.path, compression),
+            ),
+            SinkFormat::JsonLines => Box::new(JsonLinesOutput {
+                writer: Some(ManifestWriter::create(
+                    &self.path,
+                    compression,
+                    ctx.recipients,
+                )?),
+                algorithm: ctx.algorithm.to_string(),
+                run_id: ctx.run_id.to_string(),
+            }),
+            #[cfg(feature = "parquet")]
+            SinkFormat::Parquet => Box::new(crate::columnar::ParquetOutput::create(
+                &self.path,
+                ctx.algorithm,
+                ctx.run_id,
+                ctx.recipients,
+            )?),
+            #[cfg(not(feature = "parquet"))]
+            SinkFormat::Parquet => unreachable!("rejected when parsing --sink"),
+            #[cfg(feature = "kafka")]
+            SinkFormat::Kafka => Box::new(PublishOutput::kafka(
+                &self.endpoint(),
+                ctx.algorithm,
+                ctx.run_id,
+            )?),
+            #[cfg(not(feature = "kafka"))]
+            SinkFormat::Kafka => unreachable!("rejected when parsing --sink"),
+            #[cfg(feature = "nats")]
+            SinkFormat::Nats => Box::new(PublishOutput::nats(
+                &self.endpoint(),
+                ctx.algorithm,
+                ctx.run_id,
+            )?),
+            #[cfg(not(feature = "nats"))]
+            SinkFormat::Nats => unreachable!("rejected when parsing --sink"),
+            #[cfg(feature = "redis")]
+            SinkFormat::Redis => {
+                let target = RedisTarget::parse(&self.path.to_string_lossy())
+                    .expect("checked when parsing --sink");
+                Box::new(RedisIndex::connect(&target, ctx.algorithm)?)
+            }
+            #[cfg(not(feature = "redis"))]
+            SinkFormat::Redis => unreachable!("rejected when parsing --sink"),
+        })
+    }
+}
+
+/// Where to publish, for message broker sinks.
+#[cfg(any(feature = "kafka", feature = "nats"))]
+impl SinkSpec {
+    fn endpoint(&self) -> Endpoint {
+        Endpoint::parse(&self.path.to_string_lossy()).expect("checked when parsing --sink")
+    }
+}
+
+/// Writes e.g. `{"path":"a/b.txt","digest":"...","size":12,"stable":true,"algorithm":"meow","run_id":"..."}`,
+/// which `check` reads.
+struct JsonLinesOutput {
+    /// None once finished
+    writer: Option<ManifestWriter>,
+    algorithm: String,
+    run_id: String,
+}
+
+impl OutputSink for JsonLinesOutput {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        let writer = self.writer.as_mut().expect("output already finished");
+        writeln!(
+            writer,
+            "{}",
+            json_record(result, &self.algorithm, &self.run_id)
+        )
+    }
+
+    /// As with the main output, a manifest which silently lacks a file is worse than none.
+    fn on_error(&mut self, error: &HashError) -> io::Result<()> {
+        Err(io::Error::new(error.source.kind(), error.to_string()))
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        match self.writer.take() {
+            Some(w) => w.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The JSON object describing a result, without a trailing newline.
+pub fn json_record(result: &FileHash, algorithm: &str, run_id: &str) -> String {
+    format!(
+        r#"{{"path":{},"digest":"{}","size":{},"stable":{},"algorithm":{},"run_id":{}}}"#,
+        json_string(&result.path.to_string_lossy()),
+        result.digest,
+        result.size,
+        result.stable,
+        json_string(algorithm),
+        json_string(run_id),
+    )
+}
+
+/// Quote and escape a string for JSON.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
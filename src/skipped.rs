@@ -0,0 +1,70 @@
+//! Listing the files which were not hashed on purpose in the output, for --report-skipped,
+//! so that an audit can account for every file as either hashed or deliberately left out.
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+
+use recursum::sink::OutputSink;
+use recursum::walk::SkipReason;
+use recursum::{FileHash, HashError};
+
+use crate::{rewrite_path, Rewrite};
+
+/// Passes everything on to `inner`, along with the entries the walk left out (as they are found)
+/// and files which could not be hashed, once `inner` has accepted the error (i.e. with --errors-to).
+pub struct ReportSkipped {
+    inner: Box<dyn OutputSink>,
+    walked: Option<Receiver<(PathBuf, SkipReason)>>,
+    rewrite: Rewrite,
+}
+
+impl ReportSkipped {
+    pub fn new(
+        inner: Box<dyn OutputSink>,
+        walked: Option<Receiver<(PathBuf, SkipReason)>>,
+        rewrite: Rewrite,
+    ) -> Self {
+        Self {
+            inner,
+            walked,
+            rewrite,
+        }
+    }
+
+    /// Report the entries skipped by the walk so far.
+    fn drain(&mut self) -> io::Result<()> {
+        let walked = match &self.walked {
+            Some(w) => w,
+            None => return Ok(()),
+        };
+        for (path, reason) in walked.try_iter() {
+            let path = rewrite_path(path, &self.rewrite);
+            self.inner.on_skipped(&path, reason.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+impl OutputSink for ReportSkipped {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        self.drain()?;
+        self.inner.on_result(result)
+    }
+
+    fn on_error(&mut self, error: &HashError) -> io::Result<()> {
+        self.drain()?;
+        self.inner.on_error(error)?;
+        let path = rewrite_path(error.path.clone(), &self.rewrite);
+        let reason = format!("error ({}: {})", error.phase, error.source);
+        self.inner.on_skipped(&path, &reason)
+    }
+
+    fn on_skipped(&mut self, path: &Path, reason: &str) -> io::Result<()> {
+        self.inner.on_skipped(path, reason)
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        self.drain()?;
+        self.inner.on_finish()
+    }
+}
@@ -0,0 +1,358 @@
+//! Verifying a directory against a --cache in two phases: first comparing every file's size and modification time
+//! to the cached ones, then reading only the files which changed or are new,
+//! plus a random sample of the rest to catch corruption which leaves metadata alone.
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, Metadata};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use structopt::StructOpt;
+use tokio_stream::{iter, StreamExt};
+
+use recursum::hash_cache::{Entry, HashCache};
+use recursum::walk::{WalkOptions, WalkerKind};
+use recursum::{hash_stream, hashers, queue_length, runtime, HashOptions};
+
+use crate::color::{self, Role};
+use crate::cron;
+use crate::lock::{self, RunLock};
+use crate::progress::Progress;
+use crate::{hasher_for, or_num_cpus, seeded_hasher, select_hasher};
+
+#[derive(Debug, StructOpt)]
+pub struct SmartCheckOpt {
+    /// Directory to check.
+    #[structopt(parse(from_os_str))]
+    root: PathBuf,
+    /// The files' digests, sizes and modification times when they were last known to be good: a cache made by `recursum --cache FILE <root>`.
+    #[structopt(long = "baseline", parse(from_os_str))]
+    baseline: PathBuf,
+    /// Percentage of files whose size and modification time have not changed to read anyway, picked at random each run.
+    #[structopt(long = "sample-percent", default_value = "1")]
+    sample_percent: f64,
+    /// Accept the changes found: save the digests of new and changed files to the baseline, and remove missing files from it. Then only files whose contents changed without their size or modification time changing, or which could not be read, fail.
+    #[structopt(long = "update")]
+    update: bool,
+    /// Hashing threads.
+    #[structopt(short = "t", long = "threads")]
+    threads: Option<usize>,
+    /// Directory-walking threads.
+    #[structopt(short = "w", long = "walkers")]
+    walkers: Option<usize>,
+    /// Do not print OK or TOUCHED for files which were read and have the expected contents; only differences and failures are printed.
+    #[structopt(long = "quiet-ok")]
+    quiet_ok: bool,
+    /// Do not show progress information.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+    /// Print nothing but one line when finished, for scheduled jobs and log collectors: the time, root, files, bytes read, errors, mismatches (files whose contents changed without their size or modification time changing), the numbers of new, changed, touched and missing files, and the duration, as logfmt. The exit status is nonzero if the check failed, as usual.
+    #[structopt(long = "cron", conflicts_with_all = &["quiet", "quiet-ok"])]
+    cron: bool,
+}
+
+/// What became of a file which was read, or is missing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    /// Unchanged going by metadata, read as part of the sample, and the contents match.
+    Ok,
+    /// The modification time changed but the contents did not.
+    Touched,
+    New,
+    Changed,
+    Missing,
+    /// Unchanged going by metadata, but the contents do not match.
+    Failed,
+    Unreadable,
+}
+
+impl Status {
+    fn label(self) -> &'static str {
+        match self {
+            Self::Ok => "OK",
+            Self::Touched => "TOUCHED",
+            Self::New => "NEW",
+            Self::Changed => "CHANGED",
+            Self::Missing => "MISSING",
+            Self::Failed => "FAILED",
+            Self::Unreadable => "FAILED open or read",
+        }
+    }
+
+    fn role(self) -> Role {
+        match self {
+            Self::Ok | Self::Touched => Role::Good,
+            Self::New | Self::Changed | Self::Missing => Role::Warning,
+            Self::Failed | Self::Unreadable => Role::Bad,
+        }
+    }
+}
+
+/// How many files had each outcome.
+#[derive(Debug, Default)]
+struct Tally {
+    /// not read, because their metadata had not changed
+    unchanged: usize,
+    /// read from files which could be hashed
+    bytes: u64,
+    ok: usize,
+    touched: usize,
+    new: usize,
+    changed: usize,
+    missing: usize,
+    failed: usize,
+    unreadable: usize,
+}
+
+impl Tally {
+    fn add(&mut self, status: Status) {
+        let count = match status {
+            Status::Ok => &mut self.ok,
+            Status::Touched => &mut self.touched,
+            Status::New => &mut self.new,
+            Status::Changed => &mut self.changed,
+            Status::Missing => &mut self.missing,
+            Status::Failed => &mut self.failed,
+            Status::Unreadable => &mut self.unreadable,
+        };
+        *count += 1;
+    }
+}
+
+/// Options to hash with the algorithm, seed and digest length a cache's digests were made with,
+/// as identified by [HashCache::hasher], e.g. `meow seed=00ff digest-length=16`.
+fn hash_options(id: &str, threads: usize) -> HashOptions {
+    let mut fields = id.split_whitespace();
+    let name = fields.next().unwrap_or_default();
+    if !name.starts_with(hashers::BY_SIZE_PREFIX) && hashers::get(name).is_none() {
+        panic!(
+            "the baseline uses {}, which this build of recursum does not support",
+            name
+        );
+    }
+    let (hasher, by_size) = hasher_for(select_hasher(name));
+    let mut opts = HashOptions {
+        hasher,
+        by_size,
+        jobs: threads,
+        ..Default::default()
+    };
+    for field in fields {
+        match field.split_once('=') {
+            Some(("seed", seed)) => opts.seeded_hasher = Some(seeded_hasher(name, seed)),
+            Some(("digest-length", length)) => {
+                opts.truncate = Some(
+                    length
+                        .parse()
+                        .unwrap_or_else(|_| panic!("invalid digest length in baseline: {}", id)),
+                )
+            }
+            _ => panic!("unknown hasher option in baseline: {}", field),
+        }
+    }
+    opts
+}
+
+pub fn run(opt: SmartCheckOpt) {
+    let started = Instant::now();
+    if !opt.root.is_dir() {
+        panic!("{:?} is not a directory", opt.root);
+    }
+    if !(0.0..=100.0).contains(&opt.sample_percent) {
+        panic!("--sample-percent must be between 0 and 100");
+    }
+    // another run saving the baseline meanwhile would lose one set of changes
+    let _lock = opt.update.then(|| {
+        RunLock::acquire(&lock::beside(&opt.baseline), Duration::ZERO)
+            .unwrap_or_else(|e| panic!("{}", e))
+    });
+    let mut cache = HashCache::load(&opt.baseline)
+        .unwrap_or_else(|e| panic!("could not read baseline {:?}: {}", opt.baseline, e));
+    cache.verify_percent(opt.sample_percent);
+    cache.set_root(opt.root.clone());
+    let threads = or_num_cpus(opt.threads);
+    let hash_opts = hash_options(&cache.hasher, threads);
+    let mut baseline: HashMap<PathBuf, Entry> = cache.entries().into_iter().collect();
+    if !opt.cron {
+        eprintln!(
+            "checking {:?} against {} files in {:?}, made with {}",
+            opt.root,
+            baseline.len(),
+            opt.baseline,
+            cache.hasher
+        );
+    }
+
+    // phase 1: metadata
+    let walker = WalkerKind::for_order(None)
+        .build(
+            or_num_cpus(opt.walkers),
+            queue_length(threads),
+            WalkOptions {
+                order: None,
+                sort: true,
+                skip_hidden: false,
+                prune: HashSet::new(),
+                detect_case_collisions: false,
+                dir_limits: None,
+                skipped: None,
+            },
+        )
+        .unwrap_or_else(|e| panic!("{}", e));
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let mut print = |path: &str, status: Status| {
+        if opt.cron || (opt.quiet_ok && status.role() == Role::Good) {
+            return;
+        }
+        let label = color::stdout(status.role(), status.label());
+        writeln!(out, "{}: {}", path, label).expect("could not write output");
+    };
+    let mut tally = Tally::default();
+    let mut to_read: Vec<(PathBuf, Metadata, Option<Entry>)> = Vec::new();
+    for path in walker.files(&opt.root) {
+        let relative = path.strip_prefix(&opt.root).unwrap_or(&path).to_path_buf();
+        let expected = baseline.remove(&relative);
+        let meta = match fs::metadata(&path) {
+            Ok(meta) => meta,
+            Err(e) => {
+                if !opt.cron {
+                    let msg = format!("recursum: could not read metadata of {:?}: {}", path, e);
+                    eprintln!("{}", color::stderr(Role::Bad, msg));
+                }
+                tally.add(Status::Unreadable);
+                print(&relative.to_string_lossy(), Status::Unreadable);
+                continue;
+            }
+        };
+        match expected {
+            Some(e) if e.matches(&meta) && !cache.sample(&relative) => tally.unchanged += 1,
+            expected => to_read.push((path, meta, expected)),
+        }
+    }
+
+    // phase 2: contents, of only the files which need it
+    let progress = if opt.quiet || opt.cron {
+        None
+    } else {
+        let bytes = to_read.iter().map(|(_, meta, _)| meta.len()).sum();
+        Some(Progress::bounded(to_read.len() as u64, Some(bytes)))
+    };
+    let paths: Vec<_> = to_read.iter().map(|(path, _, _)| path.clone()).collect();
+    let rt = runtime(threads, false).unwrap();
+    rt.block_on(async {
+        let mut results = hash_stream(iter(paths), hash_opts);
+        for (path, meta, expected) in &to_read {
+            let result = results.next().await.expect("one result per path");
+            if let Some(p) = &progress {
+                p.update(path, meta.len());
+            }
+            let status = match (&result, expected) {
+                (Err(e), _) => {
+                    let msg = color::stderr(Role::Bad, format!("recursum: {}", e)).to_string();
+                    match &progress {
+                        Some(p) => p.println(msg),
+                        None if opt.cron => (),
+                        None => eprintln!("{}", msg),
+                    }
+                    Status::Unreadable
+                }
+                (Ok(_), None) => Status::New,
+                (Ok(hashed), Some(e)) => match (e.matches(meta), hashed.digest == e.digest) {
+                    (true, true) => Status::Ok,
+                    (true, false) => Status::Failed,
+                    (false, true) => Status::Touched,
+                    (false, false) => Status::Changed,
+                },
+            };
+            tally.add(status);
+            if let Ok(hashed) = &result {
+                tally.bytes += hashed.size;
+            }
+            let relative = path.strip_prefix(&opt.root).unwrap_or(path);
+            print(&relative.to_string_lossy(), status);
+            if let (Ok(hashed), true) = (&result, opt.update) {
+                let accepted = matches!(status, Status::New | Status::Changed | Status::Touched);
+                if accepted && hashed.stable && hashed.size == meta.len() {
+                    cache.record(path, meta, &hashed.digest);
+                }
+            }
+        }
+    });
+    if let Some(p) = &progress {
+        p.finish();
+    }
+    let mut missing: Vec<_> = baseline.into_keys().collect();
+    missing.sort();
+    for path in &missing {
+        tally.add(Status::Missing);
+        print(&path.to_string_lossy(), Status::Missing);
+    }
+    out.flush().expect("could not write output");
+    if opt.update {
+        let missing: HashSet<_> = missing.into_iter().collect();
+        cache.retain(|path, _| !missing.contains(path));
+        cache
+            .save(&opt.baseline)
+            .unwrap_or_else(|e| panic!("could not save baseline {:?}: {}", opt.baseline, e));
+    }
+    let differed = tally.new + tally.changed + tally.missing > 0;
+    let failed = tally.failed > 0 || tally.unreadable > 0 || (differed && !opt.update);
+    if opt.cron {
+        let files = tally.unchanged
+            + tally.ok
+            + tally.touched
+            + tally.new
+            + tally.changed
+            + tally.failed
+            + tally.unreadable;
+        cron::report(
+            &[("root", &opt.root), ("baseline", &opt.baseline)],
+            &[
+                ("files", files as u64),
+                ("bytes", tally.bytes),
+                ("errors", tally.unreadable as u64),
+                ("mismatches", tally.failed as u64),
+                ("new", tally.new as u64),
+                ("changed", tally.changed as u64),
+                ("touched", tally.touched as u64),
+                ("missing", tally.missing as u64),
+            ],
+            started,
+            failed,
+        );
+    } else {
+        summarise(&tally);
+        if opt.update {
+            eprintln!("Updated {:?}", opt.baseline);
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Print what was found on stderr.
+fn summarise(tally: &Tally) {
+    let sampled = tally.ok + tally.failed;
+    eprintln!(
+        "{} files unchanged going by size and modification time; {} of them read to verify them",
+        tally.unchanged + sampled,
+        sampled
+    );
+    eprintln!(
+        "{} new, {} changed, {} touched (modified without changing contents), {} missing",
+        tally.new, tally.changed, tally.touched, tally.missing
+    );
+    if tally.unreadable > 0 {
+        let msg = format!("WARNING: {} file(s) could not be read", tally.unreadable);
+        eprintln!("{}", color::stderr(Role::Bad, msg));
+    }
+    if tally.failed > 0 {
+        let msg = format!(
+            "WARNING: {} file(s) changed contents without changing size or modification time",
+            tally.failed
+        );
+        eprintln!("{}", color::stderr(Role::Bad, msg));
+    }
+}
@@ -0,0 +1,196 @@
+//! Distributions of file sizes and of the time taken to hash each file, and the largest files.
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use recursum::sink::OutputSink;
+use recursum::FileHash;
+
+use crate::progress::HumanBytes;
+
+/// Upper bounds of the size histogram's buckets; the last bucket is everything larger.
+const BUCKETS: &[u64] = &[
+    0,
+    4 * 1024,
+    64 * 1024,
+    1024 * 1024,
+    16 * 1024 * 1024,
+    256 * 1024 * 1024,
+    4 * 1024 * 1024 * 1024,
+];
+const PERCENTILES: &[f64] = &[50.0, 95.0, 99.0];
+
+/// Number and total size of files in a size range.
+#[derive(Debug, Default, Clone, Copy)]
+struct Bucket {
+    files: u64,
+    bytes: u64,
+}
+
+/// Label for the bucket of files no larger than `BUCKETS[idx]`, or larger than all of them.
+fn bucket_label(idx: usize) -> String {
+    match (idx.checked_sub(1).map(|i| BUCKETS[i]), BUCKETS.get(idx)) {
+        (None, _) => "empty".to_string(),
+        (Some(low), Some(high)) => format!("{} - {}", HumanBytes(low + 1), HumanBytes(*high)),
+        (Some(low), None) => format!("> {}", HumanBytes(low)),
+    }
+}
+
+/// Files counted by size, in buckets spanning a factor of 16.
+#[derive(Debug, Clone)]
+pub struct SizeHistogram {
+    buckets: Vec<Bucket>,
+}
+
+impl SizeHistogram {
+    pub fn new() -> Self {
+        Self {
+            buckets: vec![Bucket::default(); BUCKETS.len() + 1],
+        }
+    }
+
+    pub fn add(&mut self, size: u64) {
+        let idx = BUCKETS
+            .iter()
+            .position(|max| size <= *max)
+            .unwrap_or(BUCKETS.len());
+        self.buckets[idx].files += 1;
+        self.buckets[idx].bytes += size;
+    }
+
+    pub fn files(&self) -> u64 {
+        self.buckets.iter().map(|b| b.files).sum()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.buckets.iter().map(|b| b.bytes).sum()
+    }
+
+    /// One indented line per bucket.
+    pub fn write(&self, w: &mut dyn Write) -> io::Result<()> {
+        for (idx, bucket) in self.buckets.iter().enumerate() {
+            writeln!(
+                w,
+                "  {:>20}: {:>10} files {:>12}",
+                bucket_label(idx),
+                bucket.files,
+                HumanBytes(bucket.bytes).to_string()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The value below which `p` percent of the sorted values fall (nearest rank).
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Collects sizes and hashing times, for --stats, and prints them on stderr when finished.
+pub struct Stats {
+    sizes: SizeHistogram,
+    /// one per file; 16 bytes each, so fine for millions of files
+    elapsed: Vec<Duration>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            sizes: SizeHistogram::new(),
+            elapsed: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "file sizes:")?;
+        self.sizes.write(w)?;
+        if self.elapsed.is_empty() {
+            return Ok(());
+        }
+        self.elapsed.sort_unstable();
+        writeln!(w, "time to hash each file:")?;
+        for p in PERCENTILES {
+            writeln!(w, "  p{}: {:.2?}", p, percentile(&self.elapsed, *p))?;
+        }
+        writeln!(
+            w,
+            "  max: {:.2?} (total {:.2?})",
+            self.elapsed[self.elapsed.len() - 1],
+            self.elapsed.iter().sum::<Duration>()
+        )
+    }
+}
+
+impl OutputSink for Stats {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        self.sizes.add(result.size);
+        self.elapsed.push(result.elapsed);
+        Ok(())
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        let stderr = io::stderr();
+        let mut w = stderr.lock();
+        self.write(&mut w)
+    }
+}
+
+/// Keeps the largest files, for --report-largest, and prints them on stderr when finished.
+pub struct Largest {
+    count: usize,
+    /// smallest on top, to be pushed out by larger files
+    files: BinaryHeap<Reverse<(u64, PathBuf, String)>>,
+}
+
+impl Largest {
+    pub fn new(count: usize) -> Self {
+        Self {
+            count,
+            files: BinaryHeap::with_capacity(count + 1),
+        }
+    }
+
+    fn write(&mut self, w: &mut dyn Write) -> io::Result<()> {
+        let files = std::mem::take(&mut self.files).into_sorted_vec();
+        writeln!(w, "largest {} files:", files.len())?;
+        // sorted ascending by Reverse, i.e. largest first
+        for Reverse((size, path, digest)) in files {
+            writeln!(
+                w,
+                "  {:>12}  {}  {}",
+                HumanBytes(size).to_string(),
+                digest,
+                path.display()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl OutputSink for Largest {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        if self.files.len() == self.count {
+            match self.files.peek() {
+                Some(Reverse((smallest, _, _))) if *smallest < result.size => {
+                    self.files.pop();
+                }
+                _ => return Ok(()),
+            }
+        }
+        self.files.push(Reverse((
+            result.size,
+            result.path.clone(),
+            result.digest.clone(),
+        )));
+        Ok(())
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        let stderr = io::stderr();
+        let mut w = stderr.lock();
+        self.write(&mut w)
+    }
+}
@@ -0,0 +1,79 @@
+//! Refusing paths which the outputs could not hold as they are, for --strict-paths,
+//! rather than writing a lossily converted path which no longer names the file.
+use std::io;
+use std::path::Path;
+
+use recursum::sink::OutputSink;
+use recursum::{FileHash, HashError, Phase};
+
+use crate::OutputFormat;
+
+/// Passes results on to `inner`, except those whose path the output format would alter or misread,
+/// which are passed on as errors instead; so they stop the run, or are logged by --errors-to.
+pub struct StrictPaths {
+    inner: Box<dyn OutputSink>,
+    format: OutputFormat,
+    /// whether lines start with the digest, rather than the path
+    hash_first: bool,
+}
+
+impl StrictPaths {
+    pub fn new(inner: Box<dyn OutputSink>, format: OutputFormat, hash_first: bool) -> Self {
+        Self {
+            inner,
+            format,
+            hash_first,
+        }
+    }
+
+    /// Why the path cannot be written faithfully, if it cannot.
+    fn problem(&self, path: &Path) -> Option<&'static str> {
+        // every output is UTF-8
+        let path = match path.to_str() {
+            Some(p) => p,
+            None => return Some("path is not valid UTF-8"),
+        };
+        let line_based = matches!(self.format, OutputFormat::Text | OutputFormat::Sfv);
+        if line_based && path.contains(['\n', '\r']) {
+            return Some("path contains a line break, which would split its line in two");
+        }
+        match self.format {
+            OutputFormat::Text if !self.hash_first && path.starts_with('#') => {
+                Some("path starts with #, so its line would be read as a comment")
+            }
+            OutputFormat::Sfv if path.starts_with(';') => {
+                Some("path starts with ;, so its line would be read as a comment")
+            }
+            OutputFormat::Sfv if path.ends_with(char::is_whitespace) => {
+                Some("path ends with whitespace, which SFV readers drop")
+            }
+            // mtree and COPY rows escape anything else reversibly, and parquet holds any string
+            _ => None,
+        }
+    }
+}
+
+impl OutputSink for StrictPaths {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        match self.problem(&result.path) {
+            None => self.inner.on_result(result),
+            Some(problem) => self.inner.on_error(&HashError {
+                path: result.path.clone(),
+                phase: Phase::Path,
+                source: io::Error::new(io::ErrorKind::InvalidData, problem),
+            }),
+        }
+    }
+
+    fn on_error(&mut self, error: &HashError) -> io::Result<()> {
+        self.inner.on_error(error)
+    }
+
+    fn on_skipped(&mut self, path: &Path, reason: &str) -> io::Result<()> {
+        self.inner.on_skipped(path, reason)
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        self.inner.on_finish()
+    }
+}
@@ -0,0 +1,49 @@
+//! Digests of what files are called, how large they are and when they were modified, rather than of their contents,
+//! as with recursum --structure-only: a quick way to tell whether anything in a tree has changed at all
+//! before paying for a full run.
+//!
+//! A file's digest is of its path (relative to the root, if set), a NUL byte, its size in decimal, a NUL byte,
+//! and its modification time as seconds and nanoseconds since the epoch (`secs.nanos`), hashed with the chosen algorithm.
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::hash_cache::Mtime;
+use crate::hashers::Hasher;
+
+#[derive(Debug, Default)]
+pub struct Structure {
+    root: OnceLock<PathBuf>,
+}
+
+impl Structure {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hash paths relative to `root`, the directory being hashed, so that identical copies of a tree get identical digests.
+    /// Only the first root set is used.
+    pub fn set_root(&self, root: PathBuf) {
+        let _ = self.root.set(root);
+    }
+
+    /// `path` relative to the root, if set and under it.
+    pub fn relative<'a>(&self, path: &'a Path) -> &'a Path {
+        self.root
+            .get()
+            .and_then(|r| path.strip_prefix(r).ok())
+            .unwrap_or(path)
+    }
+
+    /// The raw digest of the file at `path`, whose metadata is `meta`.
+    pub fn digest(&self, path: &Path, meta: &Metadata, mut hasher: Box<dyn Hasher>) -> Vec<u8> {
+        hasher.update(self.relative(path).to_string_lossy().as_bytes());
+        hasher.update(b"\0");
+        hasher.update(meta.len().to_string().as_bytes());
+        hasher.update(b"\0");
+        if let Some(mtime) = Mtime::of(meta) {
+            hasher.update(mtime.to_string().as_bytes());
+        }
+        hasher.finalize()
+    }
+}
@@ -0,0 +1,104 @@
+//! Sanity checks on the results as a whole, for --flag-empty and --flag-same-hash-different-size.
+//!
+//! A manifest built from a damaged copy (e.g. a silently truncated restore) verifies perfectly against that copy,
+//! so the only hint may be files which are empty, or which cannot have the contents their digest says.
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use recursum::sink::OutputSink;
+use recursum::FileHash;
+
+use crate::color::{self, Role};
+
+/// Paths listed for each kind of problem; the rest are only counted.
+const LISTED: usize = 20;
+
+/// A file's size and path.
+type Seen = (u64, PathBuf);
+
+/// Collects suspicious files, and warns about them on stderr when finished.
+#[derive(Default)]
+pub struct Suspicious {
+    /// None unless --flag-empty
+    empty: Option<Vec<PathBuf>>,
+    /// None unless --flag-same-hash-different-size; the first size and path seen with each digest
+    first_seen: Option<HashMap<String, Seen>>,
+    /// digest, and the first file with it, and a later file with the same digest but a different size
+    mismatched: Vec<(String, Seen, Seen)>,
+}
+
+impl Suspicious {
+    pub fn new(flag_empty: bool, flag_same_hash_different_size: bool) -> Self {
+        Self {
+            empty: flag_empty.then(Vec::new),
+            first_seen: flag_same_hash_different_size.then(HashMap::new),
+            mismatched: Vec::new(),
+        }
+    }
+
+    fn write(&self, w: &mut dyn Write) -> io::Result<()> {
+        if let Some(empty) = self.empty.as_ref().filter(|e| !e.is_empty()) {
+            let msg = format!("{} empty files:", empty.len());
+            writeln!(w, "{}", color::stderr(Role::Warning, msg))?;
+            for path in empty.iter().take(LISTED) {
+                writeln!(w, "  {}", path.display())?;
+            }
+            if empty.len() > LISTED {
+                writeln!(w, "  ... and {} more", empty.len() - LISTED)?;
+            }
+        }
+        if !self.mismatched.is_empty() {
+            let msg = format!(
+                "{} files have the same digest as another file of a different size, so at least one of each pair is inconsistent:",
+                self.mismatched.len()
+            );
+            writeln!(w, "{}", color::stderr(Role::Bad, msg))?;
+            for (digest, (size1, path1), (size2, path2)) in self.mismatched.iter().take(LISTED) {
+                writeln!(
+                    w,
+                    "  {}: {} ({} bytes) and {} ({} bytes)",
+                    digest,
+                    path1.display(),
+                    size1,
+                    path2.display(),
+                    size2
+                )?;
+            }
+            if self.mismatched.len() > LISTED {
+                writeln!(w, "  ... and {} more", self.mismatched.len() - LISTED)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl OutputSink for Suspicious {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        if let Some(empty) = &mut self.empty {
+            if result.size == 0 {
+                empty.push(result.path.clone());
+            }
+        }
+        if let Some(first_seen) = &mut self.first_seen {
+            match first_seen.get(&result.digest) {
+                Some((size, path)) if *size != result.size => self.mismatched.push((
+                    result.digest.clone(),
+                    (*size, path.clone()),
+                    (result.size, result.path.clone()),
+                )),
+                Some(_) => (),
+                None => {
+                    first_seen.insert(result.digest.clone(), (result.size, result.path.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        let stderr = io::stderr();
+        let mut w = stderr.lock();
+        self.write(&mut w)
+    }
+}
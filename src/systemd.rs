@@ -0,0 +1,226 @@
+//! Reporting to systemd, for --systemd: readiness, status lines and watchdog keep-alives
+//! over the notification socket (as sd_notify(3) does), and structured log entries to the journal
+//! over its native protocol, so that a service running recursum can be supervised and searched.
+//!
+//! Both are best effort, as with sd_notify: if systemd is not listening, nothing is sent.
+use std::env;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use recursum::sink::OutputSink;
+use recursum::{FileHash, HashError};
+
+use crate::color::{self, Role};
+use crate::progress::{HumanBytes, HumanDuration};
+
+/// Where journald listens for entries in its native protocol.
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+/// How often to update the status line.
+const STATUS_EVERY: Duration = Duration::from_secs(2);
+
+/// syslog priorities, as the journal's PRIORITY field.
+#[derive(Debug, Clone, Copy)]
+pub enum Priority {
+    Error = 3,
+    Warning = 4,
+    Info = 6,
+}
+
+/// Send `state`, newline-separated assignments like `READY=1`, to the service manager, if there is one.
+pub fn notify(state: &str) {
+    let socket = match env::var_os("NOTIFY_SOCKET") {
+        Some(s) => s,
+        None => return,
+    };
+    let sender = match UnixDatagram::unbound() {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    let bytes = socket.as_encoded_bytes();
+    // a leading @ is an abstract socket
+    let _ = match bytes.strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)
+            .and_then(|addr| sender.send_to_addr(state.as_bytes(), &addr)),
+        None => sender.send_to(state.as_bytes(), Path::new(&socket)),
+    };
+}
+
+/// Log `message` to the journal with the given extra fields, whose names should be upper case,
+/// e.g. `RECURSUM_PATH`; the run ID is always included, as `RECURSUM_RUN_ID`.
+pub fn journal(priority: Priority, message: &str, fields: &[(&str, String)]) {
+    let mut entry = Vec::new();
+    field(&mut entry, "MESSAGE", message);
+    field(&mut entry, "PRIORITY", &(priority as u8).to_string());
+    field(&mut entry, "SYSLOG_IDENTIFIER", "recursum");
+    if let Some(id) = crate::RUN_ID.get() {
+        field(&mut entry, "RECURSUM_RUN_ID", id);
+    }
+    for (name, value) in fields {
+        field(&mut entry, name, value);
+    }
+    if let Ok(socket) = UnixDatagram::unbound() {
+        let _ = socket.send_to(&entry, JOURNAL_SOCKET);
+    }
+}
+
+/// Append one field of a native protocol entry; values containing newlines (e.g. paths) are length-prefixed.
+fn field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+/// How often systemd expects a keep-alive, halved for safety, if it is watching this process.
+fn watchdog_interval() -> Option<Duration> {
+    let pid_matches = env::var("WATCHDOG_PID")
+        .map(|pid| pid.parse() == Ok(std::process::id()))
+        .unwrap_or(true);
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (pid_matches && usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// Tells systemd the service is ready when created, then keeps its status line up to date,
+/// keeps the watchdog fed while files are being hashed, and logs files which could not be read,
+/// files which changed while being read, and a summary at the end, to the journal.
+///
+/// The watchdog is only fed as results arrive, so a hung read lets it fire;
+/// WatchdogSec= must be longer than the largest file takes to hash.
+pub struct Systemd {
+    started: Instant,
+    watchdog: Option<Duration>,
+    fed: Instant,
+    updated: Instant,
+    files: u64,
+    bytes: u64,
+    errors: u64,
+    unstable: u64,
+}
+
+impl Systemd {
+    pub fn new(input: &str) -> Self {
+        if env::var_os("NOTIFY_SOCKET").is_none() {
+            let msg = "warning: --systemd given, but NOTIFY_SOCKET is not set; only logging to the journal";
+            eprintln!("{}", color::stderr(Role::Warning, msg));
+        }
+        // each line of a notification is one assignment
+        let oneline = input.replace('\n', "\\n");
+        notify(&format!("READY=1\nSTATUS=hashing {}", oneline));
+        journal(
+            Priority::Info,
+            &format!("hashing {}", input),
+            &[("RECURSUM_INPUT", input.to_string())],
+        );
+        let now = Instant::now();
+        Self {
+            started: now,
+            watchdog: watchdog_interval(),
+            fed: now,
+            updated: now,
+            files: 0,
+            bytes: 0,
+            errors: 0,
+            unstable: 0,
+        }
+    }
+
+    fn status(&self) -> String {
+        let seconds = self.started.elapsed().as_secs_f64();
+        format!(
+            "hashed {} files ({}), {}/s; {} unreadable",
+            self.files,
+            HumanBytes(self.bytes),
+            HumanBytes((self.bytes as f64 / seconds.max(0.001)) as u64),
+            self.errors
+        )
+    }
+
+    fn tick(&mut self) {
+        let mut state = Vec::new();
+        if self.watchdog.is_some_and(|w| self.fed.elapsed() >= w) {
+            self.fed = Instant::now();
+            state.push("WATCHDOG=1".to_string());
+        }
+        if self.updated.elapsed() >= STATUS_EVERY {
+            self.updated = Instant::now();
+            state.push(format!("STATUS={}", self.status()));
+        }
+        if !state.is_empty() {
+            notify(&state.join("\n"));
+        }
+    }
+}
+
+impl OutputSink for Systemd {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        self.files += 1;
+        self.bytes += result.size;
+        if !result.stable {
+            self.unstable += 1;
+            let path = result.path.to_string_lossy();
+            journal(
+                Priority::Warning,
+                &format!("{} changed while being hashed", path),
+                &[("RECURSUM_PATH", path.to_string())],
+            );
+        }
+        self.tick();
+        Ok(())
+    }
+
+    fn on_error(&mut self, error: &HashError) -> io::Result<()> {
+        self.errors += 1;
+        journal(
+            Priority::Error,
+            &error.to_string(),
+            &[
+                ("RECURSUM_PATH", error.path.to_string_lossy().into_owned()),
+                ("RECURSUM_ERROR", error.source.to_string()),
+            ],
+        );
+        self.tick();
+        Ok(())
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        let elapsed = self.started.elapsed();
+        let message = format!("finished: {} in {}", self.status(), HumanDuration(elapsed));
+        notify(&format!("STOPPING=1\nSTATUS={}", message));
+        let priority = if self.errors > 0 || self.unstable > 0 {
+            Priority::Warning
+        } else {
+            Priority::Info
+        };
+        journal(
+            priority,
+            &message,
+            &[
+                ("RECURSUM_FILES", self.files.to_string()),
+                ("RECURSUM_BYTES", self.bytes.to_string()),
+                ("RECURSUM_ERRORS", self.errors.to_string()),
+                ("RECURSUM_UNSTABLE", self.unstable.to_string()),
+                ("RECURSUM_SECONDS", format!("{:.3}", elapsed.as_secs_f64())),
+            ],
+        );
+        Ok(())
+    }
+}
+
+/// Report that the run failed, before exiting.
+pub fn failed(error: &io::Error) {
+    let message = format!("failed: {}", error);
+    notify(&format!("STOPPING=1\nSTATUS={}", message));
+    journal(
+        Priority::Error,
+        &message,
+        &[("RECURSUM_ERROR", error.to_string())],
+    );
+}
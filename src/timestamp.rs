@@ -0,0 +1,204 @@
+//! RFC 3161 timestamps with --timestamp-url: once the manifest is written, a time-stamping authority
+//! signs its SHA-256 digest with the time, proving that the manifest (and so the files it describes)
+//! existed then.
+//!
+//! The authority's response is stored next to the manifest as `<manifest>.tsr`, and can be checked with e.g.
+//! `openssl ts -verify -data hashes.tsv -in hashes.tsv.tsr -CAfile tsa.pem`.
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+use recursum::sink::OutputSink;
+use recursum::{FileHash, HashError};
+
+/// Appended to the manifest's name for the file holding the response.
+pub const EXTENSION: &str = "tsr";
+const TIMEOUT: Duration = Duration::from_secs(60);
+/// DER of the AlgorithmIdentifier for SHA-256 (OID 2.16.840.1.101.3.4.2.1, with NULL parameters).
+const SHA256_ALGORITHM: [u8; 15] = [
+    0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05, 0x00,
+];
+const SEQUENCE: u8 = 0x30;
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const BOOLEAN: u8 = 0x01;
+/// PKIStatus values meaning the token was issued: granted and grantedWithMods.
+const GRANTED: [u8; 2] = [0, 1];
+
+/// A DER element with the given tag and contents.
+fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = contents.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .iter()
+            .copied()
+            .skip_while(|b| *b == 0)
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(contents);
+    out
+}
+
+/// A DER INTEGER holding a non-negative number.
+fn der_integer(n: u64) -> Vec<u8> {
+    let mut bytes: Vec<u8> = n
+        .to_be_bytes()
+        .iter()
+        .copied()
+        .skip_while(|b| *b == 0)
+        .collect();
+    if bytes.first().is_none_or(|b| b & 0x80 != 0) {
+        bytes.insert(0, 0);
+    }
+    der(INTEGER, &bytes)
+}
+
+/// A TimeStampReq (version 1) for a SHA-256 digest, asking for the authority's certificate to be included.
+fn request(digest: &[u8], nonce: u64) -> Vec<u8> {
+    let imprint = der(
+        SEQUENCE,
+        &[&SHA256_ALGORITHM[..], &der(OCTET_STRING, digest)].concat(),
+    );
+    let body = [
+        der_integer(1),
+        imprint,
+        der_integer(nonce),
+        der(BOOLEAN, &[0xff]),
+    ]
+    .concat();
+    der(SEQUENCE, &body)
+}
+
+/// Split a DER element from the start of `buf`: its tag, contents, and what follows it.
+fn read_element(buf: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = buf.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > std::mem::size_of::<usize>() || rest.len() < n {
+            return None;
+        }
+        let len = rest[..n]
+            .iter()
+            .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+        (len, &rest[n..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+/// Check that a TimeStampResp grants a token for this digest.
+fn check_response(response: &[u8], digest: &[u8]) -> Result<(), String> {
+    let malformed = || "the response is not a timestamp response".to_string();
+    let (tag, contents, _) = read_element(response).ok_or_else(malformed)?;
+    if tag != SEQUENCE {
+        return Err(malformed());
+    }
+    let (tag, status_info, token) = read_element(contents).ok_or_else(malformed)?;
+    if tag != SEQUENCE {
+        return Err(malformed());
+    }
+    let (tag, status, _) = read_element(status_info).ok_or_else(malformed)?;
+    if tag != INTEGER {
+        return Err(malformed());
+    }
+    if !matches!(status, [s] if GRANTED.contains(s)) {
+        return Err(format!(
+            "the authority refused the request (status {})",
+            status.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64)
+        ));
+    }
+    // the token is a signed structure holding the digest; it must at least be for this manifest
+    if token.is_empty() {
+        return Err("the response holds no token".to_string());
+    }
+    if !token.windows(digest.len()).any(|w| w == digest) {
+        return Err("the token is not for this manifest's digest".to_string());
+    }
+    Ok(())
+}
+
+fn sha256_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Where the response for a manifest is stored.
+pub fn response_path(manifest: &Path) -> PathBuf {
+    let mut name = manifest.as_os_str().to_owned();
+    name.push(".");
+    name.push(EXTENSION);
+    PathBuf::from(name)
+}
+
+/// Timestamps the manifest once it has been written; it must come after the main output.
+pub struct Timestamp {
+    url: String,
+    manifest: PathBuf,
+}
+
+impl Timestamp {
+    pub fn new(url: &str, manifest: &Path) -> Self {
+        Self {
+            url: url.to_string(),
+            manifest: manifest.to_path_buf(),
+        }
+    }
+
+    fn stamp(&self) -> Result<(), String> {
+        let digest = sha256_file(&self.manifest)
+            .map_err(|e| format!("could not read {:?}: {}", self.manifest, e))?;
+        let nonce = u64::from_str_radix(&crate::new_run_id(), 16).expect("run IDs are hex");
+        let response = ureq::AgentBuilder::new()
+            .timeout(TIMEOUT)
+            .build()
+            .post(&self.url)
+            .set("Content-Type", "application/timestamp-query")
+            .send_bytes(&request(&digest, nonce))
+            .map_err(|e| e.to_string())?;
+        let mut body = Vec::new();
+        response
+            .into_reader()
+            .read_to_end(&mut body)
+            .map_err(|e| e.to_string())?;
+        check_response(&body, &digest)?;
+        let path = response_path(&self.manifest);
+        std::fs::write(&path, body).map_err(|e| format!("could not write {:?}: {}", path, e))
+    }
+}
+
+impl OutputSink for Timestamp {
+    fn on_result(&mut self, _result: &FileHash) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn on_error(&mut self, _error: &HashError) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        self.stamp()
+            .map_err(|e| io::Error::other(format!("could not timestamp the manifest: {}", e)))
+    }
+}
@@ -0,0 +1,362 @@
+//! A live dashboard on the terminal with --tui, for watching long runs: totals, a throughput graph,
+//! the file each hashing thread is reading, and the latest results and messages.
+//!
+//! While it is shown, anything written to stderr (warnings, errors, a panic) is captured and shown
+//! in the dashboard instead of scribbling over it, then written to stderr once it closes (Linux only).
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::{cursor, execute, terminal};
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::widgets::{Block, Borders, List, ListItem, Paragraph, Row, Sparkline, Table};
+use ratatui::{Frame, Terminal};
+
+use recursum::activity::Activity;
+use recursum::sink::OutputSink;
+use recursum::{FileHash, HashError};
+
+use crate::progress::{HumanBytes, HumanDuration};
+
+const REDRAW: Duration = Duration::from_millis(250);
+/// Results and messages kept for display; more than fit on most terminals.
+const RECENT: usize = 200;
+/// Seconds of throughput kept for the graph.
+const GRAPH_SECONDS: usize = 600;
+/// Characters of each digest shown among the recent results.
+const SHORT_DIGEST: usize = 16;
+
+/// Updated by the dashboard's sink methods (and the stderr capture), and read to draw it.
+#[derive(Default)]
+struct State {
+    files: u64,
+    bytes: u64,
+    errors: u64,
+    /// latest last
+    recent: VecDeque<String>,
+    messages: VecDeque<String>,
+}
+
+fn push_recent(list: &mut VecDeque<String>, item: String) {
+    if list.len() == RECENT {
+        list.pop_front();
+    }
+    list.push_back(item);
+}
+
+/// The newest items in `list` which fit in `height` rows, oldest first.
+fn newest(list: &VecDeque<String>, height: u16) -> Vec<ListItem<'_>> {
+    let shown = (height as usize).min(list.len());
+    list.iter()
+        .skip(list.len() - shown)
+        .map(|s| ListItem::new(s.as_str()))
+        .collect()
+}
+
+/// Everything the drawing thread keeps between frames.
+struct Drawer {
+    state: Arc<Mutex<State>>,
+    activity: &'static Activity,
+    started: Instant,
+    /// bytes hashed in each second so far, latest last
+    per_second: VecDeque<u64>,
+    sampled_at: Instant,
+    sampled_bytes: u64,
+}
+
+impl Drawer {
+    fn sample(&mut self, bytes: u64) {
+        if self.sampled_at.elapsed() < Duration::from_secs(1) {
+            return;
+        }
+        if self.per_second.len() == GRAPH_SECONDS {
+            self.per_second.pop_front();
+        }
+        self.per_second.push_back(bytes - self.sampled_bytes);
+        self.sampled_bytes = bytes;
+        self.sampled_at = Instant::now();
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let shared = self.state.clone();
+        let state = shared.lock().expect("dashboard lock poisoned");
+        let (files, bytes, errors) = (state.files, state.bytes, state.errors);
+        self.sample(bytes);
+        let elapsed = self.started.elapsed();
+        let rate = (bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64;
+
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(3),
+                Constraint::Length(7),
+                Constraint::Min(4),
+                Constraint::Length(10),
+            ])
+            .split(frame.area());
+
+        let totals = format!(
+            "{} files | {} | {} | {}/s | {} errors",
+            files,
+            HumanBytes(bytes),
+            HumanDuration(elapsed),
+            HumanBytes(rate),
+            errors
+        );
+        frame.render_widget(
+            Paragraph::new(totals).block(Block::default().borders(Borders::ALL).title("recursum")),
+            rows[0],
+        );
+
+        let width = rows[1].width.saturating_sub(2) as usize;
+        let shown: Vec<u64> = self
+            .per_second
+            .iter()
+            .skip(self.per_second.len().saturating_sub(width))
+            .copied()
+            .collect();
+        let peak = shown.iter().copied().max().unwrap_or(0);
+        frame.render_widget(
+            Sparkline::default()
+                .data(&shown)
+                .block(Block::default().borders(Borders::ALL).title(format!(
+                    "Throughput per second (peak {}/s)",
+                    HumanBytes(peak)
+                ))),
+            rows[1],
+        );
+
+        let workers = self.activity.workers();
+        let busy = workers.iter().filter(|w| w.reading.is_some()).count();
+        let worker_rows = workers.iter().map(|w| {
+            let (file, time) = match &w.reading {
+                Some((path, time)) => (
+                    path.display().to_string(),
+                    format!("{:.1}s", time.as_secs_f64()),
+                ),
+                None => ("(idle)".to_string(), String::new()),
+            };
+            Row::new(vec![w.number.to_string(), time, file])
+        });
+        frame.render_widget(
+            Table::new(
+                worker_rows,
+                [
+                    Constraint::Length(6),
+                    Constraint::Length(8),
+                    Constraint::Min(10),
+                ],
+            )
+            .header(Row::new(vec!["Thread", "For", "Reading"]))
+            .block(Block::default().borders(Borders::ALL).title(format!(
+                "Threads ({} of {} reading)",
+                busy,
+                workers.len()
+            ))),
+            rows[2],
+        );
+
+        let bottom = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+            .split(rows[3]);
+        let height = bottom[0].height.saturating_sub(2);
+        frame.render_widget(
+            List::new(newest(&state.recent, height)).block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("Recent results"),
+            ),
+            bottom[0],
+        );
+        frame.render_widget(
+            List::new(newest(&state.messages, height))
+                .block(Block::default().borders(Borders::ALL).title("Messages")),
+            bottom[1],
+        );
+    }
+}
+
+pub struct Dashboard {
+    state: Arc<Mutex<State>>,
+    stop: Arc<AtomicBool>,
+    /// None once closed
+    drawer: Option<JoinHandle<io::Result<()>>>,
+    #[cfg(target_os = "linux")]
+    capture: Option<capture::Capture>,
+}
+
+impl Dashboard {
+    /// Take over the terminal (on stderr) until finished or dropped.
+    pub fn open(activity: &'static Activity) -> io::Result<Self> {
+        let state = Arc::new(Mutex::new(State::default()));
+        #[cfg(target_os = "linux")]
+        let (capture, screen) = {
+            let (capture, screen) = capture::Capture::start(state.clone())?;
+            (Some(capture), Box::new(screen) as Box<dyn Write + Send>)
+        };
+        #[cfg(not(target_os = "linux"))]
+        let screen = Box::new(io::stderr()) as Box<dyn Write + Send>;
+
+        let mut term = Terminal::new(CrosstermBackend::new(screen))?;
+        execute!(
+            term.backend_mut(),
+            terminal::EnterAlternateScreen,
+            cursor::Hide
+        )?;
+        term.clear()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut drawer = Drawer {
+            state: state.clone(),
+            activity,
+            started: Instant::now(),
+            per_second: VecDeque::new(),
+            sampled_at: Instant::now(),
+            sampled_bytes: 0,
+        };
+        let stopping = stop.clone();
+        let handle = thread::spawn(move || {
+            let drawn = (|| {
+                while !stopping.load(Ordering::Relaxed) {
+                    term.draw(|f| drawer.draw(f))?;
+                    thread::sleep(REDRAW);
+                }
+                Ok(())
+            })();
+            // leave the terminal as it was, even if drawing failed
+            let restored = execute!(
+                term.backend_mut(),
+                terminal::LeaveAlternateScreen,
+                cursor::Show
+            );
+            drawn.and(restored)
+        });
+        Ok(Self {
+            state,
+            stop,
+            drawer: Some(handle),
+            #[cfg(target_os = "linux")]
+            capture,
+        })
+    }
+
+    /// Give the terminal back, then write out what was sent to stderr meanwhile.
+    fn close(&mut self) -> io::Result<()> {
+        let drawer = match self.drawer.take() {
+            Some(d) => d,
+            None => return Ok(()),
+        };
+        self.stop.store(true, Ordering::Relaxed);
+        let drawn = drawer.join().expect("dashboard thread panicked");
+        #[cfg(target_os = "linux")]
+        if let Some(capture) = self.capture.take() {
+            capture.finish()?;
+        }
+        drawn
+    }
+}
+
+impl OutputSink for Dashboard {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        let mut state = self.state.lock().expect("dashboard lock poisoned");
+        state.files += 1;
+        state.bytes += result.size;
+        let digest = &result.digest[..result.digest.len().min(SHORT_DIGEST)];
+        push_recent(
+            &mut state.recent,
+            format!("{}  {}", digest, result.path.display()),
+        );
+        Ok(())
+    }
+
+    fn on_error(&mut self, _error: &HashError) -> io::Result<()> {
+        // the message itself reaches the dashboard through stderr
+        self.state.lock().expect("dashboard lock poisoned").errors += 1;
+        Ok(())
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        self.close()
+    }
+}
+
+impl Drop for Dashboard {
+    /// Hashing failed, or recursum is panicking: the terminal must still be restored, and the reason shown.
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod capture {
+    use std::fs::File;
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::os::unix::io::{FromRawFd, RawFd};
+    use std::sync::{Arc, Mutex};
+    use std::thread::{self, JoinHandle};
+
+    use super::{push_recent, State};
+
+    const STDERR: RawFd = 2;
+
+    fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret)
+        }
+    }
+
+    /// Stderr redirected into a pipe, whose lines are kept and shown in the dashboard.
+    pub struct Capture {
+        /// the real stderr, to put back
+        saved: RawFd,
+        reader: JoinHandle<Vec<String>>,
+    }
+
+    impl Capture {
+        /// Start capturing, returning the real stderr to draw on.
+        pub fn start(state: Arc<Mutex<State>>) -> io::Result<(Self, File)> {
+            let mut fds = [0; 2];
+            check(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+            let [read_end, write_end] = fds;
+            let saved = check(unsafe { libc::dup(STDERR) })?;
+            let screen = unsafe { File::from_raw_fd(check(libc::dup(STDERR))?) };
+            check(unsafe { libc::dup2(write_end, STDERR) })?;
+            unsafe { libc::close(write_end) };
+            let pipe = unsafe { File::from_raw_fd(read_end) };
+            let reader = thread::spawn(move || {
+                let mut lines = Vec::new();
+                for line in BufReader::new(pipe).lines() {
+                    let line = match line {
+                        Ok(l) => l,
+                        Err(_) => break,
+                    };
+                    let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+                    push_recent(&mut state.messages, line.clone());
+                    lines.push(line);
+                }
+                lines
+            });
+            Ok((Self { saved, reader }, screen))
+        }
+
+        /// Put the real stderr back, and write what was captured to it.
+        pub fn finish(self) -> io::Result<()> {
+            // closes the pipe's last writer, so the reader finishes
+            check(unsafe { libc::dup2(self.saved, STDERR) })?;
+            unsafe { libc::close(self.saved) };
+            let lines = self.reader.join().expect("stderr capture panicked");
+            let stderr = io::stderr();
+            let mut out = stderr.lock();
+            for line in lines {
+                writeln!(out, "{}", line)?;
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Tuning the number of concurrent jobs and the read buffer size while hashing,
+//! by measuring throughput and keeping changes which improve it.
+//!
+//! Starting from conservative settings, each change is tried for a short window:
+//! more jobs, then a larger buffer, then a smaller buffer, then fewer jobs.
+//! A change which improves throughput is repeated; otherwise it is reverted and the next is tried.
+//! Once every change has stopped helping, or the tuning period is over, the best settings are kept.
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Time to measure each setting for.
+const WINDOW: Duration = Duration::from_millis(500);
+/// Relative improvement in throughput needed to keep a change.
+const MIN_IMPROVEMENT: f64 = 1.05;
+const MIN_BUFFER: usize = 8 * 1024;
+const MAX_BUFFER: usize = 4 * 1024 * 1024;
+const INITIAL_JOBS: usize = 2;
+const INITIAL_BUFFER: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Change {
+    MoreJobs,
+    LargerBuffer,
+    SmallerBuffer,
+    FewerJobs,
+}
+
+const CHANGES: &[Change] = &[
+    Change::MoreJobs,
+    Change::LargerBuffer,
+    Change::SmallerBuffer,
+    Change::FewerJobs,
+];
+
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    jobs: usize,
+    buffer: usize,
+}
+
+#[derive(Debug)]
+struct State {
+    window_start: Instant,
+    window_bytes: u64,
+    /// settings with the highest throughput so far, and that throughput in bytes per second
+    best: Settings,
+    best_rate: f64,
+    /// index into CHANGES of the change being tried, or None before the first window
+    trying: Option<usize>,
+    done: bool,
+}
+
+/// Settings shared between the hashing loop, which reads them, and finished files, which are measured.
+#[derive(Debug)]
+pub struct AutoTune {
+    jobs: AtomicUsize,
+    buffer: AtomicUsize,
+    max_jobs: usize,
+    deadline: Instant,
+    state: Mutex<State>,
+}
+
+impl AutoTune {
+    /// Tune for `period`, with at most `max_jobs` concurrent jobs.
+    pub fn new(max_jobs: usize, period: Duration) -> Self {
+        let now = Instant::now();
+        let initial = Settings {
+            jobs: INITIAL_JOBS.min(max_jobs).max(1),
+            buffer: INITIAL_BUFFER,
+        };
+        Self {
+            jobs: AtomicUsize::new(initial.jobs),
+            buffer: AtomicUsize::new(initial.buffer),
+            max_jobs: max_jobs.max(1),
+            deadline: now + period,
+            state: Mutex::new(State {
+                window_start: now,
+                window_bytes: 0,
+                best: initial,
+                best_rate: 0.0,
+                trying: None,
+                done: false,
+            }),
+        }
+    }
+
+    /// Number of files to hash concurrently.
+    pub fn jobs(&self) -> usize {
+        self.jobs.load(Ordering::Relaxed)
+    }
+
+    /// Size of the buffer to read files with.
+    pub fn buffer_size(&self) -> usize {
+        self.buffer.load(Ordering::Relaxed)
+    }
+
+    fn settings(&self) -> Settings {
+        Settings {
+            jobs: self.jobs(),
+            buffer: self.buffer_size(),
+        }
+    }
+
+    fn apply(&self, settings: Settings) {
+        self.jobs.store(settings.jobs, Ordering::Relaxed);
+        self.buffer.store(settings.buffer, Ordering::Relaxed);
+    }
+
+    /// The settings after making `change` to `from`, or None if that would go out of bounds.
+    fn changed(&self, from: Settings, change: Change) -> Option<Settings> {
+        let to = match change {
+            Change::MoreJobs => Settings {
+                jobs: (from.jobs * 2).min(self.max_jobs),
+                ..from
+            },
+            Change::FewerJobs => Settings {
+                jobs: (from.jobs / 2).max(1),
+                ..from
+            },
+            Change::LargerBuffer => Settings {
+                buffer: (from.buffer * 2).min(MAX_BUFFER),
+                ..from
+            },
+            Change::SmallerBuffer => Settings {
+                buffer: (from.buffer / 2).max(MIN_BUFFER),
+                ..from
+            },
+        };
+        if to.jobs == from.jobs && to.buffer == from.buffer {
+            None
+        } else {
+            Some(to)
+        }
+    }
+
+    /// Try changes in order from `idx`, starting with the first which is possible from the best settings.
+    fn try_from(&self, state: &mut State, idx: usize) {
+        for (i, change) in CHANGES.iter().enumerate().skip(idx) {
+            if let Some(settings) = self.changed(state.best, *change) {
+                state.trying = Some(i);
+                self.apply(settings);
+                return;
+            }
+        }
+        self.finish(state);
+    }
+
+    fn finish(&self, state: &mut State) {
+        state.done = true;
+        self.apply(state.best);
+    }
+
+    /// Count a hashed file towards the current window's throughput.
+    pub(crate) fn record(&self, bytes: u64) {
+        let mut state = self.state.lock().expect("tuning state poisoned");
+        if state.done {
+            return;
+        }
+        state.window_bytes += bytes;
+        let now = Instant::now();
+        let elapsed = now - state.window_start;
+        if elapsed < WINDOW {
+            return;
+        }
+        let rate = state.window_bytes as f64 / elapsed.as_secs_f64();
+        state.window_start = now;
+        state.window_bytes = 0;
+
+        match state.trying {
+            // first window: a baseline for the initial settings
+            None => {
+                state.best_rate = rate;
+                self.try_from(&mut state, 0);
+            }
+            Some(idx) => {
+                if rate > state.best_rate * MIN_IMPROVEMENT {
+                    // keep it, and go further the same way
+                    state.best = self.settings();
+                    state.best_rate = rate;
+                    self.try_from(&mut state, idx);
+                } else {
+                    self.try_from(&mut state, idx + 1);
+                }
+            }
+        }
+        if now >= self.deadline && !state.done {
+            self.finish(&mut state);
+        }
+    }
+}
+
+/// The best settings found so far.
+impl fmt::Display for AutoTune {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let settings = self.state.lock().expect("tuning state poisoned").best;
+        write!(
+            f,
+            "{} concurrent jobs and a {} KiB read buffer",
+            settings.jobs,
+            settings.buffer / 1024
+        )
+    }
+}
@@ -0,0 +1,280 @@
+//! Verify the files installed by system packages against the digests their package manager recorded,
+//! as `rpm -V` or `debsums` do, but hashing many files at once.
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use structopt::StructOpt;
+use tokio_stream::{iter, StreamExt};
+
+use recursum::{hash_stream, hashers, runtime, HashOptions};
+
+use crate::color::{self, Role};
+use crate::or_num_cpus;
+use crate::progress::Progress;
+
+/// File type bits of an rpm mode.
+const TYPE_MASK: u32 = 0o170000;
+const REGULAR_FILE: u32 = 0o100000;
+/// Fields after the path in each line of `rpm -q --dump`:
+/// size, mtime, digest, mode, owner, group, isconfig, isdoc, rdev and symlink target.
+const RPM_DUMP_FIELDS: usize = 10;
+
+#[derive(Debug, StructOpt)]
+pub struct VerifyPkgOpt {
+    /// Package file lists with digests: the output of `rpm -q --dump` (e.g. `rpm -qa --dump > installed.txt`), or dpkg md5sums files (e.g. /var/lib/dpkg/info/*.md5sums). - reads stdin.
+    #[structopt(parse(from_os_str), required = true, min_values = 1)]
+    manifests: Vec<PathBuf>,
+    /// Directory the packages were installed into.
+    #[structopt(long = "root", parse(from_os_str), default_value = "/")]
+    root: PathBuf,
+    /// Do not verify files which rpm marks as configuration, which are expected to change.
+    #[structopt(long = "skip-config")]
+    skip_config: bool,
+    /// Do not report files which do not exist, e.g. documentation excluded at install time.
+    #[structopt(long = "ignore-missing")]
+    ignore_missing: bool,
+    /// Hashing threads.
+    #[structopt(short = "t", long = "threads")]
+    threads: Option<usize>,
+    /// Do not show progress information.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+}
+
+/// An installed file and what its package says it should contain.
+struct Entry {
+    /// As written by the package manager, relative to the root.
+    path: String,
+    digest: String,
+    size: Option<u64>,
+}
+
+/// How many files had each outcome.
+#[derive(Debug, Default)]
+struct Tally {
+    ok: usize,
+    tampered: usize,
+    missing: usize,
+    unreadable: usize,
+}
+
+/// The algorithm package managers use for digests of this length.
+fn algorithm_for_length(len: usize) -> Option<&'static str> {
+    match len {
+        32 => Some("md5"),
+        40 => Some("sha1"),
+        64 => Some("sha256"),
+        128 => Some("sha512"),
+        _ => None,
+    }
+}
+
+fn is_hex(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// A line of a dpkg md5sums file: digest, two spaces, path relative to the root.
+fn parse_md5sums(line: &str) -> Option<Entry> {
+    let (digest, path) = line.split_once("  ")?;
+    if digest.len() != 32 || !is_hex(digest) || path.is_empty() {
+        return None;
+    }
+    Some(Entry {
+        path: path.to_string(),
+        digest: digest.to_ascii_lowercase(),
+        size: None,
+    })
+}
+
+/// A line of `rpm -q --dump`, if it describes a regular file (and, with `skip_config`, not a configuration file);
+/// Err for lines which cannot be read.
+fn parse_rpm_dump(line: &str, skip_config: bool) -> Result<Option<Entry>, ()> {
+    let mut fields: Vec<_> = line.rsplitn(RPM_DUMP_FIELDS + 1, ' ').collect();
+    if fields.len() != RPM_DUMP_FIELDS + 1 {
+        return Err(());
+    }
+    fields.reverse();
+    let (path, size, digest, mode, is_config) =
+        (fields[0], fields[1], fields[3], fields[4], fields[7]);
+    let mode = u32::from_str_radix(mode, 8).map_err(|_| ())?;
+    if mode & TYPE_MASK != REGULAR_FILE || (skip_config && is_config == "1") {
+        return Ok(None);
+    }
+    // ghost files, which the package does not provide, have a digest of zeroes
+    if !is_hex(digest) || digest.bytes().all(|b| b == b'0') {
+        return Ok(None);
+    }
+    Ok(Some(Entry {
+        path: path.to_string(),
+        digest: digest.to_ascii_lowercase(),
+        size: Some(size.parse().map_err(|_| ())?),
+    }))
+}
+
+fn open(path: &Path) -> io::Result<Box<dyn Read>> {
+    if path.as_os_str() == "-" {
+        Ok(Box::new(io::stdin()))
+    } else {
+        Ok(Box::new(File::open(path)?))
+    }
+}
+
+/// The files listed in a package manifest of either kind, and how many lines could not be read.
+fn load(path: &Path, skip_config: bool) -> io::Result<(Vec<Entry>, usize)> {
+    let lines = BufReader::new(open(path)?)
+        .lines()
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut lines = lines.iter().filter(|l| !l.trim().is_empty()).peekable();
+    let is_md5sums = lines.peek().is_some_and(|l| parse_md5sums(l).is_some());
+    let mut entries = Vec::new();
+    let mut malformed = 0;
+    for line in lines {
+        let entry = if is_md5sums {
+            parse_md5sums(line).map(Some).ok_or(())
+        } else {
+            parse_rpm_dump(line, skip_config)
+        };
+        match entry {
+            Ok(Some(e)) => entries.push(e),
+            Ok(None) => (),
+            Err(()) => malformed += 1,
+        }
+    }
+    Ok((entries, malformed))
+}
+
+pub fn run(opt: VerifyPkgOpt) {
+    // digests of different lengths are checked with different algorithms, one at a time
+    let mut by_algorithm: BTreeMap<&str, Vec<Entry>> = BTreeMap::new();
+    let mut malformed = 0;
+    for path in &opt.manifests {
+        let (entries, bad) = load(path, opt.skip_config)
+            .unwrap_or_else(|e| panic!("could not read {:?}: {}", path, e));
+        malformed += bad;
+        for entry in entries {
+            match algorithm_for_length(entry.digest.len()) {
+                Some(a) => by_algorithm.entry(a).or_default().push(entry),
+                None => malformed += 1,
+            }
+        }
+    }
+    for algorithm in by_algorithm.keys() {
+        if hashers::get(algorithm).is_none() {
+            panic!(
+                "the packages use {}, which this build of recursum does not support",
+                algorithm
+            );
+        }
+    }
+    let n_files: usize = by_algorithm.values().map(Vec::len).sum();
+    if n_files == 0 {
+        panic!("the manifests list no files to verify");
+    }
+
+    let threads = or_num_cpus(opt.threads);
+    let progress = if opt.quiet {
+        None
+    } else {
+        let total_bytes = by_algorithm
+            .values()
+            .flatten()
+            .map(|e| e.size)
+            .sum::<Option<u64>>();
+        Some(Progress::bounded(n_files as u64, total_bytes))
+    };
+    let warn = |msg: String| match &progress {
+        Some(p) => p.println(msg),
+        None => eprintln!("{}", msg),
+    };
+
+    let rt = runtime(threads, false).unwrap();
+    let tally = rt.block_on(async {
+        let stdout = io::stdout();
+        let mut out = BufWriter::new(stdout.lock());
+        let mut tally = Tally::default();
+        for (algorithm, entries) in &by_algorithm {
+            let hash_opts = HashOptions {
+                hasher: hashers::get(algorithm).expect("algorithm was checked"),
+                jobs: threads,
+                ..Default::default()
+            };
+            let paths: Vec<_> = entries
+                .iter()
+                .map(|e| opt.root.join(e.path.trim_start_matches('/')))
+                .collect();
+            let mut results = hash_stream(iter(paths), hash_opts);
+            for entry in entries {
+                let result = results.next().await.expect("one result per path");
+                if let Some(p) = &progress {
+                    let size = entry
+                        .size
+                        .or_else(|| result.as_ref().ok().map(|h| h.size))
+                        .unwrap_or(0);
+                    p.update(Path::new(&entry.path), size);
+                }
+                let status = match result {
+                    Ok(hashed) if hashed.digest == entry.digest => {
+                        tally.ok += 1;
+                        continue;
+                    }
+                    Ok(_) => {
+                        tally.tampered += 1;
+                        "FAILED"
+                    }
+                    Err(e) if e.source.kind() == io::ErrorKind::NotFound => {
+                        if opt.ignore_missing {
+                            continue;
+                        }
+                        tally.missing += 1;
+                        "MISSING"
+                    }
+                    Err(e) => {
+                        warn(color::stderr(Role::Bad, format!("recursum: {}", e)).to_string());
+                        tally.unreadable += 1;
+                        "FAILED open or read"
+                    }
+                };
+                let status = color::stdout(Role::of_status(status), status);
+                writeln!(out, "{}: {}", entry.path, status).expect("could not write output");
+            }
+        }
+        out.flush().expect("could not write output");
+        tally
+    });
+    if let Some(p) = &progress {
+        p.finish();
+    }
+
+    eprintln!(
+        "{} of {} package files verified",
+        color::stderr(Role::Highlight, tally.ok),
+        n_files
+    );
+    if malformed > 0 {
+        let msg = format!("WARNING: {} line(s) improperly formatted", malformed);
+        eprintln!("{}", color::stderr(Role::Warning, msg));
+    }
+    if tally.missing > 0 {
+        let msg = format!("WARNING: {} package file(s) are missing", tally.missing);
+        eprintln!("{}", color::stderr(Role::Bad, msg));
+    }
+    if tally.unreadable > 0 {
+        let msg = format!(
+            "WARNING: {} package file(s) could not be read",
+            tally.unreadable
+        );
+        eprintln!("{}", color::stderr(Role::Bad, msg));
+    }
+    if tally.tampered > 0 {
+        let msg = format!(
+            "WARNING: {} package file(s) do NOT match their package",
+            tally.tampered
+        );
+        eprintln!("{}", color::stderr(Role::Bad, msg));
+    }
+    if tally.tampered > 0 || tally.missing > 0 || tally.unreadable > 0 {
+        std::process::exit(1);
+    }
+}
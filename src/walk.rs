@@ -0,0 +1,966 @@
+use std::cmp::Ordering;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+#[cfg(feature = "parallel-walk")]
+use std::sync::mpsc::sync_channel;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "parallel-walk")]
+use std::thread;
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+#[cfg(feature = "parallel-walk")]
+use ignore::{WalkBuilder, WalkState};
+#[cfg(feature = "parallel-walk")]
+use jwalk::Parallelism;
+
+/// Whether a file is hidden: its name starts with `.`,
+/// or it has the platform's hidden flag (the hidden attribute on Windows, `UF_HIDDEN` on macOS).
+pub fn is_hidden(path: &Path) -> bool {
+    let dotted = path
+        .file_name()
+        .is_some_and(|n| n.as_encoded_bytes().first() == Some(&b'.'));
+    dotted || has_hidden_flag(path)
+}
+
+#[cfg(windows)]
+fn has_hidden_flag(path: &Path) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    fs::symlink_metadata(path).is_ok_and(|m| m.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+}
+
+#[cfg(target_os = "macos")]
+fn has_hidden_flag(path: &Path) -> bool {
+    use std::os::macos::fs::MetadataExt;
+    const UF_HIDDEN: u32 = 0x8000;
+    fs::symlink_metadata(path).is_ok_and(|m| m.st_flags() & UF_HIDDEN != 0)
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn has_hidden_flag(_path: &Path) -> bool {
+    false
+}
+
+/// Compare directory entries such that a depth-first walk produces paths in byte-wise sorted order,
+/// independent of platform: directory names are compared as if they ended with `/`.
+fn path_order(a: &OsStr, a_is_dir: bool, b: &OsStr, b_is_dir: bool) -> Ordering {
+    let a_bytes = a.as_encoded_bytes().iter().chain(a_is_dir.then_some(&b'/'));
+    let b_bytes = b.as_encoded_bytes().iter().chain(b_is_dir.then_some(&b'/'));
+    a_bytes.cmp(b_bytes)
+}
+
+/// Compare directory entries for the given order.
+/// If not sorting, only partition files from directories for `Order::ByDirectory`.
+pub(crate) fn entry_order(
+    order: Order,
+    sort: bool,
+    a: &OsStr,
+    a_is_dir: bool,
+    b: &OsStr,
+    b_is_dir: bool,
+) -> Ordering {
+    match (order, sort) {
+        (Order::ByDirectory, true) => a_is_dir.cmp(&b_is_dir).then_with(|| a.cmp(b)),
+        (Order::ByDirectory, false) => a_is_dir.cmp(&b_is_dir),
+        (_, true) => path_order(a, a_is_dir, b, b_is_dir),
+        (_, false) => Ordering::Equal,
+    }
+}
+
+/// Order in which files are produced by a walk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    /// Depth first, producing paths in byte-wise sorted order.
+    Depth,
+    /// All files at one depth before any at the next, sorted by name within each directory.
+    Breadth,
+    /// Depth first, but with every file in a directory before any of its subdirectories,
+    /// so that files from the same directory are hashed together.
+    ByDirectory,
+}
+
+impl FromStr for Order {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "depth" => Ok(Self::Depth),
+            "breadth" => Ok(Self::Breadth),
+            "by-directory" => Ok(Self::ByDirectory),
+            _ => Err(format!("unknown order '{}'", s)),
+        }
+    }
+}
+
+/// Directories skipped by `--prune-defaults`:
+/// version control metadata, dependency and build directories, and caches.
+pub const DEFAULT_PRUNE: &[&str] = &[
+    ".git",
+    ".hg",
+    ".svn",
+    ".bzr",
+    "CVS",
+    "node_modules",
+    "__pycache__",
+    ".pytest_cache",
+    ".mypy_cache",
+    ".tox",
+    ".venv",
+    "target",
+    ".cache",
+];
+
+/// Shell-style globs matched against paths, e.g. `photos/2023/**`.
+///
+/// `*` does not match `/`, but `**` matches any number of directories.
+/// A path matches if it or any of its ancestors does, so a directory's path selects everything under it.
+#[derive(Debug, Clone)]
+pub struct PathGlobs(GlobSet);
+
+impl PathGlobs {
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<Self, String> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            let pattern = pattern.as_ref();
+            let glob = GlobBuilder::new(pattern.strip_prefix("./").unwrap_or(pattern))
+                .literal_separator(true)
+                .build()
+                .map_err(|e| e.to_string())?;
+            builder.add(glob);
+        }
+        builder.build().map(Self).map_err(|e| e.to_string())
+    }
+
+    /// Read globs from a file, one per line; blank lines and lines starting with `#` are ignored.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::parse(&contents)
+    }
+
+    /// Globs from the contents of a file, as [PathGlobs::load].
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let patterns: Vec<&str> = contents
+            .lines()
+            .map(str::trim_end)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .collect();
+        Self::new(&patterns)
+    }
+
+    pub fn is_match(&self, path: &Path) -> bool {
+        let path = path.strip_prefix(".").unwrap_or(path);
+        path.ancestors()
+            .take_while(|p| !p.as_os_str().is_empty())
+            .any(|p| self.0.is_match(p))
+    }
+}
+
+/// Why an entry found by a walk was left out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// a hidden file or directory, with [WalkOptions::skip_hidden]
+    Hidden,
+    /// a directory named in [WalkOptions::prune]
+    Pruned,
+    /// a directory over the [WalkOptions::dir_limits]
+    TooLarge,
+    /// symlinks are not followed
+    Symlink,
+    /// a device, FIFO, socket or anything else which is neither a file, a directory nor a symlink
+    Special,
+    /// a directory which could not be listed, or an entry whose type could not be read
+    Unreadable,
+}
+
+impl SkipReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Hidden => "hidden",
+            Self::Pruned => "pruned",
+            Self::TooLarge => "too-large",
+            Self::Symlink => "symlink",
+            Self::Special => "special",
+            Self::Unreadable => "unreadable",
+        }
+    }
+}
+
+/// Options common to all walkers.
+#[derive(Debug, Clone)]
+pub struct WalkOptions {
+    /// Order of the walk, if the walker supports it; depth first by default.
+    pub order: Option<Order>,
+    /// If false, entries within each directory are produced in whatever order
+    /// the file system lists them.
+    pub sort: bool,
+    /// Skip hidden files, and hidden directories along with everything in them.
+    pub skip_hidden: bool,
+    /// Names of directories to skip, along with everything in them.
+    pub prune: HashSet<OsString>,
+    /// Warn about files whose paths differ only by case.
+    pub detect_case_collisions: bool,
+    /// Skip directories which are too large, along with everything in them.
+    pub dir_limits: Option<DirLimits>,
+    /// Where to send each entry which is left out, and why, if anywhere.
+    /// A skipped directory is sent rather than everything in it.
+    pub skipped: Option<Sender<(PathBuf, SkipReason)>>,
+}
+
+impl WalkOptions {
+    pub(crate) fn order(&self) -> Order {
+        self.order.unwrap_or(Order::Depth)
+    }
+
+    /// Whether to skip this entry below the root (and, if it is a directory, everything in it).
+    pub(crate) fn skip(&self, path: &Path, is_dir: bool) -> bool {
+        let reason = if self.skip_hidden && is_hidden(path) {
+            SkipReason::Hidden
+        } else if is_dir && path.file_name().is_some_and(|n| self.prune.contains(n)) {
+            SkipReason::Pruned
+        } else if is_dir && self.dir_limits.as_ref().is_some_and(|l| l.exceeded(path)) {
+            SkipReason::TooLarge
+        } else {
+            return false;
+        };
+        self.report(path, reason);
+        true
+    }
+
+    /// Note that an entry which is neither a file nor a directory was left out.
+    pub(crate) fn not_file(&self, path: impl FnOnce() -> PathBuf, file_type: fs::FileType) {
+        if self.skipped.is_none() || file_type.is_dir() || file_type.is_file() {
+            return;
+        }
+        let reason = if file_type.is_symlink() {
+            SkipReason::Symlink
+        } else {
+            SkipReason::Special
+        };
+        self.report(&path(), reason);
+    }
+
+    /// Warn about an entry which could not be read, and note that it was left out if its path is known.
+    pub(crate) fn unreadable(&self, path: Option<&Path>, error: impl Display) {
+        eprintln!("warning: {}", error);
+        if let Some(path) = path {
+            self.report(path, SkipReason::Unreadable);
+        }
+    }
+
+    fn report(&self, path: &Path, reason: SkipReason) {
+        if let Some(s) = &self.skipped {
+            // the receiver may have gone if hashing stopped early
+            let _ = s.send((path.to_path_buf(), reason));
+        }
+    }
+
+    /// Whether any entries may be skipped, i.e. whether `skip` needs to be called.
+    #[cfg(feature = "parallel-walk")]
+    fn prunes(&self) -> bool {
+        self.skip_hidden || !self.prune.is_empty() || self.dir_limits.is_some()
+    }
+}
+
+/// Limits on how many files, or bytes, a directory may hold (at any depth) to be walked,
+/// for skipping e.g. caches of millions of irrelevant files.
+///
+/// Each directory is counted before it is walked, stopping as soon as it exceeds a limit,
+/// so an enormous directory costs no more to skip than one at the limit.
+/// A directory within the limits has subdirectories within them too, so they are not counted again.
+#[derive(Debug, Clone, Default)]
+pub struct DirLimits {
+    files: Option<u64>,
+    bytes: Option<u64>,
+    /// directories found to be within the limits
+    within: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl DirLimits {
+    pub fn new(files: Option<u64>, bytes: Option<u64>) -> Self {
+        Self {
+            files,
+            bytes,
+            within: Arc::default(),
+        }
+    }
+
+    /// Whether `dir` is over a limit, saying so on stderr if it is.
+    /// Symlinks are not followed, and anything which cannot be read is not counted.
+    fn exceeded(&self, dir: &Path) -> bool {
+        let within = |d: &Path| {
+            self.within
+                .lock()
+                .expect("dir limits lock poisoned")
+                .contains(d)
+        };
+        if dir.ancestors().skip(1).any(within) {
+            return false;
+        }
+        let (mut files, mut bytes) = (0, 0);
+        let entries = walkdir::WalkDir::new(dir)
+            .min_depth(1)
+            .into_iter()
+            .filter_map(Result::ok);
+        for entry in entries.filter(|e| e.file_type().is_file()) {
+            files += 1;
+            if let Some(max) = self.files.filter(|max| files > *max) {
+                eprintln!("skipping {:?}, which has more than {} files", dir, max);
+                return true;
+            }
+            if let Some(max) = self.bytes {
+                bytes += entry.metadata().map_or(0, |m| m.len());
+                if bytes > max {
+                    eprintln!("skipping {:?}, which has more than {} bytes", dir, max);
+                    return true;
+                }
+            }
+        }
+        self.within
+            .lock()
+            .expect("dir limits lock poisoned")
+            .insert(dir.to_path_buf());
+        false
+    }
+}
+
+/// Traversal of a directory tree, producing the paths of every (non-symlink) file in it.
+pub trait Walker: Send + Sync {
+    /// Lazily iterate over every file under `root`.
+    fn files(&self, root: &Path) -> Box<dyn Iterator<Item = PathBuf> + Send>;
+}
+
+/// Parallel walk using jwalk; depth first or by directory.
+#[cfg(feature = "parallel-walk")]
+pub struct JWalker {
+    threads: usize,
+    options: WalkOptions,
+}
+
+#[cfg(feature = "parallel-walk")]
+impl Walker for JWalker {
+    fn files(&self, root: &Path) -> Box<dyn Iterator<Item = PathBuf> + Send> {
+        let mut walk = jwalk::WalkDir::new(root)
+            .parallelism(Parallelism::RayonNewPool(self.threads))
+            .skip_hidden(false)
+            .follow_links(false);
+        let options = self.options.clone();
+        let (order, sort) = (options.order(), options.sort);
+        if sort || order == Order::ByDirectory || options.prunes() {
+            walk = walk.process_read_dir(move |depth, _, _, children| {
+                // with no depth, the only child is the root, which is never skipped
+                if options.prunes() && depth.is_some() {
+                    children.retain(|entry| match entry {
+                        Ok(e) => !options.skip(&e.path(), e.file_type().is_dir()),
+                        Err(_) => true,
+                    });
+                }
+                children.sort_by(|a, b| match (a, b) {
+                    (Ok(a), Ok(b)) => entry_order(
+                        order,
+                        sort,
+                        a.file_name(),
+                        a.file_type().is_dir(),
+                        b.file_name(),
+                        b.file_type().is_dir(),
+                    ),
+                    (Ok(_), Err(_)) => Ordering::Greater,
+                    (Err(_), Ok(_)) => Ordering::Less,
+                    (Err(_), Err(_)) => Ordering::Equal,
+                })
+            });
+        }
+        let options = self.options.clone();
+        Box::new(walk.into_iter().filter_map(move |entry| {
+            let e = match entry {
+                Ok(e) => e,
+                Err(err) => {
+                    options.unreadable(err.path(), &err);
+                    return None;
+                }
+            };
+            // a directory which could not be listed is produced as usual, with the error attached
+            if let Some(err) = &e.read_children_error {
+                options.unreadable(Some(&e.path()), err);
+                return None;
+            }
+            if e.file_type().is_file() {
+                Some(e.path())
+            } else {
+                options.not_file(|| e.path(), e.file_type());
+                None
+            }
+        }))
+    }
+}
+
+/// Parallel walk using the `ignore` crate; files are produced in no particular order.
+///
+/// No ignore files are applied.
+#[cfg(feature = "parallel-walk")]
+pub struct IgnoreWalker {
+    threads: usize,
+    queue_len: usize,
+    options: WalkOptions,
+}
+
+#[cfg(feature = "parallel-walk")]
+impl Walker for IgnoreWalker {
+    fn files(&self, root: &Path) -> Box<dyn Iterator<Item = PathBuf> + Send> {
+        let mut builder = WalkBuilder::new(root);
+        builder
+            .standard_filters(false)
+            .follow_links(false)
+            .threads(self.threads);
+        if self.options.prunes() {
+            let options = self.options.clone();
+            builder.filter_entry(move |e| {
+                e.depth() == 0 || !options.skip(e.path(), e.file_type().is_some_and(|t| t.is_dir()))
+            });
+        }
+        let walk = builder.build_parallel();
+        let (sender, receiver) = sync_channel(self.queue_len);
+        let options = self.options.clone();
+        thread::spawn(move || {
+            walk.run(|| {
+                let sender = sender.clone();
+                let options = options.clone();
+                Box::new(move |entry| {
+                    let e = match entry {
+                        Ok(e) => e,
+                        Err(err) => {
+                            options.unreadable(ignore_error_path(&err), &err);
+                            return WalkState::Continue;
+                        }
+                    };
+                    if let Some(t) = e.file_type().filter(|t| !t.is_file()) {
+                        options.not_file(|| e.path().to_path_buf(), t);
+                    } else if e.file_type().is_some() && sender.send(e.into_path()).is_err() {
+                        // receiver has hung up
+                        return WalkState::Quit;
+                    }
+                    WalkState::Continue
+                })
+            });
+        });
+        Box::new(receiver.into_iter())
+    }
+}
+
+/// The path an error from the `ignore` crate is about, if it names one.
+#[cfg(feature = "parallel-walk")]
+fn ignore_error_path(error: &ignore::Error) -> Option<&Path> {
+    match error {
+        ignore::Error::WithPath { path, .. } => Some(path),
+        ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => {
+            ignore_error_path(err)
+        }
+        _ => None,
+    }
+}
+
+/// Serial walk using walkdir, or a simple queue of directories for breadth-first order.
+pub struct SerialWalker {
+    options: WalkOptions,
+}
+
+impl Walker for SerialWalker {
+    fn files(&self, root: &Path) -> Box<dyn Iterator<Item = PathBuf> + Send> {
+        let walk = walkdir::WalkDir::new(root).follow_links(false);
+        let (order, sort) = (self.options.order(), self.options.sort);
+        let walk = match order {
+            Order::Breadth => return Box::new(BreadthFirst::new(root, self.options.clone())),
+            Order::Depth if !sort => walk,
+            _ => walk.sort_by(move |a, b| {
+                entry_order(
+                    order,
+                    sort,
+                    a.file_name(),
+                    a.file_type().is_dir(),
+                    b.file_name(),
+                    b.file_type().is_dir(),
+                )
+            }),
+        };
+        let options = self.options.clone();
+        let reporting = self.options.clone();
+        Box::new(
+            walk.into_iter()
+                .filter_entry(move |e| {
+                    e.depth() == 0 || !options.skip(e.path(), e.file_type().is_dir())
+                })
+                .filter_map(move |entry| {
+                    let e = match entry {
+                        Ok(e) => e,
+                        Err(err) => {
+                            reporting.unreadable(err.path(), &err);
+                            return None;
+                        }
+                    };
+                    if e.file_type().is_file() {
+                        Some(e.into_path())
+                    } else {
+                        reporting.not_file(|| e.path().to_path_buf(), e.file_type());
+                        None
+                    }
+                }),
+        )
+    }
+}
+
+/// Breadth-first iteration, reading one directory at a time.
+struct BreadthFirst {
+    dirs: VecDeque<PathBuf>,
+    files: std::vec::IntoIter<PathBuf>,
+    options: WalkOptions,
+}
+
+impl BreadthFirst {
+    fn new(root: &Path, options: WalkOptions) -> Self {
+        let mut dirs = VecDeque::new();
+        dirs.push_back(root.to_path_buf());
+        Self {
+            dirs,
+            files: Vec::new().into_iter(),
+            options,
+        }
+    }
+}
+
+impl Iterator for BreadthFirst {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<PathBuf> {
+        loop {
+            if let Some(f) = self.files.next() {
+                return Some(f);
+            }
+            let dir = self.dirs.pop_front()?;
+            let listing = match fs::read_dir(&dir) {
+                Ok(listing) => listing,
+                Err(e) => {
+                    let msg = format!("could not list {:?}: {}", dir, e);
+                    self.options.unreadable(Some(&dir), msg);
+                    continue;
+                }
+            };
+            let options = &self.options;
+            let mut entries: Vec<_> = listing
+                .filter_map(|entry| {
+                    let e = match entry {
+                        Ok(e) => e,
+                        Err(err) => {
+                            let msg = format!("could not read a directory entry: {}", err);
+                            options.unreadable(None, msg);
+                            return None;
+                        }
+                    };
+                    match e.file_type() {
+                        Ok(file_type) => Some((e, file_type)),
+                        Err(err) => {
+                            let path = e.path();
+                            let msg = format!("could not read the type of {:?}: {}", path, err);
+                            options.unreadable(Some(&path), msg);
+                            None
+                        }
+                    }
+                })
+                .filter(|(e, t)| !options.skip(&e.path(), t.is_dir()))
+                .collect();
+            if self.options.sort {
+                entries.sort_by_key(|(e, _)| e.file_name());
+            }
+
+            let mut files = Vec::with_capacity(entries.len());
+            for (e, file_type) in entries {
+                if file_type.is_dir() {
+                    self.dirs.push_back(e.path());
+                } else if file_type.is_file() {
+                    files.push(e.path());
+                } else {
+                    self.options.not_file(|| e.path(), file_type);
+                }
+            }
+            self.files = files.into_iter();
+        }
+    }
+}
+
+/// Serial, depth-first, unsorted walk which reads each directory as it goes, rather than all at once,
+/// so that memory does not grow with the number of entries in a directory.
+///
+/// One open directory is held for each level of depth being walked, and nothing else;
+/// the other walkers hold every entry of a directory (jwalk, several directories at once) while walking it.
+pub struct StreamWalker {
+    options: WalkOptions,
+}
+
+impl Walker for StreamWalker {
+    fn files(&self, root: &Path) -> Box<dyn Iterator<Item = PathBuf> + Send> {
+        let mut walk = StreamWalk {
+            options: self.options.clone(),
+            open: Vec::new(),
+        };
+        walk.open(root);
+        Box::new(walk)
+    }
+}
+
+struct StreamWalk {
+    options: WalkOptions,
+    /// directories being read, innermost last
+    open: Vec<fs::ReadDir>,
+}
+
+impl StreamWalk {
+    fn open(&mut self, dir: &Path) {
+        match fs::read_dir(dir) {
+            Ok(entries) => self.open.push(entries),
+            Err(e) => {
+                let msg = format!("could not list {:?}: {}", dir, e);
+                self.options.unreadable(Some(dir), msg);
+            }
+        }
+    }
+}
+
+impl Iterator for StreamWalk {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<PathBuf> {
+        loop {
+            let entry = match self.open.last_mut()?.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(e)) => {
+                    let msg = format!("could not read a directory entry: {}", e);
+                    self.options.unreadable(None, msg);
+                    continue;
+                }
+                None => {
+                    self.open.pop();
+                    continue;
+                }
+            };
+            let path = entry.path();
+            let file_type = match entry.file_type() {
+                Ok(t) => t,
+                Err(e) => {
+                    let msg = format!("could not read the type of {:?}: {}", path, e);
+                    self.options.unreadable(Some(&path), msg);
+                    continue;
+                }
+            };
+            if self.options.skip(&path, file_type.is_dir()) {
+                continue;
+            }
+            if file_type.is_dir() {
+                self.open(&path);
+            } else if file_type.is_file() {
+                return Some(path);
+            } else {
+                self.options.not_file(|| path, file_type);
+            }
+        }
+    }
+}
+
+/// Wraps another walker, warning on stderr about any file whose path differs from
+/// an earlier one only by case, which would clash on a case-insensitive file system.
+///
+/// Every path seen is kept in memory.
+pub struct CaseCollisionWalker {
+    pub(crate) inner: Box<dyn Walker>,
+}
+
+impl Walker for CaseCollisionWalker {
+    fn files(&self, root: &Path) -> Box<dyn Iterator<Item = PathBuf> + Send> {
+        let mut seen: HashMap<String, PathBuf> = HashMap::new();
+        Box::new(self.inner.files(root).inspect(move |path| {
+            let folded = path.to_string_lossy().to_lowercase();
+            match seen.entry(folded) {
+                Entry::Occupied(e) => eprintln!(
+                    "warning: paths differ only by case: {:?} and {:?}",
+                    e.get(),
+                    path
+                ),
+                Entry::Vacant(e) => {
+                    e.insert(path.clone());
+                }
+            }
+        }))
+    }
+}
+
+/// Paths to hash before any others, in order; see [PriorityWalker].
+#[derive(Debug, Clone)]
+pub struct PriorityList {
+    entries: Vec<PathBuf>,
+}
+
+/// Without `.` components, so that `./a/b` is under `a`.
+fn without_cur_dir(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| *c != Component::CurDir)
+        .collect()
+}
+
+/// Without `.` components, and with each `..` taking off the component before it (if there is one),
+/// without looking at the file system.
+fn lexically_normal(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::CurDir => (),
+            Component::ParentDir => match normal.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normal.pop();
+                }
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => (),
+                _ => normal.push(c),
+            },
+            _ => normal.push(c),
+        }
+    }
+    normal
+}
+
+/// Index of the first entry which is `path` or one of its ancestors.
+fn rank(entries: &[PathBuf], path: &Path) -> Option<usize> {
+    let path = without_cur_dir(path);
+    entries.iter().position(|e| path.starts_with(e))
+}
+
+impl PriorityList {
+    /// Read a list of files and directories, one per line; blank lines and lines starting with `#` are ignored.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let entries = fs::read_to_string(path)?
+            .lines()
+            .map(str::trim_end)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(PathBuf::from)
+            .collect();
+        Ok(Self { entries })
+    }
+
+    /// Entries with relative ones taken to be under `root`,
+    /// leaving out (with a warning) any which are not under it, e.g. `../elsewhere`.
+    fn resolve(&self, root: &Path) -> Vec<PathBuf> {
+        let absolute_root = std::path::absolute(root).map(|r| lexically_normal(&r));
+        self.entries
+            .iter()
+            .filter_map(|e| {
+                let relative = if e.is_absolute() {
+                    let entry = lexically_normal(e);
+                    absolute_root
+                        .as_ref()
+                        .ok()
+                        .and_then(|r| entry.strip_prefix(r).ok())
+                        .map(Path::to_path_buf)
+                } else {
+                    Some(lexically_normal(e))
+                };
+                match relative.filter(|r| r.components().next() != Some(Component::ParentDir)) {
+                    Some(r) => Some(without_cur_dir(&root.join(r))),
+                    None => {
+                        eprintln!(
+                            "warning: priority list entry {:?} is not under {:?}, so is ignored",
+                            e, root
+                        );
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Stably sort `paths` so that those under earlier entries come first, and those under none come last.
+    /// Relative entries are compared with the paths as given.
+    pub fn sort(&self, paths: &mut [PathBuf]) {
+        let entries: Vec<_> = self.entries.iter().map(|e| without_cur_dir(e)).collect();
+        paths.sort_by_cached_key(|p| rank(&entries, p).unwrap_or(usize::MAX));
+    }
+}
+
+/// Wraps another walker, yielding the files under each entry of a [PriorityList] in turn
+/// (relative entries being under the root), then every other file.
+///
+/// Entries are walked with the same options, but are walked even if they are under a directory which would be skipped.
+pub struct PriorityWalker {
+    inner: Arc<dyn Walker>,
+    list: PriorityList,
+}
+
+impl PriorityWalker {
+    pub fn new(inner: Box<dyn Walker>, list: PriorityList) -> Self {
+        Self {
+            inner: Arc::from(inner),
+            list,
+        }
+    }
+}
+
+impl Walker for PriorityWalker {
+    fn files(&self, root: &Path) -> Box<dyn Iterator<Item = PathBuf> + Send> {
+        let entries = Arc::new(self.list.resolve(root));
+        let (inner, entries_) = (Arc::clone(&self.inner), Arc::clone(&entries));
+        // each walk only starts once the one before is finished
+        let prioritised = (0..entries.len()).flat_map(move |idx| {
+            let entry = &entries_[idx];
+            let files: Box<dyn Iterator<Item = PathBuf> + Send> = if entry.is_dir() {
+                inner.files(entry)
+            } else if entry.is_file() {
+                Box::new(std::iter::once(entry.clone()))
+            } else {
+                eprintln!("warning: priority list entry {:?} not found", entry);
+                Box::new(std::iter::empty())
+            };
+            let entries = Arc::clone(&entries_);
+            // anything under an earlier entry was already yielded
+            files.filter(move |p| rank(&entries, p) == Some(idx))
+        });
+        let (inner, root) = (Arc::clone(&self.inner), root.to_path_buf());
+        let rest = std::iter::once(()).flat_map(move |_| {
+            let entries = Arc::clone(&entries);
+            inner
+                .files(&root)
+                .filter(move |p| rank(&entries, p).is_none())
+        });
+        Box::new(prioritised.chain(rest))
+    }
+}
+
+/// Wraps another walker, which must walk depth first in sorted order ([Order::Depth], sorting),
+/// to start part of the way through: yields the files whose paths relative to the root sort after `start`,
+/// then walks again from the beginning for the files up to and including it.
+/// Every file is still yielded once, even if `start` no longer exists.
+pub struct RotatedWalker {
+    inner: Arc<dyn Walker>,
+    start: PathBuf,
+}
+
+impl RotatedWalker {
+    pub fn new(inner: Box<dyn Walker>, start: PathBuf) -> Self {
+        Self {
+            inner: Arc::from(inner),
+            start,
+        }
+    }
+}
+
+/// Whether `path`, relative to `root`, sorts after `start` in a sorted depth-first walk.
+fn sorts_after(path: &Path, root: &Path, start: &Path) -> bool {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    relative.as_os_str().as_encoded_bytes() > start.as_os_str().as_encoded_bytes()
+}
+
+impl Walker for RotatedWalker {
+    fn files(&self, root: &Path) -> Box<dyn Iterator<Item = PathBuf> + Send> {
+        let (start, root_) = (self.start.clone(), root.to_path_buf());
+        let after = self
+            .inner
+            .files(root)
+            .skip_while(move |p| !sorts_after(p, &root_, &start));
+        let (inner, start, root) = (
+            Arc::clone(&self.inner),
+            self.start.clone(),
+            root.to_path_buf(),
+        );
+        // only walked once the first walk is finished, and only as far as `start`
+        let before = std::iter::once(()).flat_map(move |_| {
+            let (start, root_) = (start.clone(), root.clone());
+            inner
+                .files(&root)
+                .take_while(move |p| !sorts_after(p, &root_, &start))
+        });
+        Box::new(after.chain(before))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WalkerKind {
+    JWalk,
+    Ignore,
+    Serial,
+    Stream,
+}
+
+impl WalkerKind {
+    /// Pick a walker which supports the given order.
+    pub fn for_order(order: Option<Order>) -> Self {
+        match order {
+            Some(Order::Breadth) => Self::Serial,
+            _ if cfg!(feature = "parallel-walk") => Self::JWalk,
+            _ => Self::Serial,
+        }
+    }
+
+    /// Build a walker using the given number of threads, if it is parallel,
+    /// and a maximum number of paths to buffer ahead of the consumer.
+    pub fn build(
+        &self,
+        threads: usize,
+        queue_len: usize,
+        options: WalkOptions,
+    ) -> Result<Box<dyn Walker>, String> {
+        let detect_case_collisions = options.detect_case_collisions;
+        let walker: Box<dyn Walker> = match (self, options.order) {
+            (Self::JWalk, Some(Order::Breadth)) => {
+                return Err(
+                    "jwalk walker does not support breadth-first order; use walkdir".to_string(),
+                )
+            }
+            (Self::Ignore, Some(_)) => {
+                return Err("ignore walker is unordered; use jwalk or walkdir".to_string())
+            }
+            (Self::Stream, Some(Order::Breadth | Order::ByDirectory)) => {
+                return Err(
+                    "stream walker only walks depth first, unsorted; use jwalk or walkdir"
+                        .to_string(),
+                )
+            }
+            #[cfg(feature = "parallel-walk")]
+            (Self::JWalk, _) => Box::new(JWalker { threads, options }),
+            #[cfg(feature = "parallel-walk")]
+            (Self::Ignore, None) => Box::new(IgnoreWalker {
+                threads,
+                queue_len,
+                options,
+            }),
+            #[cfg(not(feature = "parallel-walk"))]
+            (Self::JWalk, _) | (Self::Ignore, None) => {
+                let _ = (threads, queue_len);
+                return Err(
+                    "parallel walkers were not compiled in (feature parallel-walk); use walkdir"
+                        .to_string(),
+                );
+            }
+            (Self::Serial, _) => Box::new(SerialWalker { options }),
+            (Self::Stream, _) => Box::new(StreamWalker { options }),
+        };
+        if detect_case_collisions {
+            Ok(Box::new(CaseCollisionWalker { inner: walker }))
+        } else {
+            Ok(walker)
+        }
+    }
+}
+
+impl FromStr for WalkerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jwalk" => Ok(Self::JWalk),
+            "ignore" => Ok(Self::Ignore),
+            "walkdir" | "serial" => Ok(Self::Serial),
+            "stream" => Ok(Self::Stream),
+            _ => Err(format!("unknown walker '{}'", s)),
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! POSTing results to an HTTP endpoint in JSON batches with --webhook,
+//! e.g. to push them into an inventory service without giving recursum its database credentials.
+//!
+//! Batches are sent from a background thread. If it falls behind, hashing waits for it,
+//! rather than holding an unbounded number of results in memory.
+use std::io;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, Sender};
+
+use recursum::sink::OutputSink;
+use recursum::{FileHash, HashError};
+
+use crate::sinks::{json_record, json_string};
+
+/// Batches waiting to be sent before hashing waits.
+const QUEUED_BATCHES: usize = 4;
+/// Attempts to deliver each batch before giving up.
+const ATTEMPTS: u32 = 6;
+/// Wait before the first retry, doubled for each one after.
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A batch of records as POSTed, e.g. `{"run_id":"...","sequence":0,"results":[{"path":...},...]}`.
+///
+/// `sequence` counts up from 0 within a run, so that the receiver can recognise a batch sent again after a failure.
+struct Batch {
+    sequence: u64,
+    records: Vec<String>,
+}
+
+impl Batch {
+    fn body(&self, run_id: &str) -> String {
+        format!(
+            r#"{{"run_id":{},"sequence":{},"results":[{}]}}"#,
+            json_string(run_id),
+            self.sequence,
+            self.records.join(",")
+        )
+    }
+}
+
+/// Parse a --webhook-header given as `NAME: VALUE`.
+pub fn parse_header(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected NAME: VALUE, got '{}'", s))?;
+    let name = name.trim();
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(format!("invalid header name in '{}'", s));
+    }
+    Ok((name.to_string(), value.trim().to_string()))
+}
+
+/// Whether a failed request may succeed if sent again.
+fn is_transient(e: &ureq::Error) -> bool {
+    match e {
+        ureq::Error::Status(code, _) => *code == 408 || *code == 429 || *code >= 500,
+        ureq::Error::Transport(_) => true,
+    }
+}
+
+/// Send the batch, retrying transient failures with exponential backoff.
+fn deliver(request: &ureq::Request, body: &str) -> Result<(), String> {
+    let mut wait = FIRST_RETRY;
+    let mut attempt = 1;
+    loop {
+        match request.clone().send_string(body) {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt < ATTEMPTS && is_transient(&e) => {
+                eprintln!(
+                    "warning: --webhook failed ({}); retrying in {}s",
+                    e,
+                    wait.as_secs()
+                );
+                thread::sleep(wait);
+                wait *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+pub struct Webhook {
+    batch_size: usize,
+    pending: Vec<String>,
+    sequence: u64,
+    algorithm: String,
+    run_id: String,
+    /// None once finished
+    batches: Option<Sender<Batch>>,
+    sender: Option<JoinHandle<Result<(), String>>>,
+}
+
+impl Webhook {
+    pub fn new(
+        url: &str,
+        headers: &[(String, String)],
+        batch_size: usize,
+        algorithm: &str,
+        run_id: &str,
+    ) -> Self {
+        let mut request = ureq::AgentBuilder::new()
+            .timeout(TIMEOUT)
+            .build()
+            .post(url)
+            .set("Content-Type", "application/json");
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        let (batches, to_send) = bounded::<Batch>(QUEUED_BATCHES);
+        let run = run_id.to_string();
+        let sender = thread::spawn(move || {
+            for batch in to_send {
+                deliver(&request, &batch.body(&run))
+                    .map_err(|e| format!("batch {}: {}", batch.sequence, e))?;
+            }
+            Ok(())
+        });
+        Self {
+            batch_size: batch_size.max(1),
+            pending: Vec::new(),
+            sequence: 0,
+            algorithm: algorithm.to_string(),
+            run_id: run_id.to_string(),
+            batches: Some(batches),
+            sender: Some(sender),
+        }
+    }
+
+    /// Queue the pending records, waiting if the sender is behind.
+    fn queue(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let batch = Batch {
+            sequence: self.sequence,
+            records: std::mem::take(&mut self.pending),
+        };
+        self.sequence += 1;
+        let sent = self
+            .batches
+            .as_ref()
+            .expect("output already finished")
+            .send(batch);
+        match sent {
+            Ok(()) => Ok(()),
+            // the sender has given up
+            Err(_) => self.finish_sending(),
+        }
+    }
+
+    /// Wait for every queued batch to be delivered, and report why any was not.
+    fn finish_sending(&mut self) -> io::Result<()> {
+        self.batches = None;
+        let outcome = match self.sender.take() {
+            Some(handle) => handle.join().expect("webhook sender panicked"),
+            None => return Ok(()),
+        };
+        outcome.map_err(|e| io::Error::other(format!("could not deliver to --webhook: {}", e)))
+    }
+}
+
+impl OutputSink for Webhook {
+    fn on_result(&mut self, result: &FileHash) -> io::Result<()> {
+        self.pending
+            .push(json_record(result, &self.algorithm, &self.run_id));
+        if self.pending.len() >= self.batch_size {
+            self.queue()?;
+        }
+        Ok(())
+    }
+
+    /// Files which could not be read are reported by the other outputs.
+    fn on_error(&mut self, _error: &HashError) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn on_finish(&mut self) -> io::Result<()> {
+        if self.batches.is_some() {
+            self.queue()?;
+        }
+        self.finish_sending()
+    }
+}